@@ -0,0 +1,5 @@
+-- "kind X newest first", restricted to the rows every read query selects. It supersedes
+-- event_kind, which is dropped by drop_event_kind_index rather than kept alongside it.
+CREATE INDEX CONCURRENTLY IF NOT EXISTS event_kind_created_at
+ON events (kind, created_at DESC)
+WHERE deleted = FALSE;
@@ -0,0 +1,4 @@
+-- "author + kind newest first", restricted to the rows every read query selects
+CREATE INDEX CONCURRENTLY IF NOT EXISTS event_pubkey_kind_created_at
+ON events (pubkey, kind, created_at DESC)
+WHERE deleted = FALSE;
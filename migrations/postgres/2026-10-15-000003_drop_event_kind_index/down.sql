@@ -0,0 +1 @@
+CREATE INDEX CONCURRENTLY IF NOT EXISTS event_kind ON events (kind);
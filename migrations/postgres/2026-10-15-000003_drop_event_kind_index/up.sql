@@ -0,0 +1,4 @@
+-- Every query filtering on kind also filters on deleted = FALSE, so event_kind_created_at
+-- serves all of them. Dropping the standalone index saves a write per insert; only ad-hoc
+-- queries on deleted rows by kind lose their index.
+DROP INDEX CONCURRENTLY IF EXISTS event_kind;
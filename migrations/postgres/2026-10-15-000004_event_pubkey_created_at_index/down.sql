@@ -0,0 +1 @@
+DROP INDEX CONCURRENTLY IF EXISTS event_pubkey_created_at;
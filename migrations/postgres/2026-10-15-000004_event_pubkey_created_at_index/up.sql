@@ -0,0 +1,5 @@
+-- Replaces event_pubkey: every read query filters on deleted = FALSE, so tombstoned rows
+-- don't need to be indexed, and the sort column lets "author newest first" skip sorting
+CREATE INDEX CONCURRENTLY IF NOT EXISTS event_pubkey_created_at
+ON events (pubkey, created_at DESC)
+WHERE deleted = FALSE;
@@ -0,0 +1 @@
+DROP INDEX CONCURRENTLY IF EXISTS event_created_at;
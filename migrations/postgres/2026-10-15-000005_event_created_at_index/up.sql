@@ -0,0 +1,4 @@
+-- Replaces event_date, restricted to the rows every read query selects
+CREATE INDEX CONCURRENTLY IF NOT EXISTS event_created_at
+ON events (created_at DESC)
+WHERE deleted = FALSE;
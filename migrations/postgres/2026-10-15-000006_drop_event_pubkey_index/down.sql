@@ -0,0 +1 @@
+CREATE INDEX CONCURRENTLY IF NOT EXISTS event_pubkey ON events (pubkey);
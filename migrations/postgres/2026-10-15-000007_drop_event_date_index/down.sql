@@ -0,0 +1 @@
+CREATE INDEX CONCURRENTLY IF NOT EXISTS event_date ON events (created_at);
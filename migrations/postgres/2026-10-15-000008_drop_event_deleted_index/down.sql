@@ -0,0 +1 @@
+CREATE INDEX CONCURRENTLY IF NOT EXISTS event_deleted ON events (deleted);
@@ -0,0 +1,3 @@
+-- A boolean index is rarely selective enough to be used; the partial indexes cover the
+-- deleted = FALSE case instead
+DROP INDEX CONCURRENTLY IF EXISTS event_deleted;
@@ -0,0 +1,3 @@
+-- The primary key starts with tag, so without this index joining tags to their event and
+-- cascading deletes from events scan the whole table
+CREATE INDEX CONCURRENTLY IF NOT EXISTS event_tags_event_id ON event_tags (event_id);
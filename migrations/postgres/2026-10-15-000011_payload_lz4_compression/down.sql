@@ -0,0 +1,7 @@
+DO $$
+BEGIN
+    IF current_setting('server_version_num')::int >= 140000 THEN
+        ALTER TABLE events ALTER COLUMN payload SET COMPRESSION default;
+    END IF;
+END
+$$;
@@ -0,0 +1,14 @@
+-- lz4 compresses and decompresses payloads faster than the default pglz at a similar ratio.
+-- Only affects newly written values, see NostrPostgres::recompress_payloads. Skipped before
+-- Postgres 14 and on servers built without lz4 support. The storage stays EXTENDED: the
+-- flatbuffer payloads are not compressed yet, so EXTERNAL would store them uncompressed.
+DO $$
+BEGIN
+    IF current_setting('server_version_num')::int >= 140000 THEN
+        ALTER TABLE events ALTER COLUMN payload SET COMPRESSION lz4;
+    END IF;
+EXCEPTION
+    WHEN feature_not_supported THEN
+        RAISE NOTICE 'lz4 compression is not supported by this server, keeping pglz';
+END
+$$;
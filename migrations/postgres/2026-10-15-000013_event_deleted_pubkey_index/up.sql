@@ -0,0 +1,5 @@
+-- Together with event_pubkey_created_at, which only covers live rows, this lets the
+-- statistics of a single author, deleted events included, use index scans
+CREATE INDEX CONCURRENTLY IF NOT EXISTS event_deleted_pubkey
+ON events (pubkey)
+WHERE deleted = TRUE;
@@ -0,0 +1,4 @@
+-- When the event was first stored, as Unix time. Unlike created_at it can't be set by the
+-- author, so statistics over time can rely on it. Existing events get the time of the
+-- migration; the default is stable, so no table rewrite.
+ALTER TABLE events ADD COLUMN seen_at BIGINT NOT NULL DEFAULT extract(epoch FROM now())::bigint;
@@ -0,0 +1,20 @@
+-- Authors whose events are no longer stored, see NostrPostgres::ban_author. The trigger
+-- skips their inserts silently, so every writer honours the list, COPY included.
+CREATE TABLE blocked_authors (
+    pubkey BYTEA PRIMARY KEY NOT NULL,
+    blocked_at BIGINT NOT NULL
+);
+
+CREATE FUNCTION skip_blocked_author() RETURNS trigger LANGUAGE plpgsql
+SET search_path FROM CURRENT AS $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM blocked_authors WHERE pubkey = NEW.pubkey) THEN
+        RETURN NULL;
+    END IF;
+    RETURN NEW;
+END
+$$;
+
+CREATE TRIGGER event_blocked_author
+BEFORE INSERT ON events
+FOR EACH ROW EXECUTE FUNCTION skip_blocked_author();
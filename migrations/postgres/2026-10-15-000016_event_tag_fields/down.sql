@@ -0,0 +1,2 @@
+ALTER TABLE event_tags_archive DROP COLUMN tag_fields;
+ALTER TABLE event_tags DROP COLUMN tag_fields;
@@ -0,0 +1,6 @@
+-- The elements of a tag after its name, so tag_fields[n] is element n of the tag:
+-- tag_fields[1] the value also kept in tag_value, tag_fields[2] e.g. a relay hint and
+-- tag_fields[3] a marker like "root". Existing rows get an empty array until
+-- NostrPostgres::reindex_tags rewrites them; the default is constant, so no table rewrite.
+ALTER TABLE event_tags ADD COLUMN tag_fields TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE event_tags_archive ADD COLUMN tag_fields TEXT[] NOT NULL DEFAULT '{}';
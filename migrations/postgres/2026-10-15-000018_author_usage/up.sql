@@ -0,0 +1,53 @@
+-- Live events and payload bytes of each author, for the storage quota, see
+-- NostrPostgresBuilder::quota. Statement triggers keep it current with every write,
+-- COPY, deletes and undeletes included, aggregating each statement's rows by author.
+CREATE TABLE author_usage (
+    pubkey BYTEA PRIMARY KEY NOT NULL,
+    events BIGINT NOT NULL,
+    bytes BIGINT NOT NULL
+);
+
+INSERT INTO author_usage (pubkey, events, bytes)
+SELECT pubkey, count(*), sum(octet_length(payload))
+FROM events WHERE NOT deleted GROUP BY pubkey;
+
+CREATE FUNCTION count_author_usage() RETURNS trigger LANGUAGE plpgsql
+SET search_path FROM CURRENT AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO author_usage AS u (pubkey, events, bytes)
+        SELECT pubkey, count(*), sum(octet_length(payload))
+        FROM new_rows WHERE NOT deleted GROUP BY pubkey
+        ON CONFLICT (pubkey) DO UPDATE
+        SET events = u.events + EXCLUDED.events, bytes = u.bytes + EXCLUDED.bytes;
+    ELSIF TG_OP = 'DELETE' THEN
+        UPDATE author_usage u SET events = u.events - d.events, bytes = u.bytes - d.bytes
+        FROM (SELECT pubkey, count(*) AS events, sum(octet_length(payload)) AS bytes
+              FROM old_rows WHERE NOT deleted GROUP BY pubkey) d
+        WHERE u.pubkey = d.pubkey;
+    ELSE
+        INSERT INTO author_usage AS u (pubkey, events, bytes)
+        SELECT pubkey, sum(events)::bigint, sum(bytes)::bigint FROM (
+            SELECT pubkey, 1 AS events, octet_length(payload) AS bytes
+            FROM new_rows WHERE NOT deleted
+            UNION ALL
+            SELECT pubkey, -1, -octet_length(payload) FROM old_rows WHERE NOT deleted
+        ) d GROUP BY pubkey HAVING sum(events) <> 0 OR sum(bytes) <> 0
+        ON CONFLICT (pubkey) DO UPDATE
+        SET events = u.events + EXCLUDED.events, bytes = u.bytes + EXCLUDED.bytes;
+    END IF;
+    RETURN NULL;
+END
+$$;
+
+CREATE TRIGGER event_usage_insert
+AFTER INSERT ON events REFERENCING NEW TABLE AS new_rows
+FOR EACH STATEMENT EXECUTE FUNCTION count_author_usage();
+
+CREATE TRIGGER event_usage_update
+AFTER UPDATE ON events REFERENCING OLD TABLE AS old_rows NEW TABLE AS new_rows
+FOR EACH STATEMENT EXECUTE FUNCTION count_author_usage();
+
+CREATE TRIGGER event_usage_delete
+AFTER DELETE ON events REFERENCING OLD TABLE AS old_rows
+FOR EACH STATEMENT EXECUTE FUNCTION count_author_usage();
@@ -0,0 +1,4 @@
+-- Lets the tags configured with NostrPostgresBuilder::case_insensitive_tags be matched by
+-- lower(tag_value) without scanning all values of the tag
+CREATE INDEX CONCURRENTLY IF NOT EXISTS event_tags_tag_lower_value
+ON event_tags (tag, lower(tag_value));
@@ -0,0 +1,33 @@
+-- The events table partitioned by month on created_at, see NostrPostgres::ensure_partitions.
+-- The primary key has to include the partition key; since the id hashes created_at, it is
+-- still as unique as the id alone.
+CREATE TABLE events (
+    id BYTEA NOT NULL,
+    pubkey BYTEA NOT NULL,
+    created_at BIGINT NOT NULL,
+    kind BIGINT NOT NULL,
+    payload BYTEA NOT NULL,
+    deleted BOOLEAN NOT NULL,
+    PRIMARY KEY (id, created_at)
+) PARTITION BY RANGE (created_at);
+
+-- Catches events outside the created partitions, so saving never fails for lack of one
+CREATE TABLE events_default PARTITION OF events DEFAULT;
+
+-- Same indexes as the unpartitioned layout, created on every partition
+CREATE INDEX event_created_at ON events (created_at DESC) WHERE deleted = FALSE;
+CREATE INDEX event_kind_created_at ON events (kind, created_at DESC) WHERE deleted = FALSE;
+CREATE INDEX event_pubkey_created_at ON events (pubkey, created_at DESC) WHERE deleted = FALSE;
+CREATE INDEX event_pubkey_kind_created_at
+ON events (pubkey, kind, created_at DESC)
+WHERE deleted = FALSE;
+
+-- A foreign key would have to include created_at, so tags don't reference their event
+CREATE TABLE event_tags (
+    tag TEXT NOT NULL,
+    tag_value TEXT NOT NULL,
+    event_id BYTEA NOT NULL,
+    PRIMARY KEY (tag, tag_value, event_id)
+);
+
+CREATE INDEX event_tags_event_id ON event_tags (event_id);
@@ -0,0 +1,2 @@
+DROP TABLE event_tags_archive;
+DROP TABLE events_archive;
@@ -0,0 +1,22 @@
+-- Cold storage for events moved out of the hot tables, see NostrPostgres::archive. Same
+-- shape as events and event_tags, without a foreign key so either side can be purged alone.
+CREATE TABLE events_archive (
+    id BYTEA PRIMARY KEY NOT NULL,
+    pubkey BYTEA NOT NULL,
+    created_at BIGINT NOT NULL,
+    kind BIGINT NOT NULL,
+    payload BYTEA NOT NULL,
+    deleted BOOLEAN NOT NULL
+);
+
+CREATE INDEX events_archive_created_at ON events_archive (created_at DESC);
+CREATE INDEX events_archive_pubkey_created_at ON events_archive (pubkey, created_at DESC);
+
+CREATE TABLE event_tags_archive (
+    tag TEXT NOT NULL,
+    tag_value TEXT NOT NULL,
+    event_id BYTEA NOT NULL,
+    PRIMARY KEY (tag, tag_value, event_id)
+);
+
+CREATE INDEX event_tags_archive_event_id ON event_tags_archive (event_id);
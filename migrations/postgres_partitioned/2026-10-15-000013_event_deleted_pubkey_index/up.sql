@@ -0,0 +1,6 @@
+-- Together with event_pubkey_created_at, which only covers live rows, this lets the
+-- statistics of a single author, deleted events included, use index scans. Partitioned
+-- tables can't be indexed concurrently.
+CREATE INDEX IF NOT EXISTS event_deleted_pubkey
+ON events (pubkey)
+WHERE deleted = TRUE;
@@ -0,0 +1,3 @@
+DROP TRIGGER event_blocked_author ON events;
+DROP FUNCTION skip_blocked_author();
+DROP TABLE blocked_authors;
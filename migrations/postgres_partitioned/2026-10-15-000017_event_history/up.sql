@@ -0,0 +1,14 @@
+-- Versions of replaceable and addressable events kept by the opt-in replaceable history,
+-- see NostrPostgresBuilder::replaceable_history. Only the current version stays in events;
+-- identifier is the d tag of addressable events and empty for replaceable ones.
+CREATE TABLE event_history (
+    id BYTEA PRIMARY KEY NOT NULL,
+    pubkey BYTEA NOT NULL,
+    kind BIGINT NOT NULL,
+    identifier TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    payload BYTEA NOT NULL,
+    seen_at BIGINT NOT NULL
+);
+
+CREATE INDEX event_history_coordinate ON event_history (pubkey, kind, identifier, created_at DESC);
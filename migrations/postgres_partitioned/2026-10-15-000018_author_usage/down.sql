@@ -0,0 +1,5 @@
+DROP TRIGGER event_usage_delete ON events;
+DROP TRIGGER event_usage_update ON events;
+DROP TRIGGER event_usage_insert ON events;
+DROP FUNCTION count_author_usage();
+DROP TABLE author_usage;
@@ -0,0 +1 @@
+DROP INDEX CONCURRENTLY IF EXISTS event_tags_tag_lower_value;
@@ -0,0 +1,181 @@
+use diesel::dsl::exists;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::{Array, Binary};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use nostr::event::EventId;
+use nostr::filter::Filter;
+use nostr_database::DatabaseError;
+use tracing::debug;
+
+use crate::model::EventDb;
+use crate::query::{Operation, build_filter_query_with_deleted, tagged};
+use crate::schema::postgres::{event_tags_archive, events, events_archive};
+
+/// Number of events moved per transaction by [`archive`]
+const ARCHIVE_BATCH: usize = 1000;
+
+/// moves the events and their tags to the archive tables in one statement
+const MOVE: &str = "WITH moved AS (\
+         DELETE FROM events WHERE id = ANY($1) \
+         RETURNING id, pubkey, created_at, kind, payload, deleted\
+     ), moved_tags AS (\
+         DELETE FROM event_tags WHERE event_id = ANY($1) RETURNING tag, tag_value, event_id, tag_fields\
+     ), archived_tags AS (\
+         INSERT INTO event_tags_archive (tag, tag_value, event_id, tag_fields) \
+         SELECT tag, tag_value, event_id, tag_fields FROM moved_tags ON CONFLICT DO NOTHING\
+     ) INSERT INTO events_archive (id, pubkey, created_at, kind, payload, deleted) \
+     SELECT id, pubkey, created_at, kind, payload, deleted FROM moved ON CONFLICT DO NOTHING";
+
+type ArchiveColumns = (
+    events_archive::id,
+    events_archive::pubkey,
+    events_archive::created_at,
+    events_archive::kind,
+    events_archive::payload,
+    events_archive::deleted,
+);
+
+const ARCHIVE_COLUMNS: ArchiveColumns = (
+    events_archive::id,
+    events_archive::pubkey,
+    events_archive::created_at,
+    events_archive::kind,
+    events_archive::payload,
+    events_archive::deleted,
+);
+
+/// moves the events matching `filter`, deleted ones included, with their tags from the hot
+/// tables to the archive, returning the number of moved events
+///
+/// Every batch is moved in its own statement, so an event is always either in the hot tables
+/// or in the archive.
+pub(crate) async fn archive(
+    db: &mut AsyncPgConnection,
+    filter: Filter,
+    statement_tags: bool,
+) -> Result<u64, DatabaseError> {
+    let mut remaining = filter.limit;
+    let mut total = 0;
+    loop {
+        let batch = remaining.map_or(ARCHIVE_BATCH, |r| r.min(ARCHIVE_BATCH));
+        if batch == 0 {
+            return Ok(total);
+        }
+        let ids: Vec<Vec<u8>> = tagged(
+            build_filter_query_with_deleted(filter.clone().limit(batch)).select(events::id),
+            Operation::Archive,
+            statement_tags,
+        )
+        .load(db)
+        .await
+        .map_err(DatabaseError::backend)?;
+        if ids.is_empty() {
+            return Ok(total);
+        }
+        let found = ids.len();
+        move_to_archive(db, ids, statement_tags)
+            .await
+            .map_err(DatabaseError::backend)?;
+        total += found as u64;
+        remaining = remaining.map(|r| r - found);
+        debug!("Archived {total} events");
+        if found < batch {
+            return Ok(total);
+        }
+    }
+}
+
+/// moves the events with the given ids and their tags to the archive in one statement
+pub(crate) async fn move_to_archive(
+    db: &mut AsyncPgConnection,
+    ids: Vec<Vec<u8>>,
+    statement_tags: bool,
+) -> QueryResult<usize> {
+    tagged(
+        diesel::sql_query(MOVE).bind::<Array<Binary>, _>(ids),
+        Operation::Archive,
+        statement_tags,
+    )
+    .execute(db)
+    .await
+}
+
+/// the archived events matching `filter`, deleted ones excluded
+///
+/// Tags are matched with `EXISTS` on the archived tags, so no `DISTINCT` is needed.
+pub(crate) fn archived_query<'a>(
+    filter: Filter,
+) -> events_archive::BoxedQuery<'a, Pg, diesel::dsl::SqlTypeOf<ArchiveColumns>> {
+    let mut query = events_archive::table
+        .select(ARCHIVE_COLUMNS)
+        .filter(events_archive::deleted.eq(false))
+        .order_by(events_archive::created_at.desc())
+        .into_boxed();
+
+    if let Some(limit) = filter.limit {
+        query = query.limit(limit as i64);
+    }
+
+    if let Some(ids) = filter.ids {
+        let values = ids
+            .iter()
+            .map(|id| id.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+        query = query.filter(events_archive::id.eq_any(values));
+    }
+
+    if let Some(authors) = filter.authors {
+        let values = authors
+            .iter()
+            .map(|a| a.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+        query = query.filter(events_archive::pubkey.eq_any(values));
+    }
+
+    if let Some(kinds) = filter.kinds {
+        let values = kinds.iter().map(|k| k.as_u16() as i64).collect::<Vec<_>>();
+        query = query.filter(events_archive::kind.eq_any(values));
+    }
+
+    if let Some(since) = filter.since {
+        query = query.filter(events_archive::created_at.ge(since.as_u64() as i64));
+    }
+
+    if let Some(until) = filter.until {
+        query = query.filter(events_archive::created_at.le(until.as_u64() as i64));
+    }
+
+    for (tag, values) in filter.generic_tags {
+        let values = values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        query = query.filter(exists(
+            event_tags_archive::table
+                .filter(event_tags_archive::event_id.eq(events_archive::id))
+                .filter(event_tags_archive::tag.eq(tag.to_string()))
+                .filter(event_tags_archive::tag_value.eq_any(values)),
+        ));
+    }
+
+    query
+}
+
+/// the archived row of `event_id`, deleted or not
+pub(crate) fn archived_by_id<'a>(
+    event_id: &EventId,
+) -> events_archive::BoxedQuery<'a, Pg, diesel::dsl::SqlTypeOf<ArchiveColumns>> {
+    events_archive::table
+        .select(ARCHIVE_COLUMNS)
+        .filter(events_archive::id.eq(event_id.as_bytes().to_vec()))
+        .into_boxed()
+}
+
+/// loads the archived events matching `filter`
+pub(crate) async fn query_archived(
+    db: &mut AsyncPgConnection,
+    filter: Filter,
+    statement_tags: bool,
+) -> QueryResult<Vec<EventDb>> {
+    tagged(archived_query(filter), Operation::Archive, statement_tags)
+        .load(db)
+        .await
+}
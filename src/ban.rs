@@ -0,0 +1,212 @@
+use diesel::prelude::*;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use nostr::Timestamp;
+use nostr::key::PublicKey;
+use nostr_database::DatabaseError;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::query::{Operation, tagged};
+use crate::schema::postgres::{blocked_authors, event_tags, events};
+
+/// Settings of [`NostrPostgres::ban_author`](crate::NostrPostgres::ban_author)
+#[derive(Debug, Clone)]
+pub struct BanOptions {
+    hard_delete: bool,
+    block: bool,
+    batch_size: usize,
+}
+
+impl Default for BanOptions {
+    fn default() -> Self {
+        Self {
+            hard_delete: false,
+            block: true,
+            batch_size: 1000,
+        }
+    }
+}
+
+impl BanOptions {
+    /// Remove the events and their tags instead of marking them deleted (default false)
+    ///
+    /// Marked events keep rejecting saves of the same ids; removed ones free the space.
+    pub fn hard_delete(mut self, hard_delete: bool) -> Self {
+        self.hard_delete = hard_delete;
+        self
+    }
+
+    /// Add the author to the blocklist, so later saves of their events are rejected (default
+    /// true)
+    pub fn block(mut self, block: bool) -> Self {
+        self.block = block;
+        self
+    }
+
+    /// Number of events deleted per statement (default 1000)
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+}
+
+/// Outcome of [`NostrPostgres::ban_author`](crate::NostrPostgres::ban_author)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BanReport {
+    /// Events deleted by this call
+    pub deleted: u64,
+    /// Whether the author was added to the blocklist by this call; false if already blocked
+    /// or blocking wasn't requested
+    pub blocked: bool,
+}
+
+/// blocks `pubkey` if requested, then deletes all their events batch by batch, each in its own
+/// transaction
+pub(crate) async fn ban_author(
+    db: &mut AsyncPgConnection,
+    pubkey: &PublicKey,
+    opts: BanOptions,
+    statement_tags: bool,
+) -> Result<BanReport, DatabaseError> {
+    let author = pubkey;
+    let pubkey = pubkey.as_bytes().to_vec();
+    let mut report = BanReport::default();
+    // blocked first, so no new events slip in behind the deletion
+    if opts.block {
+        report.blocked = block(db, &pubkey, statement_tags).await?;
+    }
+    let batch = opts.batch_size as i64;
+    loop {
+        let deleted = if opts.hard_delete {
+            remove_batch(db, &pubkey, batch, statement_tags).await?
+        } else {
+            mark_batch(db, &pubkey, batch, statement_tags).await?
+        };
+        if deleted == 0 {
+            break;
+        }
+        report.deleted += deleted;
+    }
+    info!("Banned author {author}: {} events deleted", report.deleted);
+    Ok(report)
+}
+
+/// marks the next batch of live events of `pubkey` deleted, returning how many
+async fn mark_batch(
+    db: &mut AsyncPgConnection,
+    pubkey: &[u8],
+    batch: i64,
+    statement_tags: bool,
+) -> Result<u64, DatabaseError> {
+    let ids: Vec<Vec<u8>> = tagged(
+        events::table
+            .select(events::id)
+            .filter(events::pubkey.eq(pubkey))
+            .filter(events::deleted.eq(false))
+            .limit(batch),
+        Operation::Delete,
+        statement_tags,
+    )
+    .load(db)
+    .await
+    .map_err(DatabaseError::backend)?;
+    if ids.is_empty() {
+        return Ok(0);
+    }
+    let marked = tagged(
+        diesel::update(events::table.filter(events::id.eq_any(&ids))).set(events::deleted.eq(true)),
+        Operation::Delete,
+        statement_tags,
+    )
+    .execute(db)
+    .await
+    .map_err(DatabaseError::backend)?;
+    Ok(marked as u64)
+}
+
+/// removes the next batch of events of `pubkey` with their tags, returning how many
+async fn remove_batch(
+    db: &mut AsyncPgConnection,
+    pubkey: &[u8],
+    batch: i64,
+    statement_tags: bool,
+) -> Result<u64, DatabaseError> {
+    db.transaction(|c| {
+        async move {
+            let ids: Vec<Vec<u8>> = tagged(
+                events::table
+                    .select(events::id)
+                    .filter(events::pubkey.eq(pubkey))
+                    .limit(batch),
+                Operation::Delete,
+                statement_tags,
+            )
+            .load(c)
+            .await?;
+            if ids.is_empty() {
+                return Ok(0);
+            }
+            // partitioned tables have no foreign key cascading to the tags
+            tagged(
+                diesel::delete(event_tags::table.filter(event_tags::event_id.eq_any(&ids))),
+                Operation::Delete,
+                statement_tags,
+            )
+            .execute(c)
+            .await?;
+            let removed = tagged(
+                diesel::delete(events::table.filter(events::id.eq_any(&ids))),
+                Operation::Delete,
+                statement_tags,
+            )
+            .execute(c)
+            .await?;
+            Ok::<_, diesel::result::Error>(removed as u64)
+        }
+        .scope_boxed()
+    })
+    .await
+    .map_err(DatabaseError::backend)
+}
+
+/// adds `pubkey` to the blocklist, returning false if it was already blocked
+async fn block(
+    db: &mut AsyncPgConnection,
+    pubkey: &[u8],
+    statement_tags: bool,
+) -> Result<bool, DatabaseError> {
+    let added = tagged(
+        diesel::insert_into(blocked_authors::table)
+            .values((
+                blocked_authors::pubkey.eq(pubkey),
+                blocked_authors::blocked_at.eq(Timestamp::now().as_u64() as i64),
+            ))
+            .on_conflict_do_nothing(),
+        Operation::Delete,
+        statement_tags,
+    )
+    .execute(db)
+    .await
+    .map_err(DatabaseError::backend)?;
+    Ok(added == 1)
+}
+
+/// removes `pubkey` from the blocklist, returning false if it wasn't blocked
+pub(crate) async fn unblock(
+    db: &mut AsyncPgConnection,
+    pubkey: &PublicKey,
+    statement_tags: bool,
+) -> Result<bool, DatabaseError> {
+    let removed = tagged(
+        diesel::delete(
+            blocked_authors::table.filter(blocked_authors::pubkey.eq(pubkey.as_bytes().to_vec())),
+        ),
+        Operation::Delete,
+        statement_tags,
+    )
+    .execute(db)
+    .await
+    .map_err(DatabaseError::backend)?;
+    Ok(removed == 1)
+}
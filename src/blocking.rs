@@ -0,0 +1,103 @@
+//! Synchronous wrapper around [`NostrPostgres`]
+//!
+//! [`BlockingNostrPostgres`] owns a Tokio runtime and blocks the calling thread on every call.
+//! It must not be used from within an async context: doing so returns an
+//! [`AsyncContextError`] instead of blocking a runtime thread.
+
+use nostr::event::{Event, EventId};
+use nostr::filter::Filter;
+use nostr_database::{DatabaseError, Events, NostrDatabase, SaveEventStatus};
+use tokio::runtime::{Builder, Handle, Runtime};
+
+use crate::builder::NostrPostgresBuilder;
+use crate::postgres::NostrPostgres;
+
+/// Blocking version of [`NostrPostgres`]
+#[derive(Debug)]
+pub struct BlockingNostrPostgres {
+    runtime: Runtime,
+    db: NostrPostgres,
+}
+
+impl BlockingNostrPostgres {
+    /// Create a new [`BlockingNostrPostgres`] instance with default options
+    pub fn new<C>(connection_string: C) -> Result<Self, DatabaseError>
+    where
+        C: AsRef<str>,
+    {
+        Self::from_builder(NostrPostgres::builder(connection_string))
+    }
+
+    /// Create a new [`BlockingNostrPostgres`] instance from a configured builder
+    pub fn from_builder(builder: NostrPostgresBuilder) -> Result<Self, DatabaseError> {
+        ensure_blocking_context()?;
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(DatabaseError::backend)?;
+        let db = runtime.block_on(builder.build())?;
+        Ok(Self { runtime, db })
+    }
+
+    /// The wrapped async instance
+    pub fn inner(&self) -> &NostrPostgres {
+        &self.db
+    }
+
+    /// Save [`Event`] into store
+    ///
+    /// **This method assumes that [`Event`] was already verified**
+    pub fn save_event(&self, event: &Event) -> Result<SaveEventStatus, DatabaseError> {
+        self.block_on(self.db.save_event(event))
+    }
+
+    /// Get [`Event`] by [`EventId`]
+    pub fn event_by_id(&self, event_id: &EventId) -> Result<Option<Event>, DatabaseError> {
+        self.block_on(NostrDatabase::event_by_id(&self.db, event_id))
+    }
+
+    /// Count the number of events found with [`Filter`]
+    pub fn count(&self, filter: Filter) -> Result<usize, DatabaseError> {
+        self.block_on(self.db.count(filter))
+    }
+
+    /// Query stored events
+    pub fn query(&self, filter: Filter) -> Result<Events, DatabaseError> {
+        self.block_on(self.db.query(filter))
+    }
+
+    /// Delete all events that match the [`Filter`]
+    pub fn delete(&self, filter: Filter) -> Result<(), DatabaseError> {
+        self.block_on(self.db.delete(filter))
+    }
+
+    fn block_on<F, T>(&self, future: F) -> Result<T, DatabaseError>
+    where
+        F: Future<Output = Result<T, DatabaseError>>,
+    {
+        ensure_blocking_context()?;
+        self.runtime.block_on(future)
+    }
+}
+
+fn ensure_blocking_context() -> Result<(), DatabaseError> {
+    match Handle::try_current() {
+        Ok(_) => Err(DatabaseError::backend(AsyncContextError)),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Returned when the blocking API is called from within an async context
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AsyncContextError;
+
+impl std::fmt::Display for AsyncContextError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the blocking API must not be called from within an async context"
+        )
+    }
+}
+
+impl std::error::Error for AsyncContextError {}
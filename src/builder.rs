@@ -0,0 +1,611 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use nostr::Timestamp;
+use nostr::filter::SingleLetterTag;
+use nostr_database::DatabaseError;
+use tracing::warn;
+
+use crate::error::{ConfigError, PoolAcquireError, SchemaDriftError};
+use crate::guard::QueryGuard;
+use crate::history::ReplaceableHistory;
+use crate::integrity::ReadVerification;
+use crate::migrations::postgres::run_migrations_with_schema;
+use crate::notify::DEFAULT_NOTIFICATION_CHANNEL;
+use crate::postgres::{NostrPostgres, build_pool};
+use crate::quota::Quota;
+use crate::rate_limit::RateLimit;
+use crate::retention::RetentionPolicy;
+use crate::retry::{ConnectRetry, RetryPolicy};
+use crate::subscription::SubscriptionOptions;
+use crate::tags::{TagIndexing, TagValueLimit};
+use crate::verify::SchemaValidation;
+
+/// `application_name` reported to Postgres unless configured otherwise
+pub(crate) const DEFAULT_APPLICATION_NAME: &str = "nostr-postgres-db";
+
+/// Builder for a [`NostrPostgres`] instance
+#[derive(Clone)]
+pub struct NostrPostgresBuilder {
+    connection_string: String,
+    retry: Option<ConnectRetry>,
+    read_replica: Option<String>,
+    warm_up: usize,
+    skip_migrations: bool,
+    partitioned: bool,
+    validation: Option<SchemaValidation>,
+    config: Config,
+}
+
+/// Runtime settings of a [`NostrPostgres`] instance
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    pub health_timeout: Duration,
+    pub close_timeout: Duration,
+    pub schema: Option<String>,
+    pub replica_fallback: bool,
+    pub query_timeout: Option<Duration>,
+    pub slow_operation_threshold: Option<Duration>,
+    pub analyze_after_bulk_load: bool,
+    pub statement_cache: bool,
+    pub pgbouncer_transaction_mode: bool,
+    pub recycling_method: RecyclingMethod,
+    pub max_connections: Option<usize>,
+    pub acquire_timeout: Option<Duration>,
+    pub application_name: String,
+    pub statement_tags: bool,
+    pub notifications: bool,
+    pub notification_channel: String,
+    pub subscriptions: SubscriptionOptions,
+    pub transient_retry: Option<RetryPolicy>,
+    pub quarantine: bool,
+    pub read_verification: ReadVerification,
+    pub tag_indexing: TagIndexing,
+    pub tag_value_limit: TagValueLimit,
+    pub case_insensitive_tags: BTreeSet<SingleLetterTag>,
+    pub query_guard: Option<QueryGuard>,
+    pub replaceable_history: Option<ReplaceableHistory>,
+    pub drop_tags_on_delete: bool,
+    pub retention: Option<RetentionPolicy>,
+    pub quota: Option<Quota>,
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// How a pooled connection is checked before it is handed out again
+///
+/// Connections failing the check are discarded and replaced; failures are logged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum RecyclingMethod {
+    /// Only check for open transactions
+    ///
+    /// A connection that died in the meantime fails on first use.
+    Fast,
+    /// Additionally run `SELECT 1` on the connection
+    #[default]
+    Verified,
+    /// Additionally run the given query on the connection
+    CustomQuery(String),
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            health_timeout: Duration::from_secs(5),
+            close_timeout: Duration::from_secs(30),
+            schema: None,
+            replica_fallback: true,
+            query_timeout: None,
+            slow_operation_threshold: None,
+            analyze_after_bulk_load: false,
+            statement_cache: true,
+            pgbouncer_transaction_mode: false,
+            recycling_method: RecyclingMethod::default(),
+            max_connections: None,
+            acquire_timeout: None,
+            application_name: DEFAULT_APPLICATION_NAME.to_string(),
+            statement_tags: false,
+            notifications: false,
+            notification_channel: DEFAULT_NOTIFICATION_CHANNEL.to_string(),
+            subscriptions: SubscriptionOptions::default(),
+            transient_retry: None,
+            quarantine: false,
+            read_verification: ReadVerification::Off,
+            tag_indexing: TagIndexing::default(),
+            tag_value_limit: TagValueLimit::default(),
+            case_insensitive_tags: BTreeSet::new(),
+            query_guard: None,
+            replaceable_history: None,
+            drop_tags_on_delete: false,
+            retention: None,
+            quota: None,
+            rate_limit: None,
+        }
+    }
+}
+
+impl Config {
+    fn validate(&self) -> Result<(), DatabaseError> {
+        if self.max_connections == Some(0) {
+            return Err(DatabaseError::backend(ConfigError::new(
+                "max_connections must be at least 1",
+            )));
+        }
+        if self.pgbouncer_transaction_mode && self.schema.is_some() {
+            return Err(DatabaseError::backend(ConfigError::new(
+                "a custom schema needs a session-level search_path, which PgBouncer \
+                 transaction mode does not keep; set the search_path on the database role instead",
+            )));
+        }
+        if self.replaceable_history.is_some() && !self.tag_indexing.is_indexed("d") {
+            return Err(DatabaseError::backend(ConfigError::new(
+                "the replaceable history needs the d tag indexed to tell addressable events apart",
+            )));
+        }
+        if let Some(limit) = &self.rate_limit
+            && !limit.is_valid()
+        {
+            return Err(DatabaseError::backend(ConfigError::new(
+                "the rate limit needs a burst of at least 1 and a finite positive rate",
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl NostrPostgresBuilder {
+    /// Create a new builder for the database with the given connection string
+    pub fn new<C>(connection_string: C) -> Self
+    where
+        C: AsRef<str>,
+    {
+        Self {
+            connection_string: connection_string.as_ref().to_string(),
+            retry: None,
+            read_replica: None,
+            warm_up: 0,
+            skip_migrations: false,
+            partitioned: false,
+            validation: None,
+            config: Config::default(),
+        }
+    }
+
+    /// the connection string and settings, for tests of the other constructors
+    #[cfg(test)]
+    pub(crate) fn parts(&self) -> (&str, &Config) {
+        (&self.connection_string, &self.config)
+    }
+
+    /// Maximum number of pooled connections (default: 4 per CPU core)
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.config.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Give up waiting for a pooled connection after `timeout` (default: wait indefinitely)
+    ///
+    /// Such failures are classified as [`PoolErrorKind::Exhausted`](crate::PoolErrorKind).
+    pub fn acquire_timeout(mut self, timeout: Duration) -> Self {
+        self.config.acquire_timeout = Some(timeout);
+        self
+    }
+
+    /// `application_name` shown in `pg_stat_activity` (default `nostr-postgres-db`)
+    ///
+    /// An `application_name` set in the connection string takes precedence; an empty name
+    /// leaves the connection string untouched.
+    pub fn application_name<S>(mut self, name: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.config.application_name = name.into();
+        self
+    }
+
+    /// Prefix statements with a `/* op:<operation> */` comment (default false)
+    ///
+    /// Lets operators attribute load per operation (`save`, `query`, `count`, `delete`,
+    /// `event_by_id`) in `pg_stat_activity` and `pg_stat_statements`.
+    pub fn statement_tags(mut self, enabled: bool) -> Self {
+        self.config.statement_tags = enabled;
+        self
+    }
+
+    /// `NOTIFY` the notification channel of every saved event (default false)
+    ///
+    /// The payload is an [`EventNotification`](crate::EventNotification) as JSON, sent in the
+    /// transaction of the save, so listeners only hear of committed events; duplicates and
+    /// rejected events are not notified. Costs one statement per save or batch.
+    /// [`NostrPostgres::bulk_load`] doesn't notify.
+    pub fn notifications(mut self, enabled: bool) -> Self {
+        self.config.notifications = enabled;
+        self
+    }
+
+    /// Channel of the notifications (default [`DEFAULT_NOTIFICATION_CHANNEL`])
+    pub fn notification_channel<S>(mut self, channel: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.config.notification_channel = channel.into();
+        self
+    }
+
+    /// Buffer size and overflow policy of the subscriptions (default
+    /// [`SubscriptionOptions::default`])
+    ///
+    /// Applies to [`NostrPostgres::subscribe`] and the other subscriptions of the instance.
+    pub fn subscriptions(mut self, options: SubscriptionOptions) -> Self {
+        self.config.subscriptions = options;
+        self
+    }
+
+    /// Which tags are written to `event_tags` (default [`TagIndexing::All`])
+    ///
+    /// Restricting the indexed tags keeps the tag table small for relays that only filter on
+    /// NIP-01 single-letter tags. Filters on a tag that is not indexed are rejected with an
+    /// [`UnindexedTagError`](crate::UnindexedTagError) instead of silently matching nothing.
+    /// Events saved under a previous policy keep their tag rows until they are reindexed.
+    pub fn tag_indexing(mut self, policy: TagIndexing) -> Self {
+        self.config.tag_indexing = policy;
+        self
+    }
+
+    /// How long tag values may get before they are skipped or truncated in `event_tags`
+    /// (default: skip values above 2048 bytes)
+    pub fn tag_value_limit(mut self, limit: TagValueLimit) -> Self {
+        self.config.tag_value_limit = limit;
+        self
+    }
+
+    /// Remove the tag rows of events when [`delete`](nostr_database::NostrDatabase::delete)
+    /// marks them deleted (default false)
+    ///
+    /// Deleted events are never returned, so their tag rows only grow the tag indexes and
+    /// slow tag filters down. The payload keeps the tags:
+    /// [`undelete`](NostrPostgres::undelete) restores the rows, and
+    /// [`reindex_tags`](NostrPostgres::reindex_tags) removes those of events deleted before.
+    pub fn drop_tags_on_delete(mut self, enabled: bool) -> Self {
+        self.config.drop_tags_on_delete = enabled;
+        self
+    }
+
+    /// Match the values of the given tags case-insensitively in query and count filters
+    /// (default none)
+    ///
+    /// For tags whose values differ only in case between clients, e.g. `t` topics, so `#t:
+    /// ["bitcoin"]` also matches `Bitcoin`. Values are compared by `lower(tag_value)`, backed by
+    /// the `event_tags_tag_lower_value` index created by the migrations, so which letters fold
+    /// depends on the collation of the database. Deletes, exports and statistics still match
+    /// exactly, as do all tags not given here, like the hex ids of `e` and `p` tags.
+    pub fn case_insensitive_tags<I>(mut self, tags: I) -> Self
+    where
+        I: IntoIterator<Item = SingleLetterTag>,
+    {
+        self.config.case_insensitive_tags = tags.into_iter().collect();
+        self
+    }
+
+    /// Bound the number of events a query may ask for (default none)
+    ///
+    /// For public relays, where a client sending `{}` would otherwise make every request read
+    /// the 10000 newest events; archival tools leave it off. Applies to
+    /// [`query`](nostr_database::NostrDatabase::query) and the other queries of client filters,
+    /// not to counts, negentropy, exports, deletes or
+    /// [`query_page_offset`](NostrPostgres::query_page_offset).
+    pub fn query_guard(mut self, guard: QueryGuard) -> Self {
+        self.config.query_guard = Some(guard);
+        self
+    }
+
+    /// Keep the replaced versions of replaceable and addressable events in a history (default
+    /// off)
+    ///
+    /// Without it every version stays in the event tables. With it only the newest version
+    /// of a coordinate stays there, so queries and lookups by id never return older ones,
+    /// while every saved version is also recorded in `event_history` for
+    /// [`history_by_coordinate`](NostrPostgres::history_by_coordinate), trimmed to the
+    /// retention of `history` on every save and by
+    /// [`trim_history`](NostrPostgres::trim_history). Versions are replaced when saving events
+    /// one by one or in batches, not by [`bulk_load`](NostrPostgres::bulk_load). Needs the
+    /// `d` tag indexed.
+    pub fn replaceable_history(mut self, history: ReplaceableHistory) -> Self {
+        self.config.replaceable_history = Some(history);
+        self
+    }
+
+    /// Remove events of some kinds once they are older than the rules of `policy` allow
+    /// (default none, keeping everything)
+    ///
+    /// Applied by [`apply_retention`](NostrPostgres::apply_retention), on demand or
+    /// periodically by [`spawn_retention_worker`](NostrPostgres::spawn_retention_worker).
+    pub fn retention(mut self, policy: RetentionPolicy) -> Self {
+        self.config.retention = Some(policy);
+        self
+    }
+
+    /// Bound the events each author stores (default none)
+    ///
+    /// Checked by [`save_event`](nostr_database::NostrDatabase::save_event) and
+    /// [`save_events`](NostrPostgres::save_events) against the usage counters, see
+    /// [`quota_usage`](NostrPostgres::quota_usage). An event beyond the quota is rejected, or
+    /// makes room by marking the author's oldest events deleted.
+    /// [`try_save_event`](NostrPostgres::try_save_event) reports such rejections as
+    /// [`SaveOutcome::OverQuota`](crate::SaveOutcome::OverQuota), `save_event` as
+    /// [`RejectedReason::Other`](nostr_database::RejectedReason::Other). `save_events` saves
+    /// the events of limited authors one by one, each in its own transaction. Imports, bulk
+    /// loads and `COPY` are administrative and counted but not checked.
+    pub fn quota(mut self, quota: Quota) -> Self {
+        self.config.quota = Some(quota);
+        self
+    }
+
+    /// Bound how fast each author saves events (default none)
+    ///
+    /// Checked by [`save_event`](nostr_database::NostrDatabase::save_event) and
+    /// [`save_events`](NostrPostgres::save_events) before the database is involved. Events
+    /// beyond the limit are rejected: [`try_save_event`](NostrPostgres::try_save_event) reports
+    /// them as [`SaveOutcome::RateLimited`](crate::SaveOutcome::RateLimited), `save_event` as
+    /// [`RejectedReason::Other`](nostr_database::RejectedReason::Other), and `save_events`
+    /// counts them as rejected. The state is per instance, see [`RateLimit`](crate::RateLimit).
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.config.rate_limit = Some(limit);
+        self
+    }
+
+    /// Retry the migrations and the initial connection with the given policy
+    pub fn retry(mut self, retry: ConnectRetry) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Retry reads and saves failing with a transient error, e.g. during a failover
+    /// (default off)
+    ///
+    /// Applies to the queries and event lookups, and to
+    /// [`save_event`](nostr_database::NostrDatabase::save_event) and
+    /// [`NostrPostgres::save_events`]: saving an event again is safe, as an event stored by
+    /// the failed attempt is detected as a duplicate on retry. Deletes and maintenance
+    /// operations are not retried.
+    pub fn retry_transient(mut self, policy: RetryPolicy) -> Self {
+        self.config.transient_retry = Some(policy);
+        self
+    }
+
+    /// Keep the ids of stored events whose payloads fail to decode (default false)
+    ///
+    /// Queries and exports skip such events with a warning and count them in
+    /// [`PoolStatus::decode_failures`](crate::PoolStatus::decode_failures) either way; with
+    /// this enabled the ids of the last 1000 are kept for
+    /// [`NostrPostgres::quarantined`].
+    pub fn quarantine(mut self, enabled: bool) -> Self {
+        self.config.quarantine = enabled;
+        self
+    }
+
+    /// Verify the stored events read by queries and lookups (default
+    /// [`ReadVerification::Off`])
+    ///
+    /// For databases written by several services: an event whose payload doesn't match its
+    /// row is logged and left out of query results, and looking it up by id returns an
+    /// [`IntegrityError`](crate::IntegrityError).
+    pub fn verify_on_read(mut self, verification: ReadVerification) -> Self {
+        self.config.read_verification = verification;
+        self
+    }
+
+    /// Deadline for [`NostrPostgres::ping`] and [`NostrPostgres::health`] (default 5s)
+    pub fn health_timeout(mut self, timeout: Duration) -> Self {
+        self.config.health_timeout = timeout;
+        self
+    }
+
+    /// Time [`NostrPostgres::close`] waits for in-flight operations (default 30s)
+    pub fn close_timeout(mut self, timeout: Duration) -> Self {
+        self.config.close_timeout = timeout;
+        self
+    }
+
+    /// Keep the tables in the given schema instead of the default search path
+    ///
+    /// The schema is created by the migrations if missing and set as `search_path` on every
+    /// pooled connection.
+    pub fn schema<S>(mut self, schema: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.config.schema = Some(schema.into());
+        self
+    }
+
+    /// Route read operations to the replica with the given connection string
+    ///
+    /// See [`NostrPostgres::with_read_replica`].
+    pub fn read_replica<C>(mut self, connection_string: C) -> Self
+    where
+        C: AsRef<str>,
+    {
+        self.read_replica = Some(connection_string.as_ref().to_string());
+        self
+    }
+
+    /// Fall back to the primary when no replica connection can be acquired (default true)
+    ///
+    /// When disabled, the replica error is returned to the caller.
+    pub fn replica_fallback(mut self, fallback: bool) -> Self {
+        self.config.replica_fallback = fallback;
+        self
+    }
+
+    /// Cancel read queries running longer than `timeout` (default none)
+    ///
+    /// Applied with `SET LOCAL statement_timeout` to `query`, `count`, `event_by_id` and
+    /// `check_id`. A timeout is reported as a [`TimeoutError`](crate::TimeoutError).
+    pub fn query_timeout(mut self, timeout: Duration) -> Self {
+        self.config.query_timeout = Some(timeout);
+        self
+    }
+
+    /// Log `query`, `count`, `delete` and `save_event` calls taking longer than `threshold`
+    /// as warnings (default none)
+    ///
+    /// The warning names the operation, the elapsed time, the number of rows and which
+    /// filter fields were set with how many values each, never the values themselves.
+    pub fn slow_operation_threshold(mut self, threshold: Duration) -> Self {
+        self.config.slow_operation_threshold = Some(threshold);
+        self
+    }
+
+    /// `ANALYZE` the event tables after every [`NostrPostgres::bulk_load`] that stored events
+    /// (default false)
+    ///
+    /// Saves waiting for autovacuum before the planner knows about the loaded rows; see
+    /// [`NostrPostgres::maintenance`].
+    pub fn analyze_after_bulk_load(mut self, enabled: bool) -> Self {
+        self.config.analyze_after_bulk_load = enabled;
+        self
+    }
+
+    /// Cache prepared statements on each pooled connection (default true)
+    ///
+    /// Statements are cached per connection for as long as it lives, so the cache survives
+    /// recycling and is dropped together with the connection. diesel offers no bound on the
+    /// cache, only on or off, so only fixed-shape statements are cached, e.g. saves and
+    /// lookups by id, a set bounded by the statements of this crate. The SQL generated for
+    /// filters varies with their tags and kinds and isn't cached, which would grow the cache
+    /// with every new shape; such statements are prepared unnamed on each use. Run
+    /// `cargo bench` for the difference on a given server.
+    pub fn statement_cache(mut self, enabled: bool) -> Self {
+        self.config.statement_cache = enabled;
+        self
+    }
+
+    /// Stay compatible with PgBouncer in transaction pooling mode (default false)
+    ///
+    /// Nothing may rely on server-side state outlasting a transaction, so this disables the
+    /// statement cache and rejects a custom [`schema`](Self::schema), which is applied with a
+    /// session-level `search_path`. Statement timeouts already use `SET LOCAL` and keep
+    /// working. Migrations should still be run against Postgres directly.
+    pub fn pgbouncer_transaction_mode(mut self, enabled: bool) -> Self {
+        self.config.pgbouncer_transaction_mode = enabled;
+        self
+    }
+
+    /// Open `n` connections before [`build`](Self::build) resolves (default 0)
+    ///
+    /// See [`NostrPostgres::warm_up`].
+    pub fn warm_up(mut self, n: usize) -> Self {
+        self.warm_up = n;
+        self
+    }
+
+    /// How pooled connections are checked before reuse (default [`RecyclingMethod::Verified`])
+    pub fn recycling_method(mut self, method: RecyclingMethod) -> Self {
+        self.config.recycling_method = method;
+        self
+    }
+
+    /// Don't run the migrations, e.g. when the schema is managed by other tooling
+    ///
+    /// Use [`NostrPostgres::verify_schema`] to check that the schema is complete.
+    pub fn skip_migrations(mut self) -> Self {
+        self.skip_migrations = true;
+        self
+    }
+
+    /// Compare the schema with the migrations on startup, see
+    /// [`NostrPostgres::validate_schema`] (default: no validation)
+    pub fn validate_schema(mut self, validation: SchemaValidation) -> Self {
+        self.validation = Some(validation);
+        self
+    }
+
+    /// Use the layout partitioning `events` by month on `created_at`
+    ///
+    /// Old months can then be dropped as a whole. Only for new databases, see
+    /// [`run_partitioned_migrations`](crate::run_partitioned_migrations). [`build`](Self::build)
+    /// creates the partitions for the current and the next month; call
+    /// [`NostrPostgres::ensure_partitions`] regularly to create the following ones.
+    pub fn partitioned(mut self) -> Self {
+        self.partitioned = true;
+        self
+    }
+
+    /// Run pending migrations (unless skipped) and create the [`NostrPostgres`] instance
+    pub async fn build(self) -> Result<NostrPostgres, DatabaseError> {
+        self.config.validate()?;
+        let connection_string = self.connection_string.as_str();
+        let schema = self.config.schema.as_deref();
+        let partitioned = self.partitioned;
+        let pool = match self.retry {
+            Some(retry) => {
+                if !self.skip_migrations {
+                    retry
+                        .run("run_migrations", || async {
+                            run_migrations_with_schema(connection_string, schema, partitioned)
+                        })
+                        .await?;
+                }
+                let pool = build_pool(connection_string, &self.config)?;
+                retry
+                    .run("connect", || async {
+                        pool.get()
+                            .await
+                            .map(|_| ())
+                            .map_err(|e| DatabaseError::backend(PoolAcquireError::from(e)))
+                    })
+                    .await?;
+                pool
+            }
+            None => {
+                if !self.skip_migrations {
+                    run_migrations_with_schema(connection_string, schema, partitioned)?;
+                }
+                build_pool(connection_string, &self.config)?
+            }
+        };
+        let read_pool = self
+            .read_replica
+            .as_deref()
+            .map(|replica| build_pool(replica, &self.config))
+            .transpose()?;
+        let db =
+            NostrPostgres::with_config(pool, self.config).with_connection_string(connection_string);
+        let db = match read_pool {
+            Some(read_pool) => db.with_read_replica(read_pool),
+            None => db,
+        };
+        if partitioned {
+            let next_month = Timestamp::now() + Duration::from_secs(31 * 24 * 60 * 60);
+            db.ensure_partitions(next_month).await?;
+        }
+        if let Some(validation) = self.validation {
+            let report = db.validate_schema().await?;
+            if !report.is_valid() {
+                match validation {
+                    SchemaValidation::Warn => warn!("Database schema drifted: {report}"),
+                    SchemaValidation::Fail => {
+                        return Err(DatabaseError::backend(SchemaDriftError::new(report)));
+                    }
+                }
+            }
+        }
+        db.warm_up(self.warm_up).await?;
+        Ok(db)
+    }
+}
+
+// connection strings may carry credentials, so they are left out
+impl std::fmt::Debug for NostrPostgresBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NostrPostgresBuilder")
+            .field("retry", &self.retry)
+            .field("read_replica", &self.read_replica.is_some())
+            .field("warm_up", &self.warm_up)
+            .field("skip_migrations", &self.skip_migrations)
+            .field("partitioned", &self.partitioned)
+            .field("validation", &self.validation)
+            .field("config", &self.config)
+            .finish()
+    }
+}
@@ -0,0 +1,161 @@
+use std::time::Instant;
+
+use futures_util::future::{Either, select};
+use futures_util::{Stream, StreamExt, pin_mut};
+use nostr_database::DatabaseError;
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres::{Client, NoTls};
+use tracing::debug;
+
+use crate::identifier::quote_identifier;
+use crate::import::{BatchOutcome, ImportReport};
+use crate::model::EventDataDb;
+
+/// merges the staged rows, skipping stored events, and returns the number of inserted events
+/// and of staged events that were stored and deleted before
+const MERGE: &str = "WITH inserted AS (\
+     INSERT INTO events (id, pubkey, created_at, kind, payload, deleted) \
+     SELECT id, pubkey, created_at, kind, payload, FALSE FROM bulk_events \
+     ON CONFLICT DO NOTHING RETURNING id\
+     ), tags AS (\
+     INSERT INTO event_tags (tag, tag_value, event_id, tag_fields) \
+     SELECT t.tag, t.tag_value, b.id, \
+     ARRAY(SELECT jsonb_array_elements_text(t.tag_fields::jsonb)) FROM bulk_events b \
+     JOIN inserted i ON i.id = b.id \
+     CROSS JOIN unnest(b.tags, b.tag_values, b.tag_fields) AS t(tag, tag_value, tag_fields) \
+     ON CONFLICT DO NOTHING\
+     ) SELECT (SELECT count(*) FROM inserted), \
+     (SELECT count(*) FROM bulk_events b JOIN events e ON e.id = b.id WHERE e.deleted), \
+     (SELECT count(*) FROM bulk_events b JOIN blocked_authors a ON a.pubkey = b.pubkey \
+     WHERE NOT EXISTS (SELECT 1 FROM events e WHERE e.id = b.id))";
+
+/// loads the rows on a dedicated connection with `COPY` into a temporary table, which is
+/// merged into the event tables in the same transaction
+pub(crate) async fn bulk_load<S>(
+    connection_string: &str,
+    schema: Option<&str>,
+    rows: S,
+) -> Result<ImportReport, DatabaseError>
+where
+    S: Stream<Item = Result<EventDataDb, DatabaseError>>,
+{
+    let start = Instant::now();
+    let (client, connection) = tokio_postgres::connect(connection_string, NoTls)
+        .await
+        .map_err(DatabaseError::backend)?;
+    let load = load(&client, schema, rows);
+    pin_mut!(load);
+    // dropping the connection on failure rolls the transaction back
+    match select(load, connection).await {
+        Either::Left((res, _)) => res.map(|mut report| {
+            report.elapsed = start.elapsed();
+            report
+        }),
+        Either::Right((res, _)) => Err(match res {
+            Err(e) => DatabaseError::backend(e),
+            Ok(()) => DatabaseError::backend(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "connection closed during bulk load",
+            )),
+        }),
+    }
+}
+
+async fn load<S>(
+    client: &Client,
+    schema: Option<&str>,
+    rows: S,
+) -> Result<ImportReport, DatabaseError>
+where
+    S: Stream<Item = Result<EventDataDb, DatabaseError>>,
+{
+    if let Some(schema) = schema {
+        client
+            .batch_execute(&format!("SET search_path TO {}", quote_identifier(schema)?))
+            .await
+            .map_err(DatabaseError::backend)?;
+    }
+    client
+        .batch_execute(
+            "BEGIN; CREATE TEMPORARY TABLE bulk_events (\
+             id BYTEA NOT NULL, pubkey BYTEA NOT NULL, created_at BIGINT NOT NULL, \
+             kind BIGINT NOT NULL, payload BYTEA NOT NULL, \
+             tags TEXT[] NOT NULL, tag_values TEXT[] NOT NULL, tag_fields TEXT[] NOT NULL\
+             ) ON COMMIT DROP",
+        )
+        .await
+        .map_err(DatabaseError::backend)?;
+
+    let sink = client
+        .copy_in("COPY bulk_events FROM STDIN (FORMAT binary)")
+        .await
+        .map_err(DatabaseError::backend)?;
+    let writer = BinaryCopyInWriter::new(
+        sink,
+        &[
+            Type::BYTEA,
+            Type::BYTEA,
+            Type::INT8,
+            Type::INT8,
+            Type::BYTEA,
+            Type::TEXT_ARRAY,
+            Type::TEXT_ARRAY,
+            Type::TEXT_ARRAY,
+        ],
+    );
+    pin_mut!(writer);
+    pin_mut!(rows);
+    let mut staged: u64 = 0;
+    while let Some(data) = rows.next().await {
+        let data = data?;
+        let mut tags = Vec::with_capacity(data.tags.len());
+        let mut values = Vec::with_capacity(data.tags.len());
+        // a text array can't be ragged, so the fields of each tag are staged as a JSON array
+        let mut fields = Vec::with_capacity(data.tags.len());
+        for tag in data.tags {
+            fields.push(serde_json::to_string(&tag.tag_fields).map_err(DatabaseError::backend)?);
+            tags.push(tag.tag);
+            values.push(tag.tag_value);
+        }
+        let event = data.event;
+        let row: [&(dyn ToSql + Sync); 8] = [
+            &event.id,
+            &event.pubkey,
+            &event.created_at,
+            &event.kind,
+            &event.payload,
+            &tags,
+            &values,
+            &fields,
+        ];
+        writer
+            .as_mut()
+            .write(&row)
+            .await
+            .map_err(DatabaseError::backend)?;
+        staged += 1;
+    }
+    writer.finish().await.map_err(DatabaseError::backend)?;
+    debug!("Staged {staged} events for bulk load");
+
+    let merged = client
+        .query_one(MERGE, &[])
+        .await
+        .map_err(DatabaseError::backend)?;
+    let inserted = merged.get::<_, i64>(0) as u64;
+    let deleted = merged.get::<_, i64>(1) as u64;
+    let rejected = merged.get::<_, i64>(2) as u64;
+    client
+        .batch_execute("COMMIT")
+        .await
+        .map_err(DatabaseError::backend)?;
+    let mut report = ImportReport::default();
+    report.record_batch(BatchOutcome {
+        inserted,
+        duplicates: staged.saturating_sub(inserted + deleted + rejected),
+        deleted,
+        rejected,
+    });
+    Ok(report)
+}
@@ -0,0 +1,187 @@
+use std::time::{Duration, Instant};
+
+use nostr::Timestamp;
+use nostr::filter::Filter;
+use nostr_database::{DatabaseError, NostrDatabase};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::import::ImportReport;
+
+use crate::postgres::NostrPostgres;
+
+/// Settings of [`NostrPostgres::copy_from`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CopyOptions {
+    since: Option<Timestamp>,
+    window: Duration,
+    page_size: usize,
+    verify_signatures: bool,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self {
+            since: None,
+            window: Duration::from_secs(24 * 60 * 60),
+            page_size: 5000,
+            verify_signatures: false,
+        }
+    }
+}
+
+impl CopyOptions {
+    /// Resume from a checkpoint, see [`CopyReport::last_timestamp`] and
+    /// [`CopyError::last_timestamp`]
+    pub fn since(mut self, since: Timestamp) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    /// Initial time window queried from the source at once (default 1 day)
+    ///
+    /// The window is halved while a query returns a full page and doubled while queries
+    /// return few events.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window.max(Duration::from_secs(1));
+        self
+    }
+
+    /// Maximum number of events requested from the source per query (default 5000)
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    /// Check the id and signature of every event, rejecting invalid ones (default false)
+    pub fn verify_signatures(mut self, verify: bool) -> Self {
+        self.verify_signatures = verify;
+        self
+    }
+}
+
+/// Outcome of [`NostrPostgres::copy_from`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CopyReport {
+    /// Counts of the copied events
+    pub stats: ImportReport,
+    /// End of the last time window that was copied completely
+    ///
+    /// Pass it to [`CopyOptions::since`] to continue the copy later; events of that second are
+    /// then counted as duplicates.
+    pub last_timestamp: Option<Timestamp>,
+}
+
+/// Returned when [`NostrPostgres::copy_from`] failed midway
+#[derive(Debug)]
+pub struct CopyError {
+    report: CopyReport,
+    source: DatabaseError,
+}
+
+impl CopyError {
+    /// Events copied before the failure
+    pub fn report(&self) -> &CopyReport {
+        &self.report
+    }
+
+    /// Checkpoint to resume from, see [`CopyReport::last_timestamp`]
+    pub fn last_timestamp(&self) -> Option<Timestamp> {
+        self.report.last_timestamp
+    }
+}
+
+impl std::fmt::Display for CopyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.report.last_timestamp {
+            Some(t) => write!(f, "copy failed after {t}: {}", self.source),
+            None => write!(f, "copy failed: {}", self.source),
+        }
+    }
+}
+
+impl std::error::Error for CopyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// copies the events matching `filter` from `source` in ascending time windows
+pub(crate) async fn copy_from<D>(
+    db: &NostrPostgres,
+    source: &D,
+    filter: Filter,
+    opts: CopyOptions,
+) -> Result<CopyReport, DatabaseError>
+where
+    D: NostrDatabase + ?Sized,
+{
+    let start = Instant::now();
+    let mut report = CopyReport::default();
+    let res = copy_windows(db, source, filter, opts, &mut report).await;
+    report.stats.elapsed = start.elapsed();
+    match res {
+        Ok(()) => Ok(report),
+        Err(source) => Err(DatabaseError::backend(CopyError { report, source })),
+    }
+}
+
+async fn copy_windows<D>(
+    db: &NostrPostgres,
+    source: &D,
+    filter: Filter,
+    opts: CopyOptions,
+    report: &mut CopyReport,
+) -> Result<(), DatabaseError>
+where
+    D: NostrDatabase + ?Sized,
+{
+    let end = filter.until.unwrap_or_else(Timestamp::now).as_u64();
+    let mut start = filter
+        .since
+        .max(opts.since)
+        .map(|t| t.as_u64())
+        .unwrap_or_default();
+    let mut window = opts.window.as_secs();
+
+    while start <= end {
+        let window_end = start.saturating_add(window - 1).min(end);
+        let page = filter
+            .clone()
+            .since(Timestamp::from(start))
+            .until(Timestamp::from(window_end))
+            .limit(opts.page_size);
+        let events = source.query(page).await?;
+        if events.len() >= opts.page_size {
+            if window > 1 {
+                window /= 2;
+                continue;
+            }
+            warn!(
+                "More than {} events at {start}, some may not have been copied",
+                opts.page_size
+            );
+        }
+
+        let mut batch = Vec::with_capacity(events.len());
+        for event in events.iter() {
+            if opts.verify_signatures
+                && let Err(e) = event.verify()
+            {
+                report.stats.rejected += 1;
+                report.stats.record_event_error(event.id, e);
+                continue;
+            }
+            batch.push(db.event_data(event)?);
+        }
+        report.stats.record_batch(db.save_batch(batch).await?);
+        report.last_timestamp = Some(Timestamp::from(window_end));
+        debug!("Copied events up to {window_end}: {report:?}");
+
+        if events.len() < opts.page_size / 4 {
+            window = window.saturating_mul(2);
+        }
+        start = window_end + 1;
+    }
+    Ok(())
+}
@@ -0,0 +1,389 @@
+use std::fmt;
+
+use diesel::QueryableByName;
+use diesel::result::Error as DieselError;
+use diesel::sql_types::{BigInt, Double, Nullable, Text};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use nostr::Timestamp;
+use nostr_database::DatabaseError;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+/// the tables reported on, in the order of [`TABLES`]
+const TABLE_NAMES: [&str; 4] = [
+    "events",
+    "event_tags",
+    "events_archive",
+    "event_tags_archive",
+];
+
+/// the crate's tables with their statistics, summed over their partitions
+const TABLES: &str = "SELECT t.name, \
+         sum(pg_table_size(p.relid))::bigint AS table_bytes, \
+         sum(pg_indexes_size(p.relid))::bigint AS index_bytes, \
+         sum(s.n_live_tup)::bigint AS live_rows, \
+         sum(s.n_dead_tup)::bigint AS dead_rows, \
+         sum(s.seq_scan)::bigint AS seq_scans, \
+         sum(s.idx_scan)::bigint AS index_scans, \
+         extract(epoch FROM max(s.last_vacuum))::bigint AS last_vacuum, \
+         extract(epoch FROM max(s.last_autovacuum))::bigint AS last_autovacuum, \
+         extract(epoch FROM max(s.last_analyze))::bigint AS last_analyze, \
+         extract(epoch FROM max(s.last_autoanalyze))::bigint AS last_autoanalyze \
+     FROM unnest(ARRAY['events', 'event_tags', 'events_archive', 'event_tags_archive']) \
+         WITH ORDINALITY t(name, position) \
+     CROSS JOIN LATERAL to_regclass(t.name) r(oid) \
+     CROSS JOIN LATERAL (SELECT r.oid AS relid UNION SELECT relid FROM pg_partition_tree(r.oid)) p \
+     LEFT JOIN pg_stat_user_tables s ON s.relid = p.relid \
+     WHERE r.oid IS NOT NULL \
+     GROUP BY t.name, t.position ORDER BY t.position";
+
+/// the indexes of the crate's tables with their statistics, summed over their partitions
+const INDEXES: &str = "SELECT t.name AS table_name, i.relname AS name, \
+         sum(pg_relation_size(p.relid))::bigint AS bytes, \
+         sum(s.idx_scan)::bigint AS scans, \
+         sum(s.idx_tup_read)::bigint AS tuples_read \
+     FROM unnest(ARRAY['events', 'event_tags', 'events_archive', 'event_tags_archive']) \
+         WITH ORDINALITY t(name, position) \
+     CROSS JOIN LATERAL to_regclass(t.name) r(oid) \
+     JOIN pg_index x ON x.indrelid = r.oid \
+     JOIN pg_class i ON i.oid = x.indexrelid \
+     CROSS JOIN LATERAL (SELECT i.oid AS relid UNION SELECT relid FROM pg_partition_tree(i.oid)) p \
+     LEFT JOIN pg_stat_user_indexes s ON s.indexrelid = p.relid \
+     GROUP BY t.name, t.position, i.relname ORDER BY t.position, i.relname";
+
+/// rows of `event_tags` sampled to estimate the orphaned ones; smaller tables are read whole
+const ORPHAN_SAMPLE_ROWS: f64 = 100_000.0;
+
+/// the tag rows without a live event in a sample of `$1` percent of the pages of `event_tags`
+const ORPHANED_SAMPLE: &str = "SELECT count(*) AS orphaned FROM event_tags t \
+     TABLESAMPLE SYSTEM ($1) WHERE NOT EXISTS (\
+     SELECT 1 FROM events e WHERE e.id = t.event_id AND NOT e.deleted)";
+
+/// Bloat and usage of the crate's tables and indexes
+///
+/// Figures come from the statistics collector of the server the report was read from and are
+/// `None` where it has none, e.g. for a table that was never vacuumed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DiagnosticsReport {
+    /// The tables, in the order `events`, `event_tags`, `events_archive`, `event_tags_archive`
+    pub tables: Vec<TableDiagnostics>,
+    /// The indexes of these tables
+    pub indexes: Vec<IndexDiagnostics>,
+    /// Estimated number of tag rows without a live event, from a sample of `event_tags`;
+    /// see [`NostrPostgres::cleanup_orphaned_tags`](crate::NostrPostgres::cleanup_orphaned_tags)
+    pub orphaned_tags: Option<u64>,
+    /// Parts of the report that couldn't be read, with the reason, e.g. missing permissions
+    pub unavailable: Vec<String>,
+}
+
+/// Bloat and usage of one table, summed over its partitions
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableDiagnostics {
+    /// Name of the table, without the schema
+    pub name: String,
+    /// Size including TOAST data, without indexes
+    pub table_bytes: Option<u64>,
+    /// Size of the indexes
+    pub index_bytes: Option<u64>,
+    /// Estimated number of live rows
+    pub live_rows: Option<u64>,
+    /// Estimated number of dead rows waiting for `VACUUM`
+    pub dead_rows: Option<u64>,
+    /// Share of dead rows among all rows, from 0 to 1
+    pub dead_ratio: Option<f64>,
+    /// Size of the indexes relative to the table
+    pub index_ratio: Option<f64>,
+    /// Number of sequential scans
+    pub seq_scans: Option<u64>,
+    /// Number of index scans
+    pub index_scans: Option<u64>,
+    /// Last manual `VACUUM`, the most recent of all partitions
+    pub last_vacuum: Option<Timestamp>,
+    /// Last `VACUUM` by autovacuum, the most recent of all partitions
+    pub last_autovacuum: Option<Timestamp>,
+    /// Last manual `ANALYZE`, the most recent of all partitions
+    pub last_analyze: Option<Timestamp>,
+    /// Last `ANALYZE` by autovacuum, the most recent of all partitions
+    pub last_autoanalyze: Option<Timestamp>,
+}
+
+/// Size and usage of one index, summed over its partitions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexDiagnostics {
+    /// Name of the indexed table
+    pub table: String,
+    /// Name of the index
+    pub name: String,
+    /// Size of the index
+    pub bytes: Option<u64>,
+    /// Number of scans using the index; an index that is never scanned only costs writes
+    pub scans: Option<u64>,
+    /// Number of index entries returned by these scans
+    pub tuples_read: Option<u64>,
+}
+
+#[derive(QueryableByName)]
+struct TableRow {
+    #[diesel(sql_type = Text)]
+    name: String,
+    #[diesel(sql_type = Nullable<BigInt>)]
+    table_bytes: Option<i64>,
+    #[diesel(sql_type = Nullable<BigInt>)]
+    index_bytes: Option<i64>,
+    #[diesel(sql_type = Nullable<BigInt>)]
+    live_rows: Option<i64>,
+    #[diesel(sql_type = Nullable<BigInt>)]
+    dead_rows: Option<i64>,
+    #[diesel(sql_type = Nullable<BigInt>)]
+    seq_scans: Option<i64>,
+    #[diesel(sql_type = Nullable<BigInt>)]
+    index_scans: Option<i64>,
+    #[diesel(sql_type = Nullable<BigInt>)]
+    last_vacuum: Option<i64>,
+    #[diesel(sql_type = Nullable<BigInt>)]
+    last_autovacuum: Option<i64>,
+    #[diesel(sql_type = Nullable<BigInt>)]
+    last_analyze: Option<i64>,
+    #[diesel(sql_type = Nullable<BigInt>)]
+    last_autoanalyze: Option<i64>,
+}
+
+#[derive(QueryableByName)]
+struct OrphanedRow {
+    #[diesel(sql_type = BigInt)]
+    orphaned: i64,
+}
+
+#[derive(QueryableByName)]
+struct IndexRow {
+    #[diesel(sql_type = Text)]
+    table_name: String,
+    #[diesel(sql_type = Text)]
+    name: String,
+    #[diesel(sql_type = Nullable<BigInt>)]
+    bytes: Option<i64>,
+    #[diesel(sql_type = Nullable<BigInt>)]
+    scans: Option<i64>,
+    #[diesel(sql_type = Nullable<BigInt>)]
+    tuples_read: Option<i64>,
+}
+
+fn unsigned(value: Option<i64>) -> Option<u64> {
+    value.map(|v| v.max(0) as u64)
+}
+
+fn timestamp(epoch: Option<i64>) -> Option<Timestamp> {
+    epoch.map(|secs| Timestamp::from(secs.max(0) as u64))
+}
+
+fn ratio(part: Option<u64>, whole: Option<u64>) -> Option<f64> {
+    match (part, whole) {
+        (Some(part), Some(whole)) if whole > 0 => Some(part as f64 / whole as f64),
+        _ => None,
+    }
+}
+
+impl From<TableRow> for TableDiagnostics {
+    fn from(row: TableRow) -> Self {
+        let table_bytes = unsigned(row.table_bytes);
+        let index_bytes = unsigned(row.index_bytes);
+        let live_rows = unsigned(row.live_rows);
+        let dead_rows = unsigned(row.dead_rows);
+        let all_rows = live_rows.zip(dead_rows).map(|(live, dead)| live + dead);
+        Self {
+            name: row.name,
+            table_bytes,
+            index_bytes,
+            live_rows,
+            dead_rows,
+            dead_ratio: ratio(dead_rows, all_rows),
+            index_ratio: ratio(index_bytes, table_bytes),
+            seq_scans: unsigned(row.seq_scans),
+            index_scans: unsigned(row.index_scans),
+            last_vacuum: timestamp(row.last_vacuum),
+            last_autovacuum: timestamp(row.last_autovacuum),
+            last_analyze: timestamp(row.last_analyze),
+            last_autoanalyze: timestamp(row.last_autoanalyze),
+        }
+    }
+}
+
+impl From<IndexRow> for IndexDiagnostics {
+    fn from(row: IndexRow) -> Self {
+        Self {
+            table: row.table_name,
+            name: row.name,
+            bytes: unsigned(row.bytes),
+            scans: unsigned(row.scans),
+            tuples_read: unsigned(row.tuples_read),
+        }
+    }
+}
+
+/// whether `e` is the server refusing access rather than a failed connection or query
+fn is_permission_error(e: &DieselError) -> bool {
+    matches!(e, DieselError::DatabaseError(_, info) if info.message().contains("permission denied"))
+}
+
+/// reads the statistics of the crate's tables and indexes from the catalog
+pub(crate) async fn diagnostics(
+    db: &mut AsyncPgConnection,
+) -> Result<DiagnosticsReport, DatabaseError> {
+    let mut report = DiagnosticsReport::default();
+    match diesel::sql_query(TABLES).load::<TableRow>(db).await {
+        Ok(rows) => {
+            report.tables = rows.into_iter().map(TableDiagnostics::from).collect();
+            // without usage on the schema the tables aren't found at all
+            for name in TABLE_NAMES {
+                if !report.tables.iter().any(|t| t.name == name) {
+                    report
+                        .unavailable
+                        .push(format!("{name}: not found or not accessible"));
+                }
+            }
+        }
+        Err(e) if is_permission_error(&e) => {
+            debug!("Table statistics unavailable: {e}");
+            report.unavailable.push(format!("tables: {e}"));
+        }
+        Err(e) => return Err(DatabaseError::backend(e)),
+    }
+    match diesel::sql_query(INDEXES).load::<IndexRow>(db).await {
+        Ok(rows) => report.indexes = rows.into_iter().map(IndexDiagnostics::from).collect(),
+        Err(e) if is_permission_error(&e) => {
+            debug!("Index statistics unavailable: {e}");
+            report.unavailable.push(format!("indexes: {e}"));
+        }
+        Err(e) => return Err(DatabaseError::backend(e)),
+    }
+    let tag_rows = report
+        .tables
+        .iter()
+        .find(|t| t.name == "event_tags")
+        .and_then(|t| t.live_rows);
+    if let Some(tag_rows) = tag_rows {
+        let percent = (ORPHAN_SAMPLE_ROWS / tag_rows.max(1) as f64 * 100.0).min(100.0);
+        match diesel::sql_query(ORPHANED_SAMPLE)
+            .bind::<Double, _>(percent)
+            .get_result::<OrphanedRow>(db)
+            .await
+        {
+            Ok(row) => {
+                report.orphaned_tags = Some((row.orphaned as f64 * 100.0 / percent).round() as u64)
+            }
+            Err(e) if is_permission_error(&e) => {
+                debug!("Orphaned tags unavailable: {e}");
+                report.unavailable.push(format!("orphaned tags: {e}"));
+            }
+            Err(e) => return Err(DatabaseError::backend(e)),
+        }
+    }
+    Ok(report)
+}
+
+/// writes `value` or `n/a`
+struct Field<T>(Option<T>);
+
+impl<T: fmt::Display> fmt::Display for Field<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(value) => value.fmt(f),
+            None => f.write_str("n/a"),
+        }
+    }
+}
+
+/// writes a byte size in binary units, e.g. `1.5 MiB`
+struct Bytes(Option<u64>);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let Some(bytes) = self.0 else {
+            return f.write_str("n/a");
+        };
+        let mut size = bytes as f64;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            write!(f, "{bytes} B")
+        } else {
+            write!(f, "{size:.1} {}", UNITS[unit])
+        }
+    }
+}
+
+/// writes a timestamp, or `never`
+struct Time(Option<Timestamp>);
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(time) => write!(f, "{}", time.to_human_datetime()),
+            None => f.write_str("never"),
+        }
+    }
+}
+
+impl fmt::Display for DiagnosticsReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for table in &self.tables {
+            writeln!(f, "{}", table.name)?;
+            writeln!(
+                f,
+                "  size: {} table, {} indexes (ratio {})",
+                Bytes(table.table_bytes),
+                Bytes(table.index_bytes),
+                Field(table.index_ratio.map(|r| format!("{r:.2}"))),
+            )?;
+            writeln!(
+                f,
+                "  rows: {} live, {} dead ({})",
+                Field(table.live_rows),
+                Field(table.dead_rows),
+                Field(table.dead_ratio.map(|r| format!("{:.1}%", r * 100.0))),
+            )?;
+            writeln!(
+                f,
+                "  scans: {} sequential, {} index",
+                Field(table.seq_scans),
+                Field(table.index_scans),
+            )?;
+            writeln!(
+                f,
+                "  vacuum: {} (auto {}), analyze: {} (auto {})",
+                Time(table.last_vacuum),
+                Time(table.last_autovacuum),
+                Time(table.last_analyze),
+                Time(table.last_autoanalyze),
+            )?;
+            for index in self.indexes.iter().filter(|i| i.table == table.name) {
+                let unused = if index.scans == Some(0) {
+                    ", unused"
+                } else {
+                    ""
+                };
+                writeln!(
+                    f,
+                    "  index {}: {}, {} scans, {} tuples read{unused}",
+                    index.name,
+                    Bytes(index.bytes),
+                    Field(index.scans),
+                    Field(index.tuples_read),
+                )?;
+            }
+        }
+        writeln!(
+            f,
+            "orphaned tag rows: {} (estimate)",
+            Field(self.orphaned_tags)
+        )?;
+        for unavailable in &self.unavailable {
+            writeln!(f, "unavailable: {unavailable}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use nostr::Timestamp;
+use nostr::event::{Event, EventId, Kind};
+use nostr::filter::{Alphabet, Filter, SingleLetterTag};
+use nostr_database::{DatabaseError, MemoryDatabase, MemoryDatabaseOptions, NostrDatabase};
+
+use crate::fixtures::{BASE_TIMESTAMP, EventFactory, HASHTAGS, Rng, TIME_SPAN};
+use crate::postgres::NostrPostgres;
+
+/// Regular kinds of the generated events, so replacement rules play no part
+const KINDS: [u16; 4] = [1, 6, 7, 1111];
+
+/// The generic tags of the filters: hashtags, mentioned authors and referenced events
+const TAGS: [SingleLetterTag; 3] = [
+    SingleLetterTag::lowercase(Alphabet::T),
+    SingleLetterTag::lowercase(Alphabet::P),
+    SingleLetterTag::lowercase(Alphabet::E),
+];
+
+/// Random but reproducible events and filters for [`compare_with_memory`]
+///
+/// The same seed always gives the same events, ids and signatures included, and the same
+/// filters. Events are regular kinds spread over a few hours, tagged with hashtags and
+/// references to their authors and earlier events; filters combine ids, authors, kinds, time
+/// bounds, one or two generic tags and a limit drawn from these events, with the occasional
+/// value matching nothing.
+#[derive(Debug, Clone)]
+pub struct DifferentialCorpus {
+    seed: u64,
+    rng: Rng,
+    authors: usize,
+}
+
+impl DifferentialCorpus {
+    /// A generator with the given seed and 10 authors
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: Rng::new(seed),
+            authors: 10,
+        }
+    }
+
+    /// Number of distinct authors of the generated events
+    pub fn authors(mut self, authors: usize) -> Self {
+        self.authors = authors.max(1);
+        self
+    }
+
+    /// Generate `count` signed events
+    pub fn events(&mut self, count: usize) -> Vec<Event> {
+        EventFactory::new(self.seed)
+            .kinds(KINDS)
+            .authors(self.authors)
+            .events(count)
+    }
+
+    /// Generate `count` filters drawn from `events`
+    pub fn filters(&mut self, events: &[Event], count: usize) -> Vec<Filter> {
+        (0..count).map(|_| self.filter(events)).collect()
+    }
+
+    fn filter(&mut self, events: &[Event]) -> Filter {
+        let mut filter = Filter::new();
+        if events.is_empty() {
+            return filter;
+        }
+        if self.rng.chance(20) {
+            let mut ids: Vec<EventId> = (0..1 + self.rng.below(3))
+                .map(|_| self.rng.pick(events).id)
+                .collect();
+            if self.rng.chance(20) {
+                ids.push(EventId::from_byte_array(self.rng.bytes32()));
+            }
+            filter = filter.ids(ids);
+        }
+        if self.rng.chance(40) {
+            let authors: Vec<_> = (0..1 + self.rng.below(3))
+                .map(|_| self.rng.pick(events).pubkey)
+                .collect();
+            filter = filter.authors(authors);
+        }
+        if self.rng.chance(40) {
+            let kinds: Vec<Kind> = (0..1 + self.rng.below(2))
+                .map(|_| Kind::from(*self.rng.pick(&KINDS)))
+                .collect();
+            filter = filter.kinds(kinds);
+        }
+        if self.rng.chance(30) {
+            filter = filter.since(Timestamp::from(BASE_TIMESTAMP + self.rng.below(TIME_SPAN)));
+        }
+        if self.rng.chance(30) {
+            filter = filter.until(Timestamp::from(BASE_TIMESTAMP + self.rng.below(TIME_SPAN)));
+        }
+        if self.rng.chance(40) {
+            let tag = self.rng.below(3);
+            filter = self.tag(filter, events, tag);
+        }
+        if self.rng.chance(30) {
+            filter = filter.limit(1 + self.rng.below(20) as usize);
+        }
+        // a second tag, which has to match another tag row of the same event
+        if self.rng.chance(15) {
+            let tag = self.rng.below(3);
+            if !filter.generic_tags.keys().any(|t| *t == TAGS[tag as usize]) {
+                filter = self.tag(filter, events, tag);
+            }
+        }
+        filter
+    }
+
+    fn tag(&mut self, filter: Filter, events: &[Event], tag: u64) -> Filter {
+        match tag {
+            0 => filter.hashtags((0..1 + self.rng.below(2)).map(|_| *self.rng.pick(&HASHTAGS))),
+            1 => filter.pubkeys((0..1 + self.rng.below(2)).map(|_| self.rng.pick(events).pubkey)),
+            _ => filter.custom_tags(
+                TAGS[2],
+                (0..1 + self.rng.below(2)).map(|_| self.rng.pick(events).id),
+            ),
+        }
+    }
+}
+
+/// A result of [`compare_with_memory`] where the two databases disagree
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// The filter, `None` for a `check_id` divergence
+    pub filter: Option<Filter>,
+    /// The operation: `query`, `count` or `check_id`
+    pub operation: &'static str,
+    /// What [`NostrPostgres`] returned
+    pub postgres: String,
+    /// What [`MemoryDatabase`] returned
+    pub memory: String,
+}
+
+/// Save `events` to `db` and to a [`MemoryDatabase`] and compare `query` and `count` for every
+/// filter and `check_id` for every event, returning where they disagree
+///
+/// A failed [`NostrPostgres`] operation is a divergence too; only failures to save the events
+/// or of the [`MemoryDatabase`] are returned as errors.
+///
+/// `db` should be empty, e.g. a fresh [`TestDb`](crate::TestDb). Documented differences are
+/// tolerated: events with the same creation time may be returned in either order, so for
+/// filters with a limit only the creation times of the results are compared, and
+/// [`NostrPostgres`] applies a default limit of 10000 to queries without one.
+pub async fn compare_with_memory(
+    db: &NostrPostgres,
+    events: &[Event],
+    filters: &[Filter],
+) -> Result<Vec<Divergence>, DatabaseError> {
+    let memory = MemoryDatabase::with_opts(MemoryDatabaseOptions {
+        events: true,
+        max_events: None,
+    });
+    for event in events {
+        db.save_event(event).await?;
+        memory.save_event(event).await?;
+    }
+
+    let mut divergences = Vec::new();
+    for filter in filters {
+        let expected = memory.query(filter.clone()).await?;
+        let (postgres, expected) = match db.query(filter.clone()).await {
+            Ok(postgres) if filter.limit.is_some() => (
+                creation_times(postgres.iter()),
+                creation_times(expected.iter()),
+            ),
+            Ok(postgres) => (event_ids(postgres.iter()), event_ids(expected.iter())),
+            Err(e) => (format!("error: {e}"), event_ids(expected.iter())),
+        };
+        if postgres != expected {
+            divergences.push(Divergence {
+                filter: Some(filter.clone()),
+                operation: "query",
+                postgres,
+                memory: expected,
+            });
+        }
+
+        let expected = memory.count(filter.clone()).await?;
+        let postgres = db.count(filter.clone()).await;
+        if postgres.as_ref().ok() != Some(&expected) {
+            divergences.push(Divergence {
+                filter: Some(filter.clone()),
+                operation: "count",
+                postgres: outcome(postgres),
+                memory: expected.to_string(),
+            });
+        }
+    }
+
+    let unknown = EventId::all_zeros();
+    for id in events.iter().map(|e| e.id).chain([unknown]) {
+        let expected = memory.check_id(&id).await?;
+        let postgres = db.check_id(&id).await;
+        if postgres.as_ref().ok() != Some(&expected) {
+            divergences.push(Divergence {
+                filter: None,
+                operation: "check_id",
+                postgres: format!("{id}: {}", outcome(postgres.map(|s| format!("{s:?}")))),
+                memory: format!("{id}: {expected:?}"),
+            });
+        }
+    }
+    Ok(divergences)
+}
+
+/// Like [`compare_with_memory`], panicking with the divergences if there are any
+pub async fn assert_agrees_with_memory(db: &NostrPostgres, events: &[Event], filters: &[Filter]) {
+    let divergences = compare_with_memory(db, events, filters)
+        .await
+        .expect("comparing with MemoryDatabase failed");
+    assert!(
+        divergences.is_empty(),
+        "NostrPostgres diverges from MemoryDatabase: {divergences:#?}"
+    );
+}
+
+/// the result of a [`NostrPostgres`] operation for a [`Divergence`]
+fn outcome<T: std::fmt::Display>(result: Result<T, DatabaseError>) -> String {
+    match result {
+        Ok(value) => value.to_string(),
+        Err(e) => format!("error: {e}"),
+    }
+}
+
+fn event_ids<'a, I>(events: I) -> String
+where
+    I: IntoIterator<Item = &'a Event>,
+{
+    let ids: BTreeSet<String> = events.into_iter().map(|e| e.id.to_hex()).collect();
+    format!("{ids:?}")
+}
+
+fn creation_times<'a, I>(events: I) -> String
+where
+    I: IntoIterator<Item = &'a Event>,
+{
+    let mut times: BTreeMap<u64, usize> = BTreeMap::new();
+    for event in events {
+        *times.entry(event.created_at.as_u64()).or_default() += 1;
+    }
+    format!("{times:?}")
+}
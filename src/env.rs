@@ -0,0 +1,148 @@
+use std::env::{self, VarError};
+
+use nostr_database::DatabaseError;
+
+use crate::builder::NostrPostgresBuilder;
+use crate::error::ConfigError;
+
+/// Prefix of the environment variables read by [`NostrPostgresBuilder::from_env`]
+pub(crate) const DEFAULT_ENV_PREFIX: &str = "NOSTR_POSTGRES_";
+
+impl NostrPostgresBuilder {
+    /// Create a builder from the `NOSTR_POSTGRES_*` environment variables
+    ///
+    /// Reads the connection string from `NOSTR_POSTGRES_URL`, falling back to `DATABASE_URL`,
+    /// and the optional `NOSTR_POSTGRES_MAX_CONNECTIONS` and `NOSTR_POSTGRES_SCHEMA`.
+    /// TLS is not supported by the connections of this crate, so there are no TLS variables.
+    pub fn from_env() -> Result<Self, DatabaseError> {
+        Self::from_env_prefixed(DEFAULT_ENV_PREFIX, Some("DATABASE_URL"))
+    }
+
+    /// Create a builder from environment variables with the given prefix
+    ///
+    /// Reads `{prefix}URL`, `{prefix}MAX_CONNECTIONS` and `{prefix}SCHEMA`, so several stores
+    /// can be configured in one process.
+    pub fn from_env_with_prefix(prefix: &str) -> Result<Self, DatabaseError> {
+        Self::from_env_prefixed(prefix, None)
+    }
+
+    fn from_env_prefixed(prefix: &str, fallback_url: Option<&str>) -> Result<Self, DatabaseError> {
+        let url_var = format!("{prefix}URL");
+        let url = match (var(&url_var)?, fallback_url) {
+            (Some(url), _) => url,
+            (None, Some(fallback)) => var(fallback)?
+                .ok_or_else(|| config_error(format!("neither {url_var} nor {fallback} is set")))?,
+            (None, None) => return Err(config_error(format!("{url_var} is not set"))),
+        };
+
+        let mut builder = Self::new(url);
+
+        let max_connections_var = format!("{prefix}MAX_CONNECTIONS");
+        if let Some(max_connections) = var(&max_connections_var)? {
+            let max_connections = max_connections
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| {
+                    config_error(format!("{max_connections_var} must be a positive integer"))
+                })?;
+            builder = builder.max_connections(max_connections);
+        }
+
+        if let Some(schema) = var(&format!("{prefix}SCHEMA"))? {
+            builder = builder.schema(schema);
+        }
+
+        Ok(builder)
+    }
+}
+
+/// reads a variable, treating empty values as unset and never echoing the value in errors
+fn var(name: &str) -> Result<Option<String>, DatabaseError> {
+    match env::var(name) {
+        Ok(value) if value.trim().is_empty() => Ok(None),
+        Ok(value) => Ok(Some(value)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(VarError::NotUnicode(_)) => Err(config_error(format!("{name} is not valid unicode"))),
+    }
+}
+
+fn config_error(message: String) -> DatabaseError {
+    DatabaseError::backend(ConfigError::new(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// sets the variables of `prefix`, unique per test as tests run in parallel
+    fn set(prefix: &str, vars: &[(&str, &str)]) {
+        for (name, value) in vars {
+            // SAFETY: no other test reads or writes the variables of this prefix
+            unsafe { env::set_var(format!("{prefix}{name}"), value) };
+        }
+    }
+
+    fn error(result: Result<NostrPostgresBuilder, DatabaseError>) -> String {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn complete_configuration() {
+        let prefix = "NOSTR_POSTGRES_TEST_COMPLETE_";
+        set(
+            prefix,
+            &[
+                ("URL", "postgres://relay:secret@db/relay"),
+                ("MAX_CONNECTIONS", " 12 "),
+                ("SCHEMA", "relay"),
+            ],
+        );
+        let builder = NostrPostgresBuilder::from_env_with_prefix(prefix).unwrap();
+        let (url, config) = builder.parts();
+        assert_eq!(url, "postgres://relay:secret@db/relay");
+        assert_eq!(config.max_connections, Some(12));
+        assert_eq!(config.schema.as_deref(), Some("relay"));
+    }
+
+    #[test]
+    fn optional_variables_keep_the_defaults() {
+        let prefix = "NOSTR_POSTGRES_TEST_MINIMAL_";
+        set(prefix, &[("URL", "postgres://db/relay"), ("SCHEMA", " ")]);
+        let builder = NostrPostgresBuilder::from_env_with_prefix(prefix).unwrap();
+        let (url, config) = builder.parts();
+        assert_eq!(url, "postgres://db/relay");
+        assert_eq!(config.max_connections, None);
+        assert_eq!(config.schema, None);
+    }
+
+    #[test]
+    fn missing_url() {
+        let prefix = "NOSTR_POSTGRES_TEST_MISSING_";
+        set(prefix, &[("MAX_CONNECTIONS", "4")]);
+        let message = error(NostrPostgresBuilder::from_env_with_prefix(prefix));
+        assert!(message.contains("NOSTR_POSTGRES_TEST_MISSING_URL is not set"));
+        // empty counts as unset
+        set(prefix, &[("URL", "")]);
+        let message = error(NostrPostgresBuilder::from_env_with_prefix(prefix));
+        assert!(message.contains("NOSTR_POSTGRES_TEST_MISSING_URL is not set"));
+    }
+
+    #[test]
+    fn malformed_max_connections() {
+        let prefix = "NOSTR_POSTGRES_TEST_MALFORMED_";
+        set(prefix, &[("URL", "postgres://db/relay")]);
+        for value in ["0", "-1", "many", "hunter2"] {
+            set(prefix, &[("MAX_CONNECTIONS", value)]);
+            let message = error(NostrPostgresBuilder::from_env_with_prefix(prefix));
+            assert!(message.contains(
+                "NOSTR_POSTGRES_TEST_MALFORMED_MAX_CONNECTIONS must be a positive integer"
+            ));
+            assert!(!message.contains(value), "{message} echoes {value}");
+        }
+    }
+}
@@ -0,0 +1,833 @@
+use std::time::Duration;
+
+use deadpool::managed::TimeoutType;
+use diesel_async::pooled_connection::deadpool::PoolError;
+use nostr::event::EventId;
+use nostr_database::DatabaseError;
+
+use crate::retry::ConnectRetryError;
+use crate::verify::SchemaReport;
+
+/// Returned when a database operation did not complete within its deadline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutError {
+    operation: &'static str,
+    timeout: Duration,
+}
+
+impl TimeoutError {
+    pub(crate) fn new(operation: &'static str, timeout: Duration) -> Self {
+        Self { operation, timeout }
+    }
+
+    /// Name of the operation that timed out
+    pub fn operation(&self) -> &'static str {
+        self.operation
+    }
+
+    /// The deadline that was exceeded
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+impl std::fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} timed out after {:?}", self.operation, self.timeout)
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+/// Yielded by a subscription whose subscriber didn't keep up, in place of the skipped events
+///
+/// The subscription goes on after it, see [`OverflowPolicy`](crate::OverflowPolicy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LaggedError {
+    skipped: u64,
+}
+
+impl LaggedError {
+    pub(crate) fn new(skipped: u64) -> Self {
+        Self { skipped }
+    }
+
+    /// Number of skipped events
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+impl std::fmt::Display for LaggedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "subscriber lagged behind, {} events skipped",
+            self.skipped
+        )
+    }
+}
+
+impl std::error::Error for LaggedError {}
+
+/// Returned by operations on a [`NostrPostgres`](crate::NostrPostgres) instance that was closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClosedError;
+
+impl std::fmt::Display for ClosedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "database is closed")
+    }
+}
+
+impl std::error::Error for ClosedError {}
+
+/// Returned when a [`NostrPostgres`](crate::NostrPostgres) instance was configured with invalid
+/// settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    message: String,
+}
+
+impl ConfigError {
+    pub(crate) fn new<S>(message: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid configuration: {}", self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Why a connection could not be acquired from the pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolErrorKind {
+    /// All connections are in use and none became available within the acquire timeout
+    Exhausted,
+    /// A new connection could not be established or set up
+    Connect,
+    /// The pool was closed
+    Closed,
+    /// Any other pool failure
+    Other,
+}
+
+/// Returned when a connection could not be acquired from the pool
+#[derive(Debug)]
+pub struct PoolAcquireError {
+    kind: PoolErrorKind,
+    source: PoolError,
+}
+
+impl PoolAcquireError {
+    /// Classification of the failure
+    pub fn kind(&self) -> PoolErrorKind {
+        self.kind
+    }
+}
+
+impl From<PoolError> for PoolAcquireError {
+    fn from(source: PoolError) -> Self {
+        let kind = match &source {
+            PoolError::Timeout(TimeoutType::Wait) => PoolErrorKind::Exhausted,
+            PoolError::Timeout(TimeoutType::Create) | PoolError::Backend(_) => {
+                PoolErrorKind::Connect
+            }
+            PoolError::Closed => PoolErrorKind::Closed,
+            _ => PoolErrorKind::Other,
+        };
+        Self { kind, source }
+    }
+}
+
+impl std::fmt::Display for PoolAcquireError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            PoolErrorKind::Exhausted => write!(f, "connection pool exhausted: {}", self.source),
+            PoolErrorKind::Connect => write!(f, "failed to connect: {}", self.source),
+            PoolErrorKind::Closed => write!(f, "connection pool closed"),
+            PoolErrorKind::Other => write!(f, "connection pool error: {}", self.source),
+        }
+    }
+}
+
+impl std::error::Error for PoolAcquireError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Classify a [`DatabaseError`] returned by [`NostrPostgres`](crate::NostrPostgres) as a pool
+/// acquisition failure
+///
+/// Returns `None` if the error did not occur while acquiring a connection.
+pub fn pool_error_kind(error: &DatabaseError) -> Option<PoolErrorKind> {
+    PostgresDbError::pool_error(error).map(|e| e.kind)
+}
+
+/// Whether the error was caused by all pooled connections being busy
+pub fn is_pool_exhausted(error: &DatabaseError) -> bool {
+    pool_error_kind(error) == Some(PoolErrorKind::Exhausted)
+}
+
+/// Returned when the database schema lacks objects created by the migrations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaMismatchError {
+    missing: Vec<String>,
+}
+
+impl SchemaMismatchError {
+    pub(crate) fn new(missing: Vec<String>) -> Self {
+        Self { missing }
+    }
+
+    /// The missing tables, columns and indexes, e.g. `table events` or `index event_kind`
+    pub fn missing(&self) -> &[String] {
+        &self.missing
+    }
+}
+
+impl std::fmt::Display for SchemaMismatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "missing from database schema: {}",
+            self.missing.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for SchemaMismatchError {}
+
+/// Returned when a filter asks for a tag that is excluded by the
+/// [`TagIndexing`](crate::TagIndexing) policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnindexedTagError {
+    tag: String,
+}
+
+impl UnindexedTagError {
+    pub(crate) fn new(tag: String) -> Self {
+        Self { tag }
+    }
+
+    /// Name of the tag
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+}
+
+impl std::fmt::Display for UnindexedTagError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tag {} is not indexed", self.tag)
+    }
+}
+
+impl std::error::Error for UnindexedTagError {}
+
+/// Returned when a filter asks for more events than the [`QueryGuard`](crate::QueryGuard)
+/// allows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryGuardError {
+    limit: usize,
+    allowed: usize,
+    selective: bool,
+}
+
+impl QueryGuardError {
+    pub(crate) fn new(limit: usize, allowed: usize, selective: bool) -> Self {
+        Self {
+            limit,
+            allowed,
+            selective,
+        }
+    }
+
+    /// Number of events the filter asks for, the default limit if it has none
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Number of events allowed for the filter
+    pub fn allowed(&self) -> usize {
+        self.allowed
+    }
+
+    /// Whether the filter has ids, authors, kinds, tags or `since`
+    pub fn is_selective(&self) -> bool {
+        self.selective
+    }
+}
+
+impl std::fmt::Display for QueryGuardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "filter asks for {} events, at most {} allowed",
+            self.limit, self.allowed
+        )?;
+        if !self.selective {
+            write!(f, " without ids, authors, kinds, tags or since")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for QueryGuardError {}
+
+/// Returned by [`query_many`](crate::NostrPostgres::query_many) for the filter that failed
+#[derive(Debug)]
+pub struct FilterError {
+    index: usize,
+    source: DatabaseError,
+}
+
+impl FilterError {
+    pub(crate) fn new(index: usize, source: DatabaseError) -> Self {
+        Self { index, source }
+    }
+
+    /// Position of the filter in the list passed to the query
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The error of the filter's query
+    pub fn error(&self) -> &DatabaseError {
+        &self.source
+    }
+}
+
+impl std::fmt::Display for FilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "filter {}: {}", self.index, self.source)
+    }
+}
+
+impl std::error::Error for FilterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Returned when [`rollback_migrations`](crate::rollback_migrations) would have to revert a
+/// migration that can't be undone without losing data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IrreversibleMigrationError {
+    version: String,
+}
+
+impl IrreversibleMigrationError {
+    pub(crate) fn new(version: String) -> Self {
+        Self { version }
+    }
+
+    /// Version of the irreversible migration
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+}
+
+impl std::fmt::Display for IrreversibleMigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "migration {} is irreversible", self.version)
+    }
+}
+
+impl std::error::Error for IrreversibleMigrationError {}
+
+/// Returned when the schema drifted from the migrations and
+/// [`SchemaValidation::Fail`](crate::SchemaValidation) was requested
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaDriftError {
+    report: SchemaReport,
+}
+
+impl SchemaDriftError {
+    pub(crate) fn new(report: SchemaReport) -> Self {
+        Self { report }
+    }
+
+    /// The differences found
+    pub fn report(&self) -> &SchemaReport {
+        &self.report
+    }
+}
+
+impl std::fmt::Display for SchemaDriftError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "database schema drifted: {}", self.report)
+    }
+}
+
+impl std::error::Error for SchemaDriftError {}
+
+/// Returned when an export failed writing to its writer, as opposed to reading from the
+/// database
+///
+/// The database side of the export is cancelled, so the export can simply be retried with a
+/// new writer.
+#[derive(Debug)]
+pub struct ExportWriteError {
+    written: u64,
+    source: std::io::Error,
+}
+
+impl ExportWriteError {
+    pub(crate) fn new(written: u64, source: std::io::Error) -> Self {
+        Self { written, source }
+    }
+
+    /// Number of events written completely before the failure
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// The error returned by the writer
+    pub fn io_error(&self) -> &std::io::Error {
+        &self.source
+    }
+}
+
+impl std::fmt::Display for ExportWriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "writing the export failed after {} events: {}",
+            self.written, self.source
+        )
+    }
+}
+
+impl std::error::Error for ExportWriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// What failed when verifying a stored event on read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityErrorKind {
+    /// The id of the payload differs from the id column
+    IdMismatch,
+    /// The id hash or the signature of the event is invalid
+    InvalidSignature,
+}
+
+/// Returned for a stored event failing the
+/// [`ReadVerification`](crate::ReadVerification) configured on the instance
+///
+/// Means the row was corrupted or tampered with; queries leave such events out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntegrityError {
+    kind: IntegrityErrorKind,
+    stored_id: String,
+    payload_id: EventId,
+}
+
+impl IntegrityError {
+    pub(crate) fn new(kind: IntegrityErrorKind, stored_id: String, payload_id: EventId) -> Self {
+        Self {
+            kind,
+            stored_id,
+            payload_id,
+        }
+    }
+
+    /// What failed
+    pub fn kind(&self) -> IntegrityErrorKind {
+        self.kind
+    }
+
+    /// The id column of the row, in hex
+    pub fn stored_id(&self) -> &str {
+        &self.stored_id
+    }
+
+    /// The id of the event decoded from the payload
+    pub fn payload_id(&self) -> EventId {
+        self.payload_id
+    }
+}
+
+impl std::fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            IntegrityErrorKind::IdMismatch => write!(
+                f,
+                "stored event {} holds the payload of event {}",
+                self.stored_id, self.payload_id
+            ),
+            IntegrityErrorKind::InvalidSignature => {
+                write!(
+                    f,
+                    "stored event {} has an invalid signature",
+                    self.stored_id
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Coarse class of a [`PostgresDbError`], e.g. to decide which errors to retry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorClass {
+    /// No connection could be acquired from the pool
+    Pool,
+    /// The connection failed or was terminated by the server, e.g. during a failover
+    Connection,
+    /// The operation did not complete within its deadline
+    Timeout,
+    /// The transaction conflicted with a concurrent one: a serialization failure or deadlock
+    Conflict,
+    /// A statement violated a constraint, e.g. a duplicate key
+    Constraint,
+    /// The server rejected a statement for any other reason
+    Sql,
+    /// A stored event or column could not be decoded
+    Decode,
+    /// A stored event failed verification on read
+    Integrity,
+    /// Running, reverting or checking the migrations failed
+    Migration,
+    /// The instance was closed
+    Closed,
+    /// The instance or the request is misconfigured
+    Config,
+    /// The operation is not supported
+    NotSupported,
+    /// Any other failure
+    Other,
+}
+
+impl ErrorClass {
+    /// The class of a [`DatabaseError`] returned by [`NostrPostgres`](crate::NostrPostgres)
+    ///
+    /// Same as converting it into a [`PostgresDbError`], without consuming it.
+    pub fn of(error: &DatabaseError) -> Self {
+        match error {
+            DatabaseError::Backend(e) => class_of(e.as_ref()),
+            DatabaseError::NotSupported => Self::NotSupported,
+        }
+    }
+
+    /// Whether errors of this class usually go away when retried: pool, connection and
+    /// conflict errors
+    pub fn is_transient(self) -> bool {
+        matches!(self, Self::Pool | Self::Connection | Self::Conflict)
+    }
+
+    /// the class of a statement error with the SQLSTATE `code`
+    fn of_sqlstate(code: Option<&str>) -> Self {
+        match code {
+            Some(code) if code.starts_with("23") => Self::Constraint,
+            Some(code) if code.starts_with("08") => Self::Connection,
+            Some("57P01" | "57P02" | "57P03") => Self::Connection,
+            Some("40001" | "40P01") => Self::Conflict,
+            Some(QUERY_CANCELED) => Self::Timeout,
+            _ => Self::Sql,
+        }
+    }
+}
+
+/// A classified error of [`NostrPostgres`](crate::NostrPostgres)
+///
+/// Tells connection problems, rejected statements, undecodable data and timeouts apart, e.g.
+/// to decide whether to retry or alert. Converts to and from [`DatabaseError`]: converting a
+/// [`DatabaseError`] returned by this crate classifies it, converting back wraps the
+/// classified error, so the class survives the [`NostrDatabase`](nostr_database::NostrDatabase)
+/// trait. The original error is the [`source`](std::error::Error::source).
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PostgresDbError {
+    /// No connection could be acquired from the pool
+    Pool(PoolAcquireError),
+    /// The connection to the server failed or was lost
+    Connection(BoxError),
+    /// The server rejected a statement
+    Sql {
+        /// The SQLSTATE of the error, e.g. `23505` for a unique violation, if known
+        code: Option<String>,
+        /// The error returned by the driver
+        source: BoxError,
+    },
+    /// A stored event or column could not be decoded
+    Decode(BoxError),
+    /// A stored event failed verification on read
+    Integrity(IntegrityError),
+    /// The operation did not complete within its deadline
+    Timeout(TimeoutError),
+    /// Running, reverting or checking the migrations failed
+    Migration(BoxError),
+    /// The instance was closed
+    Closed(ClosedError),
+    /// The instance or the request is misconfigured, e.g. a filter on an unindexed tag
+    Config(BoxError),
+    /// The operation is not supported
+    NotSupported,
+    /// Any other failure
+    Other(BoxError),
+}
+
+impl PostgresDbError {
+    /// The SQLSTATE of the failed statement, if known
+    ///
+    /// Timeouts report `57014` (query canceled).
+    pub fn sqlstate(&self) -> Option<&str> {
+        match self {
+            Self::Sql { code, .. } => code.as_deref(),
+            Self::Timeout(_) => Some(QUERY_CANCELED),
+            _ => None,
+        }
+    }
+
+    /// Whether a statement violated a constraint, SQLSTATE class `23`, e.g. a duplicate key
+    pub fn is_constraint_violation(&self) -> bool {
+        self.class() == ErrorClass::Constraint
+    }
+
+    /// Coarse class of the error, e.g. to decide whether to retry
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Self::Pool(_) => ErrorClass::Pool,
+            Self::Connection(_) => ErrorClass::Connection,
+            Self::Sql { code, .. } => ErrorClass::of_sqlstate(code.as_deref()),
+            Self::Decode(_) => ErrorClass::Decode,
+            Self::Integrity(_) => ErrorClass::Integrity,
+            Self::Timeout(_) => ErrorClass::Timeout,
+            Self::Migration(_) => ErrorClass::Migration,
+            Self::Closed(_) => ErrorClass::Closed,
+            Self::Config(_) => ErrorClass::Config,
+            Self::NotSupported => ErrorClass::NotSupported,
+            Self::Other(_) => ErrorClass::Other,
+        }
+    }
+
+    /// classifies an error boxed into a [`DatabaseError`]
+    fn classify(error: BoxError) -> Self {
+        let error = match error.downcast::<Self>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        let error = match error.downcast::<diesel::result::Error>() {
+            Ok(e) => return Self::from(*e),
+            Err(e) => e,
+        };
+        let error = match error.downcast::<tokio_postgres::Error>() {
+            Ok(e) => return Self::from(*e),
+            Err(e) => e,
+        };
+        match class_of(error.as_ref()) {
+            ErrorClass::Pool => match error.downcast::<PoolAcquireError>() {
+                Ok(e) => Self::Pool(*e),
+                Err(e) => Self::Other(e),
+            },
+            ErrorClass::Timeout => match error.downcast::<TimeoutError>() {
+                Ok(e) => Self::Timeout(*e),
+                Err(e) => Self::Other(e),
+            },
+            ErrorClass::Closed => match error.downcast::<ClosedError>() {
+                Ok(e) => Self::Closed(*e),
+                Err(e) => Self::Other(e),
+            },
+            ErrorClass::Integrity => match error.downcast::<IntegrityError>() {
+                Ok(e) => Self::Integrity(*e),
+                Err(e) => Self::Other(e),
+            },
+            ErrorClass::Connection => Self::Connection(error),
+            ErrorClass::Decode => Self::Decode(error),
+            ErrorClass::Migration => Self::Migration(error),
+            ErrorClass::Config => Self::Config(error),
+            _ => Self::Other(error),
+        }
+    }
+
+    /// the pool error, also when wrapped into a [`DatabaseError`]
+    fn pool_error(error: &DatabaseError) -> Option<&PoolAcquireError> {
+        let DatabaseError::Backend(e) = error else {
+            return None;
+        };
+        match e.downcast_ref::<Self>() {
+            Some(Self::Pool(e)) => Some(e),
+            Some(_) => None,
+            None => e.downcast_ref::<PoolAcquireError>(),
+        }
+    }
+}
+
+/// SQLSTATE of a statement cancelled by `statement_timeout`
+const QUERY_CANCELED: &str = "57014";
+
+impl From<DatabaseError> for PostgresDbError {
+    fn from(error: DatabaseError) -> Self {
+        match error {
+            DatabaseError::Backend(e) => Self::classify(e),
+            DatabaseError::NotSupported => Self::NotSupported,
+        }
+    }
+}
+
+impl From<PostgresDbError> for DatabaseError {
+    fn from(error: PostgresDbError) -> Self {
+        match error {
+            PostgresDbError::NotSupported => DatabaseError::NotSupported,
+            e => DatabaseError::backend(e),
+        }
+    }
+}
+
+impl From<PoolAcquireError> for PostgresDbError {
+    fn from(error: PoolAcquireError) -> Self {
+        Self::Pool(error)
+    }
+}
+
+impl From<diesel::result::Error> for PostgresDbError {
+    fn from(error: diesel::result::Error) -> Self {
+        match diesel_sqlstate(&error) {
+            Ok(code) => Self::Sql {
+                code: code.map(str::to_string),
+                source: Box::new(error),
+            },
+            Err(ErrorClass::Connection) => Self::Connection(Box::new(error)),
+            Err(ErrorClass::Decode) => Self::Decode(Box::new(error)),
+            Err(_) => Self::Other(Box::new(error)),
+        }
+    }
+}
+
+/// the SQLSTATE of a statement rejected by the server, if known, or the class of any other
+/// diesel error
+fn diesel_sqlstate(error: &diesel::result::Error) -> Result<Option<&'static str>, ErrorClass> {
+    use diesel::result::{DatabaseErrorKind, Error};
+
+    match error {
+        Error::DatabaseError(kind, info) => match kind {
+            DatabaseErrorKind::UniqueViolation => Ok(Some("23505")),
+            DatabaseErrorKind::ForeignKeyViolation => Ok(Some("23503")),
+            DatabaseErrorKind::NotNullViolation => Ok(Some("23502")),
+            DatabaseErrorKind::CheckViolation => Ok(Some("23514")),
+            DatabaseErrorKind::SerializationFailure => Ok(Some("40001")),
+            DatabaseErrorKind::ReadOnlyTransaction => Ok(Some("25006")),
+            DatabaseErrorKind::UnableToSendCommand | DatabaseErrorKind::ClosedConnection => {
+                Err(ErrorClass::Connection)
+            }
+            // diesel doesn't expose the SQLSTATE of the remaining errors
+            _ if info.message().contains("statement timeout") => Ok(Some(QUERY_CANCELED)),
+            _ if info.message().starts_with("deadlock detected") => Ok(Some("40P01")),
+            _ if info.message().starts_with("terminating connection") => Ok(Some("57P01")),
+            _ => Ok(None),
+        },
+        Error::DeserializationError(_) => Err(ErrorClass::Decode),
+        Error::BrokenTransactionManager => Err(ErrorClass::Connection),
+        _ => Err(ErrorClass::Other),
+    }
+}
+
+/// the class of an error boxed into a [`DatabaseError`]
+fn class_of(error: &(dyn std::error::Error + Send + Sync + 'static)) -> ErrorClass {
+    if let Some(e) = error.downcast_ref::<PostgresDbError>() {
+        e.class()
+    } else if let Some(e) = error.downcast_ref::<diesel::result::Error>() {
+        diesel_sqlstate(e).map_or_else(|class| class, ErrorClass::of_sqlstate)
+    } else if let Some(e) = error.downcast_ref::<tokio_postgres::Error>() {
+        e.code().map_or(ErrorClass::Connection, |code| {
+            ErrorClass::of_sqlstate(Some(code.code()))
+        })
+    } else if let Some(e) = error.downcast_ref::<FilterError>() {
+        ErrorClass::of(&e.source)
+    } else if error.is::<PoolAcquireError>() {
+        ErrorClass::Pool
+    } else if error.is::<TimeoutError>() {
+        ErrorClass::Timeout
+    } else if error.is::<ClosedError>() {
+        ErrorClass::Closed
+    } else if error.is::<IntegrityError>() {
+        ErrorClass::Integrity
+    } else if error.is::<ConfigError>()
+        || error.is::<UnindexedTagError>()
+        || error.is::<QueryGuardError>()
+    {
+        ErrorClass::Config
+    } else if error.is::<IrreversibleMigrationError>()
+        || error.is::<SchemaMismatchError>()
+        || error.is::<SchemaDriftError>()
+    {
+        ErrorClass::Migration
+    } else if error.is::<diesel::ConnectionError>() || error.is::<ConnectRetryError>() {
+        ErrorClass::Connection
+    } else if error.is::<nostr_database::flatbuffers::Error>()
+        || error.is::<nostr::event::Error>()
+        || error.is::<nostr::key::Error>()
+    {
+        ErrorClass::Decode
+    } else {
+        ErrorClass::Other
+    }
+}
+
+impl From<tokio_postgres::Error> for PostgresDbError {
+    fn from(error: tokio_postgres::Error) -> Self {
+        match error.code() {
+            Some(code) => Self::Sql {
+                code: Some(code.code().to_string()),
+                source: Box::new(error),
+            },
+            None => Self::Connection(Box::new(error)),
+        }
+    }
+}
+
+impl std::fmt::Display for PostgresDbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pool(e) => write!(f, "{e}"),
+            Self::Connection(e) => write!(f, "connection failed: {e}"),
+            Self::Sql {
+                code: Some(code),
+                source,
+            } => write!(f, "statement failed with SQLSTATE {code}: {source}"),
+            Self::Sql { code: None, source } => write!(f, "statement failed: {source}"),
+            Self::Decode(e) => write!(f, "failed to decode stored data: {e}"),
+            Self::Timeout(e) => write!(f, "{e}"),
+            Self::Migration(e) => write!(f, "migration failed: {e}"),
+            Self::Closed(e) => write!(f, "{e}"),
+            Self::Integrity(e) => write!(f, "{e}"),
+            Self::Config(e) => write!(f, "{e}"),
+            Self::NotSupported => write!(f, "not supported"),
+            Self::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for PostgresDbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Pool(e) => Some(e),
+            Self::Timeout(e) => Some(e),
+            Self::Closed(e) => Some(e),
+            Self::Integrity(e) => Some(e),
+            Self::Connection(e)
+            | Self::Sql { source: e, .. }
+            | Self::Decode(e)
+            | Self::Migration(e)
+            | Self::Config(e)
+            | Self::Other(e) => Some(e.as_ref()),
+            Self::NotSupported => None,
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use diesel::dsl::{exists, not};
+use diesel::prelude::*;
+use nostr::event::{EventId, Kind};
+use nostr::filter::SingleLetterTag;
+use nostr::key::PublicKey;
+
+use crate::query::EventCondition;
+use crate::schema::postgres::{event_tags, events};
+
+/// Events to leave out of [`NostrPostgres::query_excluding`](crate::NostrPostgres::query_excluding),
+/// the negation a [`Filter`](nostr::Filter) can't express
+///
+/// Every exclusion applies on top of the filter; empty lists exclude nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Exclusions {
+    not_kinds: BTreeSet<Kind>,
+    not_authors: BTreeSet<PublicKey>,
+    not_ids: BTreeSet<EventId>,
+    not_tags: BTreeMap<SingleLetterTag, BTreeSet<String>>,
+}
+
+impl Exclusions {
+    /// No exclusions
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Leave out events of these kinds
+    pub fn not_kinds<I>(mut self, kinds: I) -> Self
+    where
+        I: IntoIterator<Item = Kind>,
+    {
+        self.not_kinds.extend(kinds);
+        self
+    }
+
+    /// Leave out events of these authors, e.g. muted ones
+    pub fn not_authors<I>(mut self, authors: I) -> Self
+    where
+        I: IntoIterator<Item = PublicKey>,
+    {
+        self.not_authors.extend(authors);
+        self
+    }
+
+    /// Leave out these events
+    pub fn not_ids<I>(mut self, ids: I) -> Self
+    where
+        I: IntoIterator<Item = EventId>,
+    {
+        self.not_ids.extend(ids);
+        self
+    }
+
+    /// Leave out events with an indexed `tag` of any of these values
+    pub fn not_tag<I, S>(mut self, tag: SingleLetterTag, values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.not_tags
+            .entry(tag)
+            .or_default()
+            .extend(values.into_iter().map(Into::into));
+        self
+    }
+
+    /// the names of the excluded tags
+    pub(crate) fn tags(&self) -> impl Iterator<Item = String> + '_ {
+        self.not_tags
+            .iter()
+            .filter(|(_, values)| !values.is_empty())
+            .map(|(tag, _)| tag.to_string())
+    }
+
+    /// the condition leaving out the excluded events
+    pub(crate) fn condition<'a>(&self) -> EventCondition<'a> {
+        let mut condition: EventCondition<'a> = Box::new(diesel::dsl::sql("TRUE"));
+        if !self.not_kinds.is_empty() {
+            let kinds: Vec<i64> = self.not_kinds.iter().map(|k| k.as_u16() as i64).collect();
+            condition = Box::new(condition.and(not(events::kind.eq_any(kinds))));
+        }
+        if !self.not_authors.is_empty() {
+            let authors: Vec<Vec<u8>> = self
+                .not_authors
+                .iter()
+                .map(|a| a.as_bytes().to_vec())
+                .collect();
+            condition = Box::new(condition.and(not(events::pubkey.eq_any(authors))));
+        }
+        if !self.not_ids.is_empty() {
+            let ids: Vec<Vec<u8>> = self
+                .not_ids
+                .iter()
+                .map(|id| id.as_bytes().to_vec())
+                .collect();
+            condition = Box::new(condition.and(not(events::id.eq_any(ids))));
+        }
+        for (tag, values) in self.not_tags.iter().filter(|(_, v)| !v.is_empty()) {
+            let values: Vec<String> = values.iter().cloned().collect();
+            condition = Box::new(
+                condition.and(not(exists(
+                    event_tags::table
+                        .filter(event_tags::event_id.eq(events::id))
+                        .filter(event_tags::tag.eq(tag.to_string()))
+                        .filter(event_tags::tag_value.eq_any(values)),
+                ))),
+            );
+        }
+        condition
+    }
+}
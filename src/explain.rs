@@ -0,0 +1,195 @@
+use std::time::Duration;
+
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::query_builder::{AstPass, Query, QueryFragment, QueryId};
+use diesel::result::Error as DieselError;
+use diesel::sql_types::Json;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use nostr::filter::Filter;
+use nostr_database::DatabaseError;
+use serde::{Deserialize, Serialize};
+
+use crate::error::TimeoutError;
+use crate::model::EventDb;
+use crate::query::{build_filter_query, render, with_limit};
+
+/// The SQL of a filter query and how Postgres plans it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExplainOutput {
+    /// The SQL with `$n` placeholders instead of the filter values
+    pub sql: String,
+    /// Number of bind parameters
+    pub params: usize,
+    /// The output of `EXPLAIN (FORMAT JSON)`
+    pub plan: serde_json::Value,
+}
+
+/// The SQL [`query`](nostr_database::NostrDatabase::query) runs for `filter`, with `$n`
+/// placeholders instead of the values, and its number of bind parameters
+///
+/// Doesn't need a database, e.g. to inspect the generated SQL in tests. A tag of the filter
+/// with an empty value set becomes `FALSE`: it matches no event, as in
+/// [`Filter::match_event`], and so does the filter in queries, counts and deletes.
+pub fn render_sql(filter: &Filter) -> (String, usize) {
+    render(&filter_query(filter.clone()))
+}
+
+/// the query of [`NostrPostgres::query`](crate::NostrPostgres) for `filter`
+fn filter_query<'a>(filter: Filter) -> impl QueryFragment<Pg> + Query + QueryId + Send + 'a {
+    build_filter_query(with_limit(filter, 10000)).select(EventDb::as_select())
+}
+
+/// `EXPLAIN` of a query
+struct Explain<Q> {
+    analyze: bool,
+    query: Q,
+}
+
+impl<Q> QueryFragment<Pg> for Explain<Q>
+where
+    Q: QueryFragment<Pg>,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.push_sql("EXPLAIN (FORMAT JSON");
+        if self.analyze {
+            out.push_sql(", ANALYZE");
+        }
+        out.push_sql(") ");
+        self.query.walk_ast(out.reborrow())
+    }
+}
+
+impl<Q> QueryId for Explain<Q> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<Q> Query for Explain<Q> {
+    type SqlType = Json;
+}
+
+/// ends the transaction of an analyzed query, keeping its plan
+enum Rollback {
+    Plan(serde_json::Value),
+    Failed(DieselError),
+}
+
+impl From<DieselError> for Rollback {
+    fn from(e: DieselError) -> Self {
+        Self::Failed(e)
+    }
+}
+
+/// explains the query of `filter` in a transaction that is always rolled back, so an
+/// `ANALYZE` can't change anything
+pub(crate) async fn explain(
+    db: &mut AsyncPgConnection,
+    filter: Filter,
+    analyze: bool,
+    timeout: Option<Duration>,
+) -> Result<ExplainOutput, DatabaseError> {
+    let query = filter_query(filter);
+    let (sql, params) = render(&query);
+    let explain = Explain { analyze, query };
+    let res = db
+        .transaction(|c| {
+            async move {
+                if let Some(timeout) = timeout {
+                    diesel::sql_query(format!(
+                        "SET LOCAL statement_timeout = {}",
+                        timeout.as_millis().max(1)
+                    ))
+                    .execute(c)
+                    .await?;
+                }
+                let plan: serde_json::Value = explain.get_result(c).await?;
+                Err::<(), _>(Rollback::Plan(plan))
+            }
+            .scope_boxed()
+        })
+        .await;
+    match res {
+        Err(Rollback::Plan(plan)) => Ok(ExplainOutput { sql, params, plan }),
+        Err(Rollback::Failed(DieselError::DatabaseError(_, info)))
+            if info.message().contains("statement timeout") =>
+        {
+            Err(DatabaseError::backend(TimeoutError::new(
+                "explain",
+                timeout.unwrap_or_default(),
+            )))
+        }
+        Err(Rollback::Failed(e)) => Err(DatabaseError::backend(e)),
+        Ok(()) => unreachable!("the transaction is always rolled back"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use nostr::{Alphabet, EventId, Keys, Kind, SingleLetterTag, Timestamp};
+
+    use super::*;
+
+    #[test]
+    fn binds_the_default_limit() {
+        let (sql, params) = render_sql(&Filter::new());
+        assert_eq!(params, 2);
+        assert!(sql.contains("\"deleted\" = $1"), "{sql}");
+        assert!(sql.ends_with("LIMIT $2"), "{sql}");
+    }
+
+    #[test]
+    fn counts_every_placeholder() {
+        let t = SingleLetterTag::lowercase(Alphabet::T);
+        let filter = Filter::new()
+            .id(EventId::all_zeros())
+            .author(Keys::generate().public_key())
+            .kind(Kind::TextNote)
+            .since(Timestamp::from(1))
+            .until(Timestamp::from(2))
+            .custom_tags(t, ["a", "b"])
+            .hashtag("c")
+            .reference("d")
+            .pubkey(Keys::generate().public_key())
+            .limit(5);
+        let (sql, params) = render_sql(&filter);
+        // deleted, ids, authors, kinds, since, until, a tag and its values for #e, #p, #t, limit
+        assert_eq!(params, 13);
+        assert!(sql.contains("$13") && !sql.contains("$14"), "{sql}");
+    }
+
+    #[test]
+    fn never_renders_values() {
+        let filter = Filter::new()
+            .hashtag("secret'; DROP TABLE events; --")
+            .search("needle");
+        let (sql, _) = render_sql(&filter);
+        assert!(!sql.contains("secret") && !sql.contains("needle"), "{sql}");
+    }
+
+    #[test]
+    fn runs_of_kinds_bind_their_bounds() {
+        let (short, short_params) = render_sql(&Filter::new().kinds((0..40).map(Kind::from)));
+        let (long, long_params) = render_sql(&Filter::new().kinds((0..4000).map(Kind::from)));
+        assert!(short.contains("BETWEEN $2 AND $3"), "{short}");
+        assert_eq!(short, long);
+        assert_eq!((short_params, long_params), (4, 4));
+    }
+
+    #[test]
+    fn an_empty_tag_set_matches_nothing() {
+        let mut filter = Filter::new();
+        filter
+            .generic_tags
+            .insert(SingleLetterTag::lowercase(Alphabet::T), BTreeSet::new());
+        let (sql, params) = render_sql(&filter);
+        assert!(
+            sql.contains("FALSE") && !sql.contains("event_tags"),
+            "{sql}"
+        );
+        assert_eq!(params, 2);
+    }
+}
@@ -0,0 +1,92 @@
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use futures_util::TryStreamExt;
+use nostr::event::Event;
+use nostr::filter::Filter;
+use nostr::util::JsonUtil;
+use nostr_database::{DatabaseError, FlatBufferDecode};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::error::ExportWriteError;
+use crate::model::EventDb;
+use crate::quarantine::Quarantine;
+use crate::query::{Operation, build_filter_query, tagged};
+use crate::schema::postgres::events;
+
+/// Events written between two flushes of the writer
+const FLUSH_INTERVAL: u64 = 1000;
+
+/// streams the events matching `filter`, oldest first, to `writer` as JSON lines, skipping
+/// expired ones; returns the number of written events
+pub(crate) async fn export_jsonl<W>(
+    db: &mut AsyncPgConnection,
+    filter: Filter,
+    writer: &mut W,
+    statement_tags: bool,
+    quarantine: &Quarantine,
+) -> Result<u64, DatabaseError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let query = tagged(
+        build_filter_query(filter)
+            .select(EventDb::as_select())
+            .order_by((events::created_at.asc(), events::id.asc())),
+        Operation::Export,
+        statement_tags,
+    );
+    let mut rows = query
+        .load_stream::<EventDb>(db)
+        .await
+        .map_err(DatabaseError::backend)?;
+
+    let mut count = 0;
+    while let Some(row) = rows.try_next().await.map_err(DatabaseError::backend)? {
+        let event = match Event::decode(&row.payload) {
+            Ok(event) => event,
+            Err(e) => {
+                quarantine.record(&row.id, &e);
+                continue;
+            }
+        };
+        if event.is_expired() {
+            continue;
+        }
+        writer
+            .write_all(format!("{}\n", event.as_json()).as_bytes())
+            .await
+            .map_err(|e| write_error(count, e))?;
+        count += 1;
+        if count % FLUSH_INTERVAL == 0 {
+            writer.flush().await.map_err(|e| write_error(count, e))?;
+        }
+    }
+    writer.flush().await.map_err(|e| write_error(count, e))?;
+    Ok(count)
+}
+
+/// like [`export_jsonl`], compressing the output with gzip
+#[cfg(feature = "gzip")]
+pub(crate) async fn export_jsonl_gzip<W>(
+    db: &mut AsyncPgConnection,
+    filter: Filter,
+    writer: W,
+    statement_tags: bool,
+    quarantine: &Quarantine,
+) -> Result<u64, DatabaseError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut encoder = async_compression::tokio::write::GzipEncoder::new(writer);
+    let count = export_jsonl(db, filter, &mut encoder, statement_tags, quarantine).await?;
+    // writes the gzip trailer
+    encoder
+        .shutdown()
+        .await
+        .map_err(|e| write_error(count, e))?;
+    Ok(count)
+}
+
+fn write_error(written: u64, error: std::io::Error) -> DatabaseError {
+    DatabaseError::backend(ExportWriteError::new(written, error))
+}
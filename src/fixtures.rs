@@ -0,0 +1,232 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+
+use nostr::event::{Event, EventBuilder, EventId, Kind, Tag};
+use nostr::hashes::{Hash, sha256};
+use nostr::key::{Keys, SecretKey};
+use nostr::secp256k1::Message;
+use nostr::{SECP256K1, Timestamp};
+use nostr_database::DatabaseError;
+
+use crate::import::ImportReport;
+use crate::postgres::NostrPostgres;
+
+/// Creation time of the oldest event of a default [`EventFactory`]
+pub(crate) const BASE_TIMESTAMP: u64 = 1_700_000_000;
+
+/// Seconds over which the events of a default [`EventFactory`] are spread
+pub(crate) const TIME_SPAN: u64 = 10_000;
+
+/// Hashtags of the generated events
+pub(crate) const HASHTAGS: [&str; 5] = ["nostr", "bitcoin", "postgres", "rust", "zap"];
+
+/// Number of recent event ids kept to reference in `e` tags
+const REFERENCED_EVENTS: usize = 1000;
+
+/// A small deterministic pseudo random generator (SplitMix64)
+#[derive(Debug, Clone)]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// 32 random bytes
+    pub fn bytes32(&mut self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        for chunk in bytes.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next_u64().to_be_bytes());
+        }
+        bytes
+    }
+
+    /// a number in `0..n`, `n` must not be 0
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// a number in `range`, its start if it is empty
+    pub fn in_range(&mut self, range: &Range<u64>) -> u64 {
+        range.start + self.below(range.end.saturating_sub(range.start).max(1))
+    }
+
+    /// true with a probability of `percent` in 100
+    pub fn chance(&mut self, percent: u64) -> bool {
+        self.below(100) < percent
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len() as u64) as usize]
+    }
+}
+
+/// the keys of the author number `index` of the factory seeded with `seed`
+fn author_keys(seed: u64, index: usize) -> Keys {
+    let mut material = seed.to_be_bytes().to_vec();
+    material.extend_from_slice(&(index as u64).to_be_bytes());
+    // a hash is a valid secret key with overwhelming probability, so retry until it is one
+    let mut hash = sha256::Hash::hash(&material);
+    loop {
+        if let Ok(secret_key) = SecretKey::from_slice(hash.as_byte_array()) {
+            return Keys::new(secret_key);
+        }
+        hash = sha256::Hash::hash(hash.as_byte_array());
+    }
+}
+
+/// signs `builder` without auxiliary randomness, so the same input gives the same signature
+fn sign_deterministic(builder: EventBuilder, keys: &Keys) -> Event {
+    let mut unsigned = builder.build(keys.public_key());
+    let message = Message::from_digest(unsigned.id().to_bytes());
+    let signature = SECP256K1.sign_schnorr_no_aux_rand(&message, keys.key_pair(SECP256K1));
+    unsigned
+        .add_signature(signature)
+        .expect("signature of the computed id")
+}
+
+/// Deterministic signed events for tests and benchmarks
+///
+/// The same seed and settings always give the same events, ids and signatures included, so
+/// failures are reproducible. Authors are derived from the seed; each event gets a random
+/// author, kind and creation time within the range and a random number of tags: hashtags,
+/// `p` tags of the authors and `e` tags of earlier events.
+///
+/// The factory is an endless [`Iterator`] of events, e.g. `factory.by_ref().take(100)`.
+/// Changing a setting midway only affects the events generated after it.
+#[derive(Debug, Clone)]
+pub struct EventFactory {
+    seed: u64,
+    rng: Rng,
+    keys: Vec<Keys>,
+    kinds: Vec<Kind>,
+    tags_per_event: Range<usize>,
+    time_range: Range<u64>,
+    generated: usize,
+    recent: VecDeque<EventId>,
+}
+
+impl EventFactory {
+    /// A factory of text notes by 10 authors with up to 3 tags, spread over a few hours of
+    /// November 2023
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: Rng::new(seed),
+            keys: (0..10).map(|i| author_keys(seed, i)).collect(),
+            kinds: vec![Kind::TextNote],
+            tags_per_event: 0..4,
+            time_range: BASE_TIMESTAMP..BASE_TIMESTAMP + TIME_SPAN,
+            generated: 0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Generate events of this kind only
+    pub fn kind<K>(self, kind: K) -> Self
+    where
+        K: Into<Kind>,
+    {
+        self.kinds([kind])
+    }
+
+    /// Generate events of these kinds, picked at random
+    pub fn kinds<I, K>(mut self, kinds: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<Kind>,
+    {
+        let kinds: Vec<Kind> = kinds.into_iter().map(Into::into).collect();
+        if !kinds.is_empty() {
+            self.kinds = kinds;
+        }
+        self
+    }
+
+    /// Number of tags per event, e.g. `3..10`
+    pub fn tags_per_event(mut self, range: Range<usize>) -> Self {
+        self.tags_per_event = range;
+        self
+    }
+
+    /// Number of distinct authors
+    pub fn authors(mut self, authors: usize) -> Self {
+        let seed = self.seed;
+        self.keys = (0..authors.max(1)).map(|i| author_keys(seed, i)).collect();
+        self
+    }
+
+    /// Creation times from `since` up to, but excluding, `until`
+    pub fn time_range(mut self, since: Timestamp, until: Timestamp) -> Self {
+        self.time_range = since.as_u64()..until.as_u64();
+        self
+    }
+
+    /// The keys of the authors
+    pub fn keys(&self) -> &[Keys] {
+        &self.keys
+    }
+
+    /// Generate `count` events
+    pub fn events(&mut self, count: usize) -> Vec<Event> {
+        self.by_ref().take(count).collect()
+    }
+
+    /// Generate `count` events and save them to `db` in batches
+    pub async fn seed(
+        &mut self,
+        db: &NostrPostgres,
+        count: usize,
+    ) -> Result<ImportReport, DatabaseError> {
+        db.save_events(self.by_ref().take(count)).await
+    }
+
+    fn tag(&mut self) -> Tag {
+        match self.rng.below(3) {
+            0 => Tag::hashtag(*self.rng.pick(&HASHTAGS)),
+            1 => Tag::public_key(self.rng.pick(&self.keys).public_key()),
+            _ if !self.recent.is_empty() => {
+                let index = self.rng.below(self.recent.len() as u64) as usize;
+                Tag::event(self.recent[index])
+            }
+            _ => Tag::hashtag(*self.rng.pick(&HASHTAGS)),
+        }
+    }
+}
+
+impl Iterator for EventFactory {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        let author = self.rng.below(self.keys.len() as u64) as usize;
+        let kind = *self.rng.pick(&self.kinds);
+        let created_at = Timestamp::from(self.rng.in_range(&self.time_range));
+        let range = &self.tags_per_event;
+        let tag_count = self.rng.in_range(&(range.start as u64..range.end as u64));
+        let tags: Vec<Tag> = (0..tag_count).map(|_| self.tag()).collect();
+        let builder = EventBuilder::new(kind, format!("event {}", self.generated))
+            .tags(tags)
+            .custom_created_at(created_at);
+        let event = sign_deterministic(builder, &self.keys[author]);
+
+        self.generated += 1;
+        if self.recent.len() == REFERENCED_EVENTS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(event.id);
+        Some(event)
+    }
+}
+
+/// Save `count` events of `EventFactory::new(0)` to `db`, see [`EventFactory::seed`]
+pub async fn seed(db: &NostrPostgres, count: usize) -> Result<ImportReport, DatabaseError> {
+    EventFactory::new(0).seed(db, count).await
+}
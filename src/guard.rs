@@ -0,0 +1,178 @@
+use nostr::filter::Filter;
+use nostr_database::DatabaseError;
+
+use crate::error::QueryGuardError;
+
+/// Limit applied to queries without one
+const DEFAULT_LIMIT: usize = 10000;
+
+/// Bounds on the filters of queries, checked before any SQL runs, see
+/// [`NostrPostgresBuilder::query_guard`](crate::NostrPostgresBuilder::query_guard)
+///
+/// A filter without a limit asks for the default of 10000 events. Filters asking for more
+/// than allowed are rejected with a [`QueryGuardError`] or get their limit lowered, depending
+/// on the [`GuardAction`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueryGuard {
+    action: GuardAction,
+    max_limit: Option<usize>,
+    unselective_limit: Option<usize>,
+}
+
+/// What a [`QueryGuard`] does with a filter asking for too many events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GuardAction {
+    /// Fail the query with a [`QueryGuardError`]
+    #[default]
+    Reject,
+    /// Lower the limit of the filter to the allowed number
+    Clamp,
+}
+
+impl QueryGuard {
+    /// A guard rejecting filters beyond its bounds, without bounds yet
+    pub fn reject() -> Self {
+        Self::new(GuardAction::Reject)
+    }
+
+    /// A guard lowering the limit of filters beyond its bounds, without bounds yet
+    pub fn clamp() -> Self {
+        Self::new(GuardAction::Clamp)
+    }
+
+    /// A guard with the given action and without bounds
+    pub fn new(action: GuardAction) -> Self {
+        Self {
+            action,
+            max_limit: None,
+            unselective_limit: None,
+        }
+    }
+
+    /// Allow at most `limit` events per filter
+    pub fn max_limit(mut self, limit: usize) -> Self {
+        self.max_limit = Some(limit);
+        self
+    }
+
+    /// Allow at most `limit` events per filter without ids, authors, kinds, tags or `since`
+    ///
+    /// Such filters read the events table newest first, so their cost grows with the limit;
+    /// `0` rejects them, or matches nothing when clamping.
+    pub fn unselective_limit(mut self, limit: usize) -> Self {
+        self.unselective_limit = Some(limit);
+        self
+    }
+
+    /// What happens to filters beyond the bounds
+    pub fn action(&self) -> GuardAction {
+        self.action
+    }
+
+    /// checks `filter` against the bounds, returning it with its limit lowered if clamping
+    pub(crate) fn apply(&self, filter: Filter) -> Result<Filter, DatabaseError> {
+        let selective = is_selective(&filter);
+        let allowed = match (self.max_limit, self.unselective_limit) {
+            (max, Some(unselective)) if !selective => {
+                Some(max.map_or(unselective, |max| max.min(unselective)))
+            }
+            (max, _) => max,
+        };
+        let Some(allowed) = allowed else {
+            return Ok(filter);
+        };
+        let limit = filter.limit.unwrap_or(DEFAULT_LIMIT);
+        if limit <= allowed {
+            return Ok(filter);
+        }
+        match self.action {
+            GuardAction::Reject => Err(DatabaseError::backend(QueryGuardError::new(
+                limit, allowed, selective,
+            ))),
+            GuardAction::Clamp => Ok(filter.limit(allowed)),
+        }
+    }
+}
+
+/// whether `filter` restricts the events by anything but `until`
+fn is_selective(filter: &Filter) -> bool {
+    filter.ids.is_some()
+        || filter.authors.is_some()
+        || filter.kinds.is_some()
+        || !filter.generic_tags.is_empty()
+        || filter.since.is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr::Kind;
+
+    use super::*;
+    use crate::builder::Config;
+
+    fn guard_error(err: DatabaseError) -> QueryGuardError {
+        match err {
+            DatabaseError::Backend(e) => e
+                .downcast_ref::<QueryGuardError>()
+                .expect("query guard error")
+                .clone(),
+            e => panic!("unexpected error {e}"),
+        }
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        assert!(Config::default().query_guard.is_none());
+        let unbounded = QueryGuard::reject();
+        let filter = Filter::new().limit(1_000_000);
+        assert_eq!(unbounded.apply(filter.clone()).unwrap(), filter);
+        assert_eq!(unbounded.apply(Filter::new()).unwrap(), Filter::new());
+    }
+
+    #[test]
+    fn rejects_beyond_max_limit() {
+        let guard = QueryGuard::reject().max_limit(500);
+        let within = Filter::new().kind(Kind::TextNote).limit(500);
+        assert_eq!(guard.apply(within.clone()).unwrap(), within);
+
+        let err = guard_error(
+            guard
+                .apply(Filter::new().kind(Kind::TextNote).limit(501))
+                .unwrap_err(),
+        );
+        assert_eq!(
+            (err.limit(), err.allowed(), err.is_selective()),
+            (501, 500, true)
+        );
+
+        // no limit asks for the default
+        let err = guard_error(guard.apply(Filter::new().kind(Kind::TextNote)).unwrap_err());
+        assert_eq!(err.limit(), DEFAULT_LIMIT);
+    }
+
+    #[test]
+    fn rejects_unselective_filters_beyond_their_limit() {
+        let guard = QueryGuard::reject().max_limit(500).unselective_limit(50);
+        let err = guard_error(guard.apply(Filter::new().limit(100)).unwrap_err());
+        assert_eq!(
+            (err.limit(), err.allowed(), err.is_selective()),
+            (100, 50, false)
+        );
+        // until alone doesn't make a filter selective
+        let until = Filter::new().until(nostr::Timestamp::from(1)).limit(100);
+        assert!(guard.apply(until).is_err());
+        let selective = Filter::new().kind(Kind::TextNote).limit(100);
+        assert_eq!(guard.apply(selective.clone()).unwrap(), selective);
+    }
+
+    #[test]
+    fn clamps_to_the_allowed_limit() {
+        let guard = QueryGuard::clamp().max_limit(500).unselective_limit(50);
+        let clamped = guard.apply(Filter::new().kind(Kind::TextNote)).unwrap();
+        assert_eq!(clamped.limit, Some(500));
+        let clamped = guard.apply(Filter::new().limit(100)).unwrap();
+        assert_eq!(clamped.limit, Some(50));
+        let within = Filter::new().limit(10);
+        assert_eq!(guard.apply(within.clone()).unwrap(), within);
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use deadpool::Status;
+use diesel::dsl::sql;
+use diesel::sql_types::{Integer, Nullable, Text};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use nostr_database::DatabaseError;
+
+/// Health information of a [`NostrPostgres`](crate::NostrPostgres) instance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    /// State of the connection pool
+    pub pool: PoolStatus,
+    /// Round-trip time of a `SELECT 1`
+    pub latency: Duration,
+    /// Version of the most recently applied migration
+    pub schema_version: Option<String>,
+}
+
+/// Saturation of the connection pool and counters of the operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStatus {
+    /// Maximum number of connections
+    pub max_size: usize,
+    /// Number of connections currently held by the pool
+    pub size: usize,
+    /// Number of idle connections in the pool
+    pub available: usize,
+    /// Number of tasks waiting for a connection
+    pub waiting: usize,
+    /// Total number of connections acquired from the pool
+    pub acquires: u64,
+    /// Total time spent waiting for connections
+    pub acquire_wait: Duration,
+    /// Total number of operations retried after a transient error, see
+    /// [`RetryPolicy`](crate::RetryPolicy)
+    pub retries: u64,
+    /// Total number of stored events skipped because their payloads failed to decode, see
+    /// [`NostrPostgres::quarantined`](crate::NostrPostgres::quarantined)
+    pub decode_failures: u64,
+}
+
+/// Counters for connection acquisition
+#[derive(Debug, Default)]
+pub(crate) struct PoolMetrics {
+    acquires: AtomicU64,
+    acquire_wait_micros: AtomicU64,
+    retries: AtomicU64,
+}
+
+impl PoolMetrics {
+    pub fn record_acquire(&self, wait: Duration) {
+        self.acquires.fetch_add(1, Ordering::Relaxed);
+        self.acquire_wait_micros
+            .fetch_add(wait.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn status(&self, status: Status, decode_failures: u64) -> PoolStatus {
+        PoolStatus {
+            max_size: status.max_size,
+            size: status.size,
+            available: status.available,
+            waiting: status.waiting,
+            acquires: AtomicU64::load(&self.acquires, Ordering::Relaxed),
+            acquire_wait: Duration::from_micros(AtomicU64::load(
+                &self.acquire_wait_micros,
+                Ordering::Relaxed,
+            )),
+            retries: AtomicU64::load(&self.retries, Ordering::Relaxed),
+            decode_failures,
+        }
+    }
+}
+
+pub(crate) async fn ping(db: &mut AsyncPgConnection) -> Result<(), DatabaseError> {
+    diesel::select(sql::<Integer>("1"))
+        .get_result::<i32>(db)
+        .await
+        .map_err(DatabaseError::backend)?;
+    Ok(())
+}
+
+pub(crate) async fn schema_version(
+    db: &mut AsyncPgConnection,
+) -> Result<Option<String>, DatabaseError> {
+    diesel::select(sql::<Nullable<Text>>(
+        "(SELECT max(version) FROM __diesel_schema_migrations)",
+    ))
+    .get_result(db)
+    .await
+    .map_err(DatabaseError::backend)
+}
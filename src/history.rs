@@ -0,0 +1,190 @@
+use std::time::Duration;
+
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Bytea, Nullable, Text};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use nostr::Timestamp;
+use nostr::event::Kind;
+use nostr::nips::nip01::Coordinate;
+
+use crate::model::EventDataDb;
+use crate::query::{Operation, tagged};
+use crate::schema::postgres::event_history;
+
+/// the events stored at the coordinate `$1` (pubkey), `$2` (kind), `$3` (identifier), not
+/// deleted ones; events without a `d` tag are at the empty identifier
+const AT_COORDINATE: &str = "SELECT id FROM events e \
+     WHERE e.pubkey = $1 AND e.kind = $2 AND NOT e.deleted \
+     AND (EXISTS (SELECT 1 FROM event_tags t \
+     WHERE t.event_id = e.id AND t.tag = 'd' AND t.tag_value = $3) \
+     OR ($3 = '' AND NOT EXISTS (SELECT 1 FROM event_tags t \
+     WHERE t.event_id = e.id AND t.tag = 'd')))";
+
+/// the versions at a coordinate into the history, then all but the newest out of the hot
+/// tables
+fn replace_sql() -> String {
+    format!(
+        "WITH versions AS ({AT_COORDINATE}), recorded AS (\
+         INSERT INTO event_history (id, pubkey, kind, identifier, created_at, payload, seen_at) \
+         SELECT e.id, e.pubkey, e.kind, $3, e.created_at, e.payload, e.seen_at \
+         FROM events e JOIN versions v ON v.id = e.id ON CONFLICT DO NOTHING\
+         ), replaced AS (\
+         SELECT e.id FROM events e JOIN versions v ON v.id = e.id \
+         ORDER BY e.created_at DESC, e.id OFFSET 1\
+         ), replaced_tags AS (\
+         DELETE FROM event_tags WHERE event_id IN (SELECT id FROM replaced)\
+         ) DELETE FROM events WHERE id IN (SELECT id FROM replaced)"
+    )
+}
+
+/// removes the versions beyond the retention, all but the newest of each coordinate; `$1`,
+/// `$2` and `$3` optionally restrict it to one coordinate
+const TRIM: &str = "DELETE FROM event_history WHERE id IN (\
+     SELECT id FROM (SELECT id, seen_at, row_number() OVER (\
+     PARTITION BY pubkey, kind, identifier ORDER BY created_at DESC, id) AS position \
+     FROM event_history WHERE ($1::bytea IS NULL OR (pubkey = $1 AND kind = $2 AND identifier = $3))\
+     ) versions WHERE position > 1 AND (position > $4 OR seen_at < $5))";
+
+/// Keep the replaced versions of replaceable and addressable events, see
+/// [`NostrPostgresBuilder::replaceable_history`](crate::NostrPostgresBuilder::replaceable_history)
+///
+/// Without bounds every version is kept. The newest version of a coordinate is never
+/// trimmed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplaceableHistory {
+    max_versions: Option<usize>,
+    max_age: Option<Duration>,
+}
+
+impl ReplaceableHistory {
+    /// Keep every version
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the newest `versions` versions of each coordinate, the current one included, at
+    /// least one
+    pub fn keep_versions(mut self, versions: usize) -> Self {
+        self.max_versions = Some(versions);
+        self
+    }
+
+    /// Keep the versions first seen within `age`
+    pub fn keep_for(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    /// the bounds as binds of [`TRIM`]: the highest kept position and the oldest kept
+    /// `seen_at`
+    fn bounds(&self) -> (i64, i64) {
+        let versions = self
+            .max_versions
+            .map_or(i64::MAX, |v| i64::try_from(v).unwrap_or(i64::MAX));
+        let seen_after = self.max_age.map_or(i64::MIN, |age| {
+            Timestamp::now().as_u64().saturating_sub(age.as_secs()) as i64
+        });
+        (versions, seen_after)
+    }
+}
+
+/// the identifier of the coordinate of a replaceable or addressable event, `None` for other
+/// kinds; the first `d` tag, as the tag rows keep the order of the tags
+pub(crate) fn identifier(data: &EventDataDb) -> Option<String> {
+    let kind = Kind::from(data.event.kind as u16);
+    if kind.is_replaceable() {
+        Some(String::new())
+    } else if kind.is_addressable() {
+        Some(
+            data.tags
+                .iter()
+                .find(|t| t.tag == "d")
+                .map(|t| t.tag_value.clone())
+                .unwrap_or_default(),
+        )
+    } else {
+        None
+    }
+}
+
+/// records the versions at the coordinate of the just saved `data` in the history, keeps
+/// only the newest in the hot tables and trims the history of the coordinate
+pub(crate) async fn replace(
+    db: &mut AsyncPgConnection,
+    data: &EventDataDb,
+    identifier: &str,
+    history: &ReplaceableHistory,
+    statement_tags: bool,
+) -> QueryResult<()> {
+    tagged(
+        diesel::sql_query(replace_sql())
+            .bind::<Bytea, _>(&data.event.pubkey)
+            .bind::<BigInt, _>(data.event.kind)
+            .bind::<Text, _>(identifier),
+        Operation::Save,
+        statement_tags,
+    )
+    .execute(db)
+    .await?;
+    let (versions, seen_after) = history.bounds();
+    tagged(
+        diesel::sql_query(TRIM)
+            .bind::<Nullable<Bytea>, _>(Some(&data.event.pubkey))
+            .bind::<BigInt, _>(data.event.kind)
+            .bind::<Text, _>(identifier)
+            .bind::<BigInt, _>(versions)
+            .bind::<BigInt, _>(seen_after),
+        Operation::Save,
+        statement_tags,
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// removes the versions of all coordinates beyond the retention, returning how many
+pub(crate) async fn trim(
+    db: &mut AsyncPgConnection,
+    history: &ReplaceableHistory,
+    statement_tags: bool,
+) -> QueryResult<u64> {
+    let (versions, seen_after) = history.bounds();
+    let trimmed = tagged(
+        diesel::sql_query(TRIM)
+            .bind::<Nullable<Bytea>, _>(None::<Vec<u8>>)
+            .bind::<BigInt, _>(0)
+            .bind::<Text, _>("")
+            .bind::<BigInt, _>(versions)
+            .bind::<BigInt, _>(seen_after),
+        Operation::Delete,
+        statement_tags,
+    )
+    .execute(db)
+    .await?;
+    Ok(trimmed as u64)
+}
+
+/// the versions at `coordinate`, newest first
+pub(crate) async fn versions(
+    db: &mut AsyncPgConnection,
+    coordinate: &Coordinate,
+    statement_tags: bool,
+) -> QueryResult<Vec<(Vec<u8>, Vec<u8>)>> {
+    let identifier = if coordinate.kind.is_replaceable() {
+        ""
+    } else {
+        coordinate.identifier.as_str()
+    };
+    tagged(
+        event_history::table
+            .select((event_history::id, event_history::payload))
+            .filter(event_history::pubkey.eq(coordinate.public_key.as_bytes().to_vec()))
+            .filter(event_history::kind.eq(coordinate.kind.as_u16() as i64))
+            .filter(event_history::identifier.eq(identifier.to_string()))
+            .order_by((event_history::created_at.desc(), event_history::id)),
+        Operation::EventById,
+        statement_tags,
+    )
+    .load(db)
+    .await
+}
@@ -0,0 +1,131 @@
+use nostr::filter::Filter;
+
+/// Number of HyperLogLog registers defined by NIP-45
+pub(crate) const HLL_REGISTERS: usize = 256;
+
+/// offset into the pubkeys as defined by NIP-45, derived from the first tag value of the
+/// filter, which has to be a hex encoded id or pubkey; with several tags or values the first
+/// in alphabetical order, by tag name and then by value, not the order of the filter JSON
+pub(crate) fn hll_offset(filter: &Filter) -> Option<usize> {
+    let value = filter.generic_tags.values().flatten().next()?;
+    if value.len() != 64 {
+        return None;
+    }
+    let digit = value.chars().nth(32)?.to_digit(16)?;
+    Some(digit as usize + 8)
+}
+
+/// adds a pubkey to the registers: the byte at `offset` selects the register, which keeps the
+/// highest number of leading zero bits after that byte plus one
+pub(crate) fn hll_add(registers: &mut [u8; HLL_REGISTERS], pubkey: &[u8], offset: usize) {
+    let Some(&index) = pubkey.get(offset) else {
+        return;
+    };
+    let mut zeros = 0;
+    for byte in pubkey.iter().skip(offset + 1) {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    let rank = (zeros + 1).min(u8::MAX as u32) as u8;
+    let register = &mut registers[index as usize];
+    *register = (*register).max(rank);
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr::filter::{Alphabet, SingleLetterTag};
+
+    use super::*;
+
+    /// a hex id with `nibble` as its 33rd character, the one NIP-45 reads
+    fn hex_with(nibble: char) -> String {
+        format!("{}{nibble}{}", "0".repeat(32), "0".repeat(31))
+    }
+
+    fn tag(c: Alphabet) -> SingleLetterTag {
+        SingleLetterTag::lowercase(c)
+    }
+
+    #[test]
+    fn offset_is_the_33rd_nibble_plus_8() {
+        for (nibble, offset) in [('0', 8), ('7', 15), ('a', 18), ('f', 23), ('F', 23)] {
+            let filter = Filter::new().custom_tag(tag(Alphabet::P), hex_with(nibble));
+            assert_eq!(hll_offset(&filter), Some(offset), "nibble {nibble}");
+        }
+        let id = "7f1c0e1c2b9ba2b8a2d1c5e0b2f7e3d48a65e1cd60d6b2a8f4c9d0e1a3b5c7d9";
+        let filter = Filter::new().custom_tag(tag(Alphabet::E), id);
+        assert_eq!(hll_offset(&filter), Some(0x8 + 8));
+    }
+
+    #[test]
+    fn offset_needs_a_hex_id_or_pubkey() {
+        assert_eq!(hll_offset(&Filter::new()), None);
+        let short = Filter::new().custom_tag(tag(Alphabet::P), "abcd");
+        assert_eq!(hll_offset(&short), None);
+        let not_hex = Filter::new().custom_tag(tag(Alphabet::P), hex_with('g'));
+        assert_eq!(hll_offset(&not_hex), None);
+    }
+
+    #[test]
+    fn offset_takes_the_first_value_in_alphabetical_order() {
+        // `p` is given first, but `e` sorts before it
+        let filter = Filter::new()
+            .custom_tag(tag(Alphabet::P), hex_with('1'))
+            .custom_tag(tag(Alphabet::E), hex_with('2'));
+        assert_eq!(hll_offset(&filter), Some(2 + 8));
+        // within a tag, the smallest value
+        let filter = Filter::new().custom_tags(
+            tag(Alphabet::P),
+            [
+                format!("f{}", &hex_with('5')[1..]),
+                format!("1{}", &hex_with('3')[1..]),
+            ],
+        );
+        assert_eq!(hll_offset(&filter), Some(3 + 8));
+    }
+
+    #[test]
+    fn add_uses_the_byte_at_offset_as_register() {
+        let mut registers = [0; HLL_REGISTERS];
+        let mut pubkey = [0xff; 32];
+        pubkey[8] = 0x05;
+        // one zero byte then 0b0001_0000: 8 + 3 leading zeros
+        pubkey[9] = 0x00;
+        pubkey[10] = 0x10;
+        hll_add(&mut registers, &pubkey, 8);
+        assert_eq!(registers[5], 12);
+        assert_eq!(registers.iter().filter(|r| **r != 0).count(), 1);
+    }
+
+    #[test]
+    fn add_keeps_the_highest_rank() {
+        let mut registers = [0; HLL_REGISTERS];
+        let mut pubkey = [0xff; 32];
+        pubkey[10] = 0x20;
+        pubkey[11] = 0x01;
+        hll_add(&mut registers, &pubkey, 10);
+        assert_eq!(registers[0x20], 8);
+        // 0b1000_0000 right after the offset has no leading zeros
+        pubkey[11] = 0x80;
+        hll_add(&mut registers, &pubkey, 10);
+        assert_eq!(registers[0x20], 8);
+        pubkey[11] = 0x00;
+        pubkey[12] = 0x01;
+        hll_add(&mut registers, &pubkey, 10);
+        assert_eq!(registers[0x20], 16);
+    }
+
+    #[test]
+    fn add_counts_zeros_to_the_end_of_the_pubkey() {
+        let mut registers = [0; HLL_REGISTERS];
+        let mut pubkey = [0; 32];
+        pubkey[23] = 0xab;
+        hll_add(&mut registers, &pubkey, 23);
+        assert_eq!(registers[0xab], 8 * 8 + 1);
+        // an offset beyond the pubkey adds nothing
+        hll_add(&mut registers, &pubkey, 32);
+        assert_eq!(registers.iter().filter(|r| **r != 0).count(), 1);
+    }
+}
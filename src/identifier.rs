@@ -0,0 +1,15 @@
+use nostr_database::DatabaseError;
+
+use crate::error::ConfigError;
+
+/// Quote a Postgres identifier (schema, table, ...) for use in SQL
+///
+/// Embedded double quotes are escaped, so the result is always a single identifier.
+pub(crate) fn quote_identifier(ident: &str) -> Result<String, DatabaseError> {
+    if ident.is_empty() || ident.contains('\0') {
+        return Err(DatabaseError::backend(ConfigError::new(format!(
+            "invalid identifier {ident:?}"
+        ))));
+    }
+    Ok(format!("\"{}\"", ident.replace('"', "\"\"")))
+}
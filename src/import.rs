@@ -0,0 +1,236 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use nostr::event::{Event, EventId};
+use nostr::util::JsonUtil;
+use nostr_database::DatabaseError;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tracing::info;
+
+use crate::model::EventDataDb;
+use crate::outcome::SaveOutcome;
+use crate::postgres::NostrPostgres;
+
+/// Number of error messages kept in an [`ImportReport`]
+const ERROR_SAMPLES: usize = 10;
+
+type ProgressCallback = Arc<dyn Fn(&ImportReport) + Send + Sync>;
+
+/// Settings of [`NostrPostgres::import_jsonl`]
+#[derive(Clone)]
+pub struct ImportOptions {
+    verify_signatures: bool,
+    batch_size: usize,
+    progress_interval: u64,
+    on_progress: Option<ProgressCallback>,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            verify_signatures: true,
+            batch_size: 1000,
+            progress_interval: 100_000,
+            on_progress: None,
+        }
+    }
+}
+
+impl ImportOptions {
+    /// Check the id and signature of every event, rejecting invalid ones (default true)
+    pub fn verify_signatures(mut self, verify: bool) -> Self {
+        self.verify_signatures = verify;
+        self
+    }
+
+    /// Number of events saved per transaction (default 1000)
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Report the progress every `lines` lines (default 100000)
+    ///
+    /// Progress is logged at info level and passed to the [`on_progress`](Self::on_progress)
+    /// callback.
+    pub fn progress_interval(mut self, lines: u64) -> Self {
+        self.progress_interval = lines.max(1);
+        self
+    }
+
+    /// Call `callback` with the intermediate report whenever progress is reported
+    pub fn on_progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&ImportReport) + Send + Sync + 'static,
+    {
+        self.on_progress = Some(Arc::new(callback));
+        self
+    }
+}
+
+impl std::fmt::Debug for ImportOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImportOptions")
+            .field("verify_signatures", &self.verify_signatures)
+            .field("batch_size", &self.batch_size)
+            .field("progress_interval", &self.progress_interval)
+            .field("on_progress", &self.on_progress.is_some())
+            .finish()
+    }
+}
+
+/// Statistics of a bulk ingestion, e.g. [`NostrPostgres::import_jsonl`] or
+/// [`NostrPostgres::save_events`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportReport {
+    /// Lines read, including blank ones; 0 for sources that are not line based
+    pub lines: u64,
+    /// Events saved
+    pub imported: u64,
+    /// Events that were already stored
+    pub duplicates: u64,
+    /// Events that were not saved because they were deleted before
+    pub deleted: u64,
+    /// Events rejected by policy, e.g. for an invalid id or signature, a blocked author or the
+    /// quota
+    pub rejected: u64,
+    /// Items that could not be decoded into an event
+    pub parse_failures: u64,
+    /// Time the ingestion took
+    pub elapsed: Duration,
+    /// The first errors, prefixed with their line number or event id
+    pub errors: Vec<String>,
+}
+
+impl ImportReport {
+    pub(crate) fn record_error(&mut self, line: u64, error: impl std::fmt::Display) {
+        self.record(format!("line {line}: {error}"));
+    }
+
+    pub(crate) fn record_event_error(&mut self, id: EventId, error: impl std::fmt::Display) {
+        self.record(format!("event {id}: {error}"));
+    }
+
+    pub(crate) fn record(&mut self, error: String) {
+        if self.errors.len() < ERROR_SAMPLES {
+            self.errors.push(error);
+        }
+    }
+
+    pub(crate) fn record_outcome(&mut self, outcome: SaveOutcome) {
+        match outcome {
+            SaveOutcome::Saved => self.imported += 1,
+            SaveOutcome::Duplicate => self.duplicates += 1,
+            SaveOutcome::Blocked | SaveOutcome::OverQuota | SaveOutcome::RateLimited => {
+                self.rejected += 1
+            }
+        }
+    }
+
+    pub(crate) fn record_batch(&mut self, outcome: BatchOutcome) {
+        self.imported += outcome.inserted;
+        self.duplicates += outcome.duplicates;
+        self.deleted += outcome.deleted;
+        self.rejected += outcome.rejected;
+    }
+}
+
+/// Result of saving one batch of events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct BatchOutcome {
+    pub inserted: u64,
+    pub duplicates: u64,
+    pub deleted: u64,
+    pub rejected: u64,
+}
+
+/// saves the events read line by line from `reader` in batches
+pub(crate) async fn import_jsonl<R>(
+    db: &NostrPostgres,
+    reader: R,
+    opts: ImportOptions,
+) -> Result<ImportReport, DatabaseError>
+where
+    R: AsyncRead + Unpin,
+{
+    let start = Instant::now();
+    let mut reader = BufReader::new(reader);
+    let mut report = ImportReport::default();
+    let mut batch = Vec::with_capacity(opts.batch_size);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader
+            .read_until(b'\n', &mut line)
+            .await
+            .map_err(DatabaseError::backend)?
+            == 0
+        {
+            break;
+        }
+        report.lines += 1;
+        if report.lines % opts.progress_interval == 0 {
+            report.elapsed = start.elapsed();
+            progress(&opts, &report);
+        }
+
+        let Ok(json) = std::str::from_utf8(&line) else {
+            report.parse_failures += 1;
+            report.record_error(report.lines, "invalid UTF-8");
+            continue;
+        };
+        let json = json.trim();
+        if json.is_empty() {
+            continue;
+        }
+        let event = match Event::from_json(json) {
+            Ok(event) => event,
+            Err(e) => {
+                report.parse_failures += 1;
+                report.record_error(report.lines, e);
+                continue;
+            }
+        };
+        if opts.verify_signatures
+            && let Err(e) = event.verify()
+        {
+            report.rejected += 1;
+            report.record_error(report.lines, e);
+            continue;
+        }
+        batch.push(db.event_data(&event)?);
+        if batch.len() >= opts.batch_size {
+            flush(db, &mut batch, &mut report).await?;
+        }
+    }
+    flush(db, &mut batch, &mut report).await?;
+    report.elapsed = start.elapsed();
+    progress(&opts, &report);
+    Ok(report)
+}
+
+async fn flush(
+    db: &NostrPostgres,
+    batch: &mut Vec<EventDataDb>,
+    report: &mut ImportReport,
+) -> Result<(), DatabaseError> {
+    report.record_batch(db.save_batch(std::mem::take(batch)).await?);
+    Ok(())
+}
+
+fn progress(opts: &ImportOptions, report: &ImportReport) {
+    info!(
+        "Import progress: {} lines, {} imported, {} duplicates, {} deleted, {} rejected, \
+         {} parse failures",
+        report.lines,
+        report.imported,
+        report.duplicates,
+        report.deleted,
+        report.rejected,
+        report.parse_failures
+    );
+    if let Some(callback) = &opts.on_progress {
+        callback(report);
+    }
+}
@@ -0,0 +1,192 @@
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use nostr::event::{Event, EventId};
+use nostr_database::{DatabaseError, FlatBufferDecode};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::error::{IntegrityError, IntegrityErrorKind};
+use crate::model::EventDb;
+use crate::postgres::hex;
+use crate::schema::postgres::events;
+
+/// Checks of the stored events read by queries and lookups, see
+/// [`NostrPostgresBuilder::verify_on_read`](crate::NostrPostgresBuilder::verify_on_read)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadVerification {
+    /// Trust the stored payloads
+    #[default]
+    Off,
+    /// Check that the id of the decoded event matches the id column, a byte comparison
+    Id,
+    /// Additionally check the id hash and the signature of the event, which costs a
+    /// signature verification per event read
+    Signature,
+}
+
+impl ReadVerification {
+    /// checks the event decoded from the row with the id column `stored_id`
+    pub(crate) fn check(self, stored_id: &[u8], event: &Event) -> Result<(), IntegrityError> {
+        let kind = match self {
+            Self::Off => return Ok(()),
+            _ if event.id.as_bytes() != stored_id => IntegrityErrorKind::IdMismatch,
+            Self::Signature if event.verify().is_err() => IntegrityErrorKind::InvalidSignature,
+            _ => return Ok(()),
+        };
+        Err(IntegrityError::new(kind, hex(stored_id), event.id))
+    }
+}
+
+/// Settings of [`NostrPostgres::verify_integrity`](crate::NostrPostgres::verify_integrity)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IntegrityOptions {
+    verify_signatures: bool,
+    batch_size: usize,
+    limit: Option<u64>,
+    after: Option<EventId>,
+    max_offending: usize,
+}
+
+impl Default for IntegrityOptions {
+    fn default() -> Self {
+        Self {
+            verify_signatures: false,
+            batch_size: 1000,
+            limit: None,
+            after: None,
+            max_offending: 100,
+        }
+    }
+}
+
+impl IntegrityOptions {
+    /// Also check the id hash and signature of every decoded event (default false)
+    pub fn verify_signatures(mut self, verify: bool) -> Self {
+        self.verify_signatures = verify;
+        self
+    }
+
+    /// Number of rows read per query (default 1000)
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Stop after checking `rows` rows
+    pub fn limit(mut self, rows: u64) -> Self {
+        self.limit = Some(rows);
+        self
+    }
+
+    /// Resume after the row with this id, see [`IntegrityReport::last_id`]
+    pub fn after(mut self, id: EventId) -> Self {
+        self.after = Some(id);
+        self
+    }
+
+    /// Number of offending event ids kept in the report (default 100)
+    pub fn max_offending(mut self, max: usize) -> Self {
+        self.max_offending = max;
+        self
+    }
+}
+
+/// Result of [`NostrPostgres::verify_integrity`](crate::NostrPostgres::verify_integrity)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IntegrityReport {
+    /// Rows checked
+    pub checked: u64,
+    /// Rows whose payload doesn't decode
+    pub undecodable: u64,
+    /// Rows whose id column differs from the id of the payload
+    pub id_mismatches: u64,
+    /// Rows whose pubkey, kind or created_at column differs from the payload
+    pub column_mismatches: u64,
+    /// Decoded events with an invalid id hash or signature, if verified
+    pub invalid_signatures: u64,
+    /// The id columns of the first offending rows
+    pub offending: Vec<EventId>,
+    /// Id of the last checked row, to resume with [`IntegrityOptions::after`]; `None` if
+    /// no row was checked
+    pub last_id: Option<EventId>,
+}
+
+impl IntegrityReport {
+    /// Whether all checked rows are intact
+    pub fn is_ok(&self) -> bool {
+        self.undecodable == 0
+            && self.id_mismatches == 0
+            && self.column_mismatches == 0
+            && self.invalid_signatures == 0
+    }
+
+    fn check(&mut self, row: &EventDb, opts: &IntegrityOptions) {
+        self.checked += 1;
+        let intact = match Event::decode(&row.payload) {
+            Err(_) => {
+                self.undecodable += 1;
+                false
+            }
+            Ok(event) if event.id.as_bytes() != row.id.as_slice() => {
+                self.id_mismatches += 1;
+                false
+            }
+            Ok(event)
+                if event.pubkey.as_bytes() != row.pubkey.as_slice()
+                    || event.kind.as_u16() as i64 != row.kind
+                    || event.created_at.as_u64() as i64 != row.created_at =>
+            {
+                self.column_mismatches += 1;
+                false
+            }
+            Ok(event) if opts.verify_signatures && event.verify().is_err() => {
+                self.invalid_signatures += 1;
+                false
+            }
+            Ok(_) => true,
+        };
+        if !intact
+            && self.offending.len() < opts.max_offending
+            && let Ok(id) = EventId::from_slice(&row.id)
+        {
+            self.offending.push(id);
+        }
+    }
+}
+
+/// checks the stored rows, deleted ones included, in the order of their id
+pub(crate) async fn verify_integrity(
+    db: &mut AsyncPgConnection,
+    opts: IntegrityOptions,
+) -> Result<IntegrityReport, DatabaseError> {
+    let mut report = IntegrityReport::default();
+    let mut cursor = opts.after.map(|id| id.as_bytes().to_vec());
+    loop {
+        let remaining = opts.limit.map_or(u64::MAX, |limit| limit - report.checked);
+        let batch = remaining.min(opts.batch_size as u64) as i64;
+        if batch == 0 {
+            return Ok(report);
+        }
+        let mut query = events::table
+            .select(EventDb::as_select())
+            .order_by(events::id)
+            .limit(batch)
+            .into_boxed();
+        if let Some(cursor) = cursor {
+            query = query.filter(events::id.gt(cursor));
+        }
+        let rows: Vec<EventDb> = query.load(db).await.map_err(DatabaseError::backend)?;
+        for row in &rows {
+            report.check(row, &opts);
+        }
+        let Some(last) = rows.last() else {
+            return Ok(report);
+        };
+        report.last_id = EventId::from_slice(&last.id).ok().or(report.last_id);
+        cursor = Some(last.id.clone());
+        debug!("Checked the integrity of {} events", report.checked);
+        if (rows.len() as i64) < batch {
+            return Ok(report);
+        }
+    }
+}
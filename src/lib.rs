@@ -1,7 +1,108 @@
+mod archive;
+mod ban;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+mod builder;
+mod bulk;
+mod copy;
+mod diagnostics;
+#[cfg(feature = "test-utils")]
+mod differential;
+mod env;
+mod error;
+mod exclusions;
+mod explain;
+mod export;
+#[cfg(feature = "test-utils")]
+mod fixtures;
+mod guard;
+mod health;
+mod history;
+mod hll;
+mod identifier;
+mod import;
+mod integrity;
+mod lifecycle;
+mod maintenance;
+#[cfg(feature = "test-utils")]
+mod memory_store;
 mod migrations;
 mod model;
+mod notify;
+mod outcome;
+mod partition;
 mod postgres;
+mod quarantine;
 mod query;
+mod quota;
+mod rate_limit;
+mod reconcile;
+#[cfg(feature = "relay")]
+mod relay;
+mod retention;
+mod retry;
 mod schema;
-pub use migrations::postgres::run_migrations;
-pub use postgres::{NostrPostgres, postgres_connection_pool};
+mod stats;
+mod store;
+mod subscription;
+mod tag_match;
+mod tags;
+#[cfg(feature = "test-utils")]
+mod test_utils;
+mod undelete;
+mod verify;
+pub use ban::{BanOptions, BanReport};
+pub use builder::{NostrPostgresBuilder, RecyclingMethod};
+pub use copy::{CopyError, CopyOptions, CopyReport};
+pub use diagnostics::{DiagnosticsReport, IndexDiagnostics, TableDiagnostics};
+#[cfg(feature = "test-utils")]
+pub use differential::{
+    DifferentialCorpus, Divergence, assert_agrees_with_memory, compare_with_memory,
+};
+pub use error::{
+    ClosedError, ConfigError, ErrorClass, ExportWriteError, FilterError, IntegrityError,
+    IntegrityErrorKind, IrreversibleMigrationError, LaggedError, PoolAcquireError, PoolErrorKind,
+    PostgresDbError, QueryGuardError, SchemaDriftError, SchemaMismatchError, TimeoutError,
+    UnindexedTagError, is_pool_exhausted, pool_error_kind,
+};
+pub use exclusions::Exclusions;
+pub use explain::{ExplainOutput, render_sql};
+#[cfg(feature = "test-utils")]
+pub use fixtures::{EventFactory, seed};
+pub use guard::{GuardAction, QueryGuard};
+pub use health::{HealthReport, PoolStatus};
+pub use history::ReplaceableHistory;
+pub use import::{ImportOptions, ImportReport};
+pub use integrity::{IntegrityOptions, IntegrityReport, ReadVerification};
+pub use maintenance::{
+    MaintenanceReport, MaintenanceStep, MaintenanceTable, MaintenanceTask, MaintenanceTasks,
+};
+#[cfg(feature = "test-utils")]
+pub use memory_store::MemoryEventStore;
+pub use migrations::postgres::{
+    MIGRATIONS_LOCK_KEY, MigrationStatus, migration_status, rollback_migrations, run_migrations,
+    run_migrations_in_schema, run_partitioned_migrations,
+};
+pub use notify::{DEFAULT_NOTIFICATION_CHANNEL, EventNotification};
+pub use outcome::SaveOutcome;
+pub use postgres::{NostrPostgres, PostgresConnectionPool, postgres_connection_pool};
+pub use quota::{Quota, QuotaMode, QuotaUsage};
+pub use rate_limit::RateLimit;
+pub use reconcile::ReconcileReport;
+#[cfg(feature = "relay")]
+pub use relay::RelayImportOptions;
+pub use retention::{RetentionAction, RetentionPolicy, RetentionReport, RetentionRule};
+pub use retry::{ConnectRetry, ConnectRetryError, RetryPolicy};
+pub use stats::{
+    AuthorStats, GroupBy, GroupKey, HistogramBucket, KindStats, StorageStats, TableStats,
+};
+pub use store::NostrEventStore;
+pub use subscription::{
+    EventSubscription, FollowItem, FollowStream, OverflowPolicy, SubscriptionOptions,
+};
+pub use tag_match::TagMatch;
+pub use tags::{OversizedTagValues, TRUNCATION_MARKER, TagIndexing, TagValueLimit};
+#[cfg(feature = "test-utils")]
+pub use test_utils::{DEFAULT_POSTGRES_VERSION, TestContainer, TestDb, TestTransaction};
+pub use undelete::UndeleteOptions;
+pub use verify::{SchemaReport, SchemaValidation};
@@ -0,0 +1,72 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use nostr_database::DatabaseError;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{ClosedError, TimeoutError};
+
+/// Tracks in-flight operations so an instance can be closed gracefully
+#[derive(Debug, Default)]
+pub(crate) struct Lifecycle {
+    closed: AtomicBool,
+    in_flight: AtomicUsize,
+    idle: Notify,
+    shutdown: CancellationToken,
+}
+
+impl Lifecycle {
+    /// registers a new operation, fails if the instance was closed
+    pub fn enter(self: &Arc<Self>) -> Result<InFlightGuard, DatabaseError> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlightGuard {
+            lifecycle: self.clone(),
+        };
+        if self.is_closed() {
+            return Err(DatabaseError::backend(ClosedError));
+        }
+        Ok(guard)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// cancelled once the instance is closed, for background workers to stop on
+    pub fn shutdown(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// stops accepting new operations, cancels the workers and waits for in-flight operations
+    pub async fn close(&self, timeout: Duration) -> Result<(), DatabaseError> {
+        self.closed.store(true, Ordering::SeqCst);
+        self.shutdown.cancel();
+        tokio::time::timeout(timeout, async {
+            loop {
+                let idle = self.idle.notified();
+                if self.in_flight.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        })
+        .await
+        .map_err(|_| DatabaseError::backend(TimeoutError::new("close", timeout)))
+    }
+}
+
+/// Marks an operation as in-flight until dropped
+#[derive(Debug)]
+pub(crate) struct InFlightGuard {
+    lifecycle: Arc<Lifecycle>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.lifecycle.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.lifecycle.idle.notify_waiters();
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use std::time::{Duration, Instant};
+
+use diesel::QueryableByName;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use diesel::sql_types::{BigInt, Bool, Bytea, Integer, Nullable, Text};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl, SimpleAsyncConnection};
+use nostr::event::{Event, EventId};
+use nostr::filter::Filter;
+use nostr_database::{DatabaseError, FlatBufferDecode};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::error::ConfigError;
+use crate::postgres::{NostrPostgres, hex};
+use crate::query::filter_conditions;
+use crate::schema::postgres::{event_tags, events};
+
+/// A table of the crate handled by [`NostrPostgres::maintenance`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MaintenanceTable {
+    /// `events`
+    Events,
+    /// `event_tags`
+    EventTags,
+    /// `events_archive`
+    EventsArchive,
+    /// `event_tags_archive`
+    EventTagsArchive,
+}
+
+impl MaintenanceTable {
+    /// All tables, in the order they are processed
+    pub const ALL: [Self; 4] = [
+        Self::Events,
+        Self::EventTags,
+        Self::EventsArchive,
+        Self::EventTagsArchive,
+    ];
+
+    /// Name of the table, without the schema
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Events => "events",
+            Self::EventTags => "event_tags",
+            Self::EventsArchive => "events_archive",
+            Self::EventTagsArchive => "event_tags_archive",
+        }
+    }
+}
+
+/// A statement run by [`NostrPostgres::maintenance`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MaintenanceTask {
+    /// `VACUUM`, never `VACUUM FULL`
+    Vacuum,
+    /// `ANALYZE`
+    Analyze,
+}
+
+impl MaintenanceTask {
+    fn statement(&self, table: MaintenanceTable) -> String {
+        match self {
+            Self::Vacuum => format!("VACUUM {}", table.name()),
+            Self::Analyze => format!("ANALYZE {}", table.name()),
+        }
+    }
+}
+
+/// What [`NostrPostgres::maintenance`] runs, nothing by default
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaintenanceTasks {
+    vacuum: bool,
+    analyze: bool,
+    tables: Option<Vec<MaintenanceTable>>,
+}
+
+impl MaintenanceTasks {
+    /// `VACUUM` the tables, reclaiming the space of deleted and updated rows
+    pub fn vacuum(mut self, vacuum: bool) -> Self {
+        self.vacuum = vacuum;
+        self
+    }
+
+    /// `ANALYZE` the tables, refreshing the statistics of the query planner
+    pub fn analyze(mut self, analyze: bool) -> Self {
+        self.analyze = analyze;
+        self
+    }
+
+    /// Only process these tables (default [`MaintenanceTable::ALL`])
+    pub fn tables<I>(mut self, tables: I) -> Self
+    where
+        I: IntoIterator<Item = MaintenanceTable>,
+    {
+        self.tables = Some(tables.into_iter().collect());
+        self
+    }
+}
+
+/// One statement run by [`NostrPostgres::maintenance`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceStep {
+    /// The statement
+    pub task: MaintenanceTask,
+    /// The table it ran on
+    pub table: MaintenanceTable,
+    /// How long it took
+    pub elapsed: Duration,
+}
+
+/// Result of [`NostrPostgres::maintenance`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceReport {
+    /// The statements in the order they ran
+    pub steps: Vec<MaintenanceStep>,
+    /// Total duration
+    pub elapsed: Duration,
+}
+
+/// runs the `tasks` on `db`, which must not be in a transaction
+///
+/// Each table is vacuumed before it is analyzed, so the statistics see the cleaned table.
+pub(crate) async fn maintenance(
+    db: &mut AsyncPgConnection,
+    tasks: &MaintenanceTasks,
+) -> Result<MaintenanceReport, DatabaseError> {
+    let start = Instant::now();
+    let mut report = MaintenanceReport::default();
+    let tables = tasks.tables.as_deref().unwrap_or(&MaintenanceTable::ALL);
+    let selected = [
+        (tasks.vacuum, MaintenanceTask::Vacuum),
+        (tasks.analyze, MaintenanceTask::Analyze),
+    ];
+    for &table in tables {
+        for (_, task) in selected.iter().filter(|(enabled, _)| *enabled) {
+            let step_start = Instant::now();
+            db.batch_execute(&task.statement(table))
+                .await
+                .map_err(DatabaseError::backend)?;
+            let elapsed = step_start.elapsed();
+            info!("{task:?} of {} took {elapsed:?}", table.name());
+            report.steps.push(MaintenanceStep {
+                task: *task,
+                table,
+                elapsed,
+            });
+        }
+    }
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+/// Number of events whose tags are rebuilt per transaction
+const REINDEX_BATCH: i64 = 1000;
+
+/// Number of tagged events checked per statement by [`cleanup_orphaned_tags`]
+const ORPHAN_BATCH: i64 = 1000;
+
+/// checks the next `$2` tagged events after `$1` and removes the tag rows of those without a
+/// live event unless `$3` is false, returning the last checked event and the number of
+/// orphaned rows
+const ORPHANED_TAGS: &str = "WITH batch AS (\
+         SELECT DISTINCT event_id FROM event_tags WHERE event_id > $1 ORDER BY event_id LIMIT $2\
+     ), orphans AS (\
+         SELECT b.event_id FROM batch b WHERE NOT EXISTS (\
+         SELECT 1 FROM events e WHERE e.id = b.event_id AND NOT e.deleted)\
+     ), removed AS (\
+         DELETE FROM event_tags WHERE $3 AND event_id IN (SELECT event_id FROM orphans)\
+     ) SELECT (SELECT event_id FROM batch ORDER BY event_id DESC LIMIT 1) AS last, \
+     (SELECT count(*) FROM event_tags WHERE event_id IN (SELECT event_id FROM orphans)) \
+     AS orphaned";
+
+#[derive(QueryableByName)]
+struct OrphanBatch {
+    #[diesel(sql_type = Nullable<Bytea>)]
+    last: Option<Vec<u8>>,
+    #[diesel(sql_type = BigInt)]
+    orphaned: i64,
+}
+
+/// first `server_version_num` supporting per-column compression methods
+const COLUMN_COMPRESSION_VERSION: i32 = 140000;
+
+#[derive(QueryableByName)]
+struct VersionDb {
+    #[diesel(sql_type = Integer)]
+    version: i32,
+}
+
+#[derive(QueryableByName)]
+struct CompressionDb {
+    #[diesel(sql_type = Nullable<Text>)]
+    compression: Option<String>,
+}
+
+/// whether a server of the given `server_version_num` supports `SET COMPRESSION`
+pub(crate) fn supports_column_compression(server_version_num: i32) -> bool {
+    server_version_num >= COLUMN_COMPRESSION_VERSION
+}
+
+pub(crate) async fn server_version_num(db: &mut AsyncPgConnection) -> Result<i32, DatabaseError> {
+    let version: VersionDb =
+        diesel::sql_query("SELECT current_setting('server_version_num')::int AS version")
+            .get_result(db)
+            .await
+            .map_err(DatabaseError::backend)?;
+    Ok(version.version)
+}
+
+/// compression method configured for `events.payload`, `None` if the server default applies
+pub(crate) async fn payload_compression(
+    db: &mut AsyncPgConnection,
+) -> Result<Option<String>, DatabaseError> {
+    if !supports_column_compression(server_version_num(db).await?) {
+        return Ok(None);
+    }
+    let compression: CompressionDb = diesel::sql_query(
+        "SELECT CASE attcompression WHEN 'l' THEN 'lz4' WHEN 'p' THEN 'pglz' END AS compression \
+         FROM pg_attribute WHERE attrelid = 'events'::regclass AND attname = 'payload'",
+    )
+    .get_result(db)
+    .await
+    .map_err(DatabaseError::backend)?;
+    Ok(compression.compression)
+}
+
+/// rewrites pglz compressed payloads in batches of `batch_size`, returning the number of
+/// rewritten rows
+pub(crate) async fn recompress_payloads(
+    db: &mut AsyncPgConnection,
+    batch_size: usize,
+) -> Result<u64, DatabaseError> {
+    if payload_compression(db).await?.as_deref() != Some("lz4") {
+        return Err(DatabaseError::backend(ConfigError::new(
+            "payload compression is not set to lz4",
+        )));
+    }
+    // concatenating detoasts the value, so it's compressed again with the column's method
+    let query = format!(
+        "UPDATE events SET payload = payload || ''::bytea WHERE id IN (\
+         SELECT id FROM events WHERE pg_column_compression(payload) = 'pglz' LIMIT {})",
+        batch_size.max(1)
+    );
+    let mut total = 0;
+    loop {
+        let rows = diesel::sql_query(&query)
+            .execute(db)
+            .await
+            .map_err(DatabaseError::backend)?;
+        if rows == 0 {
+            return Ok(total);
+        }
+        total += rows as u64;
+        debug!("Recompressed {total} payloads");
+    }
+}
+
+/// rebuilds the tag rows of the events matching `filter` after `after` in the order of their
+/// id, without rows for deleted events if `drop_deleted`, returning the number of reindexed
+/// events
+pub(crate) async fn reindex_tags(
+    db: &NostrPostgres,
+    filter: Filter,
+    after: Option<EventId>,
+    drop_deleted: bool,
+) -> Result<u64, DatabaseError> {
+    let mut cursor = after.map(|id| id.as_bytes().to_vec());
+    let mut total = 0;
+    loop {
+        let mut conn = db.get_connection().await?;
+        let mut query = events::table
+            .filter(filter_conditions(filter.clone(), true))
+            .select((events::id, events::payload, events::deleted))
+            .order_by(events::id)
+            .limit(REINDEX_BATCH)
+            .into_boxed();
+        if let Some(cursor) = cursor {
+            query = query.filter(events::id.gt(cursor));
+        }
+        let rows: Vec<(Vec<u8>, Vec<u8>, bool)> = query
+            .load(&mut conn)
+            .await
+            .map_err(DatabaseError::backend)?;
+        let Some((last, _, _)) = rows.last() else {
+            return Ok(total);
+        };
+        cursor = Some(last.clone());
+
+        let mut ids = Vec::with_capacity(rows.len());
+        let mut tags = Vec::new();
+        for (id, payload, deleted) in &rows {
+            if *deleted && drop_deleted {
+                ids.push(id.clone());
+                continue;
+            }
+            match Event::decode(payload) {
+                Ok(event) => {
+                    ids.push(id.clone());
+                    tags.extend(db.event_data(&event)?.tags);
+                }
+                Err(e) => warn!("Skipping undecodable event {}: {e}", hex(id)),
+            }
+        }
+        let reindexed = ids.len() as u64;
+        conn.transaction(|c| {
+            async move {
+                diesel::delete(event_tags::table.filter(event_tags::event_id.eq_any(ids)))
+                    .execute(c)
+                    .await?;
+                // stays below the limit of 65535 bind parameters per statement
+                for chunk in tags.chunks(10000) {
+                    diesel::insert_into(event_tags::table)
+                        .values(chunk)
+                        .on_conflict_do_nothing()
+                        .execute(c)
+                        .await?;
+                }
+                Ok::<_, DieselError>(())
+            }
+            .scope_boxed()
+        })
+        .await
+        .map_err(DatabaseError::backend)?;
+        total += reindexed;
+        info!("Reindexed the tags of {total} events, up to {}", hex(last));
+        if (rows.len() as i64) < REINDEX_BATCH {
+            return Ok(total);
+        }
+    }
+}
+
+/// removes, or only counts if `dry_run`, the tag rows of events that don't exist or are
+/// deleted, walking the tagged events after `after` in the order of their id
+pub(crate) async fn cleanup_orphaned_tags(
+    db: &NostrPostgres,
+    after: Option<EventId>,
+    dry_run: bool,
+) -> Result<u64, DatabaseError> {
+    let mut cursor = after.map_or_else(Vec::new, |id| id.as_bytes().to_vec());
+    let mut total = 0;
+    loop {
+        let mut conn = db.get_connection().await?;
+        let batch: OrphanBatch = diesel::sql_query(ORPHANED_TAGS)
+            .bind::<Bytea, _>(&cursor)
+            .bind::<BigInt, _>(ORPHAN_BATCH)
+            .bind::<Bool, _>(!dry_run)
+            .get_result(&mut conn)
+            .await
+            .map_err(DatabaseError::backend)?;
+        let Some(last) = batch.last else {
+            return Ok(total);
+        };
+        total += batch.orphaned as u64;
+        if dry_run {
+            debug!("Found {total} orphaned tag rows, up to {}", hex(&last));
+        } else {
+            info!("Removed {total} orphaned tag rows, up to {}", hex(&last));
+        }
+        cursor = last;
+    }
+}
@@ -0,0 +1,408 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+use nostr::Timestamp;
+use nostr::event::{Event, EventId, Kind};
+use nostr::filter::{Filter, MatchEventOptions};
+use nostr::key::PublicKey;
+use nostr::prelude::JsonUtil;
+use nostr_database::prelude::BoxedFuture;
+use nostr_database::{
+    Backend, DatabaseError, DatabaseEventStatus, Events, NostrDatabase, RejectedReason,
+    SaveEventStatus,
+};
+
+use crate::import::ImportReport;
+use crate::query::with_limit;
+use crate::stats::{AuthorStats, KindStats};
+use crate::store::NostrEventStore;
+
+/// An in-memory [`NostrEventStore`] for unit tests
+///
+/// Follows the rules of [`NostrPostgres`](crate::NostrPostgres) closely enough to stand in
+/// for it: saving a stored event, deleted or archived ones included, is rejected as a
+/// duplicate; [`delete`](NostrDatabase::delete) marks events as deleted, so
+/// [`check_id`](NostrDatabase::check_id) reports them while queries leave them out; queries
+/// skip expired events and apply a default limit of 10000, deletes one of 999.
+///
+/// Replaceable events are not replaced and timeouts are ignored. Payload sizes in the stats
+/// are the lengths of the JSON events. Clones share the events.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryEventStore {
+    state: Arc<RwLock<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    events: BTreeMap<EventId, Stored>,
+    archive: BTreeMap<EventId, Event>,
+}
+
+#[derive(Debug)]
+struct Stored {
+    event: Event,
+    deleted: bool,
+}
+
+impl State {
+    /// whether the event is stored, deleted or archived
+    fn contains(&self, id: &EventId) -> bool {
+        self.events.contains_key(id) || self.archive.contains_key(id)
+    }
+}
+
+impl MemoryEventStore {
+    /// An empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn query_events(&self, filter: Filter) -> Events {
+        let filter = with_limit(filter, 10000);
+        let mut events = Events::new(&filter);
+        events.extend(
+            self.read()
+                .events
+                .values()
+                .filter(|s| !s.deleted && !s.event.is_expired() && matches(&filter, &s.event))
+                .map(|s| s.event.clone()),
+        );
+        events
+    }
+
+    /// stats of every author, or of `pubkey` only
+    fn author_stats_all(&self, pubkey: Option<&PublicKey>) -> Vec<AuthorStats> {
+        let mut authors: HashMap<PublicKey, AuthorStats> = HashMap::new();
+        for stored in self.read().events.values() {
+            let event = &stored.event;
+            if pubkey.is_some_and(|p| *p != event.pubkey) {
+                continue;
+            }
+            let stats = authors.entry(event.pubkey).or_insert(AuthorStats {
+                pubkey: event.pubkey,
+                count: 0,
+                deleted: 0,
+                payload_bytes: 0,
+                first_seen: event.created_at,
+                last_seen: event.created_at,
+            });
+            record(&mut stats.count, &mut stats.deleted, stored.deleted);
+            stats.payload_bytes += event.as_json().len() as u64;
+            stats.first_seen = stats.first_seen.min(event.created_at);
+            stats.last_seen = stats.last_seen.max(event.created_at);
+        }
+        authors.into_values().collect()
+    }
+}
+
+fn matches(filter: &Filter, event: &Event) -> bool {
+    filter.match_event(event, MatchEventOptions::new())
+}
+
+/// the ids of the events matching `filter`, newest first and at most its limit
+fn newest<'a, I>(filter: &Filter, events: I) -> Vec<EventId>
+where
+    I: IntoIterator<Item = &'a Event>,
+{
+    let mut matching: Vec<&Event> = events.into_iter().filter(|e| matches(filter, e)).collect();
+    matching.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(a.id.cmp(&b.id)));
+    matching
+        .into_iter()
+        .take(filter.limit.unwrap_or(usize::MAX))
+        .map(|e| e.id)
+        .collect()
+}
+
+impl NostrDatabase for MemoryEventStore {
+    fn backend(&self) -> Backend {
+        Backend::Memory
+    }
+
+    fn save_event<'a>(
+        &'a self,
+        event: &'a Event,
+    ) -> BoxedFuture<'a, Result<SaveEventStatus, DatabaseError>> {
+        Box::pin(async move {
+            let mut state = self.write();
+            if state.contains(&event.id) {
+                return Ok(SaveEventStatus::Rejected(RejectedReason::Duplicate));
+            }
+            state.events.insert(
+                event.id,
+                Stored {
+                    event: event.clone(),
+                    deleted: false,
+                },
+            );
+            Ok(SaveEventStatus::Success)
+        })
+    }
+
+    fn check_id<'a>(
+        &'a self,
+        event_id: &'a EventId,
+    ) -> BoxedFuture<'a, Result<DatabaseEventStatus, DatabaseError>> {
+        Box::pin(async move {
+            let state = self.read();
+            let status = match state.events.get(event_id) {
+                Some(s) if s.deleted => DatabaseEventStatus::Deleted,
+                Some(_) => DatabaseEventStatus::Saved,
+                None if state.archive.contains_key(event_id) => DatabaseEventStatus::Saved,
+                None => DatabaseEventStatus::NotExistent,
+            };
+            Ok(status)
+        })
+    }
+
+    fn event_by_id<'a>(
+        &'a self,
+        event_id: &'a EventId,
+    ) -> BoxedFuture<'a, Result<Option<Event>, DatabaseError>> {
+        Box::pin(async move {
+            Ok(self
+                .read()
+                .events
+                .get(event_id)
+                .filter(|s| !s.deleted)
+                .map(|s| s.event.clone()))
+        })
+    }
+
+    fn count(&self, filter: Filter) -> BoxedFuture<'_, Result<usize, DatabaseError>> {
+        Box::pin(async move { Ok(self.query_events(filter).len()) })
+    }
+
+    fn query(&self, filter: Filter) -> BoxedFuture<'_, Result<Events, DatabaseError>> {
+        Box::pin(async move { Ok(self.query_events(filter)) })
+    }
+
+    fn delete(&self, filter: Filter) -> BoxedFuture<'_, Result<(), DatabaseError>> {
+        Box::pin(async move {
+            let filter = with_limit(filter, 999);
+            let mut state = self.write();
+            let live = state
+                .events
+                .values()
+                .filter(|s| !s.deleted && !s.event.is_expired())
+                .map(|s| &s.event);
+            for id in newest(&filter, live) {
+                if let Some(stored) = state.events.get_mut(&id) {
+                    stored.deleted = true;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Not supported, like on [`NostrPostgres`](crate::NostrPostgres)
+    fn wipe(&self) -> BoxedFuture<'_, Result<(), DatabaseError>> {
+        Box::pin(async move { Err(DatabaseError::NotSupported) })
+    }
+}
+
+impl NostrEventStore for MemoryEventStore {
+    fn ping(&self) -> BoxedFuture<'_, Result<(), DatabaseError>> {
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn save_events(
+        &self,
+        events: Vec<Event>,
+    ) -> BoxedFuture<'_, Result<ImportReport, DatabaseError>> {
+        Box::pin(async move {
+            let start = Instant::now();
+            let mut report = ImportReport::default();
+            let mut state = self.write();
+            for event in events {
+                match state.events.get(&event.id) {
+                    Some(s) if s.deleted => report.deleted += 1,
+                    Some(_) => report.duplicates += 1,
+                    None if state.archive.contains_key(&event.id) => report.duplicates += 1,
+                    None => {
+                        report.imported += 1;
+                        state.events.insert(
+                            event.id,
+                            Stored {
+                                event,
+                                deleted: false,
+                            },
+                        );
+                    }
+                }
+            }
+            report.elapsed = start.elapsed();
+            Ok(report)
+        })
+    }
+
+    fn has_event<'a>(&'a self, id: &'a EventId) -> BoxedFuture<'a, Result<bool, DatabaseError>> {
+        Box::pin(async move { Ok(self.read().contains(id)) })
+    }
+
+    fn has_active_event<'a>(
+        &'a self,
+        id: &'a EventId,
+    ) -> BoxedFuture<'a, Result<bool, DatabaseError>> {
+        Box::pin(async move { Ok(self.read().events.get(id).is_some_and(|s| !s.deleted)) })
+    }
+
+    fn which_exist<'a>(
+        &'a self,
+        ids: &'a [EventId],
+    ) -> BoxedFuture<'a, Result<HashSet<EventId>, DatabaseError>> {
+        Box::pin(async move {
+            let state = self.read();
+            Ok(ids
+                .iter()
+                .filter(|id| state.contains(id))
+                .copied()
+                .collect())
+        })
+    }
+
+    fn query_on_primary(&self, filter: Filter) -> BoxedFuture<'_, Result<Events, DatabaseError>> {
+        Box::pin(async move { Ok(self.query_events(filter)) })
+    }
+
+    fn query_with_timeout(
+        &self,
+        filter: Filter,
+        _timeout: Duration,
+    ) -> BoxedFuture<'_, Result<Events, DatabaseError>> {
+        Box::pin(async move { Ok(self.query_events(filter)) })
+    }
+
+    fn archive(&self, filter: Filter) -> BoxedFuture<'_, Result<u64, DatabaseError>> {
+        Box::pin(async move {
+            let mut state = self.write();
+            let live = state
+                .events
+                .values()
+                .filter(|s| !s.deleted)
+                .map(|s| &s.event);
+            let ids = newest(&filter, live);
+            for id in &ids {
+                if let Some(stored) = state.events.remove(id) {
+                    state.archive.insert(*id, stored.event);
+                }
+            }
+            Ok(ids.len() as u64)
+        })
+    }
+
+    fn query_including_archived(
+        &self,
+        filter: Filter,
+    ) -> BoxedFuture<'_, Result<Events, DatabaseError>> {
+        Box::pin(async move {
+            let mut events = self.query_events(filter.clone());
+            let filter = with_limit(filter, 10000);
+            let state = self.read();
+            let archived = state.archive.values();
+            for id in newest(&filter, archived) {
+                events.insert(state.archive[&id].clone());
+            }
+            Ok(events)
+        })
+    }
+
+    fn event_by_id_including_archived<'a>(
+        &'a self,
+        event_id: &'a EventId,
+    ) -> BoxedFuture<'a, Result<Option<Event>, DatabaseError>> {
+        Box::pin(async move {
+            let state = self.read();
+            let event = match state.events.get(event_id) {
+                Some(s) if s.deleted => None,
+                Some(s) => Some(s.event.clone()),
+                None => state.archive.get(event_id).cloned(),
+            };
+            Ok(event)
+        })
+    }
+
+    fn stats_by_kind(
+        &self,
+        filter: Option<Filter>,
+    ) -> BoxedFuture<'_, Result<Vec<KindStats>, DatabaseError>> {
+        Box::pin(async move {
+            let filter = filter.unwrap_or_default().remove_limit();
+            let mut kinds: HashMap<Kind, KindStats> = HashMap::new();
+            for stored in self.read().events.values() {
+                let event = &stored.event;
+                if !matches(&filter, event) {
+                    continue;
+                }
+                let stats = kinds.entry(event.kind).or_insert(KindStats {
+                    kind: event.kind,
+                    count: 0,
+                    deleted: 0,
+                    payload_bytes: 0,
+                    newest: event.created_at,
+                });
+                record(&mut stats.count, &mut stats.deleted, stored.deleted);
+                stats.payload_bytes += event.as_json().len() as u64;
+                stats.newest = stats.newest.max(event.created_at);
+            }
+            let mut kinds: Vec<KindStats> = kinds.into_values().collect();
+            kinds.sort_by(|a, b| b.count.cmp(&a.count).then(a.kind.cmp(&b.kind)));
+            Ok(kinds)
+        })
+    }
+
+    fn stats_by_author(
+        &self,
+        limit: usize,
+        offset: usize,
+    ) -> BoxedFuture<'_, Result<Vec<AuthorStats>, DatabaseError>> {
+        Box::pin(async move {
+            let mut authors = self.author_stats_all(None);
+            authors.sort_by(|a, b| b.count.cmp(&a.count).then(a.pubkey.cmp(&b.pubkey)));
+            Ok(authors.into_iter().skip(offset).take(limit).collect())
+        })
+    }
+
+    fn author_stats<'a>(
+        &'a self,
+        pubkey: &'a PublicKey,
+    ) -> BoxedFuture<'a, Result<Option<AuthorStats>, DatabaseError>> {
+        Box::pin(async move { Ok(self.author_stats_all(Some(pubkey)).pop()) })
+    }
+
+    fn time_range(
+        &self,
+        filter: Option<Filter>,
+    ) -> BoxedFuture<'_, Result<Option<(Timestamp, Timestamp)>, DatabaseError>> {
+        Box::pin(async move {
+            let filter = filter.unwrap_or_default();
+            let state = self.read();
+            let times = state
+                .events
+                .values()
+                .filter(|s| !s.deleted && matches(&filter, &s.event))
+                .map(|s| s.event.created_at);
+            Ok(times.fold(None, |range, t| match range {
+                None => Some((t, t)),
+                Some((oldest, newest)) => Some((oldest.min(t), newest.max(t))),
+            }))
+        })
+    }
+}
+
+/// counts an event as deleted or not
+fn record(count: &mut u64, deleted_count: &mut u64, deleted: bool) {
+    if deleted {
+        *deleted_count += 1;
+    } else {
+        *count += 1;
+    }
+}
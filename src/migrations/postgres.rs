@@ -1,19 +1,308 @@
-use diesel::{Connection, PgConnection};
+use std::thread;
+use std::time::Duration;
+
+use diesel::dsl::sql;
+use diesel::sql_types::{Bool, Text};
+use diesel::{Connection, PgConnection, QueryableByName, RunQueryDsl};
 use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
 use nostr_database::DatabaseError;
-use tracing::info;
+use tracing::{info, warn};
+
+use crate::error::{ConfigError, IrreversibleMigrationError, PostgresDbError};
+use crate::identifier::quote_identifier;
 
 const MIGRATIONS: EmbeddedMigrations = embed_migrations!("./migrations/postgres");
+const PARTITIONED_MIGRATIONS: EmbeddedMigrations =
+    embed_migrations!("./migrations/postgres_partitioned");
+
+/// first migration of the unpartitioned layout creating the events table
+const EVENTS_VERSION: &str = "20250411095120";
+/// first migration of the partitioned layout creating the events table
+const PARTITIONED_EVENTS_VERSION: &str = "20261015000010";
+
+/// migration creating the archive tables, in both layouts
+const ARCHIVE_VERSION: &str = "20261015000012";
+
+/// migrations whose rollback would drop the stored or archived events
+const IRREVERSIBLE: &[&str] = &[
+    "00000000000000",
+    EVENTS_VERSION,
+    PARTITIONED_EVENTS_VERSION,
+    ARCHIVE_VERSION,
+];
+
+/// Key of the advisory lock held while running migrations (the bytes of "nostr")
+///
+/// Serializes concurrent [`run_migrations`] calls, e.g. from several replicas starting at
+/// once, across all schemas of a database.
+pub const MIGRATIONS_LOCK_KEY: i64 = 0x6e6f737472;
+
+/// How often a caller waiting for [`MIGRATIONS_LOCK_KEY`] tries again
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 /// programatically run the db migrations
+///
+/// Applied migrations are recorded in `__diesel_schema_migrations`, so every migration is
+/// applied once, inside its own transaction, and only pending ones run on startup.
+/// Concurrent callers wait on an advisory lock ([`MIGRATIONS_LOCK_KEY`]) until the first one is
+/// done.
 pub fn run_migrations(connection_string: &str) -> Result<(), DatabaseError> {
+    run_migrations_with_schema(connection_string, None, false)
+}
+
+/// programatically run the db migrations inside the given schema, creating it if missing
+pub fn run_migrations_in_schema(
+    connection_string: &str,
+    schema: &str,
+) -> Result<(), DatabaseError> {
+    run_migrations_with_schema(connection_string, Some(schema), false)
+}
+
+/// programatically run the db migrations of the partitioned layout, optionally inside the
+/// given schema
+///
+/// `events` is created as a table partitioned by month on `created_at`, see
+/// [`NostrPostgres::ensure_partitions`](crate::NostrPostgres::ensure_partitions). Only for new
+/// databases: converting the unpartitioned layout is not supported and either layout refuses
+/// to migrate a database created with the other one.
+pub fn run_partitioned_migrations(
+    connection_string: &str,
+    schema: Option<&str>,
+) -> Result<(), DatabaseError> {
+    run_migrations_with_schema(connection_string, schema, true)
+}
+
+pub(crate) fn run_migrations_with_schema(
+    connection_string: &str,
+    schema: Option<&str>,
+    partitioned: bool,
+) -> Result<(), DatabaseError> {
     info!("Running db migrations in postgres database",);
-    let mut connection =
-        PgConnection::establish(connection_string).map_err(DatabaseError::backend)?;
+    let mut connection = establish(connection_string, schema, true)?;
 
-    let res = connection
-        .run_pending_migrations(MIGRATIONS)
-        .map_err(DatabaseError::Backend)?;
+    lock(&mut connection)?;
+    let migrations = if partitioned {
+        PARTITIONED_MIGRATIONS
+    } else {
+        MIGRATIONS
+    };
+    let res = check_layout(&mut connection, partitioned)
+        .and_then(|_| drop_invalid_indexes(&mut connection))
+        .and_then(|_| {
+            connection
+                .run_pending_migrations(migrations)
+                .map(|versions| versions.iter().map(|v| v.to_string()).collect::<Vec<_>>())
+                .map_err(migration_error)
+        });
+    // the lock is also released when the connection closes, should unlocking fail
+    let unlocked = diesel::sql_query(format!("SELECT pg_advisory_unlock({MIGRATIONS_LOCK_KEY})"))
+        .execute(&mut connection)
+        .map_err(DatabaseError::backend);
+    let res = res?;
+    unlocked?;
     info!("Successfully executed postgres db migrations {:?}", res);
     Ok(())
 }
+
+/// Revert the applied migrations newer than `to_version`, newest first, optionally inside
+/// the given schema
+///
+/// Never run automatically. `to_version` is a version as listed by [`migration_status`], e.g.
+/// `20261015000001`. Each migration is reverted inside its own transaction unless it builds
+/// indexes concurrently. Refuses with an
+/// [`IrreversibleMigrationError`](crate::IrreversibleMigrationError) before reverting anything
+/// if one of the migrations creates the tables. Returns the reverted versions.
+pub fn rollback_migrations(
+    connection_string: &str,
+    schema: Option<&str>,
+    to_version: &str,
+) -> Result<Vec<String>, DatabaseError> {
+    info!("Rolling back postgres db migrations to {to_version}");
+    let mut connection = establish(connection_string, schema, false)?;
+
+    lock(&mut connection)?;
+    let res = revert_migrations(&mut connection, to_version);
+    // the lock is also released when the connection closes, should unlocking fail
+    let unlocked = diesel::sql_query(format!("SELECT pg_advisory_unlock({MIGRATIONS_LOCK_KEY})"))
+        .execute(&mut connection)
+        .map_err(DatabaseError::backend);
+    let res = res?;
+    unlocked?;
+    info!("Successfully rolled back postgres db migrations {:?}", res);
+    Ok(res)
+}
+
+/// classifies an error of the migration harness
+fn migration_error(error: Box<dyn std::error::Error + Send + Sync>) -> DatabaseError {
+    PostgresDbError::Migration(error).into()
+}
+
+fn revert_migrations(
+    connection: &mut PgConnection,
+    to_version: &str,
+) -> Result<Vec<String>, DatabaseError> {
+    let mut applied = connection
+        .applied_migrations()
+        .map_err(migration_error)?
+        .into_iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>();
+    if !applied.iter().any(|v| v == to_version) {
+        return Err(DatabaseError::backend(ConfigError::new(format!(
+            "migration {to_version} is not applied"
+        ))));
+    }
+    let partitioned = applied.iter().any(|v| v == PARTITIONED_EVENTS_VERSION);
+    applied.retain(|v| v.as_str() > to_version);
+    applied.sort_by(|a, b| b.cmp(a));
+    if let Some(version) = applied.iter().find(|v| IRREVERSIBLE.contains(&v.as_str())) {
+        return Err(DatabaseError::backend(IrreversibleMigrationError::new(
+            version.clone(),
+        )));
+    }
+
+    let mut reverted = Vec::new();
+    for _ in 0..applied.len() {
+        let version = connection
+            .revert_last_migration(if partitioned {
+                PARTITIONED_MIGRATIONS
+            } else {
+                MIGRATIONS
+            })
+            .map_err(migration_error)?;
+        reverted.push(version.to_string());
+    }
+    Ok(reverted)
+}
+
+/// Applied and pending migrations of a database
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationStatus {
+    /// Versions of the applied migrations
+    pub applied: Vec<String>,
+    /// Versions of the migrations that [`run_migrations`] would apply
+    pub pending: Vec<String>,
+}
+
+/// List the applied and pending migrations, optionally inside the given schema
+pub fn migration_status(
+    connection_string: &str,
+    schema: Option<&str>,
+) -> Result<MigrationStatus, DatabaseError> {
+    let mut connection = establish(connection_string, schema, false)?;
+    let mut applied = connection
+        .applied_migrations()
+        .map_err(migration_error)?
+        .into_iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>();
+    applied.sort();
+    let migrations = if applied.iter().any(|v| v == PARTITIONED_EVENTS_VERSION) {
+        PARTITIONED_MIGRATIONS
+    } else {
+        MIGRATIONS
+    };
+    let pending = connection
+        .pending_migrations(migrations)
+        .map_err(migration_error)?
+        .into_iter()
+        .map(|m| m.name().version().to_string())
+        .collect();
+    Ok(MigrationStatus { applied, pending })
+}
+
+/// fails if the database was set up with the other layout
+fn check_layout(connection: &mut PgConnection, partitioned: bool) -> Result<(), DatabaseError> {
+    let other = if partitioned {
+        EVENTS_VERSION
+    } else {
+        PARTITIONED_EVENTS_VERSION
+    };
+    let applied = connection.applied_migrations().map_err(migration_error)?;
+    if applied.iter().any(|v| v.to_string() == other) {
+        let (wanted, found) = if partitioned {
+            ("partitioned", "unpartitioned")
+        } else {
+            ("unpartitioned", "partitioned")
+        };
+        return Err(DatabaseError::backend(ConfigError::new(format!(
+            "the {wanted} layout was requested, but the database uses the {found} one"
+        ))));
+    }
+    Ok(())
+}
+
+/// waits for the migrations lock by polling
+///
+/// A session blocked in `pg_advisory_lock` keeps its statement open, and `CREATE INDEX
+/// CONCURRENTLY` in the migrations of the lock holder waits for every open transaction, so
+/// the two would deadlock.
+fn lock(connection: &mut PgConnection) -> Result<(), DatabaseError> {
+    loop {
+        let locked = diesel::select(sql::<Bool>(&format!(
+            "pg_try_advisory_lock({MIGRATIONS_LOCK_KEY})"
+        )))
+        .get_result::<bool>(connection)
+        .map_err(DatabaseError::backend)?;
+        if locked {
+            return Ok(());
+        }
+        thread::sleep(LOCK_POLL_INTERVAL);
+    }
+}
+
+#[derive(QueryableByName)]
+struct InvalidIndexDb {
+    #[diesel(sql_type = Text)]
+    name: String,
+}
+
+/// drops indexes in the schema of the migrations left invalid by a failed
+/// `CREATE INDEX CONCURRENTLY`
+///
+/// Migrations creating indexes concurrently are marked with `run_in_transaction = false` in
+/// their `metadata.toml`. If such a migration fails it is not recorded as applied, but the
+/// invalid index stays behind and would be skipped by `IF NOT EXISTS` on the next attempt.
+/// Filtering on the schema rather than on table names keeps this working as later
+/// migrations index further tables.
+fn drop_invalid_indexes(connection: &mut PgConnection) -> Result<(), DatabaseError> {
+    let invalid: Vec<InvalidIndexDb> = diesel::sql_query(
+        "SELECT quote_ident(n.nspname) || '.' || quote_ident(c.relname) AS name \
+         FROM pg_index i \
+         JOIN pg_class c ON c.oid = i.indexrelid \
+         JOIN pg_namespace n ON n.oid = c.relnamespace \
+         WHERE NOT i.indisvalid AND n.nspname = current_schema()",
+    )
+    .load(connection)
+    .map_err(DatabaseError::backend)?;
+    for index in invalid {
+        warn!("Dropping invalid index {} before migrating", index.name);
+        diesel::sql_query(format!("DROP INDEX CONCURRENTLY IF EXISTS {}", index.name))
+            .execute(connection)
+            .map_err(DatabaseError::backend)?;
+    }
+    Ok(())
+}
+
+/// connects for running migrations, selecting (and creating) the schema if given
+fn establish(
+    connection_string: &str,
+    schema: Option<&str>,
+    create_schema: bool,
+) -> Result<PgConnection, DatabaseError> {
+    let mut connection =
+        PgConnection::establish(connection_string).map_err(DatabaseError::backend)?;
+
+    if let Some(schema) = schema {
+        let schema = quote_identifier(schema)?;
+        if create_schema {
+            diesel::sql_query(format!("CREATE SCHEMA IF NOT EXISTS {schema}"))
+                .execute(&mut connection)
+                .map_err(DatabaseError::backend)?;
+        }
+        diesel::sql_query(format!("SET search_path TO {schema}"))
+            .execute(&mut connection)
+            .map_err(DatabaseError::backend)?;
+    }
+    Ok(connection)
+}
@@ -1,6 +1,7 @@
 use std::sync::{Mutex, OnceLock};
 
 use diesel::prelude::*;
+use diesel::sql_types::Bytea;
 use nostr::event::Event;
 use nostr_database::{DatabaseError, FlatBufferBuilder, FlatBufferEncode};
 
@@ -18,6 +19,15 @@ pub struct EventDb {
     pub deleted: bool,
 }
 
+/// The id and payload of a stored event, as read by raw queries
+#[derive(QueryableByName, Debug, Clone)]
+pub struct StoredEventDb {
+    #[diesel(sql_type = Bytea)]
+    pub id: Vec<u8>,
+    #[diesel(sql_type = Bytea)]
+    pub payload: Vec<u8>,
+}
+
 /// DB representation of [`EventTag`]
 #[derive(Queryable, Selectable, Insertable, AsChangeset, Debug, Clone)]
 #[diesel(table_name = event_tags)]
@@ -25,6 +35,7 @@ pub struct EventTagDb {
     pub tag: String,
     pub tag_value: String,
     pub event_id: Vec<u8>,
+    pub tag_fields: Vec<String>,
 }
 
 /// A data container for extracting data from [`Event`] and its tags
@@ -62,20 +73,20 @@ fn encode_payload(value: &Event) -> Vec<u8> {
     }
 }
 
+/// the tag rows of `event`; a tag without a value, like `["x"]`, gets an empty value, so
+/// `{"#x": [""]}` finds it
+///
+/// All elements after the name are kept in `tag_fields`, untruncated, for
+/// [`TagMatch::position`](crate::TagMatch::position).
 fn extract_tags(event: &Event) -> Vec<EventTagDb> {
     event
         .tags
         .iter()
-        .filter_map(|tag| {
-            if let (kind, Some(content)) = (tag.kind(), tag.content()) {
-                Some(EventTagDb {
-                    tag: kind.to_string(),
-                    tag_value: content.to_string(),
-                    event_id: event.id.as_bytes().to_vec(),
-                })
-            } else {
-                None
-            }
+        .map(|tag| EventTagDb {
+            tag: tag.kind().to_string(),
+            tag_value: tag.content().unwrap_or_default().to_string(),
+            event_id: event.id.as_bytes().to_vec(),
+            tag_fields: tag.as_slice()[1..].to_vec(),
         })
         .collect()
 }
@@ -0,0 +1,96 @@
+use diesel::QueryResult;
+use diesel::sql_types::{Array, Text};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use nostr::Timestamp;
+use nostr::event::{EventId, Kind};
+use nostr::filter::Filter;
+use nostr::key::PublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::model::EventDb;
+use crate::query::{Operation, tagged};
+
+/// Channel of the notifications sent for saved events, see
+/// [`NostrPostgresBuilder::notifications`](crate::NostrPostgresBuilder::notifications)
+pub const DEFAULT_NOTIFICATION_CHANNEL: &str = "nostr_events";
+
+/// The JSON payload of the `NOTIFY` sent for a saved event
+///
+/// Carries just enough to decide whether the event is of interest, about 170 bytes, well
+/// below the 8000 byte limit of `NOTIFY`; fetch the event by id for the rest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventNotification {
+    /// The event id
+    pub id: EventId,
+    /// The event kind
+    pub kind: Kind,
+    /// The author
+    pub pubkey: PublicKey,
+    /// The creation time
+    pub created_at: Timestamp,
+}
+
+impl EventNotification {
+    /// Parse the payload of a notification
+    pub fn from_payload(payload: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(payload)
+    }
+
+    /// Whether the event may match `filter`, judging by the fields of the notification
+    ///
+    /// Checks ids, authors, kinds and the time bounds; tag and search conditions need the
+    /// event itself.
+    pub fn may_match(&self, filter: &Filter) -> bool {
+        filter.ids.as_ref().is_none_or(|ids| ids.contains(&self.id))
+            && filter
+                .authors
+                .as_ref()
+                .is_none_or(|authors| authors.contains(&self.pubkey))
+            && filter
+                .kinds
+                .as_ref()
+                .is_none_or(|kinds| kinds.contains(&self.kind))
+            && filter.since.is_none_or(|since| self.created_at >= since)
+            && filter.until.is_none_or(|until| self.created_at <= until)
+    }
+
+    fn from_row(row: &EventDb) -> Option<Self> {
+        Some(Self {
+            id: EventId::from_slice(&row.id).ok()?,
+            kind: Kind::from(row.kind as u16),
+            pubkey: PublicKey::from_slice(&row.pubkey).ok()?,
+            created_at: Timestamp::from(row.created_at as u64),
+        })
+    }
+}
+
+/// notifies `channel` of the saved `events` in one statement, delivered when the transaction
+/// commits
+pub(crate) async fn notify_saved<'a, I>(
+    db: &mut AsyncPgConnection,
+    channel: &str,
+    events: I,
+    statement_tags: bool,
+) -> QueryResult<()>
+where
+    I: IntoIterator<Item = &'a EventDb>,
+{
+    let payloads: Vec<String> = events
+        .into_iter()
+        .filter_map(EventNotification::from_row)
+        .filter_map(|n| serde_json::to_string(&n).ok())
+        .collect();
+    if payloads.is_empty() {
+        return Ok(());
+    }
+    tagged(
+        diesel::sql_query("SELECT pg_notify($1, payload) FROM unnest($2) AS payload")
+            .bind::<Text, _>(channel)
+            .bind::<Array<Text>, _>(payloads),
+        Operation::Save,
+        statement_tags,
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use nostr_database::{RejectedReason, SaveEventStatus};
+
+/// What [`NostrPostgres::try_save_event`](crate::NostrPostgres::try_save_event) did with an
+/// event
+///
+/// Tells apart the rejections that [`SaveEventStatus`] reports as
+/// [`RejectedReason::Other`], which it converts into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SaveOutcome {
+    /// The event was stored
+    Saved,
+    /// The event is already stored, or was deleted
+    Duplicate,
+    /// The author is on the blocklist, see
+    /// [`ban_author`](crate::NostrPostgres::ban_author)
+    Blocked,
+    /// The author is at their [quota](crate::NostrPostgresBuilder::quota)
+    OverQuota,
+    /// The author saves faster than their [rate limit](crate::NostrPostgresBuilder::rate_limit)
+    /// allows
+    RateLimited,
+}
+
+impl SaveOutcome {
+    /// Whether the event was stored
+    pub fn is_saved(&self) -> bool {
+        *self == Self::Saved
+    }
+
+    /// the reason logged for a rejection
+    pub(crate) fn reason(&self) -> &'static str {
+        match self {
+            Self::Saved => "saved",
+            Self::Duplicate => "duplicate",
+            Self::Blocked => "blocked author",
+            Self::OverQuota => "over quota",
+            Self::RateLimited => "rate limited",
+        }
+    }
+}
+
+impl From<SaveOutcome> for SaveEventStatus {
+    fn from(outcome: SaveOutcome) -> Self {
+        match outcome {
+            SaveOutcome::Saved => Self::Success,
+            SaveOutcome::Duplicate => Self::Rejected(RejectedReason::Duplicate),
+            SaveOutcome::Blocked | SaveOutcome::OverQuota | SaveOutcome::RateLimited => {
+                Self::Rejected(RejectedReason::Other)
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+
+use diesel::QueryableByName;
+use diesel::sql_types::Text;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use nostr::Timestamp;
+use nostr_database::DatabaseError;
+use tracing::info;
+
+use crate::error::ConfigError;
+
+#[derive(QueryableByName)]
+struct RelkindDb {
+    #[diesel(sql_type = Text)]
+    relkind: String,
+}
+
+#[derive(QueryableByName)]
+struct PartitionDb {
+    #[diesel(sql_type = Text)]
+    name: String,
+}
+
+/// creates the monthly partitions of `events` from the current month up to the one
+/// containing `until`, returning the names of the created ones
+pub(crate) async fn ensure_partitions(
+    db: &mut AsyncPgConnection,
+    until: Timestamp,
+) -> Result<Vec<String>, DatabaseError> {
+    let table: RelkindDb =
+        diesel::sql_query("SELECT relkind::text FROM pg_class WHERE oid = 'events'::regclass")
+            .get_result(db)
+            .await
+            .map_err(DatabaseError::backend)?;
+    if table.relkind != "p" {
+        return Err(DatabaseError::backend(ConfigError::new(
+            "events is not partitioned, see run_partitioned_migrations",
+        )));
+    }
+
+    let existing: HashSet<String> = diesel::sql_query(
+        "SELECT c.relname::text AS name FROM pg_inherits i \
+         JOIN pg_class c ON c.oid = i.inhrelid \
+         WHERE i.inhparent = 'events'::regclass",
+    )
+    .load::<PartitionDb>(db)
+    .await
+    .map_err(DatabaseError::backend)?
+    .into_iter()
+    .map(|p| p.name)
+    .collect();
+
+    let mut created = Vec::new();
+    let (mut year, mut month) = year_month(Timestamp::now().as_u64() as i64);
+    let last = year_month(until.as_u64() as i64);
+    while (year, month) <= last {
+        let (next_year, next_month) = if month == 12 {
+            (year + 1, 1)
+        } else {
+            (year, month + 1)
+        };
+        let name = format!("events_p{year:04}{month:02}");
+        if !existing.contains(&name) {
+            diesel::sql_query(format!(
+                "CREATE TABLE {name} PARTITION OF events FOR VALUES FROM ({}) TO ({})",
+                month_start(year, month),
+                month_start(next_year, next_month),
+            ))
+            .execute(db)
+            .await
+            .map_err(DatabaseError::backend)?;
+            info!("Created partition {name}");
+            created.push(name);
+        }
+        (year, month) = (next_year, next_month);
+    }
+    Ok(created)
+}
+
+/// year and month (1-12) of a unix timestamp in UTC
+fn year_month(timestamp: i64) -> (i64, u32) {
+    // civil_from_days by Howard Hinnant
+    let z = timestamp.div_euclid(86400) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month)
+}
+
+/// unix timestamp of the first second of the given month in UTC
+fn month_start(year: i64, month: u32) -> i64 {
+    // days_from_civil by Howard Hinnant
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = i64::from(month);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    (era * 146097 + doe - 719468) * 86400
+}
@@ -1,89 +1,2415 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use deadpool::Runtime;
 use deadpool::managed::{Object, Pool};
+use diesel::ConnectionError;
 use diesel::QueryResult;
+use diesel::connection::CacheSize;
 use diesel::prelude::*;
 use diesel::result::{DatabaseErrorKind, Error as DieselError};
-use diesel_async::pooled_connection::AsyncDieselConnectionManager;
-use diesel_async::scoped_futures::ScopedFutureExt;
-use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use diesel::sql_types::{Array, BigInt};
+use diesel_async::pooled_connection::{AsyncDieselConnectionManager, ManagerConfig};
+use diesel_async::scoped_futures::{ScopedBoxFuture, ScopedFutureExt};
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl, SimpleAsyncConnection};
+use futures_util::future::{join_all, try_join_all};
+use futures_util::{FutureExt, Stream, StreamExt, TryStreamExt};
+use nostr::Timestamp;
 use nostr::event::*;
 use nostr::filter::Filter;
+use nostr::key::PublicKey;
+use nostr::nips::nip01::Coordinate;
+use nostr::util::JsonUtil;
 use nostr_database::*;
 use prelude::BoxedFuture;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tracing::{Instrument, Span, debug, debug_span, field, info_span, warn};
+
+use super::model::{EventDataDb, EventDb, StoredEventDb};
+use super::schema::postgres::{event_tags, events, events_archive};
+use crate::archive::{archive, archived_by_id, query_archived};
+use crate::ban::{BanOptions, BanReport, ban_author, unblock};
+use crate::builder::{Config, NostrPostgresBuilder, RecyclingMethod};
+use crate::bulk::bulk_load;
+use crate::copy::{CopyOptions, CopyReport, copy_from};
+use crate::diagnostics::{DiagnosticsReport, diagnostics};
+use crate::error::{
+    ClosedError, ConfigError, FilterError, PoolAcquireError, PostgresDbError, TimeoutError,
+};
+use crate::exclusions::Exclusions;
+use crate::explain::{ExplainOutput, explain};
+use crate::export::export_jsonl;
+#[cfg(feature = "gzip")]
+use crate::export::export_jsonl_gzip;
+use crate::health::{HealthReport, PoolMetrics, PoolStatus, ping, schema_version};
+use crate::history::{identifier, replace, trim, versions};
+use crate::hll::{HLL_REGISTERS, hll_add, hll_offset};
+use crate::identifier::quote_identifier;
+use crate::import::{BatchOutcome, ImportOptions, ImportReport, import_jsonl};
+use crate::integrity::{IntegrityOptions, IntegrityReport, ReadVerification, verify_integrity};
+use crate::lifecycle::{InFlightGuard, Lifecycle};
+use crate::maintenance::{
+    MaintenanceReport, MaintenanceTable, MaintenanceTasks, cleanup_orphaned_tags, maintenance,
+    recompress_payloads, reindex_tags,
+};
+use crate::notify::notify_saved;
+use crate::outcome::SaveOutcome;
+use crate::partition::ensure_partitions;
+use crate::quarantine::Quarantine;
+use crate::query::{
+    EventCondition, NOT_EXPIRED_BEFORE, NOT_EXPIRED_END, Operation, authored_or_delegated,
+    bind_count, build_filter_query, build_filter_query_folded, build_filter_query_with_deleted,
+    event_by_id, filter_conditions, filter_conditions_folded, filter_shape, not_expired,
+    per_author as per_author_query, tagged, with_limit,
+};
+use crate::quota::{QuotaUsage, make_room, usage};
+use crate::rate_limit::RateLimiter;
+use crate::reconcile::{ReconcileReport, reconcile};
+#[cfg(feature = "relay")]
+use crate::relay::{RelayImportOptions, import_from_relay};
+use crate::retention::{RetentionReport, apply_retention};
+use crate::stats::{
+    AuthorStats, GroupBy, GroupKey, HistogramBucket, KindStats, StorageStats, author_stats,
+    author_stats_page, count_authors, count_grouped, histogram, kind_stats, storage_stats,
+    tag_names, tag_values,
+};
+use crate::subscription::{EventSubscription, FollowStream, follow, subscribe};
+use crate::tag_match::TagMatch;
+use crate::undelete::{UndeleteOptions, undelete};
+use crate::verify::{SchemaReport, schema_report, verify_schema};
+
+/// rows decoded and serialized per blocking task by `query_json`
+const JSON_BATCH: usize = 500;
+
+/// events restored per transaction by `undelete`
+const UNDELETE_BATCH: usize = 1000;
+
+/// Shorthand for a database connection pool type
+pub type PostgresConnectionPool = Pool<AsyncDieselConnectionManager<AsyncPgConnection>>;
+
+/// A connection checked out of a [`PostgresConnectionPool`]
+pub(crate) type PooledConnection = Object<AsyncDieselConnectionManager<AsyncPgConnection>>;
+
+/// A connection all operations of an instance share, `None` once it was released
+pub(crate) type PinnedConnection = Arc<Mutex<Option<PooledConnection>>>;
+
+/// A pooled connection that keeps its operation registered as in-flight
+pub(crate) struct PostgresConnection {
+    conn: Connection,
+    _guard: InFlightGuard,
+}
+
+/// where an operation got its connection from
+enum Connection {
+    Pooled(PooledConnection),
+    /// locked for the operation, checked to hold a connection on acquisition
+    Pinned(OwnedMutexGuard<Option<PooledConnection>>),
+}
+
+impl Deref for PostgresConnection {
+    type Target = AsyncPgConnection;
+
+    fn deref(&self) -> &Self::Target {
+        match &self.conn {
+            Connection::Pooled(conn) => conn,
+            Connection::Pinned(conn) => conn.as_ref().expect("pinned connection was released"),
+        }
+    }
+}
+
+impl DerefMut for PostgresConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match &mut self.conn {
+            Connection::Pooled(conn) => conn,
+            Connection::Pinned(conn) => conn.as_mut().expect("pinned connection was released"),
+        }
+    }
+}
+
+/// Inplements NostrDatabase trait for a Postgres database backend
+#[derive(Clone)]
+pub struct NostrPostgres {
+    pool: PostgresConnectionPool,
+    read_pool: Option<PostgresConnectionPool>,
+    config: Arc<Config>,
+    lifecycle: Arc<Lifecycle>,
+    metrics: Arc<PoolMetrics>,
+    quarantine: Arc<Quarantine>,
+    rate_limiter: Arc<RateLimiter>,
+    connection_string: Option<Arc<str>>,
+    pinned: Option<PinnedConnection>,
+}
+
+impl NostrPostgres {
+    /// Create a new [`NostrPostgres`] instance
+    pub async fn new<C>(connection_string: C) -> Result<Self, DatabaseError>
+    where
+        C: AsRef<str>,
+    {
+        Self::builder(connection_string).build().await
+    }
+
+    /// Create a new [`NostrPostgres`] instance backed by a single connection
+    ///
+    /// Meant for one-off tools that don't need a pool. Concurrent calls wait for the
+    /// connection in turn, and a dropped connection is re-established on the next call.
+    pub async fn single_connection<C>(connection_string: C) -> Result<Self, DatabaseError>
+    where
+        C: AsRef<str>,
+    {
+        Self::builder(connection_string)
+            .max_connections(1)
+            .build()
+            .await
+    }
+
+    /// Create a new [`NostrPostgres`] instance configured from environment variables
+    ///
+    /// See [`NostrPostgresBuilder::from_env`].
+    pub async fn from_env() -> Result<Self, DatabaseError> {
+        NostrPostgresBuilder::from_env()?.build().await
+    }
+
+    /// Create a new [`NostrPostgres`] instance configured from prefixed environment variables
+    ///
+    /// See [`NostrPostgresBuilder::from_env_with_prefix`].
+    pub async fn from_env_with_prefix(prefix: &str) -> Result<Self, DatabaseError> {
+        NostrPostgresBuilder::from_env_with_prefix(prefix)?
+            .build()
+            .await
+    }
+
+    /// Create a [`NostrPostgresBuilder`] to configure a new instance
+    pub fn builder<C>(connection_string: C) -> NostrPostgresBuilder
+    where
+        C: AsRef<str>,
+    {
+        NostrPostgresBuilder::new(connection_string)
+    }
+
+    pub(crate) fn with_config(pool: PostgresConnectionPool, config: Config) -> Self {
+        Self {
+            pool,
+            read_pool: None,
+            quarantine: Arc::new(Quarantine::new(config.quarantine)),
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limit.clone())),
+            config: Arc::new(config),
+            lifecycle: Arc::new(Lifecycle::default()),
+            metrics: Arc::new(PoolMetrics::default()),
+            connection_string: None,
+            pinned: None,
+        }
+    }
+
+    /// runs all operations on `conn` instead of connections from the pool, one at a time
+    #[cfg(feature = "test-utils")]
+    pub(crate) fn with_pinned_connection(mut self, conn: PinnedConnection) -> Self {
+        self.pinned = Some(conn);
+        self
+    }
+
+    /// keeps the connection string for operations needing a dedicated connection
+    pub(crate) fn with_connection_string(mut self, connection_string: &str) -> Self {
+        let connection_string =
+            with_application_name(connection_string, &self.config.application_name);
+        self.connection_string = Some(connection_string.into());
+        self
+    }
+
+    /// Route read operations (`query`, `count`, `event_by_id` and `check_id`) to a replica
+    ///
+    /// Writes and migrations stay on the primary pool. Whether a failure to get a replica
+    /// connection falls back to the primary is configured with
+    /// [`NostrPostgresBuilder::replica_fallback`].
+    pub fn with_read_replica(mut self, pool: PostgresConnectionPool) -> Self {
+        self.read_pool = Some(pool);
+        self
+    }
+
+    /// The underlying connection pool
+    pub fn pool(&self) -> &PostgresConnectionPool {
+        &self.pool
+    }
+
+    /// Current saturation of the connection pool and acquisition counters
+    pub fn pool_status(&self) -> PoolStatus {
+        self.metrics
+            .status(self.pool.status(), self.quarantine.failures())
+    }
+
+    /// Ids of the stored events whose payloads failed to decode, oldest first
+    ///
+    /// Empty unless [`quarantine`](NostrPostgresBuilder::quarantine) is enabled; the last
+    /// 1000 are kept. The events are left in place for investigation, e.g. with
+    /// [`verify_integrity`](Self::verify_integrity).
+    pub fn quarantined(&self) -> Vec<EventId> {
+        self.quarantine.ids()
+    }
+
+    /// Eagerly open `n` connections so the first requests don't pay for establishing them
+    ///
+    /// `n` is capped at the maximum pool size; `0` is a no-op. Fails if any of the
+    /// connections can't be established.
+    pub async fn warm_up(&self, n: usize) -> Result<(), DatabaseError> {
+        let n = n.min(self.pool.status().max_size);
+        if n == 0 {
+            return Ok(());
+        }
+        let connections = try_join_all((0..n).map(|_| self.get_connection())).await?;
+        debug!("Warmed up {} pool connections", connections.len());
+        Ok(())
+    }
+
+    /// Close the instance gracefully
+    ///
+    /// New operations are rejected right away, background workers are stopped and in-flight
+    /// operations get until the configured close timeout to finish before the pool is closed.
+    /// Closing affects all clones of this instance.
+    pub async fn close(&self) -> Result<(), DatabaseError> {
+        let res = self.lifecycle.close(self.config.close_timeout).await;
+        self.pool.close();
+        if let Some(read_pool) = &self.read_pool {
+            read_pool.close();
+        }
+        res
+    }
+
+    /// Whether [`NostrPostgres::close`] was called on this instance or one of its clones
+    pub fn is_closed(&self) -> bool {
+        self.lifecycle.is_closed()
+    }
+
+    /// Check that the database is reachable by running `SELECT 1`
+    ///
+    /// Fails if no answer was received within the configured health timeout.
+    pub async fn ping(&self) -> Result<(), DatabaseError> {
+        let timeout = self.config.health_timeout;
+        tokio::time::timeout(timeout, async {
+            let mut db = self.get_connection().await?;
+            ping(&mut db).await
+        })
+        .await
+        .map_err(|_| DatabaseError::backend(TimeoutError::new("ping", timeout)))?
+    }
+
+    /// Report the state of the connection pool, the round-trip latency and the schema version
+    ///
+    /// Fails if no answer was received within the configured health timeout.
+    pub async fn health(&self) -> Result<HealthReport, DatabaseError> {
+        let timeout = self.config.health_timeout;
+        tokio::time::timeout(timeout, async {
+            let mut db = self.get_connection().await?;
+            let start = Instant::now();
+            ping(&mut db).await?;
+            let latency = start.elapsed();
+            let schema_version = schema_version(&mut db).await?;
+            drop(db);
+            Ok(HealthReport {
+                pool: self.pool_status(),
+                latency,
+                schema_version,
+            })
+        })
+        .await
+        .map_err(|_| DatabaseError::backend(TimeoutError::new("health", timeout)))?
+    }
+
+    /// Check that the tables, columns and indexes created by the migrations exist
+    ///
+    /// Fails with a [`SchemaMismatchError`](crate::SchemaMismatchError) listing everything
+    /// that is missing.
+    pub async fn verify_schema(&self) -> Result<(), DatabaseError> {
+        let mut db = self.get_connection().await?;
+        verify_schema(&mut db).await
+    }
+
+    /// Compare the live schema with the one created by the migrations
+    ///
+    /// Unlike [`verify_schema`](Self::verify_schema) this also reports columns and indexes
+    /// added to the crate's tables and columns whose type or nullability changed.
+    pub async fn validate_schema(&self) -> Result<SchemaReport, DatabaseError> {
+        let mut db = self.get_connection().await?;
+        schema_report(&mut db).await
+    }
+
+    /// Rewrite payloads still compressed with pglz in batches of `batch_size` rows, so they
+    /// pick up the lz4 compression set by the migrations
+    ///
+    /// Returns the number of rewritten rows. Each batch commits on its own, so the method can
+    /// be interrupted and run again. Fails if the payload column doesn't use lz4, e.g. before
+    /// Postgres 14.
+    pub async fn recompress_payloads(&self, batch_size: usize) -> Result<u64, DatabaseError> {
+        let mut db = self.get_connection().await?;
+        recompress_payloads(&mut db, batch_size).await
+    }
+
+    /// Create the monthly partitions of the partitioned layout from the current month up to
+    /// the one containing `until`
+    ///
+    /// Returns the names (`events_pYYYYMM`) of the created partitions; existing ones are kept.
+    /// Events outside all partitions are stored in `events_default`; creating a partition
+    /// fails while that table holds events of its month. Fails if the database doesn't use
+    /// the partitioned layout, see [`NostrPostgresBuilder::partitioned`].
+    pub async fn ensure_partitions(&self, until: Timestamp) -> Result<Vec<String>, DatabaseError> {
+        let mut db = self.get_connection().await?;
+        ensure_partitions(&mut db, until).await
+    }
+
+    /// Write the events matching `filter` to `writer` as NIP-01 JSON, one event per line
+    ///
+    /// Events are streamed oldest first, so memory use doesn't grow with the result, and the
+    /// writer is flushed every 1000 events. The default query limit doesn't apply; deleted and
+    /// expired events are left out. Returns the number of exported events.
+    ///
+    /// Failures of the writer are reported as an [`ExportWriteError`](crate::ExportWriteError),
+    /// distinct from database errors.
+    pub async fn export_jsonl<W>(&self, filter: Filter, mut writer: W) -> Result<u64, DatabaseError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.config.tag_indexing.check_filter(&filter)?;
+        let mut db = self.get_read_connection().await?;
+        export_jsonl(
+            &mut db,
+            filter,
+            &mut writer,
+            self.config.statement_tags,
+            &self.quarantine,
+        )
+        .await
+    }
+
+    /// Like [`export_jsonl`](Self::export_jsonl), compressing the output with gzip
+    #[cfg(feature = "gzip")]
+    pub async fn export_jsonl_gzip<W>(
+        &self,
+        filter: Filter,
+        writer: W,
+    ) -> Result<u64, DatabaseError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.config.tag_indexing.check_filter(&filter)?;
+        let mut db = self.get_read_connection().await?;
+        export_jsonl_gzip(
+            &mut db,
+            filter,
+            writer,
+            self.config.statement_tags,
+            &self.quarantine,
+        )
+        .await
+    }
+
+    /// Save many events at once, 1000 per transaction, skipping already stored ones
+    ///
+    /// Like [`save_event`](NostrDatabase::save_event), signatures are not verified.
+    pub async fn save_events<I>(&self, events: I) -> Result<ImportReport, DatabaseError>
+    where
+        I: IntoIterator<Item = Event>,
+    {
+        let start = Instant::now();
+        let mut report = ImportReport::default();
+        let mut batch = Vec::new();
+        for event in events {
+            if !self.rate_limiter.try_acquire(&event.pubkey) {
+                debug!("Rejected event {}: rate limited", event.id);
+                report.record_outcome(SaveOutcome::RateLimited);
+                continue;
+            }
+            // the quota is checked per event, in its own transaction
+            if let Some(quota) = &self.config.quota
+                && quota.limits(&event.pubkey)
+            {
+                let outcome = self
+                    .retrying("save_events", || async {
+                        self.save(self.event_data(&event)?).await
+                    })
+                    .await?;
+                report.record_outcome(outcome);
+                continue;
+            }
+            batch.push(self.event_data(&event)?);
+            if batch.len() >= 1000 {
+                let batch = std::mem::take(&mut batch);
+                report.record_batch(
+                    self.retrying("save_events", || self.save_batch(batch.clone()))
+                        .await?,
+                );
+            }
+        }
+        report.record_batch(
+            self.retrying("save_events", || self.save_batch(batch.clone()))
+                .await?,
+        );
+        report.elapsed = start.elapsed();
+        Ok(report)
+    }
+
+    /// Save the NIP-01 JSON events read from `reader`, one event per line
+    ///
+    /// Events are saved in batches of [`ImportOptions::batch_size`] per transaction; already
+    /// stored events are counted as duplicates. Blank lines are skipped, malformed lines and
+    /// invalid events are counted in the [`ImportReport`] instead of aborting the import.
+    /// Fails on read or database errors; batches saved until then stay saved.
+    pub async fn import_jsonl<R>(
+        &self,
+        reader: R,
+        opts: ImportOptions,
+    ) -> Result<ImportReport, DatabaseError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        import_jsonl(self, reader, opts).await
+    }
+
+    /// The ids and creation times of the events matching `filter`, oldest first and by id
+    /// within the same second, as needed for NIP-77 negentropy reconciliation
+    ///
+    /// Only the two columns are read, so no payload is decoded. The default query limit
+    /// doesn't apply; a limit set on the filter keeps the newest matches. Deleted events are
+    /// left out, as are expired ones as long as the `expiration` tag is indexed.
+    pub async fn negentropy_items(
+        &self,
+        filter: Filter,
+    ) -> Result<Vec<(EventId, Timestamp)>, DatabaseError> {
+        self.config.tag_indexing.check_filter(&filter)?;
+        let query = tagged(
+            build_filter_query_folded(filter, &self.config.case_insensitive_tags)
+                .select((events::id, events::created_at))
+                .filter(not_expired(Timestamp::now().as_u64() as i64))
+                .then_order_by(events::id.desc()),
+            Operation::NegentropyItems,
+            self.config.statement_tags,
+        );
+        let mut db = self.get_read_connection().await?;
+        let rows: Vec<(Vec<u8>, i64)> = with_statement_timeout(
+            &mut db,
+            self.config.query_timeout,
+            "negentropy_items",
+            |c| query.load(c).scope_boxed(),
+        )
+        .await?;
+        // newest first in SQL, so a limit keeps the newest matches
+        rows.into_iter()
+            .rev()
+            .map(|(id, created_at)| {
+                let id =
+                    EventId::from_slice(&id).map_err(|e| PostgresDbError::Decode(Box::new(e)))?;
+                let created_at = u64::try_from(created_at).map_err(DatabaseError::backend)?;
+                Ok((id, Timestamp::from(created_at)))
+            })
+            .collect()
+    }
+
+    /// The `per_kind` newest events of each of `kinds`, keyed by kind, in one query
+    ///
+    /// Newest first within a kind, by id within the same second. Deleted events are left out,
+    /// as are expired ones as long as the `expiration` tag is indexed; kinds without events are
+    /// absent from the map. Only the selected payloads are read and decoded.
+    pub async fn latest_per_kind(
+        &self,
+        kinds: &[Kind],
+        per_kind: usize,
+    ) -> Result<HashMap<Kind, Vec<Event>>, DatabaseError> {
+        if kinds.is_empty() || per_kind == 0 {
+            return Ok(HashMap::new());
+        }
+        let kinds: Vec<i64> = kinds.iter().map(|kind| kind.as_u16() as i64).collect();
+        let query = tagged(
+            diesel::sql_query(format!(
+                "SELECT id, payload FROM (SELECT id, payload, created_at, kind, \
+                 row_number() OVER (PARTITION BY kind ORDER BY created_at DESC, id) AS position \
+                 FROM events WHERE kind = ANY($1) AND NOT deleted \
+                 AND {NOT_EXPIRED_BEFORE}$3{NOT_EXPIRED_END}) latest \
+                 WHERE position <= $2 ORDER BY kind, created_at DESC, id"
+            ))
+            .bind::<Array<BigInt>, _>(kinds)
+            .bind::<BigInt, _>(per_kind as i64)
+            .bind::<BigInt, _>(Timestamp::now().as_u64() as i64),
+            Operation::Query,
+            self.config.statement_tags,
+        );
+        let mut db = self.get_read_connection().await?;
+        let rows: Vec<StoredEventDb> =
+            with_statement_timeout(&mut db, self.config.query_timeout, "latest_per_kind", |c| {
+                query.load(c).scope_boxed()
+            })
+            .await?;
+        let mut latest: HashMap<Kind, Vec<Event>> = HashMap::new();
+        for row in rows {
+            if let Ok(event) = self.decode_stored(&row.id, &row.payload) {
+                latest.entry(event.kind).or_default().push(event);
+            }
+        }
+        Ok(latest)
+    }
+
+    /// Count the events matching `filter` together with the NIP-45 HyperLogLog registers of
+    /// their authors
+    ///
+    /// The registers are computed from the pubkey bytes at the offset NIP-45 derives from the
+    /// filter's first tag value, so the filter has to contain a tag with a hex id or pubkey,
+    /// e.g. `{"#p": [<pubkey>], "kinds": [3]}`; other filters are
+    /// [`NotSupported`](DatabaseError::NotSupported). Pubkeys are streamed, so memory use
+    /// doesn't grow with the number of matches.
+    pub async fn count_hll(
+        &self,
+        filter: Filter,
+    ) -> Result<(usize, [u8; HLL_REGISTERS]), DatabaseError> {
+        self.config.tag_indexing.check_filter(&filter)?;
+        let offset = hll_offset(&filter).ok_or(DatabaseError::NotSupported)?;
+        let query = tagged(
+            build_filter_query_folded(filter, &self.config.case_insensitive_tags).select((
+                events::id,
+                events::pubkey,
+                events::created_at,
+            )),
+            Operation::CountHll,
+            self.config.statement_tags,
+        );
+        let mut db = self.get_read_connection().await?;
+        with_statement_timeout(&mut db, self.config.query_timeout, "count_hll", |c| {
+            async move {
+                let mut rows = query.load_stream::<(Vec<u8>, Vec<u8>, i64)>(c).await?;
+                let mut count = 0;
+                let mut registers = [0; HLL_REGISTERS];
+                while let Some((_, pubkey, _)) = rows.try_next().await? {
+                    count += 1;
+                    hll_add(&mut registers, &pubkey, offset);
+                }
+                Ok((count, registers))
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+
+    /// Write all stored events in the format of `strfry export`, optionally restricted to a
+    /// time range
+    ///
+    /// Same as [`export_jsonl`](Self::export_jsonl): one compact event per line, oldest first,
+    /// without deleted and expired events, which strfry purges as well.
+    pub async fn export_strfry<W>(
+        &self,
+        since: Option<Timestamp>,
+        until: Option<Timestamp>,
+        writer: W,
+    ) -> Result<u64, DatabaseError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut filter = Filter::new();
+        filter.since = since;
+        filter.until = until;
+        self.export_jsonl(filter, writer).await
+    }
+
+    /// Save events from the output of `strfry export`
+    ///
+    /// Like `strfry import`, signatures are verified and blank lines as well as surrounding
+    /// whitespace are ignored. See [`import_jsonl`](Self::import_jsonl) for the report.
+    pub async fn import_strfry<R>(&self, reader: R) -> Result<ImportReport, DatabaseError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.import_jsonl(reader, ImportOptions::default()).await
+    }
+
+    /// Save the events the relay at `url` sends for `filters`, one subscription per filter
+    ///
+    /// Finishes once the relay sent all stored events, or in
+    /// [`follow`](RelayImportOptions::follow) mode once the duration passed. A lost
+    /// connection is reopened according to the options' reconnect policy and the
+    /// subscriptions are requested again; events received twice count as duplicates.
+    #[cfg(feature = "relay")]
+    pub async fn import_from_relay(
+        &self,
+        url: &str,
+        filters: Vec<Filter>,
+        opts: RelayImportOptions,
+    ) -> Result<ImportReport, DatabaseError> {
+        import_from_relay(self, url, filters, opts).await
+    }
+
+    /// Save a stream of events with `COPY`, which is several times faster than inserting
+    /// them for large initial loads
+    ///
+    /// The events are staged in a temporary table on a dedicated connection and merged in a
+    /// single transaction, skipping already stored events, so an error or dropping the future
+    /// midway leaves the tables untouched. Only available on instances created with a
+    /// connection string, not from a bare pool.
+    pub async fn bulk_load<S>(&self, events: S) -> Result<ImportReport, DatabaseError>
+    where
+        S: Stream<Item = Event>,
+    {
+        let _guard = self.lifecycle.enter()?;
+        let Some(connection_string) = &self.connection_string else {
+            return Err(DatabaseError::backend(ConfigError::new(
+                "bulk_load needs an instance created with a connection string",
+            )));
+        };
+        let rows = events.map(|event| self.event_data(&event));
+        let report = bulk_load(connection_string, self.config.schema.as_deref(), rows).await?;
+        if self.config.analyze_after_bulk_load && report.imported > 0 {
+            let tasks = MaintenanceTasks::default()
+                .analyze(true)
+                .tables([MaintenanceTable::Events, MaintenanceTable::EventTags]);
+            // the events are stored, so a failed ANALYZE is left to autovacuum
+            if let Err(e) = self.maintenance(tasks).await {
+                warn!("Analyzing the tables after the bulk load failed: {e}");
+            }
+        }
+        Ok(report)
+    }
+
+    /// Run `VACUUM` and `ANALYZE` on the crate's tables, e.g. after large imports or purges
+    ///
+    /// The statements can't run in a transaction, so they run on a dedicated connection
+    /// outside the pool. Only available on instances created with a connection string, not
+    /// from a bare pool.
+    pub async fn maintenance(
+        &self,
+        tasks: MaintenanceTasks,
+    ) -> Result<MaintenanceReport, DatabaseError> {
+        let _guard = self.lifecycle.enter()?;
+        let Some(connection_string) = &self.connection_string else {
+            return Err(DatabaseError::backend(ConfigError::new(
+                "maintenance needs an instance created with a connection string",
+            )));
+        };
+        let connection_string =
+            with_application_name(connection_string, &self.config.application_name);
+        let mut db = AsyncPgConnection::establish(&connection_string)
+            .await
+            .map_err(DatabaseError::backend)?;
+        if let Some(schema) = &self.config.schema {
+            db.batch_execute(&format!("SET search_path TO {}", quote_identifier(schema)?))
+                .await
+                .map_err(DatabaseError::backend)?;
+        }
+        maintenance(&mut db, &tasks).await
+    }
+
+    /// Bring this instance and `other` in sync for the events matching `filter`, e.g. a
+    /// primary and a disaster recovery database
+    ///
+    /// The ids of both sides are compared in ascending time windows, so neither side loads
+    /// all ids at once. Events missing on one side are copied from the other one, and an event
+    /// deleted on one side is deleted on the other one as well: deletion is final, so it
+    /// always takes precedence. Both sides are read on their primaries.
+    pub async fn reconcile(
+        &self,
+        other: &NostrPostgres,
+        filter: Filter,
+    ) -> Result<ReconcileReport, DatabaseError> {
+        reconcile(self, other, filter).await
+    }
+
+    /// Copy the events matching `filter` from another database, e.g. when migrating off
+    /// another backend
+    ///
+    /// The source is queried in ascending time windows sized to stay below
+    /// [`CopyOptions::page_size`] and each window is saved in one transaction; already stored
+    /// events are counted as duplicates. Failures are reported as a
+    /// [`CopyError`](crate::CopyError) carrying the checkpoint to resume from.
+    pub async fn copy_from<D>(
+        &self,
+        source: &D,
+        filter: Filter,
+        opts: CopyOptions,
+    ) -> Result<CopyReport, DatabaseError>
+    where
+        D: NostrDatabase + ?Sized,
+    {
+        copy_from(self, source, filter, opts).await
+    }
+
+    /// Delete all events of `pubkey` and by default block the author, returning the counts
+    ///
+    /// Unlike [`delete`](NostrDatabase::delete) there is no cap: the events are deleted in
+    /// batches of [`BanOptions::batch_size`], each in its own statement, so an interrupted ban
+    /// is resumed by calling it again. The author is blocked before the deletion starts;
+    /// saves of events of a blocked author are then skipped by every instance, and
+    /// [`save_event`](NostrDatabase::save_event) reports them as rejected. Archived events are
+    /// kept.
+    pub async fn ban_author(
+        &self,
+        pubkey: &PublicKey,
+        opts: BanOptions,
+    ) -> Result<BanReport, DatabaseError> {
+        let mut db = self.get_connection().await?;
+        ban_author(&mut db, pubkey, opts, self.config.statement_tags).await
+    }
+
+    /// Remove `pubkey` from the blocklist, returning false if the author wasn't blocked
+    ///
+    /// Events deleted by [`ban_author`](Self::ban_author) stay deleted.
+    pub async fn unblock_author(&self, pubkey: &PublicKey) -> Result<bool, DatabaseError> {
+        let mut db = self.get_connection().await?;
+        unblock(&mut db, pubkey, self.config.statement_tags).await
+    }
+
+    /// Restore the deleted events matching `filter`, returning how many were restored
+    ///
+    /// Clears the deleted flag, e.g. after a moderation delete matched too much, and rebuilds
+    /// the tag rows from the payloads in case they were
+    /// [dropped](crate::NostrPostgresBuilder::drop_tags_on_delete); tag filters don't match
+    /// events whose tag rows were dropped. Events whose author asked for the deletion, with a
+    /// stored deletion request (NIP-09) or request to vanish (NIP-62), stay deleted unless
+    /// [forced](UndeleteOptions::force); recognizing deletion requests needs the `e` and `a`
+    /// tags indexed. The limit of `filter` counts the deleted events it matches. Archived
+    /// events are not restored.
+    pub async fn undelete(
+        &self,
+        filter: Filter,
+        opts: UndeleteOptions,
+    ) -> Result<u64, DatabaseError> {
+        self.config.tag_indexing.check_filter(&filter)?;
+        let tag = self.config.statement_tags;
+        let ids: Vec<Vec<u8>> = self
+            .retrying("undelete", || async {
+                let query = tagged(
+                    build_filter_query_with_deleted(filter.clone())
+                        .filter(events::deleted.eq(true))
+                        .select(events::id),
+                    Operation::Delete,
+                    tag,
+                );
+                let mut db = self.get_connection().await?;
+                query.load(&mut db).await.map_err(DatabaseError::backend)
+            })
+            .await?;
+        self.undelete_ids(ids, opts).await
+    }
+
+    /// Restore the deleted events with the given ids, returning how many were restored
+    ///
+    /// Like [`undelete`](Self::undelete); ids that aren't stored or deleted are skipped.
+    pub async fn undelete_by_ids(
+        &self,
+        ids: &[EventId],
+        opts: UndeleteOptions,
+    ) -> Result<u64, DatabaseError> {
+        let ids = ids.iter().map(|id| id.as_bytes().to_vec()).collect();
+        self.undelete_ids(ids, opts).await
+    }
+
+    /// restores the events and their tag rows in batches, replacing the versions of restored
+    /// replaceable events if the history is on
+    async fn undelete_ids(
+        &self,
+        ids: Vec<Vec<u8>>,
+        opts: UndeleteOptions,
+    ) -> Result<u64, DatabaseError> {
+        let tag = self.config.statement_tags;
+        let history = self.config.replaceable_history;
+        let mut total = 0;
+        for chunk in ids.chunks(UNDELETE_BATCH) {
+            let mut db = self.get_connection().await?;
+            let restored = db
+                .transaction(|c| {
+                    async move {
+                        let restored = undelete(c, chunk, opts, tag).await?;
+                        let data: Vec<EventDataDb> = restored
+                            .iter()
+                            .filter_map(|row| {
+                                let event = Event::decode(&row.payload).ok()?;
+                                self.event_data(&event).ok()
+                            })
+                            .collect();
+                        // the tags may have been dropped on delete
+                        let tags: Vec<_> = data.iter().flat_map(|d| d.tags.clone()).collect();
+                        for chunk in tags.chunks(10000) {
+                            tagged(
+                                diesel::insert_into(event_tags::table)
+                                    .values(chunk)
+                                    .on_conflict_do_nothing(),
+                                Operation::Save,
+                                tag,
+                            )
+                            .execute(c)
+                            .await?;
+                        }
+                        if let Some(history) = history {
+                            // a restored older version must not show next to the current one
+                            for data in &data {
+                                if let Some(identifier) = identifier(data) {
+                                    replace(c, data, &identifier, &history, tag).await?;
+                                }
+                            }
+                        }
+                        Ok::<_, DieselError>(restored.len() as u64)
+                    }
+                    .scope_boxed()
+                })
+                .await
+                .map_err(DatabaseError::backend)?;
+            total += restored;
+        }
+        debug!("Restored {total} deleted events");
+        Ok(total)
+    }
+
+    /// Move the events matching `filter`, deleted ones included, together with their tags
+    /// from the hot tables to the archive, returning the number of moved events
+    ///
+    /// Archived events are left out of [`query`](NostrDatabase::query) and
+    /// [`event_by_id`](NostrDatabase::event_by_id), see
+    /// [`query_including_archived`](Self::query_including_archived), but
+    /// [`check_id`](NostrDatabase::check_id) still reports them, so relays checking before
+    /// saving don't store them again.
+    /// Without a limit on the filter all matches are moved, in batches of 1000 per statement,
+    /// e.g. `Filter::new().until(cutoff)` archives everything older than `cutoff`.
+    pub async fn archive(&self, filter: Filter) -> Result<u64, DatabaseError> {
+        self.config.tag_indexing.check_filter(&filter)?;
+        let mut db = self.get_connection().await?;
+        archive(&mut db, filter, self.config.statement_tags).await
+    }
+
+    /// Query stored events including the archived ones, see [`archive`](Self::archive)
+    pub async fn query_including_archived(&self, filter: Filter) -> Result<Events, DatabaseError> {
+        let filter = with_limit(filter, 10000);
+        let mut db = self.get_read_connection().await?;
+        let mut events = self
+            .query_events_with_timeout(filter.clone(), &mut db, self.config.query_timeout)
+            .await?;
+        let tag = self.config.statement_tags;
+        let archived: Vec<EventDb> =
+            with_statement_timeout(&mut db, self.config.query_timeout, "query", |c| {
+                query_archived(c, filter, tag).scope_boxed()
+            })
+            .await?;
+        for item in archived {
+            if let Ok(event) = self.decode_stored(&item.id, &item.payload) {
+                events.insert(event);
+            }
+        }
+        Ok(events)
+    }
+
+    /// Get an event by id, looking into the archive if it isn't in the hot tables
+    pub async fn event_by_id_including_archived(
+        &self,
+        event_id: &EventId,
+    ) -> Result<Option<Event>, DatabaseError> {
+        let event = match self.event_or_archived_by_id(event_id).await? {
+            Some(e) if !e.deleted => Some(self.decode_stored(&e.id, &e.payload)?),
+            _ => None,
+        };
+        Ok(event)
+    }
+
+    /// Number and size of the stored events by kind, most events first
+    ///
+    /// Computed in a single `GROUP BY` over the events matching `filter`, or all events
+    /// without one; deleted events are counted separately and the limit of the filter is
+    /// ignored.
+    pub async fn stats_by_kind(
+        &self,
+        filter: Option<Filter>,
+    ) -> Result<Vec<KindStats>, DatabaseError> {
+        let filter = filter.unwrap_or_default();
+        self.config.tag_indexing.check_filter(&filter)?;
+        let mut db = self.get_read_connection().await?;
+        kind_stats(&mut db, filter, self.config.statement_tags).await
+    }
+
+    /// Number of stored events matching `filter` by kind, author or day, most events first
+    ///
+    /// One `GROUP BY` query over the matching events, deleted ones left out. The limit of the
+    /// filter, if any, caps the number of groups returned, e.g. the top 10 kinds.
+    pub async fn count_grouped(
+        &self,
+        filter: Filter,
+        group_by: GroupBy,
+    ) -> Result<Vec<(GroupKey, u64)>, DatabaseError> {
+        self.config.tag_indexing.check_filter(&filter)?;
+        let mut db = self.get_read_connection().await?;
+        count_grouped(&mut db, filter, group_by, self.config.statement_tags).await
+    }
+
+    /// The `limit` authors with the most events created since `since`, most events first
+    ///
+    /// Deleted events are left out; `kind` restricts the count to events of that kind, e.g.
+    /// the top posters of text notes. A grouped count of the authors, see
+    /// [`count_grouped`](Self::count_grouped).
+    pub async fn top_authors(
+        &self,
+        since: Option<Timestamp>,
+        limit: usize,
+        kind: Option<Kind>,
+    ) -> Result<Vec<(PublicKey, u64)>, DatabaseError> {
+        let mut filter = Filter::new().limit(limit);
+        filter.since = since;
+        if let Some(kind) = kind {
+            filter = filter.kind(kind);
+        }
+        let groups = self.count_grouped(filter, GroupBy::Author).await?;
+        Ok(groups
+            .into_iter()
+            .filter_map(|(key, count)| match key {
+                GroupKey::Author(pubkey) => Some((pubkey, count)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Number of stored events matching `filter` per hour, day or week of their creation
+    /// time, oldest first
+    ///
+    /// Buckets without events are left out. Deleted events are left out, as are expired ones
+    /// as long as the `expiration` tag is indexed; the limit of the filter is ignored. The
+    /// creation time is set by the authors, see
+    /// [`histogram_by_seen_at`](Self::histogram_by_seen_at) for one they can't skew.
+    pub async fn histogram(
+        &self,
+        filter: Filter,
+        bucket: HistogramBucket,
+    ) -> Result<Vec<(Timestamp, u64)>, DatabaseError> {
+        self.histogram_of(filter, bucket, "created_at").await
+    }
+
+    /// Like [`histogram`](Self::histogram), but by the time the events were first stored
+    ///
+    /// Events stored before the `seen_at` column was added count as seen at the time of the
+    /// migration.
+    pub async fn histogram_by_seen_at(
+        &self,
+        filter: Filter,
+        bucket: HistogramBucket,
+    ) -> Result<Vec<(Timestamp, u64)>, DatabaseError> {
+        self.histogram_of(filter, bucket, "seen_at").await
+    }
+
+    async fn histogram_of(
+        &self,
+        filter: Filter,
+        bucket: HistogramBucket,
+        column: &str,
+    ) -> Result<Vec<(Timestamp, u64)>, DatabaseError> {
+        self.config.tag_indexing.check_filter(&filter)?;
+        let mut db = self.get_read_connection().await?;
+        histogram(
+            &mut db,
+            filter,
+            bucket,
+            column,
+            Timestamp::now(),
+            self.config.statement_tags,
+        )
+        .await
+    }
+
+    /// Number of distinct authors of the stored events matching `filter`, e.g. of the text
+    /// notes of the last week
+    ///
+    /// Counted with `count(DISTINCT pubkey)` in the database. Deleted events are left out, as
+    /// are expired ones as long as the `expiration` tag is indexed; the limit of the filter is
+    /// ignored.
+    pub async fn count_authors(&self, filter: Filter) -> Result<usize, DatabaseError> {
+        self.config.tag_indexing.check_filter(&filter)?;
+        let tag = self.config.statement_tags;
+        let count = self
+            .retrying("count_authors", || async {
+                let mut db = self.get_read_connection().await?;
+                count_authors(&mut db, filter.clone(), Timestamp::now(), tag).await
+            })
+            .await?;
+        Ok(count as usize)
+    }
+
+    /// The indexed tag names with the number of events using them, most used first
+    ///
+    /// Deleted events are left out. Reads the whole tag table unless `since` restricts it to
+    /// the events created since then, so bound it on large stores.
+    pub async fn tag_names(
+        &self,
+        since: Option<Timestamp>,
+    ) -> Result<Vec<(String, u64)>, DatabaseError> {
+        let mut db = self.get_read_connection().await?;
+        tag_names(&mut db, since, self.config.statement_tags).await
+    }
+
+    /// The `limit` most used values of the indexed tag `name` with the number of events using
+    /// them, most used first
+    ///
+    /// Deleted events are left out; `since` restricts the count to the events created since
+    /// then, see [`tag_names`](Self::tag_names).
+    pub async fn tag_values(
+        &self,
+        name: &str,
+        limit: usize,
+        since: Option<Timestamp>,
+    ) -> Result<Vec<(String, u64)>, DatabaseError> {
+        let mut db = self.get_read_connection().await?;
+        tag_values(&mut db, name, limit, since, self.config.statement_tags).await
+    }
+
+    /// Number and size of the stored events by author, most events first
+    ///
+    /// Returns the page of `limit` authors after skipping `offset`. Groups the whole events
+    /// table, so prefer [`author_stats`](Self::author_stats) for single authors.
+    pub async fn stats_by_author(
+        &self,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<AuthorStats>, DatabaseError> {
+        let mut db = self.get_read_connection().await?;
+        author_stats_page(&mut db, limit, offset, self.config.statement_tags).await
+    }
+
+    /// Number and size of the stored events of `pubkey`, `None` if none are stored
+    ///
+    /// An author whose events are all deleted has a count of 0.
+    pub async fn author_stats(
+        &self,
+        pubkey: &PublicKey,
+    ) -> Result<Option<AuthorStats>, DatabaseError> {
+        let mut db = self.get_read_connection().await?;
+        author_stats(&mut db, pubkey, self.config.statement_tags).await
+    }
+
+    /// Creation times of the oldest and newest stored event matching `filter`, `None` if
+    /// there is none
+    ///
+    /// Deleted events are left out; the limit of the filter is ignored.
+    pub async fn time_range(
+        &self,
+        filter: Option<Filter>,
+    ) -> Result<Option<(Timestamp, Timestamp)>, DatabaseError> {
+        let filter = filter.unwrap_or_default();
+        self.config.tag_indexing.check_filter(&filter)?;
+        let query = tagged(
+            events::table
+                .filter(filter_conditions(filter, false))
+                .select((
+                    diesel::dsl::min(events::created_at),
+                    diesel::dsl::max(events::created_at),
+                )),
+            Operation::Stats,
+            self.config.statement_tags,
+        );
+        let mut db = self.get_read_connection().await?;
+        let range: (Option<i64>, Option<i64>) =
+            with_statement_timeout(&mut db, self.config.query_timeout, "time_range", |c| {
+                query.get_result(c).scope_boxed()
+            })
+            .await?;
+        Ok(match range {
+            (Some(oldest), Some(newest)) => Some((
+                Timestamp::from(oldest as u64),
+                Timestamp::from(newest as u64),
+            )),
+            _ => None,
+        })
+    }
+
+    /// Check that the stored payloads decode and match their columns
+    ///
+    /// Reads all rows, deleted ones included, in batches ordered by id, so a large table can
+    /// be checked in several runs with [`IntegrityOptions::limit`] and
+    /// [`IntegrityOptions::after`]. Queries skip rows that don't decode, so this is the way to
+    /// find them.
+    pub async fn verify_integrity(
+        &self,
+        opts: IntegrityOptions,
+    ) -> Result<IntegrityReport, DatabaseError> {
+        let mut db = self.get_read_connection().await?;
+        verify_integrity(&mut db, opts).await
+    }
+
+    /// Rebuild the tag rows of the stored events matching `filter`, or of all events, from
+    /// their payloads, returning the number of reindexed events
+    ///
+    /// Applies the current [`TagIndexing`](crate::TagIndexing) and
+    /// [`TagValueLimit`](crate::TagValueLimit), e.g. after changing them, indexes the tags
+    /// without a value that earlier versions skipped and fills the elements of tags stored
+    /// before [`TagMatch::position`] could match them. Deleted events get no tag rows if
+    /// [`drop_tags_on_delete`](crate::NostrPostgresBuilder::drop_tags_on_delete) is on. The
+    /// events are
+    /// processed in the order of their id, 1000 per transaction, so it can run alongside
+    /// normal writes; the progress is logged with the last processed id, see
+    /// [`reindex_tags_after`](Self::reindex_tags_after). Rows that don't decode keep their
+    /// tags.
+    pub async fn reindex_tags(&self, filter: Option<Filter>) -> Result<u64, DatabaseError> {
+        let filter = filter.unwrap_or_default();
+        self.config.tag_indexing.check_filter(&filter)?;
+        reindex_tags(self, filter, None, self.config.drop_tags_on_delete).await
+    }
+
+    /// Resume [`reindex_tags`](Self::reindex_tags) after the event with id `after`
+    pub async fn reindex_tags_after(
+        &self,
+        filter: Option<Filter>,
+        after: EventId,
+    ) -> Result<u64, DatabaseError> {
+        let filter = filter.unwrap_or_default();
+        self.config.tag_indexing.check_filter(&filter)?;
+        reindex_tags(self, filter, Some(after), self.config.drop_tags_on_delete).await
+    }
+
+    /// Remove the tag rows of events that don't exist or are deleted, returning how many were
+    /// removed
+    ///
+    /// Such rows are left by hard deletes that bypass the foreign key, which partitioned
+    /// tables don't have, or belong to deleted events, whose tags
+    /// [`undelete`](Self::undelete) rebuilds from the payload. The tagged events are checked
+    /// in the order of their id, 1000 per statement, so no lock is held for long; the
+    /// progress is logged with the last checked id, see
+    /// [`cleanup_orphaned_tags_after`](Self::cleanup_orphaned_tags_after).
+    /// [`DiagnosticsReport::orphaned_tags`] estimates whether it's worth running.
+    pub async fn cleanup_orphaned_tags(&self) -> Result<u64, DatabaseError> {
+        cleanup_orphaned_tags(self, None, false).await
+    }
+
+    /// Resume [`cleanup_orphaned_tags`](Self::cleanup_orphaned_tags) after the event with id
+    /// `after`
+    pub async fn cleanup_orphaned_tags_after(&self, after: EventId) -> Result<u64, DatabaseError> {
+        cleanup_orphaned_tags(self, Some(after), false).await
+    }
+
+    /// Count the tag rows [`cleanup_orphaned_tags`](Self::cleanup_orphaned_tags) would remove,
+    /// without removing them
+    ///
+    /// Walks the whole tag table in the same batches; see
+    /// [`DiagnosticsReport::orphaned_tags`] for a cheap estimate.
+    pub async fn count_orphaned_tags(&self) -> Result<u64, DatabaseError> {
+        cleanup_orphaned_tags(self, None, true).await
+    }
+
+    /// Disk usage of the crate's tables in the configured schema
+    ///
+    /// Partitioned tables are summed over their partitions. Row counts are the planner's
+    /// estimates, so they are only as recent as the last `ANALYZE` or autovacuum run.
+    pub async fn storage_stats(&self) -> Result<StorageStats, DatabaseError> {
+        let mut db = self.get_read_connection().await?;
+        storage_stats(&mut db).await
+    }
+
+    /// Bloat and usage statistics of the crate's tables and indexes, e.g. to find unused
+    /// indexes
+    ///
+    /// Only reads the statistics views of the primary, which any role may do; parts that can't
+    /// be read are listed in [`DiagnosticsReport::unavailable`] instead of failing.
+    pub async fn diagnostics(&self) -> Result<DiagnosticsReport, DatabaseError> {
+        let mut db = self.get_connection().await?;
+        diagnostics(&mut db).await
+    }
+
+    /// The SQL [`query`](NostrDatabase::query) runs for `filter` and how Postgres plans it
+    ///
+    /// With `analyze` the query is executed to report actual row counts and timings, inside a
+    /// transaction that is always rolled back. The configured query timeout applies.
+    pub async fn explain(
+        &self,
+        filter: Filter,
+        analyze: bool,
+    ) -> Result<ExplainOutput, DatabaseError> {
+        self.config.tag_indexing.check_filter(&filter)?;
+        let mut db = self.get_read_connection().await?;
+        explain(&mut db, filter, analyze, self.config.query_timeout).await
+    }
+
+    /// Get the stored events with the given ids from the primary, in the order of `ids`
+    ///
+    /// Ids of events that are not stored, or deleted, are skipped.
+    pub async fn events_by_ids(&self, ids: &[EventId]) -> Result<Vec<Event>, DatabaseError> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let filter = Filter::new().ids(ids.iter().copied()).limit(ids.len());
+        let mut events = self.query_on_primary(filter).await?.to_vec();
+        events.sort_by_key(|e| ids.iter().position(|id| *id == e.id));
+        Ok(events)
+    }
+
+    /// The current version of the replaceable or addressable event at `coordinate`
+    ///
+    /// The newest event of the author and kind, with the `d` tag of the coordinate for
+    /// addressable kinds, by the lowest id within the same second as NIP-01 has it, so older
+    /// versions still stored don't matter. Deleted events are left out; `None` if nothing was
+    /// published at the coordinate. Addressable kinds need the `d` tag indexed.
+    pub async fn event_by_coordinate(
+        &self,
+        coordinate: &Coordinate,
+    ) -> Result<Option<Event>, DatabaseError> {
+        let mut filter = Filter::new()
+            .author(coordinate.public_key)
+            .kind(coordinate.kind)
+            .limit(1);
+        if !coordinate.kind.is_replaceable() {
+            filter = filter.identifier(coordinate.identifier.clone());
+        }
+        self.config.tag_indexing.check_filter(&filter)?;
+        let tag = self.config.statement_tags;
+        let rows: Vec<EventDb> = self
+            .retrying("event_by_coordinate", || async {
+                let query = tagged(
+                    build_filter_query(filter.clone())
+                        .select(EventDb::as_select())
+                        .then_order_by(events::id),
+                    Operation::EventById,
+                    tag,
+                );
+                let mut db = self.get_read_connection().await?;
+                query.load(&mut db).await.map_err(DatabaseError::backend)
+            })
+            .await?;
+        match rows.into_iter().next() {
+            Some(row) => Ok(Some(self.decode_stored(&row.id, &row.payload)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The recorded versions of the replaceable or addressable event at `coordinate`, newest
+    /// first, the current one included
+    ///
+    /// Empty unless the [replaceable history](crate::NostrPostgresBuilder::replaceable_history)
+    /// is on; versions saved before it was turned on are recorded once the coordinate gets a
+    /// new one.
+    pub async fn history_by_coordinate(
+        &self,
+        coordinate: &Coordinate,
+    ) -> Result<Vec<Event>, DatabaseError> {
+        let tag = self.config.statement_tags;
+        let rows = self
+            .retrying("history_by_coordinate", || async {
+                let mut db = self.get_read_connection().await?;
+                versions(&mut db, coordinate, tag)
+                    .await
+                    .map_err(DatabaseError::backend)
+            })
+            .await?;
+        Ok(rows
+            .iter()
+            .filter_map(|(id, payload)| self.decode_stored(id, payload).ok())
+            .collect())
+    }
+
+    /// Remove the versions beyond the retention of the replaceable history from all
+    /// coordinates, returning how many were removed
+    ///
+    /// Saving a version trims its own coordinate; run this periodically for retention by
+    /// age to also reach coordinates without new versions. Does nothing without the history.
+    pub async fn trim_history(&self) -> Result<u64, DatabaseError> {
+        let Some(history) = self.config.replaceable_history else {
+            return Ok(0);
+        };
+        let mut db = self.get_connection().await?;
+        trim(&mut db, &history, self.config.statement_tags)
+            .await
+            .map_err(DatabaseError::backend)
+    }
+
+    /// The live events and payload bytes `author` stores, as counted against the
+    /// [quota](crate::NostrPostgresBuilder::quota)
+    ///
+    /// Read from a counter kept current by every write, so it's cheap, and available whether
+    /// or not a quota is configured.
+    pub async fn quota_usage(&self, author: &PublicKey) -> Result<QuotaUsage, DatabaseError> {
+        let mut db = self.get_read_connection().await?;
+        usage(&mut db, author, self.config.statement_tags)
+            .await
+            .map_err(DatabaseError::backend)
+    }
+
+    /// Apply the [retention policy](crate::NostrPostgresBuilder::retention), removing the
+    /// events older than their rule allows
+    ///
+    /// Each rule works in batches of 1000 events, so a large backlog doesn't hold long
+    /// transactions. Kinds without a rule are never touched. Returns an empty report without
+    /// a policy.
+    pub async fn apply_retention(&self) -> Result<RetentionReport, DatabaseError> {
+        let Some(policy) = &self.config.retention else {
+            return Ok(RetentionReport::default());
+        };
+        let _guard = self.lifecycle.enter()?;
+        let mut db = self.get_connection().await?;
+        apply_retention(
+            &mut db,
+            policy,
+            self.config.drop_tags_on_delete,
+            self.config.statement_tags,
+        )
+        .await
+    }
+
+    /// Apply the retention policy every `every` in a background task, until
+    /// [`close`](Self::close)
+    ///
+    /// The first run is after `every`. Failed runs are logged and retried at the next one.
+    pub fn spawn_retention_worker(&self, every: Duration) -> tokio::task::JoinHandle<()> {
+        let db = self.clone();
+        let shutdown = self.lifecycle.shutdown();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = tokio::time::sleep(every) => {}
+                }
+                if let Err(e) = db.apply_retention().await {
+                    warn!("Retention failed: {e}");
+                }
+            }
+            debug!("Stopped the retention worker");
+        })
+    }
+
+    /// The newest event of `kind` of each of `authors` in one query, e.g. their profiles
+    ///
+    /// Picked with `DISTINCT ON (pubkey)`, by the lowest id within the same second, so older
+    /// versions still stored don't matter. The authors are bound as one array parameter.
+    /// Deleted events are left out; authors without an event are absent from the map.
+    pub async fn latest_by_authors(
+        &self,
+        kind: Kind,
+        authors: &[PublicKey],
+    ) -> Result<HashMap<PublicKey, Event>, DatabaseError> {
+        if authors.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let authors: Vec<Vec<u8>> = authors.iter().map(|a| a.as_bytes().to_vec()).collect();
+        let tag = self.config.statement_tags;
+        let rows: Vec<EventDb> = self
+            .retrying("latest_by_authors", || async {
+                let query = tagged(
+                    events::table
+                        .select(EventDb::as_select())
+                        .filter(events::pubkey.eq_any(authors.clone()))
+                        .filter(events::kind.eq(kind.as_u16() as i64))
+                        .filter(events::deleted.eq(false))
+                        .distinct_on(events::pubkey)
+                        .order_by((events::pubkey, events::created_at.desc(), events::id)),
+                    Operation::Query,
+                    tag,
+                );
+                let mut db = self.get_read_connection().await?;
+                query.load(&mut db).await.map_err(DatabaseError::backend)
+            })
+            .await?;
+        let mut latest = HashMap::with_capacity(rows.len());
+        for row in rows {
+            if let Ok(event) = self.decode_stored(&row.id, &row.payload) {
+                latest.insert(event.pubkey, event);
+            }
+        }
+        Ok(latest)
+    }
+
+    /// Whether the event is stored, deleted and archived events included
+    ///
+    /// Like [`check_id`](NostrDatabase::check_id) returning anything but
+    /// [`NotExistent`](DatabaseEventStatus::NotExistent), without reading the payload: a saved
+    /// event for which this returns `true` is rejected as a duplicate or was archived. See
+    /// [`has_active_event`](Self::has_active_event) to leave deleted events out.
+    pub async fn has_event(&self, id: &EventId) -> Result<bool, DatabaseError> {
+        let id = id.as_bytes().to_vec();
+        let tag = self.config.statement_tags;
+        self.retrying("has_event", || async {
+            let query = diesel::select(
+                diesel::dsl::exists(events::table.filter(events::id.eq(id.clone()))).or(
+                    diesel::dsl::exists(
+                        events_archive::table.filter(events_archive::id.eq(id.clone())),
+                    ),
+                ),
+            );
+            let mut db = self.get_read_connection().await?;
+            tagged(query, Operation::EventById, tag)
+                .get_result(&mut db)
+                .await
+                .map_err(DatabaseError::backend)
+        })
+        .await
+    }
 
-use super::model::{EventDataDb, EventDb};
-use super::schema::postgres::{event_tags, events};
-use crate::query::{build_filter_query, event_by_id, with_limit};
+    /// Whether the event is stored and not deleted; archived events don't count
+    pub async fn has_active_event(&self, id: &EventId) -> Result<bool, DatabaseError> {
+        let id = id.as_bytes().to_vec();
+        let tag = self.config.statement_tags;
+        self.retrying("has_active_event", || async {
+            let query = diesel::select(diesel::dsl::exists(
+                events::table
+                    .filter(events::id.eq(id.clone()))
+                    .filter(events::deleted.eq(false)),
+            ));
+            let mut db = self.get_read_connection().await?;
+            tagged(query, Operation::EventById, tag)
+                .get_result(&mut db)
+                .await
+                .map_err(DatabaseError::backend)
+        })
+        .await
+    }
 
-/// Shorthand for a database connection pool type
-pub type PostgresConnectionPool = Pool<AsyncDieselConnectionManager<AsyncPgConnection>>;
-pub type PostgresConnection = Object<AsyncDieselConnectionManager<AsyncPgConnection>>;
+    /// The ids of `ids` that are stored, deleted and archived events included, in one query
+    ///
+    /// The batched [`has_event`](Self::has_event), e.g. to skip known events before saving a
+    /// batch.
+    pub async fn which_exist(&self, ids: &[EventId]) -> Result<HashSet<EventId>, DatabaseError> {
+        if ids.is_empty() {
+            return Ok(HashSet::new());
+        }
+        let ids: Vec<Vec<u8>> = ids.iter().map(|id| id.as_bytes().to_vec()).collect();
+        let tag = self.config.statement_tags;
+        let found: Vec<Vec<u8>> = self
+            .retrying("which_exist", || async {
+                let query = events::table
+                    .select(events::id)
+                    .filter(events::id.eq_any(ids.clone()))
+                    .union(
+                        events_archive::table
+                            .select(events_archive::id)
+                            .filter(events_archive::id.eq_any(ids.clone())),
+                    );
+                let mut db = self.get_read_connection().await?;
+                tagged(query, Operation::EventById, tag)
+                    .load(&mut db)
+                    .await
+                    .map_err(DatabaseError::backend)
+            })
+            .await?;
+        found
+            .iter()
+            .map(|id| {
+                EventId::from_slice(id).map_err(|e| PostgresDbError::Decode(Box::new(e)).into())
+            })
+            .collect()
+    }
 
-/// Inplements NostrDatabase trait for a Postgres database backend
-#[derive(Clone)]
-pub struct NostrPostgres {
-    pool: PostgresConnectionPool,
-}
+    /// The indexed tags of the event as (name, value) pairs, empty for unknown events
+    ///
+    /// Reads the tag table only, no payload is decoded, so it shows what got indexed under
+    /// the current [`TagIndexing`](crate::TagIndexing), e.g. to check a
+    /// [`reindex_tags`](Self::reindex_tags). Archived events have no indexed tags here.
+    pub async fn tags_by_event_id(
+        &self,
+        id: &EventId,
+    ) -> Result<Vec<(String, String)>, DatabaseError> {
+        let mut tags = self.tags_by_event_ids(std::slice::from_ref(id)).await?;
+        Ok(tags.remove(id).unwrap_or_default())
+    }
 
-impl NostrPostgres {
-    /// Create a new [`NostrPostgres`] instance
-    pub async fn new<C>(connection_string: C) -> Result<Self, DatabaseError>
+    /// The indexed tags of many events in one query, keyed by event id
+    ///
+    /// Events without indexed tags, unknown ones included, are left out of the map. See
+    /// [`tags_by_event_id`](Self::tags_by_event_id).
+    pub async fn tags_by_event_ids(
+        &self,
+        ids: &[EventId],
+    ) -> Result<HashMap<EventId, Vec<(String, String)>>, DatabaseError> {
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let ids: Vec<Vec<u8>> = ids.iter().map(|id| id.as_bytes().to_vec()).collect();
+        let tag = self.config.statement_tags;
+        let rows: Vec<(Vec<u8>, String, String)> = self
+            .retrying("tags_by_event_ids", || async {
+                let query = event_tags::table
+                    .select((event_tags::event_id, event_tags::tag, event_tags::tag_value))
+                    .filter(event_tags::event_id.eq_any(ids.clone()))
+                    .order_by((event_tags::event_id, event_tags::tag, event_tags::tag_value));
+                let mut db = self.get_read_connection().await?;
+                tagged(query, Operation::EventById, tag)
+                    .load(&mut db)
+                    .await
+                    .map_err(DatabaseError::backend)
+            })
+            .await?;
+        let mut tags: HashMap<EventId, Vec<(String, String)>> = HashMap::new();
+        for (id, name, value) in rows {
+            let id = EventId::from_slice(&id).map_err(|e| PostgresDbError::Decode(Box::new(e)))?;
+            tags.entry(id).or_default().push((name, value));
+        }
+        Ok(tags)
+    }
+
+    /// Subscribe to the events saved from now on, by any instance on the database
+    ///
+    /// Listens on the notification channel on a dedicated connection outside the pool and
+    /// fetches the notified events from the primary in small batches; the saving instances
+    /// need [`notifications`](NostrPostgresBuilder::notifications) enabled. Returns once the
+    /// connection listens, so every event saved afterwards is delivered, in the order of their
+    /// commits. If the connection fails it is reopened, and events saved meanwhile are missed.
+    /// A subscriber falling behind gets a [`LaggedError`](crate::LaggedError) for the skipped
+    /// events, see [`subscriptions`](NostrPostgresBuilder::subscriptions).
+    /// Only available on instances created with a connection string, not from a bare pool.
+    pub async fn subscribe(&self) -> Result<EventSubscription, DatabaseError> {
+        self.subscribe_filtered(Filter::new()).await
+    }
+
+    /// Subscribe to the events matching `filter` saved from now on, like a relay `REQ`
+    ///
+    /// Notifications of events not matching the ids, authors, kinds or time bounds of the
+    /// filter are dropped without fetching the event; tag and search conditions are checked
+    /// on the fetched events. The limit of the filter is ignored. See
+    /// [`subscribe`](Self::subscribe).
+    pub async fn subscribe_filtered(
+        &self,
+        filter: Filter,
+    ) -> Result<EventSubscription, DatabaseError> {
+        let _guard = self.lifecycle.enter()?;
+        let Some(connection_string) = &self.connection_string else {
+            return Err(DatabaseError::backend(ConfigError::new(
+                "subscribe needs an instance created with a connection string",
+            )));
+        };
+        subscribe(
+            self.clone(),
+            Arc::clone(connection_string),
+            self.config.notification_channel.clone(),
+            filter,
+            self.config.subscriptions,
+        )
+        .await
+    }
+
+    /// Query the stored events matching `filter` and follow with the ones saved from now on
+    ///
+    /// Yields the stored events, newest first, then
+    /// [`FollowItem::EndOfStoredEvents`](crate::FollowItem::EndOfStoredEvents), then the newly
+    /// saved events like [`subscribe_filtered`](Self::subscribe_filtered). Listens before
+    /// querying, so events saved during the query are not missed, and an event both stored and
+    /// notified is delivered once. The query runs on the primary.
+    pub async fn query_and_follow(&self, filter: Filter) -> Result<FollowStream, DatabaseError> {
+        let _guard = self.lifecycle.enter()?;
+        let Some(connection_string) = &self.connection_string else {
+            return Err(DatabaseError::backend(ConfigError::new(
+                "query_and_follow needs an instance created with a connection string",
+            )));
+        };
+        follow(
+            self.clone(),
+            Arc::clone(connection_string),
+            self.config.notification_channel.clone(),
+            filter,
+            self.config.subscriptions,
+        )
+        .await
+    }
+
+    /// Query stored events on the primary, bypassing the read replica
+    ///
+    /// Use this to read your own writes when replication lag matters.
+    pub async fn query_on_primary(&self, filter: Filter) -> Result<Events, DatabaseError> {
+        self.retrying("query", || async {
+            let db = self.get_connection().await?;
+            self.query_events(filter.clone(), db).await
+        })
+        .await
+    }
+
+    /// Query stored events, the authors of `filter` also matching the events they delegated
+    /// with a NIP-26 `delegation` tag
+    ///
+    /// The delegation token isn't validated, check it on the returned events where it matters.
+    /// Needs the `delegation` tag indexed, which [`TagIndexing::All`](crate::TagIndexing::All)
+    /// does; otherwise fails with an [`UnindexedTagError`](crate::UnindexedTagError).
+    pub async fn query_with_delegation(&self, filter: Filter) -> Result<Events, DatabaseError> {
+        self.config.tag_indexing.check_filter(&filter)?;
+        let Some(authors) = filter.authors.clone() else {
+            return NostrDatabase::query(self, filter).await;
+        };
+        self.config.tag_indexing.check_tag("delegation")?;
+        let authors: Vec<PublicKey> = authors.into_iter().collect();
+        let mut rest = filter.clone();
+        rest.authors = None;
+        self.query_where(filter, || {
+            Box::new(
+                filter_conditions_folded(rest.clone(), false, &self.config.case_insensitive_tags)
+                    .and(authored_or_delegated(&authors)),
+            )
+        })
+        .await
+    }
+
+    /// Query stored events matching `filter` except those of `exclusions`, e.g. everything
+    /// but reactions or all text notes but those of muted authors
+    ///
+    /// The exclusions become `NOT` conditions combined with the filter; excluded tags need to
+    /// be indexed like filtered ones.
+    pub async fn query_excluding(
+        &self,
+        filter: Filter,
+        exclusions: Exclusions,
+    ) -> Result<Events, DatabaseError> {
+        self.config.tag_indexing.check_filter(&filter)?;
+        for tag in exclusions.tags() {
+            self.config.tag_indexing.check_tag(&tag)?;
+        }
+        let conditions = filter.clone();
+        self.query_where(filter, || {
+            Box::new(
+                filter_conditions_folded(
+                    conditions.clone(),
+                    false,
+                    &self.config.case_insensitive_tags,
+                )
+                .and(exclusions.condition()),
+            )
+        })
+        .await
+    }
+
+    /// Query stored events matching `filter` that also have every tag of `matches`, e.g. the
+    /// replies to a thread by the `root` marker of their `e` tags
+    ///
+    /// The `#e` of a filter only looks at the value of a tag; a [`TagMatch`] also constrains
+    /// its other elements, all on the same tag. Matched tags need to be indexed like filtered
+    /// ones.
+    pub async fn query_tag_matches(
+        &self,
+        filter: Filter,
+        matches: Vec<TagMatch>,
+    ) -> Result<Events, DatabaseError> {
+        self.config.tag_indexing.check_filter(&filter)?;
+        for tag_match in &matches {
+            self.config.tag_indexing.check_tag(&tag_match.tag())?;
+        }
+        let conditions = filter.clone();
+        self.query_where(filter, || {
+            matches.iter().fold(
+                Box::new(filter_conditions_folded(
+                    conditions.clone(),
+                    false,
+                    &self.config.case_insensitive_tags,
+                )),
+                |condition: EventCondition<'_>, tag_match| {
+                    Box::new(condition.and(tag_match.condition()))
+                },
+            )
+        })
+        .await
+    }
+
+    /// A page of the stored events matching `filter` by offset, for admin and debugging tools
+    /// browsing by page number
+    ///
+    /// Skips `offset` events and returns the next `limit` ones, newest first and by id within
+    /// the same second, so consecutive pages neither overlap nor leave gaps while nothing is
+    /// saved or deleted in between. The limit of `filter` is ignored and there is no default
+    /// cap. Postgres still reads and discards the skipped rows, so deep pages get slow on large
+    /// tables: don't serve clients with it, page by `until` instead.
+    pub async fn query_page_offset(
+        &self,
+        filter: Filter,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<Event>, DatabaseError> {
+        self.config.tag_indexing.check_filter(&filter)?;
+        let tag = self.config.statement_tags;
+        let rows: Vec<EventDb> = self
+            .retrying("query_page_offset", || async {
+                let query = tagged(
+                    events::table
+                        .select(EventDb::as_select())
+                        .filter(filter_conditions_folded(
+                            filter.clone(),
+                            false,
+                            &self.config.case_insensitive_tags,
+                        ))
+                        .order_by((events::created_at.desc(), events::id))
+                        .limit(limit as i64)
+                        .offset(offset as i64),
+                    Operation::Query,
+                    tag,
+                );
+                let mut db = self.get_read_connection().await?;
+                with_statement_timeout(&mut db, self.config.query_timeout, "query", |c| {
+                    query.load(c).scope_boxed()
+                })
+                .await
+            })
+            .await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| self.decode_stored(&row.id, &row.payload).ok())
+            .collect())
+    }
+
+    /// Query stored events matching `filter`, at most `per_author` of the newest of each
+    /// author, e.g. a feed of the latest 3 notes of each followed account
+    ///
+    /// The events are ranked with `row_number()` per author, newest first and by id within the
+    /// same second. The limit of `filter` caps the total after that, keeping the newest; the
+    /// default limit of 10000 applies as for [`query`](NostrDatabase::query).
+    pub async fn query_per_author(
+        &self,
+        filter: Filter,
+        per_author: usize,
+    ) -> Result<Events, DatabaseError> {
+        self.config.tag_indexing.check_filter(&filter)?;
+        let filter = with_limit(self.guard(filter)?, 10000);
+        let limit = filter.limit.unwrap_or(10000);
+        let mut events = Events::new(&filter);
+        if per_author == 0 {
+            return Ok(events);
+        }
+        let tag = self.config.statement_tags;
+        let rows: Vec<(Vec<u8>, Vec<u8>)> = self
+            .retrying("query_per_author", || async {
+                let query = tagged(
+                    per_author_query(
+                        filter.clone(),
+                        per_author,
+                        limit,
+                        &self.config.case_insensitive_tags,
+                    ),
+                    Operation::Query,
+                    tag,
+                );
+                let mut db = self.get_read_connection().await?;
+                with_statement_timeout(&mut db, self.config.query_timeout, "query", |c| {
+                    query.load(c).scope_boxed()
+                })
+                .await
+            })
+            .await?;
+        for (id, payload) in rows {
+            if let Ok(event) = self.decode_stored(&id, &payload) {
+                events.insert(event);
+            }
+        }
+        Ok(events)
+    }
+
+    /// queries the events matching `condition`, newest first up to the limit of `filter`,
+    /// 10000 by default
+    async fn query_where<C>(&self, filter: Filter, condition: C) -> Result<Events, DatabaseError>
     where
-        C: AsRef<str>,
+        C: Fn() -> EventCondition<'static> + Sync,
+    {
+        let filter = with_limit(self.guard(filter)?, 10000);
+        let limit = filter.limit.unwrap_or(10000) as i64;
+        let tag = self.config.statement_tags;
+        let rows: Vec<EventDb> = self
+            .retrying("query", || async {
+                let query = tagged(
+                    events::table
+                        .select(EventDb::as_select())
+                        .filter(condition())
+                        .order_by(events::created_at.desc())
+                        .limit(limit),
+                    Operation::Query,
+                    tag,
+                );
+                let mut db = self.get_read_connection().await?;
+                with_statement_timeout(&mut db, self.config.query_timeout, "query", |c| {
+                    query.load(c).scope_boxed()
+                })
+                .await
+            })
+            .await?;
+        let mut events = Events::new(&filter);
+        for row in rows {
+            if let Ok(event) = self.decode_stored(&row.id, &row.payload) {
+                events.insert(event);
+            }
+        }
+        Ok(events)
+    }
+
+    /// Query stored events as NIP-01 JSON, in the order and with the limit of
+    /// [`query`](NostrDatabase::query)
+    ///
+    /// Skips building the [`Events`] collection: the payloads are decoded and serialized in
+    /// batches on the blocking thread pool, in parallel and off the async workers, ready to be
+    /// sent to clients.
+    pub async fn query_json(&self, filter: Filter) -> Result<Vec<String>, DatabaseError> {
+        let mut rows = self
+            .retrying("query", || async {
+                let mut db = self.get_read_connection().await?;
+                self.query_rows(filter.clone(), &mut db, self.config.query_timeout)
+                    .await
+            })
+            .await?;
+        // the order of Events: newest first, by id within the same second
+        rows.sort_unstable_by(|a, b| {
+            b.created_at
+                .cmp(&a.created_at)
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        let mut batches = Vec::new();
+        while !rows.is_empty() {
+            let rest = rows.split_off(rows.len().min(JSON_BATCH));
+            batches.push(std::mem::replace(&mut rows, rest));
+        }
+        let verification = self.config.read_verification;
+        let serialized = try_join_all(batches.into_iter().map(|batch| {
+            let quarantine = self.quarantine.clone();
+            tokio::task::spawn_blocking(move || {
+                batch
+                    .iter()
+                    .filter_map(|row| {
+                        decode_row(&quarantine, verification, &row.id, &row.payload).ok()
+                    })
+                    .map(|event| event.as_json())
+                    .collect::<Vec<_>>()
+            })
+        }))
+        .await
+        .map_err(DatabaseError::backend)?;
+        Ok(serialized.into_iter().flatten().collect())
+    }
+
+    /// Query the events matching any of `filters`, like the filters of a relay `REQ`
+    ///
+    /// Every filter gets its own query with its own limit, as in [`query`](NostrDatabase::query),
+    /// and the results are merged. The queries are pipelined on a single connection: all are
+    /// sent at once instead of waiting for each result, so they cost about one round trip and
+    /// one pool slot. A failing filter is reported as a [`FilterError`](crate::FilterError)
+    /// with its position in `filters`.
+    pub async fn query_many(&self, filters: Vec<Filter>) -> Result<Events, DatabaseError> {
+        let filters = filters
+            .into_iter()
+            .enumerate()
+            .map(|(index, filter)| {
+                self.config
+                    .tag_indexing
+                    .check_filter(&filter)
+                    .and_then(|_| self.guard(filter))
+                    .map(|filter| with_limit(filter, 10000))
+                    .map_err(|e| DatabaseError::backend(FilterError::new(index, e)))
+            })
+            .collect::<Result<Vec<Filter>, DatabaseError>>()?;
+        let tag = self.config.statement_tags;
+        let timeout = self.config.query_timeout;
+        let results: Vec<QueryResult<Vec<EventDb>>> = self
+            .retrying("query_many", || async {
+                let queries: Vec<_> = filters
+                    .iter()
+                    .map(|filter| {
+                        tagged(
+                            build_filter_query_folded(
+                                filter.clone(),
+                                &self.config.case_insensitive_tags,
+                            )
+                            .select(EventDb::as_select()),
+                            Operation::Query,
+                            tag,
+                        )
+                    })
+                    .collect();
+                let mut db = self.get_read_connection().await?;
+                with_statement_timeout(&mut db, timeout, "query_many", |c| {
+                    async move {
+                        let mut pending = Vec::with_capacity(queries.len());
+                        for query in queries {
+                            pending.push(query.load::<EventDb>(c));
+                        }
+                        Ok(join_all(pending).await)
+                    }
+                    .scope_boxed()
+                })
+                .await
+            })
+            .await?;
+        let mut events: Option<Events> = None;
+        for (index, (filter, rows)) in filters.iter().zip(results).enumerate() {
+            // the first failure is the cause, later queries of a transaction are aborted by it
+            let rows = rows.map_err(|e| {
+                DatabaseError::backend(FilterError::new(
+                    index,
+                    statement_error(e, "query_many", timeout),
+                ))
+            })?;
+            let mut matched = Events::new(filter);
+            for row in rows {
+                if let Ok(event) = self.decode_stored(&row.id, &row.payload) {
+                    matched.insert(event);
+                }
+            }
+            events = Some(match events {
+                Some(events) => events.merge(matched),
+                None => matched,
+            });
+        }
+        Ok(events.unwrap_or_else(|| Events::new(&Filter::new())))
+    }
+
+    /// Query stored events, cancelling the query if it runs longer than `timeout`
+    ///
+    /// Overrides the query timeout configured on the builder for this call. A timeout is
+    /// reported as a [`TimeoutError`].
+    pub async fn query_with_timeout(
+        &self,
+        filter: Filter,
+        timeout: Duration,
+    ) -> Result<Events, DatabaseError> {
+        self.retrying("query", || async {
+            let mut db = self.get_read_connection().await?;
+            self.query_events_with_timeout(filter.clone(), &mut db, Some(timeout))
+                .await
+        })
+        .await
+    }
+
+    /// runs `op`, retrying transient errors if configured, see
+    /// [`NostrPostgresBuilder::retry_transient`]
+    async fn retrying<T, F, Fut>(
+        &self,
+        operation: &'static str,
+        mut op: F,
+    ) -> Result<T, DatabaseError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, DatabaseError>>,
     {
-        crate::migrations::postgres::run_migrations(connection_string.as_ref())?;
-        let pool = postgres_connection_pool(connection_string).await?;
-        Ok(Self { pool })
+        match &self.config.transient_retry {
+            Some(policy) => {
+                policy
+                    .run(operation, || self.metrics.record_retry(), op)
+                    .await
+            }
+            None => op().await,
+        }
     }
 
     pub(crate) async fn get_connection(&self) -> Result<PostgresConnection, DatabaseError> {
-        self.pool.get().await.map_err(DatabaseError::backend)
+        self.acquire(&self.pool).await
+    }
+
+    /// connection on the read replica if there is one
+    pub(crate) async fn get_read_connection(&self) -> Result<PostgresConnection, DatabaseError> {
+        let Some(read_pool) = self.read_pool.as_ref().filter(|_| self.pinned.is_none()) else {
+            return self.get_connection().await;
+        };
+        match self.acquire(read_pool).await {
+            Ok(conn) => Ok(conn),
+            Err(e) if self.config.replica_fallback && !self.is_closed() => {
+                warn!("Read replica unavailable, falling back to primary: {e}");
+                self.get_connection().await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn acquire(
+        &self,
+        pool: &PostgresConnectionPool,
+    ) -> Result<PostgresConnection, DatabaseError> {
+        let guard = self.lifecycle.enter()?;
+        let span = debug_span!("acquire", wait_ms = field::Empty);
+        let start = Instant::now();
+        let conn = match &self.pinned {
+            Some(pinned) => {
+                let conn = Arc::clone(pinned)
+                    .lock_owned()
+                    .instrument(span.clone())
+                    .await;
+                if conn.is_none() {
+                    return Err(DatabaseError::backend(ClosedError));
+                }
+                Connection::Pinned(conn)
+            }
+            None => Connection::Pooled(
+                pool.get()
+                    .instrument(span.clone())
+                    .await
+                    .map_err(|e| DatabaseError::backend(PoolAcquireError::from(e)))?,
+            ),
+        };
+        self.metrics.record_acquire(start.elapsed());
+        span.record("wait_ms", elapsed_ms(start));
+        Ok(PostgresConnection {
+            conn,
+            _guard: guard,
+        })
+    }
+
+    /// the filter shape for [`log_slow`](Self::log_slow), only computed if slow operations
+    /// are logged
+    fn slow_log_shape(&self, filter: &Filter) -> Option<String> {
+        self.config
+            .slow_operation_threshold
+            .map(|_| filter_shape(filter))
+    }
+
+    /// warns if the operation started at `start` exceeded the slow operation threshold
+    fn log_slow(&self, operation: &str, start: Instant, shape: Option<String>, rows: u64) {
+        let elapsed = start.elapsed();
+        if self
+            .config
+            .slow_operation_threshold
+            .is_some_and(|threshold| elapsed >= threshold)
+        {
+            warn!(
+                operation,
+                elapsed_ms = elapsed.as_secs_f64() * 1000.0,
+                filter = shape.as_deref().unwrap_or_default(),
+                rows,
+                "Slow {operation} took {elapsed:?}"
+            );
+        }
+    }
+
+    /// converts the event, keeping only the tags to index
+    pub(crate) fn event_data(&self, event: &Event) -> Result<EventDataDb, DatabaseError> {
+        let mut data = EventDataDb::try_from(event)?;
+        self.config.tag_indexing.retain(&mut data.tags);
+        self.config.tag_value_limit.apply(&mut data.tags);
+        // a tag repeated on the event, or values equal after truncation, is one row
+        let mut seen = HashSet::new();
+        data.tags
+            .retain(|t| seen.insert((t.tag.clone(), t.tag_value.clone())));
+        Ok(data)
     }
 
-    pub(crate) async fn save(
+    /// saves the events in one transaction, skipping already stored ones
+    pub(crate) async fn save_batch(
         &self,
-        event_data: EventDataDb,
-    ) -> Result<SaveEventStatus, DatabaseError> {
+        batch: Vec<EventDataDb>,
+    ) -> Result<BatchOutcome, DatabaseError> {
+        if batch.is_empty() {
+            return Ok(BatchOutcome::default());
+        }
+        let tag = self.config.statement_tags;
+        let channel = self.notification_channel();
+        let history = self.config.replaceable_history;
+        let mut db = self.get_connection().await?;
+        db.transaction(|c| {
+            async move {
+                let total = batch.len();
+                let replaceable: Vec<(EventDataDb, String)> = match history {
+                    Some(_) => batch
+                        .iter()
+                        .filter_map(|e| identifier(e).map(|identifier| (e.clone(), identifier)))
+                        .collect(),
+                    None => Vec::new(),
+                };
+                let (events, tags): (Vec<_>, Vec<_>) =
+                    batch.into_iter().map(|e| (e.event, e.tags)).unzip();
+                let inserted: Vec<Vec<u8>> = tagged(
+                    diesel::insert_into(events::table)
+                        .values(&events)
+                        .on_conflict_do_nothing()
+                        .returning(events::id),
+                    Operation::Save,
+                    tag,
+                )
+                .load(c)
+                .await?;
+
+                let inserted_ids: HashSet<&Vec<u8>> = inserted.iter().collect();
+                let tags: Vec<_> = tags
+                    .into_iter()
+                    .flatten()
+                    .filter(|t| inserted_ids.contains(&t.event_id))
+                    .collect();
+                // stays below the limit of 65535 bind parameters per statement
+                for chunk in tags.chunks(10000) {
+                    tagged(
+                        diesel::insert_into(event_tags::table)
+                            .values(chunk)
+                            .on_conflict_do_nothing(),
+                        Operation::Save,
+                        tag,
+                    )
+                    .execute(c)
+                    .await?;
+                }
+                if let Some(history) = history {
+                    for (data, identifier) in replaceable
+                        .iter()
+                        .filter(|(data, _)| inserted_ids.contains(&data.event.id))
+                    {
+                        replace(c, data, identifier, &history, tag).await?;
+                    }
+                }
+                if let Some(channel) = channel {
+                    let saved = events.iter().filter(|e| inserted_ids.contains(&e.id));
+                    notify_saved(c, channel, saved, tag).await?;
+                }
+
+                // an id repeated in the batch is looked up and counted once
+                let skipped: Vec<&Vec<u8>> = events
+                    .iter()
+                    .map(|e| &e.id)
+                    .filter(|id| !inserted_ids.contains(id))
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .collect();
+                // skipped events that aren't stored were skipped by the blocklist
+                let stored: Vec<bool> = if skipped.is_empty() {
+                    Vec::new()
+                } else {
+                    events::table
+                        .filter(events::id.eq_any(&skipped))
+                        .select(events::deleted)
+                        .load(c)
+                        .await?
+                };
+                let deleted = stored.iter().filter(|deleted| **deleted).count() as u64;
+                let inserted = inserted.len() as u64;
+                let rejected = skipped.len().saturating_sub(stored.len()) as u64;
+                Ok::<_, DieselError>(BatchOutcome {
+                    inserted,
+                    duplicates: (total as u64)
+                        .saturating_sub(inserted)
+                        .saturating_sub(deleted)
+                        .saturating_sub(rejected),
+                    deleted,
+                    rejected,
+                })
+            }
+            .scope_boxed()
+        })
+        .await
+        .map_err(DatabaseError::backend)
+    }
+
+    /// saves one event in its own transaction, for
+    /// [`save_event`](NostrDatabase::save_event) and [`try_save_event`](Self::try_save_event)
+    async fn save_outcome(&self, event: &Event) -> Result<SaveOutcome, DatabaseError> {
+        if !self.rate_limiter.try_acquire(&event.pubkey) {
+            debug!("Rejected event {}: rate limited", event.id);
+            return Ok(SaveOutcome::RateLimited);
+        }
+        let span = info_span!(
+            "save_event",
+            kind = event.kind.as_u16(),
+            tags = field::Empty,
+            db_ms = field::Empty
+        );
+        async move {
+            let start = Instant::now();
+            let outcome = self
+                .retrying("save_event", || async {
+                    self.save(self.event_data(event)?).await
+                })
+                .await?;
+            let rows = u64::from(outcome.is_saved());
+            self.log_slow("save_event", start, None, rows);
+            Ok(outcome)
+        }
+        .instrument(span)
+        .await
+    }
+
+    pub(crate) async fn save(&self, event_data: EventDataDb) -> Result<SaveOutcome, DatabaseError> {
+        let tag = self.config.statement_tags;
+        let channel = self.notification_channel();
+        let id = EventId::from_slice(&event_data.event.id).ok();
+        let history = self.config.replaceable_history;
+        let quota = self.config.quota.as_ref();
+        let drop_tags = self.config.drop_tags_on_delete;
+        Span::current().record("tags", event_data.tags.len());
         let mut db = self.get_connection().await?;
-        let result: QueryResult<bool> = db
+        let start = Instant::now();
+        let result: QueryResult<SaveOutcome> = db
             .transaction(|c| {
                 async move {
-                    diesel::insert_into(events::table)
-                        .values(&event_data.event)
-                        .execute(c)
-                        .await?;
+                    if let Some(quota) = quota
+                        && !make_room(c, quota, &event_data.event, drop_tags, tag).await?
+                    {
+                        return Ok(SaveOutcome::OverQuota);
+                    }
+                    let inserted = tagged(
+                        diesel::insert_into(events::table).values(&event_data.event),
+                        Operation::Save,
+                        tag,
+                    )
+                    .execute(c)
+                    .await?;
+                    // skipped by the trigger of the blocklist; rolled back so events evicted
+                    // for the quota stay
+                    if inserted == 0 {
+                        return Err(DieselError::RollbackTransaction);
+                    }
 
-                    diesel::insert_into(event_tags::table)
-                        .values(&event_data.tags)
-                        .execute(c)
-                        .await?;
+                    tagged(
+                        diesel::insert_into(event_tags::table).values(&event_data.tags),
+                        Operation::Save,
+                        tag,
+                    )
+                    .execute(c)
+                    .await?;
 
-                    Ok(true)
+                    if let Some(history) = history
+                        && let Some(identifier) = identifier(&event_data)
+                    {
+                        replace(c, &event_data, &identifier, &history, tag).await?;
+                    }
+
+                    if let Some(channel) = channel {
+                        notify_saved(c, channel, [&event_data.event], tag).await?;
+                    }
+
+                    Ok(SaveOutcome::Saved)
                 }
                 .scope_boxed()
             })
             .await;
+        Span::current().record("db_ms", elapsed_ms(start));
 
-        match result {
-            Ok(_) => Ok(SaveEventStatus::Success),
-            Err(e) => match e {
-                DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
-                    Ok(SaveEventStatus::Rejected(RejectedReason::Duplicate))
-                }
-                e => Err(DatabaseError::backend(e)),
-            },
+        let outcome = match result {
+            Ok(outcome) => outcome,
+            Err(DieselError::RollbackTransaction) => SaveOutcome::Blocked,
+            Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                SaveOutcome::Duplicate
+            }
+            Err(e) => return Err(DatabaseError::backend(e)),
+        };
+        if !outcome.is_saved()
+            && let Some(id) = id
+        {
+            debug!("Rejected event {id}: {}", outcome.reason());
         }
+        Ok(outcome)
+    }
+
+    /// checks `filter` against the query guard, if configured
+    fn guard(&self, filter: Filter) -> Result<Filter, DatabaseError> {
+        match &self.config.query_guard {
+            Some(guard) => guard.apply(filter),
+            None => Ok(filter),
+        }
+    }
+
+    /// decodes the payload of the row with the id column `id`, verifying the event if
+    /// configured; failures are logged
+    fn decode_stored(&self, id: &[u8], payload: &[u8]) -> Result<Event, PostgresDbError> {
+        decode_row(&self.quarantine, self.config.read_verification, id, payload)
+    }
+
+    /// the channel to notify of saved events, if enabled
+    fn notification_channel(&self) -> Option<&str> {
+        self.config
+            .notifications
+            .then_some(self.config.notification_channel.as_str())
     }
 
     pub(crate) async fn event_by_id(
         &self,
         event_id: &EventId,
     ) -> Result<Option<EventDb>, DatabaseError> {
-        let res = event_by_id(event_id)
-            .first(&mut self.get_connection().await?)
+        let query = tagged(
+            event_by_id(event_id),
+            Operation::EventById,
+            self.config.statement_tags,
+        );
+        let mut db = self.get_read_connection().await?;
+        let start = Instant::now();
+        let event =
+            with_statement_timeout(&mut db, self.config.query_timeout, "event_by_id", |c| {
+                async move { query.get_result(c).await.optional() }.scope_boxed()
+            })
+            .await;
+        Span::current().record("db_ms", elapsed_ms(start));
+        event
+    }
+
+    /// the stored row of `event_id`, falling back to the archive
+    async fn event_or_archived_by_id(
+        &self,
+        event_id: &EventId,
+    ) -> Result<Option<EventDb>, DatabaseError> {
+        if let Some(event) = self.event_by_id(event_id).await? {
+            return Ok(Some(event));
+        }
+        let query = tagged(
+            archived_by_id(event_id),
+            Operation::EventById,
+            self.config.statement_tags,
+        );
+        let mut db = self.get_read_connection().await?;
+        with_statement_timeout(&mut db, self.config.query_timeout, "event_by_id", |c| {
+            async move { query.get_result(c).await.optional() }.scope_boxed()
+        })
+        .await
+    }
+
+    pub(crate) async fn query_events(
+        &self,
+        filter: Filter,
+        mut db: PostgresConnection,
+    ) -> Result<Events, DatabaseError> {
+        self.query_events_with_timeout(filter, &mut db, self.config.query_timeout)
             .await
-            .optional()
-            .map_err(DatabaseError::backend)?;
-        Ok(res)
+    }
+
+    async fn query_events_with_timeout(
+        &self,
+        filter: Filter,
+        db: &mut PostgresConnection,
+        timeout: Option<Duration>,
+    ) -> Result<Events, DatabaseError> {
+        let filter = with_limit(filter, 10000);
+        let mut events = Events::new(&filter);
+        for item in self.query_rows(filter, db, timeout).await? {
+            if let Ok(event) = self.decode_stored(&item.id, &item.payload) {
+                events.insert(event);
+            }
+        }
+        Ok(events)
+    }
+
+    /// the rows of [`query`](NostrDatabase::query) for `filter`, not decoded
+    async fn query_rows(
+        &self,
+        filter: Filter,
+        db: &mut PostgresConnection,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<EventDb>, DatabaseError> {
+        self.config.tag_indexing.check_filter(&filter)?;
+        let filter = with_limit(self.guard(filter)?, 10000);
+        let query = tagged(
+            build_filter_query_folded(filter, &self.config.case_insensitive_tags)
+                .select(EventDb::as_select()),
+            Operation::Query,
+            self.config.statement_tags,
+        );
+        let span = Span::current();
+        span.record("params", bind_count(&query));
+        let start = Instant::now();
+        let result: Vec<EventDb> =
+            with_statement_timeout(db, timeout, "query", |c| query.load(c).scope_boxed()).await?;
+        span.record("db_ms", elapsed_ms(start));
+        span.record("rows", result.len());
+        Ok(result)
+    }
+}
+
+/// The [`NostrDatabase`] operations returning a classified [`PostgresDbError`]
+impl NostrPostgres {
+    /// [`save_event`](NostrDatabase::save_event), see [`PostgresDbError`], telling the reasons
+    /// of rejections apart
+    pub async fn try_save_event(&self, event: &Event) -> Result<SaveOutcome, PostgresDbError> {
+        Ok(self.save_outcome(event).await?)
+    }
+
+    /// [`check_id`](NostrDatabase::check_id), see [`PostgresDbError`]
+    pub async fn try_check_id(
+        &self,
+        event_id: &EventId,
+    ) -> Result<DatabaseEventStatus, PostgresDbError> {
+        Ok(NostrDatabase::check_id(self, event_id).await?)
+    }
+
+    /// [`event_by_id`](NostrDatabase::event_by_id), see [`PostgresDbError`]
+    pub async fn try_event_by_id(
+        &self,
+        event_id: &EventId,
+    ) -> Result<Option<Event>, PostgresDbError> {
+        Ok(NostrDatabase::event_by_id(self, event_id).await?)
+    }
+
+    /// [`count`](NostrDatabase::count), see [`PostgresDbError`]
+    pub async fn try_count(&self, filter: Filter) -> Result<usize, PostgresDbError> {
+        Ok(NostrDatabase::count(self, filter).await?)
+    }
+
+    /// [`query`](NostrDatabase::query), see [`PostgresDbError`]
+    pub async fn try_query(&self, filter: Filter) -> Result<Events, PostgresDbError> {
+        Ok(NostrDatabase::query(self, filter).await?)
+    }
+
+    /// [`delete`](NostrDatabase::delete), see [`PostgresDbError`]
+    pub async fn try_delete(&self, filter: Filter) -> Result<(), PostgresDbError> {
+        Ok(NostrDatabase::delete(self, filter).await?)
     }
 }
 
@@ -99,18 +2425,21 @@ impl NostrDatabase for NostrPostgres {
         &'a self,
         event: &'a Event,
     ) -> BoxedFuture<'a, Result<SaveEventStatus, DatabaseError>> {
-        Box::pin(async move { self.save(EventDataDb::try_from(event)?).await })
+        Box::pin(async move { Ok(self.save_outcome(event).await?.into()) })
     }
 
     /// Check event status by ID
     ///
-    /// Check if the event is saved, deleted or not existent.
+    /// Check if the event is saved, deleted or not existent. Archived events count as saved.
     fn check_id<'a>(
         &'a self,
         event_id: &'a EventId,
     ) -> BoxedFuture<'a, Result<DatabaseEventStatus, DatabaseError>> {
         Box::pin(async move {
-            let status = match self.event_by_id(event_id).await? {
+            let stored = self
+                .retrying("check_id", || self.event_or_archived_by_id(event_id))
+                .await?;
+            let status = match stored {
                 Some(e) if e.deleted => DatabaseEventStatus::Deleted,
                 Some(_) => DatabaseEventStatus::Saved,
                 None => DatabaseEventStatus::NotExistent,
@@ -124,65 +2453,165 @@ impl NostrDatabase for NostrPostgres {
         &'a self,
         event_id: &'a EventId,
     ) -> BoxedFuture<'a, Result<Option<Event>, DatabaseError>> {
-        Box::pin(async move {
-            let event = match self.event_by_id(event_id).await? {
-                Some(e) if !e.deleted => {
-                    Some(Event::decode(&e.payload).map_err(DatabaseError::backend)?)
-                }
-                _ => None,
-            };
-            Ok(event)
-        })
+        let span = info_span!("event_by_id", found = field::Empty, db_ms = field::Empty);
+        Box::pin(
+            async move {
+                let stored = self
+                    .retrying("event_by_id", || self.event_by_id(event_id))
+                    .await?;
+                let event = match stored {
+                    Some(e) if !e.deleted => Some(self.decode_stored(&e.id, &e.payload)?),
+                    _ => None,
+                };
+                Span::current().record("found", event.is_some());
+                Ok(event)
+            }
+            .instrument(span),
+        )
     }
 
     /// Count the number of events found with [`Filter`].
     ///
     /// Use `Filter::new()` or `Filter::default()` to count all events.
     fn count(&self, filter: Filter) -> BoxedFuture<'_, Result<usize, DatabaseError>> {
-        Box::pin(async move {
-            let res: i64 = build_filter_query(filter)
-                .count()
-                .get_result(&mut self.get_connection().await?)
-                .await
-                .map_err(DatabaseError::backend)?;
-            Ok(res as usize)
-        })
+        let span = info_span!(
+            "count",
+            params = field::Empty,
+            rows = field::Empty,
+            db_ms = field::Empty
+        );
+        Box::pin(
+            async move {
+                self.config.tag_indexing.check_filter(&filter)?;
+                let start = Instant::now();
+                let shape = self.slow_log_shape(&filter);
+                // the limit caps the count, so it's applied to the result
+                let limit = filter.limit;
+                let count_query = |filter| {
+                    tagged(
+                        events::table
+                            .filter(filter_conditions_folded(
+                                filter,
+                                false,
+                                &self.config.case_insensitive_tags,
+                            ))
+                            .count(),
+                        Operation::Count,
+                        self.config.statement_tags,
+                    )
+                };
+                let span = Span::current();
+                span.record("params", bind_count(&count_query(filter.clone())));
+                let db_start = Instant::now();
+                let res: i64 = self
+                    .retrying("count", || async {
+                        let query = count_query(filter.clone());
+                        let mut db = self.get_read_connection().await?;
+                        with_statement_timeout(&mut db, self.config.query_timeout, "count", |c| {
+                            query.get_result(c).scope_boxed()
+                        })
+                        .await
+                    })
+                    .await?;
+                let res = limit.map_or(res, |limit| res.min(limit as i64));
+                span.record("db_ms", elapsed_ms(db_start));
+                span.record("rows", res);
+                self.log_slow("count", start, shape, res as u64);
+                Ok(res as usize)
+            }
+            .instrument(span),
+        )
     }
 
     /// Query stored events.
     fn query(&self, filter: Filter) -> BoxedFuture<'_, Result<Events, DatabaseError>> {
-        let filter = with_limit(filter, 10000);
-        Box::pin(async move {
-            let mut events = Events::new(&filter);
-            let result = build_filter_query(filter.clone())
-                .select(EventDb::as_select())
-                .load(&mut self.get_connection().await?)
-                .await
-                .map_err(DatabaseError::backend)?;
-
-            for item in result.into_iter() {
-                if let Ok(event) = Event::decode(&item.payload) {
-                    events.insert(event);
-                }
+        let span = info_span!(
+            "query",
+            params = field::Empty,
+            rows = field::Empty,
+            db_ms = field::Empty
+        );
+        Box::pin(
+            async move {
+                let start = Instant::now();
+                let shape = self.slow_log_shape(&filter);
+                let events = self
+                    .retrying("query", || async {
+                        let db = self.get_read_connection().await?;
+                        self.query_events(filter.clone(), db).await
+                    })
+                    .await?;
+                self.log_slow("query", start, shape, events.len() as u64);
+                Ok(events)
             }
-            Ok(events)
-        })
+            .instrument(span),
+        )
     }
 
     /// Delete all events that match the [Filter]
     fn delete(&self, filter: Filter) -> BoxedFuture<'_, Result<(), DatabaseError>> {
         let filter = with_limit(filter, 999);
-        Box::pin(async move {
-            let filter = build_filter_query(filter);
-            diesel::update(events::table)
-                .set(events::deleted.eq(true))
-                .filter(events::id.eq_any(filter.select(events::id)))
-                .execute(&mut self.get_connection().await?)
-                .await
-                .map_err(DatabaseError::backend)?;
+        let span = info_span!(
+            "delete",
+            params = field::Empty,
+            rows = field::Empty,
+            db_ms = field::Empty
+        );
+        Box::pin(
+            async move {
+                self.config.tag_indexing.check_filter(&filter)?;
+                let start = Instant::now();
+                let shape = self.slow_log_shape(&filter);
+                let filter = build_filter_query(filter);
+                let tag = self.config.statement_tags;
+                let query = tagged(
+                    diesel::update(events::table)
+                        .set(events::deleted.eq(true))
+                        .filter(events::id.eq_any(filter.select(events::id)))
+                        .returning(events::id),
+                    Operation::Delete,
+                    tag,
+                );
+                let span = Span::current();
+                span.record("params", bind_count(&query));
+                let drop_tags = self.config.drop_tags_on_delete;
+                let mut db = self.get_connection().await?;
+                let db_start = Instant::now();
+                let rows = if !drop_tags {
+                    query
+                        .execute(&mut db)
+                        .await
+                        .map_err(DatabaseError::backend)?
+                } else {
+                    db.transaction(|c| {
+                        async move {
+                            let ids: Vec<Vec<u8>> = query.load(c).await?;
+                            if !ids.is_empty() {
+                                tagged(
+                                    diesel::delete(
+                                        event_tags::table.filter(event_tags::event_id.eq_any(&ids)),
+                                    ),
+                                    Operation::Delete,
+                                    tag,
+                                )
+                                .execute(c)
+                                .await?;
+                            }
+                            Ok::<_, DieselError>(ids.len())
+                        }
+                        .scope_boxed()
+                    })
+                    .await
+                    .map_err(DatabaseError::backend)?
+                };
+                span.record("db_ms", elapsed_ms(db_start));
+                span.record("rows", rows);
+                self.log_slow("delete", start, shape, rows as u64);
 
-            Ok(())
-        })
+                Ok(())
+            }
+            .instrument(span),
+        )
     }
 
     fn wipe(&self) -> BoxedFuture<'_, prelude::Result<(), DatabaseError>> {
@@ -190,10 +2619,89 @@ impl NostrDatabase for NostrPostgres {
     }
 }
 
+/// milliseconds since `start`, for span fields
+fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
+/// hex representation of an id column, which may not hold a valid event id
+pub(crate) fn hex(id: &[u8]) -> String {
+    id.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// decodes the payload of the row with the id column `id`, verifying the event if
+/// configured; failures are logged
+fn decode_row(
+    quarantine: &Quarantine,
+    verification: ReadVerification,
+    id: &[u8],
+    payload: &[u8],
+) -> Result<Event, PostgresDbError> {
+    let event = Event::decode(payload).map_err(|e| {
+        quarantine.record(id, &e);
+        PostgresDbError::Decode(Box::new(e))
+    })?;
+    verification.check(id, &event).map_err(|e| {
+        warn!("{e}");
+        PostgresDbError::Integrity(e)
+    })?;
+    Ok(event)
+}
+
+/// runs `callback` with `statement_timeout` set for its transaction, if a timeout is given
+async fn with_statement_timeout<'a, R, F>(
+    db: &mut PostgresConnection,
+    timeout: Option<Duration>,
+    operation: &'static str,
+    callback: F,
+) -> Result<R, DatabaseError>
+where
+    F: for<'r> FnOnce(&'r mut PostgresConnection) -> ScopedBoxFuture<'a, 'r, QueryResult<R>>
+        + Send
+        + 'a,
+    R: Send + 'a,
+{
+    let Some(timeout) = timeout else {
+        return callback(db).await.map_err(DatabaseError::backend);
+    };
+    let res = db
+        .transaction(|c| {
+            async move {
+                diesel::sql_query(format!(
+                    "SET LOCAL statement_timeout = {}",
+                    timeout.as_millis().max(1)
+                ))
+                .execute(c)
+                .await?;
+                callback(c).await
+            }
+            .scope_boxed()
+        })
+        .await;
+    res.map_err(|e| statement_error(e, operation, Some(timeout)))
+}
+
+/// `error` of a statement run with the statement timeout `timeout`, a [`TimeoutError`] if
+/// the timeout cancelled it
+fn statement_error(
+    error: DieselError,
+    operation: &'static str,
+    timeout: Option<Duration>,
+) -> DatabaseError {
+    match (error, timeout) {
+        (DieselError::DatabaseError(_, ref info), Some(timeout))
+            if info.message().contains("statement timeout") =>
+        {
+            DatabaseError::backend(TimeoutError::new(operation, timeout))
+        }
+        (e, _) => DatabaseError::backend(e),
+    }
+}
+
 /// Create a new [`NostrPostgres`] instance from an existing connection pool
 impl From<PostgresConnectionPool> for NostrPostgres {
     fn from(pool: PostgresConnectionPool) -> Self {
-        Self { pool }
+        Self::with_config(pool, Config::default())
     }
 }
 
@@ -204,17 +2712,163 @@ pub async fn postgres_connection_pool<C>(
 where
     C: AsRef<str>,
 {
-    let config = AsyncDieselConnectionManager::<AsyncPgConnection>::new(connection_string.as_ref());
-    let pool: PostgresConnectionPool = Pool::builder(config)
+    build_pool(connection_string.as_ref(), &Config::default())
+}
+
+pub(crate) fn build_pool(
+    connection_string: &str,
+    config: &Config,
+) -> Result<PostgresConnectionPool, DatabaseError> {
+    let search_path = config
+        .schema
+        .as_deref()
+        .map(|schema| {
+            Ok::<_, DatabaseError>(format!("SET search_path TO {}", quote_identifier(schema)?))
+        })
+        .transpose()?;
+    let cache_size = if config.statement_cache && !config.pgbouncer_transaction_mode {
+        CacheSize::Unbounded
+    } else {
+        CacheSize::Disabled
+    };
+    let mut manager_config = ManagerConfig::default();
+    manager_config.recycling_method = recycling_method(&config.recycling_method);
+    manager_config.custom_setup = Box::new(move |url| {
+        let search_path = search_path.clone();
+        async move {
+            let mut conn = AsyncPgConnection::establish(url).await?;
+            conn.set_prepared_statement_cache_size(cache_size);
+            if let Some(search_path) = search_path {
+                conn.batch_execute(&search_path)
+                    .await
+                    .map_err(|e| ConnectionError::BadConnection(e.to_string()))?;
+            }
+            Ok(conn)
+        }
+        .boxed()
+    });
+    let connection_string = with_application_name(connection_string, &config.application_name);
+    let manager = AsyncDieselConnectionManager::<AsyncPgConnection>::new_with_config(
+        connection_string,
+        manager_config,
+    );
+    let mut builder = Pool::builder(manager)
+        .runtime(Runtime::Tokio1)
+        .wait_timeout(config.acquire_timeout);
+    if let Some(max_connections) = config.max_connections {
+        builder = builder.max_size(max_connections);
+    }
+    let pool: PostgresConnectionPool = builder
         .build()
         .map_err(|e| DatabaseError::Backend(Box::new(e)))?;
     Ok(pool)
 }
 
+/// adds `application_name` to the connection string unless it already sets one
+fn with_application_name(connection_string: &str, application_name: &str) -> String {
+    if application_name.is_empty() || connection_string.contains("application_name") {
+        return connection_string.to_string();
+    }
+    if connection_string.starts_with("postgres://")
+        || connection_string.starts_with("postgresql://")
+    {
+        let separator = if connection_string.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        let encoded: String = application_name
+            .bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                    (b as char).to_string()
+                }
+                b => format!("%{b:02X}"),
+            })
+            .collect();
+        format!("{connection_string}{separator}application_name={encoded}")
+    } else {
+        let quoted = application_name.replace('\\', "\\\\").replace('\'', "\\'");
+        format!("{connection_string} application_name='{quoted}'")
+    }
+}
+
+/// maps the configured method to a diesel-async one that logs recycle failures
+fn recycling_method(
+    method: &RecyclingMethod,
+) -> diesel_async::pooled_connection::RecyclingMethod<AsyncPgConnection> {
+    let query = match method {
+        RecyclingMethod::Fast => return diesel_async::pooled_connection::RecyclingMethod::Fast,
+        RecyclingMethod::Verified => "SELECT 1".to_string(),
+        RecyclingMethod::CustomQuery(query) => query.clone(),
+    };
+    diesel_async::pooled_connection::RecyclingMethod::CustomFunction(Box::new(move |conn| {
+        let query = query.clone();
+        async move {
+            let res = conn.batch_execute(&query).await;
+            if let Err(e) = &res {
+                warn!("Discarding pooled connection that failed recycling: {e}");
+            }
+            res
+        }
+        .boxed()
+    }))
+}
+
 impl std::fmt::Debug for NostrPostgres {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("NostrPostgres")
-            .field("pool", &self.pool.status())
+            .field("pool", &self.pool_status())
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_the_application_name_to_urls() {
+        assert_eq!(
+            with_application_name("postgres://db/relay", "relay"),
+            "postgres://db/relay?application_name=relay"
+        );
+        assert_eq!(
+            with_application_name("postgresql://db/relay?sslmode=disable", "relay"),
+            "postgresql://db/relay?sslmode=disable&application_name=relay"
+        );
+        assert_eq!(
+            with_application_name("postgres://db/relay", "my relay/1.0 ü"),
+            "postgres://db/relay?application_name=my%20relay%2F1.0%20%C3%BC"
+        );
+    }
+
+    #[test]
+    fn appends_the_application_name_to_key_value_strings() {
+        assert_eq!(
+            with_application_name("host=db dbname=relay", "relay"),
+            "host=db dbname=relay application_name='relay'"
+        );
+        assert_eq!(
+            with_application_name("host=db", r"it's a\relay"),
+            r"host=db application_name='it\'s a\\relay'"
+        );
+    }
+
+    #[test]
+    fn keeps_a_configured_application_name() {
+        for connection_string in [
+            "postgres://db/relay?application_name=mine",
+            "host=db application_name=mine",
+        ] {
+            assert_eq!(
+                with_application_name(connection_string, "relay"),
+                connection_string
+            );
+        }
+        assert_eq!(
+            with_application_name("postgres://db/relay", ""),
+            "postgres://db/relay"
+        );
+    }
+}
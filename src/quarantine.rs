@@ -0,0 +1,61 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use nostr::event::EventId;
+use tracing::warn;
+
+use crate::postgres::hex;
+
+/// Number of quarantined event ids kept, the oldest are dropped beyond it
+const QUARANTINE_CAPACITY: usize = 1000;
+
+/// Tracks the stored events whose payloads failed to decode
+#[derive(Debug, Default)]
+pub(crate) struct Quarantine {
+    failures: AtomicU64,
+    ids: Option<Mutex<VecDeque<EventId>>>,
+}
+
+impl Quarantine {
+    /// a quarantine that only counts failures unless `keep_ids`
+    pub fn new(keep_ids: bool) -> Self {
+        Self {
+            failures: AtomicU64::new(0),
+            ids: keep_ids.then(Mutex::default),
+        }
+    }
+
+    /// logs and counts the failure to decode the event `id`, keeping the id if enabled
+    pub fn record(&self, id: &[u8], error: &dyn Display) {
+        warn!("Undecodable payload of event {}: {error}", hex(id));
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        let (Some(ids), Ok(id)) = (&self.ids, EventId::from_slice(id)) else {
+            return;
+        };
+        let mut ids = ids.lock().unwrap_or_else(|e| e.into_inner());
+        if ids.contains(&id) {
+            return;
+        }
+        if ids.len() == QUARANTINE_CAPACITY {
+            ids.pop_front();
+        }
+        ids.push_back(id);
+    }
+
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// the kept ids, oldest first
+    pub fn ids(&self) -> Vec<EventId> {
+        self.ids
+            .as_ref()
+            .map(|ids| {
+                let ids = ids.lock().unwrap_or_else(|e| e.into_inner());
+                ids.iter().copied().collect()
+            })
+            .unwrap_or_default()
+    }
+}
@@ -1,86 +1,376 @@
-use diesel::dsl::{AsSelect, Eq, Filter as DieselFilter, IntoBoxed, LeftJoin, SqlTypeOf};
+use std::collections::BTreeSet;
+
+use diesel::dsl::{AsExprOf, exists, sql};
+use diesel::dsl::{AsSelect, SqlTypeOf};
 use diesel::expression::SqlLiteral;
+use diesel::expression::UncheckedBind;
+use diesel::expression::ValidGrouping;
+use diesel::pg::{Pg, PgQueryBuilder};
 use diesel::prelude::*;
-use diesel::sql_types::Binary;
+use diesel::query_builder::{AstPass, Query, QueryBuilder, QueryFragment, QueryId};
+use diesel::sql_types::{Array, BigInt, Binary, Bool, Text};
 use nostr::event::*;
-use nostr::filter::Filter;
+use nostr::filter::{Filter, SingleLetterTag};
+use nostr::key::PublicKey;
 use nostr_database::*;
 
 use super::model::EventDb;
 use super::schema::postgres::{event_tags, events};
 
-// filter type of a join query.
-type QuerySetJoinTypeDb<'a, DB> = IntoBoxed<
-    'a,
-    DieselFilter<
-        LeftJoin<events::table, event_tags::table>,
-        Eq<event_tags::event_id, SqlLiteral<Binary>>,
-    >,
-    DB,
->;
+// filter type of a query on the events table.
+type FilterQueryTypeDb<'a, DB> = events::BoxedQuery<'a, DB>;
 type SelectEventTypeDb<DB> = SqlTypeOf<AsSelect<EventDb, DB>>;
 type BoxedEventQueryDb<'a, DB> = events::BoxedQuery<'a, DB, SelectEventTypeDb<DB>>;
 
-type QuerySetJoinType<'a> = QuerySetJoinTypeDb<'a, diesel::pg::Pg>;
+type FilterQueryType<'a> = FilterQueryTypeDb<'a, diesel::pg::Pg>;
 type BoxedEventQuery<'a> = BoxedEventQueryDb<'a, diesel::pg::Pg>;
 
-pub fn build_filter_query<'a>(filter: Filter) -> QuerySetJoinType<'a> {
+pub fn build_filter_query<'a>(filter: Filter) -> FilterQueryType<'a> {
+    build_query(filter, false, &BTreeSet::new())
+}
+
+/// like [`build_filter_query`], but also matching soft-deleted events
+pub fn build_filter_query_with_deleted<'a>(filter: Filter) -> FilterQueryType<'a> {
+    build_query(filter, true, &BTreeSet::new())
+}
+
+/// like [`build_filter_query`], but matching the values of the `folded` tags
+/// case-insensitively
+pub fn build_filter_query_folded<'a>(
+    filter: Filter,
+    folded: &BTreeSet<SingleLetterTag>,
+) -> FilterQueryType<'a> {
+    build_query(filter, false, folded)
+}
+
+/// the events matching `filter`, newest first up to its limit
+///
+/// Tags are matched with `EXISTS` as in [`filter_conditions`], so no join or `DISTINCT` is
+/// needed and Postgres can stop at the limit while walking an index by creation time.
+fn build_query<'a>(
+    filter: Filter,
+    with_deleted: bool,
+    folded: &BTreeSet<SingleLetterTag>,
+) -> FilterQueryType<'a> {
+    let limit = filter.limit;
     let mut query = events::table
-        .distinct()
-        .left_join(event_tags::table)
-        .filter(events::deleted.eq(false))
+        .filter(filter_conditions_folded(filter, with_deleted, folded))
         .order_by(events::created_at.desc())
         .into_boxed();
-
-    if let Some(limit) = filter.limit {
+    if let Some(limit) = limit {
         query = query.limit(limit as i64);
     }
+    query
+}
 
-    if !has_filters(&filter) {
-        return query;
-    }
+/// A condition on the events table
+pub type EventCondition<'a> = Box<dyn BoxableExpression<events::table, Pg, SqlType = Bool> + 'a>;
+
+/// the conditions of `filter` on the events table, without joining the tags
+///
+/// Tags are matched with `EXISTS`, so rows stay unique without `DISTINCT` and the condition
+/// can be used in grouped or aggregated queries. A tag with an empty value set matches no
+/// event, as in [`Filter::match_event`]. The limit of the filter is ignored. Statements
+/// containing the condition are not cached, see [`Uncached`].
+pub fn filter_conditions<'a>(filter: Filter, with_deleted: bool) -> EventCondition<'a> {
+    filter_conditions_folded(filter, with_deleted, &BTreeSet::new())
+}
 
-    if let Some(ids) = filter.ids.clone() {
+/// like [`filter_conditions`], but matching the values of the `folded` tags
+/// case-insensitively
+pub fn filter_conditions_folded<'a>(
+    filter: Filter,
+    with_deleted: bool,
+    folded: &BTreeSet<SingleLetterTag>,
+) -> EventCondition<'a> {
+    let mut condition: EventCondition<'a> = if with_deleted {
+        Box::new(sql::<Bool>("TRUE"))
+    } else {
+        Box::new(events::deleted.eq(false))
+    };
+
+    if let Some(ids) = filter.ids {
         let values = ids
             .iter()
             .map(|id| id.as_bytes().to_vec())
             .collect::<Vec<_>>();
-        query = query.filter(events::id.eq_any(values));
+        condition = Box::new(condition.and(events::id.eq_any(values)));
     }
 
-    if let Some(authors) = filter.authors.clone() {
+    if let Some(authors) = filter.authors {
         let values = authors
             .iter()
             .map(|a| a.as_bytes().to_vec())
             .collect::<Vec<_>>();
-        query = query.filter(events::pubkey.eq_any(values));
+        condition = Box::new(condition.and(events::pubkey.eq_any(values)));
     }
 
-    if let Some(kinds) = filter.kinds.clone() {
-        let values = kinds.iter().map(|k| k.as_u16() as i64).collect::<Vec<_>>();
-        query = query.filter(events::kind.eq_any(values));
+    if let Some(kinds) = filter.kinds {
+        condition = Box::new(condition.and(kinds_condition(&kinds)));
     }
 
     if let Some(since) = filter.since {
-        query = query.filter(events::created_at.ge(since.as_u64() as i64));
+        condition = Box::new(condition.and(events::created_at.ge(since.as_u64() as i64)));
     }
 
     if let Some(until) = filter.until {
-        query = query.filter(events::created_at.le(until.as_u64() as i64));
+        condition = Box::new(condition.and(events::created_at.le(until.as_u64() as i64)));
     }
 
-    if !filter.generic_tags.is_empty() {
-        for (tag, values) in filter.generic_tags.into_iter() {
-            let values = values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
-            query = query.filter(
-                event_tags::tag
-                    .eq(tag.to_string())
-                    .and(event_tags::tag_value.eq_any(values)),
+    for (tag, values) in filter.generic_tags {
+        if values.is_empty() {
+            condition = Box::new(condition.and(sql::<Bool>("FALSE")));
+            continue;
+        }
+        if folded.contains(&tag) {
+            condition = Box::new(
+                condition.and(exists(
+                    event_tags::table
+                        .filter(event_tags::event_id.eq(events::id))
+                        .filter(event_tags::tag.eq(tag.to_string()))
+                        .filter(lowercase_matches(values)),
+                )),
             );
+            continue;
         }
+        let values = values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        condition = Box::new(
+            condition.and(exists(
+                event_tags::table
+                    .filter(event_tags::event_id.eq(events::id))
+                    .filter(event_tags::tag.eq(tag.to_string()))
+                    .filter(event_tags::tag_value.eq_any(values)),
+            )),
+        );
     }
 
-    query
+    Box::new(Uncached(condition))
+}
+
+/// the tag value matches one of `values` ignoring case, as `lower(tag_value)` to use the
+/// `event_tags_tag_lower_value` index
+///
+/// Both sides are lowercased by Postgres, since `lower` depends on the collation, e.g. it
+/// leaves `É` alone in the C locale; lowercasing the values in Rust would miss identical text.
+fn lowercase_matches<'a, QS>(
+    values: BTreeSet<String>,
+) -> Box<dyn BoxableExpression<QS, Pg, SqlType = Bool> + 'a>
+where
+    QS: 'a,
+{
+    Box::new(
+        sql::<Bool>("lower(\"event_tags\".\"tag_value\") = ANY (SELECT lower(v) FROM unnest(")
+            .bind::<Array<Text>, _>(values.into_iter().collect::<Vec<_>>())
+            .sql(") v)"),
+    )
+}
+
+/// Runs of at least this many consecutive kinds are matched with `BETWEEN`
+const KIND_RANGE_MIN: usize = 16;
+
+/// matches `kinds`, with `BETWEEN` for long runs of consecutive kinds and `= ANY` for the
+/// rest, so e.g. all addressable kinds don't bind 10000 array elements
+fn kinds_condition<'a, QS>(
+    kinds: &BTreeSet<Kind>,
+) -> Box<dyn BoxableExpression<QS, Pg, SqlType = Bool> + 'a>
+where
+    QS: 'a,
+    events::kind: SelectableExpression<QS>,
+{
+    let (ranges, singles) = kind_ranges(kinds);
+    let mut ranges = ranges.into_iter();
+    let mut condition: Box<dyn BoxableExpression<QS, Pg, SqlType = Bool> + 'a> = match ranges.next()
+    {
+        Some((first, last)) if singles.is_empty() => Box::new(events::kind.between(first, last)),
+        Some((first, last)) => Box::new(
+            events::kind
+                .eq_any(singles)
+                .or(events::kind.between(first, last)),
+        ),
+        None => Box::new(events::kind.eq_any(singles)),
+    };
+    for (first, last) in ranges {
+        condition = Box::new(condition.or(events::kind.between(first, last)));
+    }
+    condition
+}
+
+/// splits the sorted `kinds` into runs of at least [`KIND_RANGE_MIN`] consecutive kinds and
+/// the remaining single kinds
+fn kind_ranges(kinds: &BTreeSet<Kind>) -> (Vec<(i64, i64)>, Vec<i64>) {
+    let mut ranges = Vec::new();
+    let mut singles = Vec::new();
+    let mut run: Vec<i64> = Vec::new();
+    let mut flush = |run: &mut Vec<i64>| {
+        if run.len() >= KIND_RANGE_MIN {
+            ranges.push((run[0], run[run.len() - 1]));
+        } else {
+            singles.extend_from_slice(run);
+        }
+        run.clear();
+    };
+    for kind in kinds.iter().map(|k| k.as_u16() as i64) {
+        if run.last().is_some_and(|last| last + 1 != kind) {
+            flush(&mut run);
+        }
+        run.push(kind);
+    }
+    flush(&mut run);
+    (ranges, singles)
+}
+
+/// matches events of `authors` or delegated by them with an indexed NIP-26 `delegation` tag,
+/// whose value is the delegator
+pub fn authored_or_delegated<'a>(authors: &[PublicKey]) -> EventCondition<'a> {
+    let pubkeys = authors
+        .iter()
+        .map(|a| a.as_bytes().to_vec())
+        .collect::<Vec<_>>();
+    let delegators = authors.iter().map(|a| a.to_hex()).collect::<Vec<_>>();
+    Box::new(
+        events::pubkey.eq_any(pubkeys).or(exists(
+            event_tags::table
+                .filter(event_tags::event_id.eq(events::id))
+                .filter(event_tags::tag.eq("delegation"))
+                .filter(event_tags::tag_value.eq_any(delegators)),
+        )),
+    )
+}
+
+/// The events matching a filter, at most `per_author` newest ones of each author and `limit`
+/// in total, as `(id, payload)` rows
+pub struct PerAuthor<'a> {
+    ranked: events::BoxedQuery<'a, Pg, (Binary, Binary, BigInt, BigInt)>,
+    per_author: i64,
+    limit: i64,
+}
+
+/// ranks the events matching `filter` per author, newest first and by id within the same
+/// second, keeping the first `per_author` of each and `limit` of all; the values of the
+/// `folded` tags are matched case-insensitively
+pub fn per_author<'a>(
+    filter: Filter,
+    per_author: usize,
+    limit: usize,
+    folded: &BTreeSet<SingleLetterTag>,
+) -> PerAuthor<'a> {
+    PerAuthor {
+        ranked: events::table
+            .select((
+                events::id,
+                events::payload,
+                events::created_at,
+                sql::<BigInt>(
+                    "row_number() OVER (PARTITION BY events.pubkey \
+                     ORDER BY events.created_at DESC, events.id)",
+                ),
+            ))
+            .filter(filter_conditions_folded(filter, false, folded))
+            .into_boxed(),
+        per_author: per_author as i64,
+        limit: limit as i64,
+    }
+}
+
+impl QueryFragment<Pg> for PerAuthor<'_> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.push_sql("SELECT id, payload FROM (");
+        QueryFragment::<Pg>::walk_ast(&self.ranked, out.reborrow())?;
+        out.push_sql(") ranked (id, payload, created_at, position) WHERE position <= ");
+        out.push_bind_param::<BigInt, _>(&self.per_author)?;
+        out.push_sql(" ORDER BY created_at DESC, id LIMIT ");
+        out.push_bind_param::<BigInt, _>(&self.limit)
+    }
+}
+
+impl QueryId for PerAuthor<'_> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl Query for PerAuthor<'_> {
+    type SqlType = (Binary, Binary);
+}
+
+/// excludes events whose indexed NIP-40 `expiration` tag lies before `now`
+///
+/// Works on the tag table, so events are only excluded while the `expiration` tag is indexed.
+pub fn not_expired(
+    now: i64,
+) -> SqlLiteral<Bool, UncheckedBind<SqlLiteral<Bool>, AsExprOf<i64, BigInt>>> {
+    sql::<Bool>(NOT_EXPIRED_BEFORE)
+        .bind::<BigInt, _>(now)
+        .sql(NOT_EXPIRED_END)
+}
+
+/// the SQL of [`not_expired`] around its bind parameter, for raw queries
+pub(crate) const NOT_EXPIRED_BEFORE: &str = "NOT EXISTS (SELECT 1 FROM event_tags expiration \
+     WHERE expiration.event_id = events.id AND expiration.tag = 'expiration' \
+     AND CASE WHEN expiration.tag_value ~ '^[0-9]{1,18}$' \
+     THEN expiration.tag_value::bigint < ";
+pub(crate) const NOT_EXPIRED_END: &str = " ELSE FALSE END)";
+
+/// number of bind parameters of `query`, counted from its SQL with placeholders, so no
+/// values are rendered
+pub fn bind_count<Q>(query: &Q) -> usize
+where
+    Q: QueryFragment<Pg>,
+{
+    render(query).1
+}
+
+/// the SQL of `query` with placeholders and its number of bind parameters
+pub fn render<Q>(query: &Q) -> (String, usize)
+where
+    Q: QueryFragment<Pg>,
+{
+    let mut builder = PgQueryBuilder::default();
+    if query.to_sql(&mut builder, &Pg).is_err() {
+        return (String::new(), 0);
+    }
+    let sql = builder.finish();
+    // placeholders are numbered from $1, so the count is the highest number
+    let params = sql
+        .split('$')
+        .skip(1)
+        .filter_map(|s| {
+            let digits = s.bytes().take_while(u8::is_ascii_digit).count();
+            s[..digits].parse::<usize>().ok()
+        })
+        .max()
+        .unwrap_or(0);
+    (sql, params)
+}
+
+/// the fields set on `filter` with their number of values, e.g. `authors=2 kinds=1 #p=3
+/// limit`, without the values themselves
+pub fn filter_shape(filter: &Filter) -> String {
+    let mut shape = Vec::new();
+    if let Some(ids) = &filter.ids {
+        shape.push(format!("ids={}", ids.len()));
+    }
+    if let Some(authors) = &filter.authors {
+        shape.push(format!("authors={}", authors.len()));
+    }
+    if let Some(kinds) = &filter.kinds {
+        shape.push(format!("kinds={}", kinds.len()));
+    }
+    for (tag, values) in &filter.generic_tags {
+        shape.push(format!("#{tag}={}", values.len()));
+    }
+    if filter.search.is_some() {
+        shape.push("search".to_string());
+    }
+    if filter.since.is_some() {
+        shape.push("since".to_string());
+    }
+    if filter.until.is_some() {
+        shape.push("until".to_string());
+    }
+    if filter.limit.is_some() {
+        shape.push("limit".to_string());
+    }
+    shape.join(" ")
 }
 
 /// sets the given default limit on a Nostr filter if not set
@@ -99,13 +389,204 @@ pub fn event_by_id<'a>(event_id: &EventId) -> BoxedEventQuery<'a> {
         .into_boxed()
 }
 
-// determine if the filter has any filters set
-fn has_filters(filter: &Filter) -> bool {
-    filter.ids.is_some()
-        || filter.authors.is_some()
-        || filter.kinds.is_some()
-        || filter.since.is_some()
-        || filter.until.is_some()
-        || !filter.generic_tags.is_empty()
-        || filter.limit.is_some()
+/// Operations that can be attributed in `pg_stat_activity` and `pg_stat_statements`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Save,
+    EventById,
+    Count,
+    Query,
+    Delete,
+    Export,
+    NegentropyItems,
+    CountHll,
+    Archive,
+    Stats,
+}
+
+impl Operation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Save => "save",
+            Self::EventById => "event_by_id",
+            Self::Count => "count",
+            Self::Query => "query",
+            Self::Delete => "delete",
+            Self::Export => "export",
+            Self::NegentropyItems => "negentropy_items",
+            Self::CountHll => "count_hll",
+            Self::Archive => "archive",
+            Self::Stats => "stats",
+        }
+    }
+}
+
+/// A statement optionally prefixed with a `/* op:<operation> */` comment
+///
+/// The comment is built from [`Operation`] only, so it never contains user data.
+#[derive(Debug, Clone)]
+pub struct Tagged<Q> {
+    operation: Option<Operation>,
+    query: Q,
+}
+
+/// tags `query` with the operation if `enabled`
+pub fn tagged<Q>(query: Q, operation: Operation, enabled: bool) -> Tagged<Q> {
+    Tagged {
+        operation: enabled.then_some(operation),
+        query,
+    }
+}
+
+impl<Q> QueryFragment<Pg> for Tagged<Q>
+where
+    Q: QueryFragment<Pg>,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        if let Some(operation) = self.operation {
+            out.push_sql("/* op:");
+            out.push_sql(operation.as_str());
+            out.push_sql(" */ ");
+        }
+        self.query.walk_ast(out.reborrow())
+    }
+}
+
+impl<Q> QueryId for Tagged<Q> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<Q> Query for Tagged<Q>
+where
+    Q: Query,
+{
+    type SqlType = Q::SqlType;
+}
+
+/// A condition keeping the statement containing it out of the prepared statement cache
+///
+/// diesel caches statements without a static query id by their SQL, for as long as the
+/// connection lives and without a bound: it offers only an unbounded or no cache. The SQL of
+/// the filter conditions varies with the tags and kind ranges of the filter, so caching it
+/// would grow the cache with every new shape; such statements use the unnamed prepared
+/// statement instead.
+#[derive(Debug, Clone)]
+pub struct Uncached<E>(E);
+
+impl<E> Expression for Uncached<E>
+where
+    E: Expression,
+{
+    type SqlType = E::SqlType;
+}
+
+impl<E> QueryFragment<Pg> for Uncached<E>
+where
+    E: QueryFragment<Pg>,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+        self.0.walk_ast(out.reborrow())
+    }
+}
+
+impl<E> QueryId for Uncached<E> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<E, QS> AppearsOnTable<QS> for Uncached<E> where E: AppearsOnTable<QS> {}
+
+impl<E, QS> SelectableExpression<QS> for Uncached<E> where E: SelectableExpression<QS> {}
+
+impl<E, GB> ValidGrouping<GB> for Uncached<E>
+where
+    E: ValidGrouping<GB>,
+{
+    type IsAggregate = E::IsAggregate;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift64*, enough to vary the kind sets deterministically
+    fn next(state: &mut u64) -> u64 {
+        *state ^= *state >> 12;
+        *state ^= *state << 25;
+        *state ^= *state >> 27;
+        state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// scattered kinds and runs around [`KIND_RANGE_MIN`], some adjacent or overlapping
+    fn random_kinds(state: &mut u64) -> BTreeSet<Kind> {
+        let mut kinds = BTreeSet::new();
+        for _ in 0..next(state) % 8 {
+            let start = next(state) % 400;
+            let len = match next(state) % 4 {
+                0 => KIND_RANGE_MIN as u64 - 1,
+                1 => KIND_RANGE_MIN as u64,
+                2 => KIND_RANGE_MIN as u64 + 1,
+                _ => next(state) % 60,
+            };
+            kinds.extend((start..start + len).map(|k| Kind::from(k as u16)));
+        }
+        for _ in 0..next(state) % 30 {
+            kinds.insert(Kind::from((next(state) % 500) as u16));
+        }
+        if next(state).is_multiple_of(10) {
+            kinds.extend([Kind::from(0), Kind::from(u16::MAX)]);
+        }
+        kinds
+    }
+
+    #[test]
+    fn kind_ranges_match_the_same_kinds_as_the_kinds() {
+        let mut state = 0x9e37_79b9_7f4a_7c15;
+        for _ in 0..500 {
+            let kinds = random_kinds(&mut state);
+            let (ranges, singles) = kind_ranges(&kinds);
+            for (first, last) in &ranges {
+                assert!((last - first + 1) as usize >= KIND_RANGE_MIN, "{kinds:?}");
+            }
+            // every generated kind is below 600, or one of the extremes
+            for kind in (0..600).chain([u16::MAX - 1, u16::MAX]) {
+                let k = kind as i64;
+                let matched = singles.contains(&k)
+                    || ranges
+                        .iter()
+                        .any(|(first, last)| (*first..=*last).contains(&k));
+                assert_eq!(
+                    matched,
+                    kinds.contains(&Kind::from(kind)),
+                    "kind {kind} of {kinds:?}"
+                );
+            }
+            let covered: usize = singles.len()
+                + ranges
+                    .iter()
+                    .map(|(first, last)| (last - first + 1) as usize)
+                    .sum::<usize>();
+            assert_eq!(covered, kinds.len(), "{kinds:?}");
+        }
+    }
+
+    #[test]
+    fn kind_ranges_split_at_the_minimum_run() {
+        let run = |start: u16, len: u16| (start..start + len).map(Kind::from);
+        let short: BTreeSet<Kind> = run(100, KIND_RANGE_MIN as u16 - 1).collect();
+        let (ranges, singles) = kind_ranges(&short);
+        assert!(ranges.is_empty());
+        assert_eq!(singles.len(), KIND_RANGE_MIN - 1);
+
+        let long: BTreeSet<Kind> = run(100, KIND_RANGE_MIN as u16)
+            .chain([Kind::from(7)])
+            .collect();
+        let (ranges, singles) = kind_ranges(&long);
+        assert_eq!(ranges, [(100, 100 + KIND_RANGE_MIN as i64 - 1)]);
+        assert_eq!(singles, [7]);
+
+        assert_eq!(kind_ranges(&BTreeSet::new()), (Vec::new(), Vec::new()));
+    }
 }
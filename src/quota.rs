@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+
+use diesel::QueryableByName;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Bytea};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use nostr::key::PublicKey;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::model::EventDb;
+use crate::query::{Operation, tagged};
+use crate::schema::postgres::{author_usage, event_tags};
+
+/// marks deleted the oldest live events of the author `$1` that must go for `$2` more events
+/// and `$3` more bytes to be freed: those with fewer events, or fewer bytes, before them
+const EVICT: &str = "WITH ranked AS (\
+     SELECT id, count(*) OVER w - 1 AS events_before, \
+     sum(octet_length(payload)) OVER w - octet_length(payload) AS bytes_before \
+     FROM events WHERE pubkey = $1 AND NOT deleted \
+     WINDOW w AS (ORDER BY created_at, id ROWS UNBOUNDED PRECEDING)\
+     ) UPDATE events SET deleted = TRUE WHERE pubkey = $1 AND id IN (\
+     SELECT id FROM ranked WHERE events_before < $2 OR bytes_before < $3) RETURNING id";
+
+#[derive(QueryableByName)]
+struct Evicted {
+    #[diesel(sql_type = Bytea)]
+    id: Vec<u8>,
+}
+
+/// What [`save_event`](nostr_database::NostrDatabase::save_event) does with an event of an
+/// author at their [`Quota`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuotaMode {
+    /// Reject the event
+    #[default]
+    Reject,
+    /// Mark the author's oldest events deleted until the event fits
+    Evict,
+}
+
+/// Bounds on the events each author stores, see
+/// [`NostrPostgresBuilder::quota`](crate::NostrPostgresBuilder::quota)
+///
+/// Without bounds nothing is enforced. Only live events count, not deleted or archived ones,
+/// by the size of their stored payload.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Quota {
+    max_events: Option<u64>,
+    max_bytes: Option<u64>,
+    mode: QuotaMode,
+    exempt: HashSet<PublicKey>,
+}
+
+impl Quota {
+    /// A quota without bounds, rejecting once bounds are set
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store at most `events` events per author
+    pub fn max_events(mut self, events: u64) -> Self {
+        self.max_events = Some(events);
+        self
+    }
+
+    /// Store at most `bytes` bytes of payload per author
+    pub fn max_bytes(mut self, bytes: u64) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    /// What happens to events beyond the quota (default [`QuotaMode::Reject`])
+    pub fn mode(mut self, mode: QuotaMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Authors without a quota, e.g. the relay's own keys or paying users
+    pub fn exempt<I>(mut self, authors: I) -> Self
+    where
+        I: IntoIterator<Item = PublicKey>,
+    {
+        self.exempt.extend(authors);
+        self
+    }
+
+    /// Whether `author` is exempt
+    pub fn is_exempt(&self, author: &PublicKey) -> bool {
+        self.exempt.contains(author)
+    }
+
+    /// whether the saves of `author` are checked, with bounds and not exempt
+    pub(crate) fn limits(&self, author: &PublicKey) -> bool {
+        (self.max_events.is_some() || self.max_bytes.is_some()) && !self.is_exempt(author)
+    }
+}
+
+/// What an author stores, see [`NostrPostgres::quota_usage`](crate::NostrPostgres::quota_usage)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    /// Live events
+    pub events: u64,
+    /// Bytes of their payloads
+    pub bytes: u64,
+}
+
+/// makes room for `event` within the quota of its author, evicting their oldest events if
+/// configured; false if it doesn't fit. Locks the usage row of the author, so concurrent
+/// saves of the same author are checked one after the other.
+pub(crate) async fn make_room(
+    db: &mut AsyncPgConnection,
+    quota: &Quota,
+    event: &EventDb,
+    drop_tags: bool,
+    statement_tags: bool,
+) -> QueryResult<bool> {
+    if let Ok(author) = PublicKey::from_slice(&event.pubkey)
+        && !quota.limits(&author)
+    {
+        return Ok(true);
+    }
+    let (events, bytes): (i64, i64) = tagged(
+        diesel::insert_into(author_usage::table)
+            .values((
+                author_usage::pubkey.eq(&event.pubkey),
+                author_usage::events.eq(0),
+                author_usage::bytes.eq(0),
+            ))
+            .on_conflict(author_usage::pubkey)
+            .do_update()
+            .set(author_usage::events.eq(author_usage::events))
+            .returning((author_usage::events, author_usage::bytes)),
+        Operation::Save,
+        statement_tags,
+    )
+    .get_result(db)
+    .await?;
+    let size = event.payload.len() as i64;
+    let max_events = quota
+        .max_events
+        .map_or(i64::MAX, |m| m.min(i64::MAX as u64) as i64);
+    let max_bytes = quota
+        .max_bytes
+        .map_or(i64::MAX, |m| m.min(i64::MAX as u64) as i64);
+    let excess_events = (events + 1).saturating_sub(max_events).max(0);
+    let excess_bytes = bytes.saturating_add(size).saturating_sub(max_bytes).max(0);
+    if excess_events == 0 && excess_bytes == 0 {
+        return Ok(true);
+    }
+    // an event that can't fit even alone is rejected without evicting anything
+    if quota.mode == QuotaMode::Reject || max_events == 0 || size > max_bytes {
+        return Ok(false);
+    }
+    let evicted: Vec<Evicted> = tagged(
+        diesel::sql_query(EVICT)
+            .bind::<Bytea, _>(&event.pubkey)
+            .bind::<BigInt, _>(excess_events)
+            .bind::<BigInt, _>(excess_bytes),
+        Operation::Save,
+        statement_tags,
+    )
+    .load(db)
+    .await?;
+    let ids: Vec<Vec<u8>> = evicted.into_iter().map(|e| e.id).collect();
+    debug!("Evicted {} events to make room within the quota", ids.len());
+    if drop_tags {
+        tagged(
+            diesel::delete(event_tags::table.filter(event_tags::event_id.eq_any(&ids))),
+            Operation::Save,
+            statement_tags,
+        )
+        .execute(db)
+        .await?;
+    }
+    Ok(true)
+}
+
+/// the usage of `author`, zero without stored events
+pub(crate) async fn usage(
+    db: &mut AsyncPgConnection,
+    author: &PublicKey,
+    statement_tags: bool,
+) -> QueryResult<QuotaUsage> {
+    let usage: Option<(i64, i64)> = tagged(
+        author_usage::table
+            .select((author_usage::events, author_usage::bytes))
+            .filter(author_usage::pubkey.eq(author.as_bytes().to_vec())),
+        Operation::Stats,
+        statement_tags,
+    )
+    .get_result(db)
+    .await
+    .optional()?;
+    let (events, bytes) = usage.unwrap_or_default();
+    Ok(QuotaUsage {
+        events: events.max(0) as u64,
+        bytes: bytes.max(0) as u64,
+    })
+}
@@ -0,0 +1,223 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use nostr::key::PublicKey;
+
+/// Number of authors tracked by default, see [`RateLimit::max_tracked`]
+const DEFAULT_MAX_TRACKED: usize = 10_000;
+
+/// Bounds on how fast each author saves events, see
+/// [`NostrPostgresBuilder::rate_limit`](crate::NostrPostgresBuilder::rate_limit)
+///
+/// A token bucket per author: it holds up to `burst` events and refills at `per_second`, so
+/// an author may save `burst` events at once and `per_second` sustained. The state is kept in
+/// memory by each instance, clones included, so the limit applies per process, not across
+/// instances sharing the database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    burst: u32,
+    per_second: f64,
+    allowed: HashSet<PublicKey>,
+    max_tracked: usize,
+}
+
+impl RateLimit {
+    /// Allow `burst` events at once and `per_second` events per second sustained
+    pub fn new(burst: u32, per_second: f64) -> Self {
+        Self {
+            burst,
+            per_second,
+            allowed: HashSet::new(),
+            max_tracked: DEFAULT_MAX_TRACKED,
+        }
+    }
+
+    /// Authors without a limit, e.g. the relay's own keys
+    pub fn allow<I>(mut self, authors: I) -> Self
+    where
+        I: IntoIterator<Item = PublicKey>,
+    {
+        self.allowed.extend(authors);
+        self
+    }
+
+    /// Track the buckets of at most `authors` authors (default 10000)
+    ///
+    /// Bounds the memory: beyond it the least recently active author is forgotten and starts
+    /// again with a full bucket.
+    pub fn max_tracked(mut self, authors: usize) -> Self {
+        self.max_tracked = authors.max(1);
+        self
+    }
+
+    /// whether the bucket ever refills and holds a token
+    pub(crate) fn is_valid(&self) -> bool {
+        self.burst >= 1 && self.per_second.is_finite() && self.per_second > 0.0
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    /// the position in [`Buckets::recent`]
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<PublicKey, Bucket>,
+    /// the tracked authors by their last activity, oldest first
+    recent: BTreeMap<u64, PublicKey>,
+    next: u64,
+}
+
+/// Takes a token from the bucket of the author of each saved event, if configured
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    limit: Option<RateLimit>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// whether `author` may save one more event now, taking a token if so
+    pub fn try_acquire(&self, author: &PublicKey) -> bool {
+        let Some(limit) = &self.limit else {
+            return true;
+        };
+        if limit.allowed.contains(author) {
+            return true;
+        }
+        let now = Instant::now();
+        let mut state = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let Buckets {
+            buckets,
+            recent,
+            next,
+        } = &mut *state;
+        let used = *next;
+        *next += 1;
+        let bucket = buckets.entry(*author).or_insert_with(|| Bucket {
+            tokens: f64::from(limit.burst),
+            refilled: now,
+            used,
+        });
+        recent.remove(&bucket.used);
+        recent.insert(used, *author);
+        bucket.used = used;
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(f64::from(limit.burst));
+        bucket.refilled = now;
+        let acquired = bucket.tokens >= 1.0;
+        if acquired {
+            bucket.tokens -= 1.0;
+        }
+        while buckets.len() > limit.max_tracked {
+            let Some((_, oldest)) = recent.pop_first() else {
+                break;
+            };
+            buckets.remove(&oldest);
+        }
+        acquired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use nostr::Keys;
+
+    use super::*;
+
+    fn author() -> PublicKey {
+        Keys::generate().public_key()
+    }
+
+    /// moves the last refill of the bucket of `author` back by `by`, as if time passed
+    fn age(limiter: &RateLimiter, author: &PublicKey, by: Duration) {
+        let mut state = limiter.buckets.lock().unwrap();
+        let bucket = state.buckets.get_mut(author).unwrap();
+        bucket.refilled -= by;
+    }
+
+    fn tracked(limiter: &RateLimiter, author: &PublicKey) -> bool {
+        limiter.buckets.lock().unwrap().buckets.contains_key(author)
+    }
+
+    #[test]
+    fn unlimited_without_a_limit() {
+        let limiter = RateLimiter::new(None);
+        let author = author();
+        assert!((0..100).all(|_| limiter.try_acquire(&author)));
+    }
+
+    #[test]
+    fn rejects_beyond_the_burst() {
+        let limiter = RateLimiter::new(Some(RateLimit::new(3, 0.01)));
+        let (a, b) = (author(), author());
+        assert!((0..3).all(|_| limiter.try_acquire(&a)));
+        assert!(!limiter.try_acquire(&a));
+        // each author has their own bucket
+        assert!(limiter.try_acquire(&b));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = RateLimiter::new(Some(RateLimit::new(2, 1.0)));
+        let author = author();
+        assert!(limiter.try_acquire(&author));
+        assert!(limiter.try_acquire(&author));
+        assert!(!limiter.try_acquire(&author));
+        age(&limiter, &author, Duration::from_millis(1100));
+        assert!(limiter.try_acquire(&author));
+        assert!(!limiter.try_acquire(&author));
+        // never beyond the burst, however long the author was idle
+        age(&limiter, &author, Duration::from_secs(60));
+        assert!(limiter.try_acquire(&author));
+        assert!(limiter.try_acquire(&author));
+        assert!(!limiter.try_acquire(&author));
+    }
+
+    #[test]
+    fn allowed_authors_are_never_limited() {
+        let allowed = author();
+        let limiter = RateLimiter::new(Some(RateLimit::new(1, 0.01).allow([allowed])));
+        assert!((0..100).all(|_| limiter.try_acquire(&allowed)));
+        assert!(!tracked(&limiter, &allowed));
+    }
+
+    #[test]
+    fn forgets_the_least_recently_active_author() {
+        let limiter = RateLimiter::new(Some(RateLimit::new(1, 0.01).max_tracked(2)));
+        let (a, b, c) = (author(), author(), author());
+        assert!(limiter.try_acquire(&a));
+        assert!(limiter.try_acquire(&b));
+        // a is active again, so b is the least recently active one
+        assert!(!limiter.try_acquire(&a));
+        assert!(limiter.try_acquire(&c));
+        assert!(tracked(&limiter, &a));
+        assert!(!tracked(&limiter, &b));
+        assert!(tracked(&limiter, &c));
+        // forgotten, b starts again with a full bucket
+        assert!(limiter.try_acquire(&b));
+        assert!(!tracked(&limiter, &a));
+    }
+
+    #[test]
+    fn validates_the_limit() {
+        assert!(RateLimit::new(1, 0.5).is_valid());
+        assert!(!RateLimit::new(0, 1.0).is_valid());
+        assert!(!RateLimit::new(1, 0.0).is_valid());
+        assert!(!RateLimit::new(1, f64::NAN).is_valid());
+        assert!(!RateLimit::new(1, f64::INFINITY).is_valid());
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use nostr::Timestamp;
+use nostr::event::{Event, EventId};
+use nostr::filter::Filter;
+use nostr_database::{DatabaseError, FlatBufferDecode};
+use tracing::debug;
+
+use crate::model::EventDb;
+use crate::postgres::NostrPostgres;
+use crate::query::build_filter_query_with_deleted;
+use crate::schema::postgres::events;
+
+/// First time window compared at once
+const INITIAL_WINDOW: u64 = 24 * 60 * 60;
+/// Windows with fewer ids than this grow, windows with more than ten times as many shrink
+const TARGET_IDS: usize = 10_000;
+/// Ids per statement when fetching or updating events
+const CHUNK: usize = 10_000;
+
+/// Outcome of [`NostrPostgres::reconcile`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReconcileReport {
+    /// Events copied from this instance to the other one
+    pub copied_to_other: u64,
+    /// Events copied from the other instance to this one
+    pub copied_from_other: u64,
+    /// Events marked deleted on the other instance because they are deleted here
+    pub deleted_on_other: u64,
+    /// Events marked deleted here because they are deleted on the other instance
+    pub deleted_here: u64,
+    /// Events that could not be copied because their payload can't be decoded
+    pub failed: Vec<EventId>,
+}
+
+/// compares both instances in ascending time windows and copies what's missing
+pub(crate) async fn reconcile(
+    db: &NostrPostgres,
+    other: &NostrPostgres,
+    mut filter: Filter,
+) -> Result<ReconcileReport, DatabaseError> {
+    filter.limit = None;
+    let mut report = ReconcileReport::default();
+    let end = filter.until.unwrap_or_else(Timestamp::now).as_u64();
+    let start = match filter.since {
+        Some(since) => Some(since.as_u64()),
+        None => match (oldest(db).await?, oldest(other).await?) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        },
+    };
+    let Some(mut start) = start else {
+        return Ok(report);
+    };
+
+    let mut window = INITIAL_WINDOW;
+    while start <= end {
+        let window_end = start.saturating_add(window - 1).min(end);
+        let window_filter = filter
+            .clone()
+            .since(Timestamp::from(start))
+            .until(Timestamp::from(window_end));
+        let here = states(db, window_filter.clone()).await?;
+        let there = states(other, window_filter).await?;
+
+        let mut missing_there = Vec::new();
+        let mut delete_there = Vec::new();
+        for (id, deleted) in here.iter() {
+            match there.get(id) {
+                None => missing_there.push(id.clone()),
+                Some(false) if *deleted => delete_there.push(id.clone()),
+                Some(_) => {}
+            }
+        }
+        let mut missing_here = Vec::new();
+        let mut delete_here = Vec::new();
+        for (id, deleted) in there.iter() {
+            match here.get(id) {
+                None => missing_here.push(id.clone()),
+                Some(false) if *deleted => delete_here.push(id.clone()),
+                Some(_) => {}
+            }
+        }
+
+        report.copied_to_other += copy(db, other, missing_there, &mut report.failed).await?;
+        report.copied_from_other += copy(other, db, missing_here, &mut report.failed).await?;
+        report.deleted_on_other += mark_deleted(other, delete_there).await?;
+        report.deleted_here += mark_deleted(db, delete_here).await?;
+        debug!("Reconciled events up to {window_end}: {report:?}");
+
+        let ids = here.len().max(there.len());
+        if ids < TARGET_IDS {
+            window = window.saturating_mul(2);
+        } else if ids > TARGET_IDS * 10 {
+            window = (window / 2).max(1);
+        }
+        start = window_end + 1;
+    }
+    Ok(report)
+}
+
+/// creation time of the oldest stored event, deleted or not
+async fn oldest(db: &NostrPostgres) -> Result<Option<u64>, DatabaseError> {
+    let mut conn = db.get_connection().await?;
+    let oldest: Option<i64> = events::table
+        .select(diesel::dsl::min(events::created_at))
+        .get_result(&mut conn)
+        .await
+        .map_err(DatabaseError::backend)?;
+    Ok(oldest.map(|t| t.max(0) as u64))
+}
+
+/// ids of the matching events and whether they are deleted
+async fn states(
+    db: &NostrPostgres,
+    filter: Filter,
+) -> Result<HashMap<Vec<u8>, bool>, DatabaseError> {
+    let mut conn = db.get_connection().await?;
+    let rows: Vec<(Vec<u8>, bool)> = build_filter_query_with_deleted(filter)
+        .select((events::id, events::deleted))
+        .load(&mut conn)
+        .await
+        .map_err(DatabaseError::backend)?;
+    Ok(rows.into_iter().collect())
+}
+
+/// copies the events with the given ids, keeping them deleted if they are deleted in `from`
+async fn copy(
+    from: &NostrPostgres,
+    to: &NostrPostgres,
+    ids: Vec<Vec<u8>>,
+    failed: &mut Vec<EventId>,
+) -> Result<u64, DatabaseError> {
+    let mut copied = 0;
+    for chunk in ids.chunks(CHUNK) {
+        let rows: Vec<EventDb> = {
+            let mut conn = from.get_connection().await?;
+            events::table
+                .filter(events::id.eq_any(chunk))
+                .select(EventDb::as_select())
+                .load(&mut conn)
+                .await
+                .map_err(DatabaseError::backend)?
+        };
+        let mut batch = Vec::with_capacity(rows.len());
+        let mut deleted = Vec::new();
+        for row in rows {
+            match Event::decode(&row.payload) {
+                Ok(event) => {
+                    batch.push(to.event_data(&event)?);
+                    if row.deleted {
+                        deleted.push(row.id);
+                    }
+                }
+                Err(_) => {
+                    if let Ok(id) = EventId::from_slice(&row.id) {
+                        failed.push(id);
+                    }
+                }
+            }
+        }
+        copied += to.save_batch(batch).await?.inserted;
+        mark_deleted(to, deleted).await?;
+    }
+    Ok(copied)
+}
+
+/// soft-deletes the events with the given ids, returning the number of changed rows
+async fn mark_deleted(db: &NostrPostgres, ids: Vec<Vec<u8>>) -> Result<u64, DatabaseError> {
+    let mut updated = 0;
+    for chunk in ids.chunks(CHUNK) {
+        let mut conn = db.get_connection().await?;
+        updated += diesel::update(events::table)
+            .filter(events::id.eq_any(chunk))
+            .filter(events::deleted.eq(false))
+            .set(events::deleted.eq(true))
+            .execute(&mut conn)
+            .await
+            .map_err(DatabaseError::backend)? as u64;
+    }
+    Ok(updated)
+}
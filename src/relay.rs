@@ -0,0 +1,311 @@
+use std::time::{Duration, Instant};
+
+use async_wsocket::futures_util::{SinkExt, StreamExt};
+use async_wsocket::{ConnectionMode, Message, Url, WebSocket};
+use nostr::Timestamp;
+use nostr::filter::Filter;
+use nostr::message::{ClientMessage, RelayMessage, SubscriptionId};
+use nostr::util::JsonUtil;
+use nostr_database::DatabaseError;
+use tokio::time::timeout;
+use tracing::{debug, info, warn};
+
+use crate::error::ConfigError;
+use crate::import::ImportReport;
+use crate::model::EventDataDb;
+use crate::postgres::NostrPostgres;
+use crate::retry::ConnectRetry;
+
+/// Interval in which received events are saved even if fewer than the unsaved cap arrived
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Settings of [`NostrPostgres::import_from_relay`]
+#[derive(Debug, Clone, Copy)]
+pub struct RelayImportOptions {
+    verify_signatures: bool,
+    max_unsaved: usize,
+    follow: Option<Duration>,
+    max_events: Option<u64>,
+    connect_timeout: Duration,
+    reconnect: ConnectRetry,
+}
+
+impl Default for RelayImportOptions {
+    fn default() -> Self {
+        Self {
+            verify_signatures: true,
+            max_unsaved: 1000,
+            follow: None,
+            max_events: None,
+            connect_timeout: Duration::from_secs(10),
+            reconnect: ConnectRetry::exponential(Duration::from_secs(1), 5),
+        }
+    }
+}
+
+impl RelayImportOptions {
+    /// Check the id and signature of every event, rejecting invalid ones (default true)
+    pub fn verify_signatures(mut self, verify: bool) -> Self {
+        self.verify_signatures = verify;
+        self
+    }
+
+    /// Maximum number of received events held before they are saved (default 1000)
+    ///
+    /// Reading from the relay pauses while they are saved.
+    pub fn max_unsaved(mut self, max_unsaved: usize) -> Self {
+        self.max_unsaved = max_unsaved.max(1);
+        self
+    }
+
+    /// Keep receiving new events after the stored ones, until `duration` has passed
+    pub fn follow(mut self, duration: Duration) -> Self {
+        self.follow = Some(duration);
+        self
+    }
+
+    /// Stop after receiving `max_events` events
+    pub fn max_events(mut self, max_events: u64) -> Self {
+        self.max_events = Some(max_events);
+        self
+    }
+
+    /// Timeout for opening the websocket (default 10s)
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Policy for (re)connecting to the relay (default 5 attempts starting at 1s)
+    pub fn reconnect(mut self, retry: ConnectRetry) -> Self {
+        self.reconnect = retry;
+        self
+    }
+}
+
+struct Subscription {
+    id: SubscriptionId,
+    filter: Filter,
+    eose: bool,
+    closed: bool,
+}
+
+/// how a connection to the relay ended
+enum SessionEnd {
+    Finished,
+    Disconnected,
+}
+
+struct Import<'a> {
+    db: &'a NostrPostgres,
+    opts: RelayImportOptions,
+    deadline: Option<Instant>,
+    subscriptions: Vec<Subscription>,
+    received: u64,
+    last_seen: Timestamp,
+    batch: Vec<EventDataDb>,
+    report: ImportReport,
+}
+
+/// saves the events the relay at `url` sends for `filters`
+pub(crate) async fn import_from_relay(
+    db: &NostrPostgres,
+    url: &str,
+    filters: Vec<Filter>,
+    opts: RelayImportOptions,
+) -> Result<ImportReport, DatabaseError> {
+    let start = Instant::now();
+    let url = Url::parse(url).map_err(|e| {
+        DatabaseError::backend(ConfigError::new(format!("invalid relay url {url}: {e}")))
+    })?;
+    let mut import = Import {
+        db,
+        opts,
+        deadline: opts.follow.map(|follow| start + follow),
+        subscriptions: filters
+            .into_iter()
+            .enumerate()
+            .map(|(i, filter)| Subscription {
+                id: SubscriptionId::new(format!("import-{i}")),
+                filter,
+                eose: false,
+                closed: false,
+            })
+            .collect(),
+        received: 0,
+        last_seen: Timestamp::now(),
+        batch: Vec::new(),
+        report: ImportReport::default(),
+    };
+
+    while !import.is_done() {
+        let mut socket = opts
+            .reconnect
+            .run("connecting to the relay", || async {
+                async_wsocket::connect(&url, &ConnectionMode::direct(), opts.connect_timeout)
+                    .await
+                    .map_err(DatabaseError::backend)
+            })
+            .await?;
+        debug!("Connected to {url}");
+        let end = import.session(&mut socket).await;
+        import.flush().await?;
+        match end? {
+            SessionEnd::Finished => {
+                let _ = socket.close().await;
+                break;
+            }
+            SessionEnd::Disconnected => {
+                warn!("Lost the connection to {url}, reconnecting");
+                import.resume();
+            }
+        }
+    }
+
+    let mut report = import.report;
+    report.elapsed = start.elapsed();
+    info!(
+        "Imported {} events from {url}, {} duplicates, {} deleted, {} rejected",
+        report.imported, report.duplicates, report.deleted, report.rejected
+    );
+    Ok(report)
+}
+
+impl Import<'_> {
+    fn is_done(&self) -> bool {
+        if self.opts.max_events.is_some_and(|max| self.received >= max) {
+            return true;
+        }
+        match self.deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => self.subscriptions.iter().all(|s| s.eose || s.closed),
+        }
+    }
+
+    /// requests the events and saves them until done or the connection is lost
+    async fn session(&mut self, socket: &mut WebSocket) -> Result<SessionEnd, DatabaseError> {
+        for subscription in self.subscriptions.iter().filter(|s| !s.eose && !s.closed) {
+            let req = ClientMessage::req(subscription.id.clone(), subscription.filter.clone());
+            if let Err(e) = socket.send(Message::Text(req.as_json())).await {
+                warn!("Sending the subscription failed: {e}");
+                return Ok(SessionEnd::Disconnected);
+            }
+        }
+
+        loop {
+            if self.is_done() {
+                for subscription in &self.subscriptions {
+                    let close = ClientMessage::close(subscription.id.clone());
+                    let _ = socket.send(Message::Text(close.as_json())).await;
+                }
+                return Ok(SessionEnd::Finished);
+            }
+            let wait = self.deadline.map_or(FLUSH_INTERVAL, |deadline| {
+                FLUSH_INTERVAL.min(deadline.saturating_duration_since(Instant::now()))
+            });
+            let text = match timeout(wait, socket.next()).await {
+                Err(_) => {
+                    self.flush().await?;
+                    continue;
+                }
+                Ok(Some(Ok(Message::Text(text)))) => text,
+                Ok(Some(Ok(Message::Close(_)))) | Ok(None) => return Ok(SessionEnd::Disconnected),
+                Ok(Some(Ok(_))) => continue,
+                Ok(Some(Err(e))) => {
+                    warn!("Reading from the relay failed: {e}");
+                    return Ok(SessionEnd::Disconnected);
+                }
+            };
+            self.last_seen = Timestamp::now();
+            self.handle(&text).await?;
+        }
+    }
+
+    async fn handle(&mut self, text: &str) -> Result<(), DatabaseError> {
+        let message = match RelayMessage::from_json(text) {
+            Ok(message) => message,
+            Err(e) => {
+                self.report.parse_failures += 1;
+                self.report.record(format!("relay message: {e}"));
+                return Ok(());
+            }
+        };
+        match message {
+            RelayMessage::Event {
+                subscription_id,
+                event,
+            } => {
+                if !self.subscriptions.iter().any(|s| s.id == *subscription_id) {
+                    return Ok(());
+                }
+                self.received += 1;
+                if self.opts.verify_signatures
+                    && let Err(e) = event.verify()
+                {
+                    self.report.rejected += 1;
+                    self.report.record_event_error(event.id, e);
+                    return Ok(());
+                }
+                self.batch.push(self.db.event_data(&event)?);
+                if self.batch.len() >= self.opts.max_unsaved {
+                    self.flush().await?;
+                }
+            }
+            RelayMessage::EndOfStoredEvents(subscription_id) => {
+                if let Some(subscription) = self
+                    .subscriptions
+                    .iter_mut()
+                    .find(|s| s.id == *subscription_id)
+                {
+                    subscription.eose = true;
+                }
+                self.flush().await?;
+            }
+            RelayMessage::Closed {
+                subscription_id,
+                message,
+            } => {
+                if let Some(subscription) = self
+                    .subscriptions
+                    .iter_mut()
+                    .find(|s| s.id == *subscription_id)
+                {
+                    warn!("Relay closed subscription {subscription_id}: {message}");
+                    self.report
+                        .record(format!("subscription {subscription_id}: {message}"));
+                    subscription.closed = true;
+                }
+            }
+            RelayMessage::Notice(message) => warn!("Relay notice: {message}"),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// requests the subscriptions again after a lost connection
+    ///
+    /// Followed subscriptions that were past their stored events only ask for the events
+    /// since the last message received.
+    fn resume(&mut self) {
+        if self.deadline.is_none() {
+            return;
+        }
+        for subscription in self
+            .subscriptions
+            .iter_mut()
+            .filter(|s| s.eose && !s.closed)
+        {
+            subscription.filter = subscription.filter.clone().since(self.last_seen);
+            subscription.eose = false;
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), DatabaseError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let outcome = self.db.save_batch(std::mem::take(&mut self.batch)).await?;
+        self.report.record_batch(outcome);
+        Ok(())
+    }
+}
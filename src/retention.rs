@@ -0,0 +1,268 @@
+use std::time::{Duration, Instant};
+
+use diesel::dsl::not;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use nostr::Timestamp;
+use nostr::event::Kind;
+use nostr_database::DatabaseError;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::archive::move_to_archive;
+use crate::query::{EventCondition, Operation, tagged};
+use crate::schema::postgres::{event_tags, events};
+
+/// Number of events a rule removes per statement
+const RETENTION_BATCH: i64 = 1000;
+
+/// What a [`RetentionRule`] does with events past their age
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RetentionAction {
+    /// Mark them deleted, like [`delete`](nostr_database::NostrDatabase::delete)
+    #[default]
+    SoftDelete,
+    /// Remove them and their tags
+    HardDelete,
+    /// Move them to the archive, like [`NostrPostgres::archive`](crate::NostrPostgres::archive)
+    Archive,
+}
+
+/// How long events of a kind or range of kinds are kept, see [`RetentionPolicy`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionRule {
+    first_kind: u16,
+    last_kind: u16,
+    max_age: Duration,
+    action: RetentionAction,
+}
+
+impl RetentionRule {
+    /// Keep events of `kind` created within `max_age`, soft-deleting older ones
+    pub fn kind(kind: Kind, max_age: Duration) -> Self {
+        Self::kinds(kind, kind, max_age)
+    }
+
+    /// Keep events of the kinds from `first` to `last`, both included, created within
+    /// `max_age`, soft-deleting older ones
+    pub fn kinds(first: Kind, last: Kind, max_age: Duration) -> Self {
+        let (first, last) = (first.as_u16(), last.as_u16());
+        Self {
+            first_kind: first.min(last),
+            last_kind: first.max(last),
+            max_age,
+            action: RetentionAction::SoftDelete,
+        }
+    }
+
+    /// Remove older events and their tags instead of marking them deleted
+    pub fn hard_delete(mut self) -> Self {
+        self.action = RetentionAction::HardDelete;
+        self
+    }
+
+    /// Move older events to the archive instead of marking them deleted
+    pub fn archive(mut self) -> Self {
+        self.action = RetentionAction::Archive;
+        self
+    }
+
+    /// The first kind of the rule
+    pub fn first_kind(&self) -> Kind {
+        Kind::from(self.first_kind)
+    }
+
+    /// The last kind of the rule, the first one for a single kind
+    pub fn last_kind(&self) -> Kind {
+        Kind::from(self.last_kind)
+    }
+
+    /// How long events are kept, by `created_at`
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    /// What happens to older events
+    pub fn action(&self) -> RetentionAction {
+        self.action
+    }
+
+    /// the number of kinds the rule covers less one, smaller is more specific
+    fn width(&self) -> u16 {
+        self.last_kind - self.first_kind
+    }
+
+    fn covers(&self) -> EventCondition<'static> {
+        Box::new(events::kind.between(self.first_kind as i64, self.last_kind as i64))
+    }
+}
+
+/// Rules for how long events are kept, applied by
+/// [`NostrPostgres::apply_retention`](crate::NostrPostgres::apply_retention)
+///
+/// Every kind follows the most specific rule covering it, the one with the fewest kinds, or
+/// the first given among equally specific ones; e.g. a rule for kind 1 overrides one for kinds
+/// 0 to 9999 for kind 1 only. Kinds without a rule are kept forever.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    rules: Vec<RetentionRule>,
+}
+
+impl RetentionPolicy {
+    /// A policy without rules, keeping everything
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule
+    pub fn rule(mut self, rule: RetentionRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// The rules in the order they were added
+    pub fn rules(&self) -> &[RetentionRule] {
+        &self.rules
+    }
+
+    /// the indexes of the rules, most specific first
+    fn by_specificity(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.rules.len()).collect();
+        order.sort_by_key(|&i| self.rules[i].width());
+        order
+    }
+}
+
+/// Outcome of [`NostrPostgres::apply_retention`](crate::NostrPostgres::apply_retention)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetentionReport {
+    /// The rules in the order of the policy, with the number of events each removed
+    pub rules: Vec<(RetentionRule, u64)>,
+    /// Time taken
+    pub elapsed: Duration,
+}
+
+impl RetentionReport {
+    /// Events removed by all rules
+    pub fn total(&self) -> u64 {
+        self.rules.iter().map(|(_, n)| n).sum()
+    }
+}
+
+/// applies the rules of `policy` most specific first, each in batches, removing the tags of
+/// soft-deleted events if `drop_tags`
+pub(crate) async fn apply_retention(
+    db: &mut AsyncPgConnection,
+    policy: &RetentionPolicy,
+    drop_tags: bool,
+    statement_tags: bool,
+) -> Result<RetentionReport, DatabaseError> {
+    let start = Instant::now();
+    let now = Timestamp::now().as_u64();
+    let mut counts = vec![0; policy.rules.len()];
+    let order = policy.by_specificity();
+    for (position, &index) in order.iter().enumerate() {
+        let rule = &policy.rules[index];
+        let cutoff = now.saturating_sub(rule.max_age.as_secs()) as i64;
+        // events of kinds with a more specific rule follow that one
+        let more_specific: Vec<&RetentionRule> = order[..position]
+            .iter()
+            .map(|&i| &policy.rules[i])
+            .collect();
+        let condition = || {
+            let mut condition: EventCondition<'static> =
+                Box::new(rule.covers().and(events::created_at.lt(cutoff)));
+            for other in &more_specific {
+                condition = Box::new(condition.and(not(other.covers())));
+            }
+            if rule.action == RetentionAction::SoftDelete {
+                condition = Box::new(condition.and(events::deleted.eq(false)));
+            }
+            condition
+        };
+        loop {
+            let ids: Vec<Vec<u8>> = tagged(
+                events::table
+                    .select(events::id)
+                    .filter(condition())
+                    .limit(RETENTION_BATCH),
+                Operation::Delete,
+                statement_tags,
+            )
+            .load(db)
+            .await
+            .map_err(DatabaseError::backend)?;
+            if ids.is_empty() {
+                break;
+            }
+            let found = ids.len();
+            remove(db, ids, rule.action, drop_tags, statement_tags)
+                .await
+                .map_err(DatabaseError::backend)?;
+            counts[index] += found as u64;
+            debug!(
+                "Retention of kinds {}..={} removed {} events",
+                rule.first_kind, rule.last_kind, counts[index]
+            );
+            if (found as i64) < RETENTION_BATCH {
+                break;
+            }
+        }
+    }
+    let report = RetentionReport {
+        rules: policy.rules.iter().cloned().zip(counts).collect(),
+        elapsed: start.elapsed(),
+    };
+    info!(
+        "Retention removed {} events in {:?}",
+        report.total(),
+        report.elapsed
+    );
+    Ok(report)
+}
+
+/// applies `action` to the events with the given ids
+async fn remove(
+    db: &mut AsyncPgConnection,
+    ids: Vec<Vec<u8>>,
+    action: RetentionAction,
+    drop_tags: bool,
+    statement_tags: bool,
+) -> QueryResult<()> {
+    if action == RetentionAction::Archive {
+        move_to_archive(db, ids, statement_tags).await?;
+        return Ok(());
+    }
+    db.transaction(|c| {
+        async move {
+            if action == RetentionAction::HardDelete || drop_tags {
+                tagged(
+                    diesel::delete(event_tags::table.filter(event_tags::event_id.eq_any(&ids))),
+                    Operation::Delete,
+                    statement_tags,
+                )
+                .execute(c)
+                .await?;
+            }
+            let events = events::table.filter(events::id.eq_any(&ids));
+            if action == RetentionAction::HardDelete {
+                tagged(diesel::delete(events), Operation::Delete, statement_tags)
+                    .execute(c)
+                    .await?;
+            } else {
+                tagged(
+                    diesel::update(events).set(events::deleted.eq(true)),
+                    Operation::Delete,
+                    statement_tags,
+                )
+                .execute(c)
+                .await?;
+            }
+            Ok::<_, DieselError>(())
+        }
+        .scope_boxed()
+    })
+    .await
+}
@@ -0,0 +1,288 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+use nostr_database::DatabaseError;
+use tracing::{debug, warn};
+
+use crate::error::ErrorClass;
+
+/// Retry policy used while establishing the initial database connection
+///
+/// Applied to running the migrations and to acquiring the first pooled connection, so a
+/// service can start before Postgres is ready to accept connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectRetry {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    deadline: Option<Duration>,
+}
+
+impl ConnectRetry {
+    /// Retry up to `max_attempts` times, doubling the backoff after each failed attempt
+    pub fn exponential(initial_backoff: Duration, max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            initial_backoff,
+            max_backoff: Duration::from_secs(30),
+            deadline: None,
+        }
+    }
+
+    /// Upper bound for the backoff between two attempts (default 30s)
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Overall deadline after which no further attempt is started
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Maximum number of attempts
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    /// runs `op` until it succeeds or the policy is exhausted
+    pub(crate) async fn run<T, F, Fut>(
+        &self,
+        operation: &str,
+        mut op: F,
+    ) -> Result<T, DatabaseError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, DatabaseError>>,
+    {
+        let start = Instant::now();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let err = match op().await {
+                Ok(res) => return Ok(res),
+                Err(e) => e,
+            };
+
+            let backoff = self.backoff(attempt);
+            let deadline_reached = self
+                .deadline
+                .is_some_and(|deadline| start.elapsed() + backoff > deadline);
+            if attempt >= self.max_attempts || deadline_reached {
+                return Err(DatabaseError::backend(ConnectRetryError {
+                    operation: operation.to_string(),
+                    attempts: attempt,
+                    elapsed: start.elapsed(),
+                    source: err,
+                }));
+            }
+
+            warn!(
+                "{operation} failed (attempt {attempt}/{}), retrying in {backoff:?}: {err}",
+                self.max_attempts
+            );
+            tokio::time::sleep(backoff).await;
+        }
+    }
+}
+
+/// Returned when all attempts of a [`ConnectRetry`] policy failed
+#[derive(Debug)]
+pub struct ConnectRetryError {
+    operation: String,
+    attempts: u32,
+    elapsed: Duration,
+    source: DatabaseError,
+}
+
+impl ConnectRetryError {
+    /// Number of attempts that were made
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Time spent trying
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// The error of the last attempt
+    pub fn last_error(&self) -> &DatabaseError {
+        &self.source
+    }
+}
+
+impl std::fmt::Display for ConnectRetryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} failed after {} attempts in {:?}: {}",
+            self.operation, self.attempts, self.elapsed, self.source
+        )
+    }
+}
+
+impl std::error::Error for ConnectRetryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Retry policy for operations failing with a transient error, see
+/// [`NostrPostgresBuilder::retry_transient`](crate::NostrPostgresBuilder::retry_transient)
+///
+/// Failed attempts are retried with an exponentially growing, jittered backoff while the
+/// error is of a retryable [`ErrorClass`], attempts are left and the next attempt would
+/// start within the total time budget. Constraint violations and decode errors are never
+/// retried. Each retry is logged as a warning and counted in
+/// [`PoolStatus::retries`](crate::PoolStatus::retries).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    max_elapsed: Duration,
+    jitter: bool,
+    retryable: Vec<ErrorClass>,
+}
+
+impl Default for RetryPolicy {
+    /// 3 attempts, 100ms initial and 2s maximum backoff, jitter, 5s total, retrying pool,
+    /// connection and conflict errors
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            max_elapsed: Duration::from_secs(5),
+            jitter: true,
+            retryable: vec![
+                ErrorClass::Pool,
+                ErrorClass::Connection,
+                ErrorClass::Conflict,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Make up to `max_attempts` attempts, doubling the backoff after each failed attempt
+    pub fn exponential(initial_backoff: Duration, max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            initial_backoff,
+            ..Self::default()
+        }
+    }
+
+    /// Upper bound for the backoff between two attempts (default 2s)
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Time after which no further attempt is started (default 5s)
+    ///
+    /// Bounds the total time of an operation to this plus the duration of the last attempt.
+    pub fn max_elapsed(mut self, max_elapsed: Duration) -> Self {
+        self.max_elapsed = max_elapsed;
+        self
+    }
+
+    /// Randomize each backoff between half and all of its value, so clients failing together
+    /// don't retry together (default true)
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// The error classes to retry, replacing the default pool, connection and conflict
+    /// errors
+    ///
+    /// [`ErrorClass::Constraint`] and [`ErrorClass::Decode`] are ignored: retrying them can't
+    /// succeed.
+    pub fn retry_on<I>(mut self, classes: I) -> Self
+    where
+        I: IntoIterator<Item = ErrorClass>,
+    {
+        self.retryable = classes
+            .into_iter()
+            .filter(|c| !matches!(c, ErrorClass::Constraint | ErrorClass::Decode))
+            .collect();
+        self
+    }
+
+    /// Maximum number of attempts
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Whether errors of `class` are retried
+    pub fn is_retryable(&self, class: ErrorClass) -> bool {
+        self.retryable.contains(&class)
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        if !self.jitter {
+            return backoff;
+        }
+        let random = RandomState::new().build_hasher().finish();
+        backoff / 2 + backoff.mul_f64((random % 1000) as f64 / 2000.0)
+    }
+
+    /// runs `op` until it succeeds, fails with an error that isn't retried or the policy is
+    /// exhausted, calling `on_retry` before each retry
+    pub(crate) async fn run<T, F, Fut>(
+        &self,
+        operation: &str,
+        on_retry: impl Fn(),
+        mut op: F,
+    ) -> Result<T, DatabaseError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, DatabaseError>>,
+    {
+        let start = Instant::now();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let err = match op().await {
+                Ok(res) => return Ok(res),
+                Err(e) => e,
+            };
+
+            let class = ErrorClass::of(&err);
+            if !self.is_retryable(class) {
+                return Err(err);
+            }
+            let backoff = self.backoff(attempt);
+            if attempt >= self.max_attempts || start.elapsed() + backoff > self.max_elapsed {
+                debug!("{operation} failed after {attempt} attempts, giving up: {err}");
+                return Err(err);
+            }
+
+            warn!(
+                "{operation} failed with a {class:?} error (attempt {attempt}/{}), retrying in \
+                 {backoff:?}: {err}",
+                self.max_attempts
+            );
+            on_retry();
+            tokio::time::sleep(backoff).await;
+        }
+    }
+}
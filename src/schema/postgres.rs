@@ -1,10 +1,47 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    author_usage (pubkey) {
+        pubkey -> Bytea,
+        events -> Int8,
+        bytes -> Int8,
+    }
+}
+
+diesel::table! {
+    blocked_authors (pubkey) {
+        pubkey -> Bytea,
+        blocked_at -> Int8,
+    }
+}
+
+diesel::table! {
+    event_history (id) {
+        id -> Bytea,
+        pubkey -> Bytea,
+        kind -> Int8,
+        identifier -> Text,
+        created_at -> Int8,
+        payload -> Bytea,
+        seen_at -> Int8,
+    }
+}
+
 diesel::table! {
     event_tags (tag, tag_value, event_id) {
         tag -> Text,
         tag_value -> Text,
         event_id -> Bytea,
+        tag_fields -> Array<Text>,
+    }
+}
+
+diesel::table! {
+    event_tags_archive (tag, tag_value, event_id) {
+        tag -> Text,
+        tag_value -> Text,
+        event_id -> Bytea,
+        tag_fields -> Array<Text>,
     }
 }
 
@@ -16,9 +53,29 @@ diesel::table! {
         kind -> Int8,
         payload -> Bytea,
         deleted -> Bool,
+        seen_at -> Int8,
+    }
+}
+
+diesel::table! {
+    events_archive (id) {
+        id -> Bytea,
+        pubkey -> Bytea,
+        created_at -> Int8,
+        kind -> Int8,
+        payload -> Bytea,
+        deleted -> Bool,
     }
 }
 
 diesel::joinable!(event_tags -> events (event_id));
 
-diesel::allow_tables_to_appear_in_same_query!(event_tags, events);
+diesel::allow_tables_to_appear_in_same_query!(
+    author_usage,
+    blocked_authors,
+    event_history,
+    event_tags,
+    event_tags_archive,
+    events,
+    events_archive,
+);
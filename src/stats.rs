@@ -0,0 +1,499 @@
+use diesel::dsl::{count, count_star, sql};
+use diesel::expression::SqlLiteral;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Text};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use nostr::Timestamp;
+use nostr::event::Kind;
+use nostr::filter::Filter;
+use nostr::key::PublicKey;
+use nostr_database::DatabaseError;
+use serde::{Deserialize, Serialize};
+
+use crate::error::PostgresDbError;
+use crate::query::{Operation, filter_conditions, not_expired, tagged};
+use crate::schema::postgres::{event_tags, events};
+
+/// aggregates shared by the statistics queries
+const LIVE_COUNT: &str = "count(*) FILTER (WHERE NOT deleted)";
+const DELETED_COUNT: &str = "count(*) FILTER (WHERE deleted)";
+const PAYLOAD_BYTES: &str = "coalesce(sum(pg_column_size(payload)), 0)::bigint";
+
+/// midnight UTC of the creation day, as Unix time
+const DAY: &str = "created_at - created_at % 86400";
+
+type AuthorColumns = (
+    events::pubkey,
+    SqlLiteral<BigInt>,
+    SqlLiteral<BigInt>,
+    SqlLiteral<BigInt>,
+    diesel::helper_types::min<events::created_at>,
+    diesel::helper_types::max<events::created_at>,
+);
+
+type AuthorRow = (Vec<u8>, i64, i64, i64, Option<i64>, Option<i64>);
+
+/// relations reported by [`storage_stats`], summed over their partitions
+const STORAGE: &str = "SELECT t.name, \
+         coalesce(sum(pg_table_size(p.relid)), 0)::bigint AS table_bytes, \
+         coalesce(sum(pg_indexes_size(p.relid)), 0)::bigint AS index_bytes, \
+         coalesce(sum(greatest(c.reltuples, 0)), 0)::bigint AS live_rows, \
+         coalesce(sum(s.n_dead_tup), 0)::bigint AS dead_rows \
+     FROM unnest(ARRAY['events', 'event_tags', 'events_archive', 'event_tags_archive', \
+         '__diesel_schema_migrations']) WITH ORDINALITY t(name, position) \
+     CROSS JOIN LATERAL to_regclass(t.name) r(oid) \
+     CROSS JOIN LATERAL (SELECT r.oid AS relid UNION SELECT relid FROM pg_partition_tree(r.oid)) p \
+     JOIN pg_class c ON c.oid = p.relid \
+     LEFT JOIN pg_stat_user_tables s ON s.relid = p.relid \
+     GROUP BY t.name, t.position ORDER BY t.position";
+
+/// Number and size of the stored events of one kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KindStats {
+    /// The kind
+    pub kind: Kind,
+    /// Events that are not deleted
+    pub count: u64,
+    /// Deleted events
+    pub deleted: u64,
+    /// Stored size of the payloads after compression, deleted events included
+    pub payload_bytes: u64,
+    /// Creation time of the newest event, deleted events included
+    pub newest: Timestamp,
+}
+
+/// How [`NostrPostgres::count_grouped`](crate::NostrPostgres::count_grouped) groups the
+/// events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GroupBy {
+    /// By kind
+    Kind,
+    /// By author
+    Author,
+    /// By UTC day of the creation time
+    Day,
+}
+
+/// The key of a group of events, see [`GroupBy`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GroupKey {
+    /// The kind of the events
+    Kind(Kind),
+    /// The author of the events
+    Author(PublicKey),
+    /// Midnight UTC of the day the events were created
+    Day(Timestamp),
+}
+
+/// The width of the buckets of [`NostrPostgres::histogram`](crate::NostrPostgres::histogram),
+/// in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HistogramBucket {
+    /// From the full hour
+    Hour,
+    /// From midnight
+    Day,
+    /// From midnight on Monday
+    Week,
+}
+
+impl HistogramBucket {
+    fn field(self) -> &'static str {
+        match self {
+            Self::Hour => "hour",
+            Self::Day => "day",
+            Self::Week => "week",
+        }
+    }
+}
+
+/// Number and size of the stored events of one author
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthorStats {
+    /// The author
+    pub pubkey: PublicKey,
+    /// Events that are not deleted
+    pub count: u64,
+    /// Deleted events
+    pub deleted: u64,
+    /// Stored size of the payloads after compression, deleted events included
+    pub payload_bytes: u64,
+    /// Creation time of the oldest event, deleted events included
+    pub first_seen: Timestamp,
+    /// Creation time of the newest event, deleted events included
+    pub last_seen: Timestamp,
+}
+
+impl AuthorStats {
+    fn from_row(row: AuthorRow) -> Result<Self, DatabaseError> {
+        let (pubkey, count, deleted, payload_bytes, first_seen, last_seen) = row;
+        Ok(Self {
+            pubkey: PublicKey::from_slice(&pubkey)
+                .map_err(|e| PostgresDbError::Decode(Box::new(e)))?,
+            count: count as u64,
+            deleted: deleted as u64,
+            payload_bytes: payload_bytes as u64,
+            first_seen: Timestamp::from(first_seen.unwrap_or_default() as u64),
+            last_seen: Timestamp::from(last_seen.unwrap_or_default() as u64),
+        })
+    }
+}
+
+fn author_columns() -> AuthorColumns {
+    (
+        events::pubkey,
+        sql::<BigInt>(LIVE_COUNT),
+        sql::<BigInt>(DELETED_COUNT),
+        sql::<BigInt>(PAYLOAD_BYTES),
+        diesel::dsl::min(events::created_at),
+        diesel::dsl::max(events::created_at),
+    )
+}
+
+/// one page of the authors ordered by their number of events, most first
+pub(crate) async fn author_stats_page(
+    db: &mut AsyncPgConnection,
+    limit: usize,
+    offset: usize,
+    statement_tags: bool,
+) -> Result<Vec<AuthorStats>, DatabaseError> {
+    let rows: Vec<AuthorRow> = tagged(
+        events::table
+            .group_by(events::pubkey)
+            .select(author_columns())
+            .order_by((sql::<BigInt>(LIVE_COUNT).desc(), events::pubkey))
+            .limit(limit as i64)
+            .offset(offset as i64),
+        Operation::Stats,
+        statement_tags,
+    )
+    .load(db)
+    .await
+    .map_err(DatabaseError::backend)?;
+    rows.into_iter().map(AuthorStats::from_row).collect()
+}
+
+/// the statistics of a single author, `None` if nothing of them is stored
+pub(crate) async fn author_stats(
+    db: &mut AsyncPgConnection,
+    pubkey: &PublicKey,
+    statement_tags: bool,
+) -> Result<Option<AuthorStats>, DatabaseError> {
+    let pubkey = pubkey.as_bytes().to_vec();
+    // one condition per partial index, which Postgres combines with a bitmap OR
+    let live = events::pubkey
+        .eq(pubkey.clone())
+        .and(events::deleted.eq(false));
+    let deleted = events::pubkey.eq(pubkey).and(events::deleted.eq(true));
+    let row: Option<AuthorRow> = tagged(
+        events::table
+            .filter(live.or(deleted))
+            .group_by(events::pubkey)
+            .select(author_columns()),
+        Operation::Stats,
+        statement_tags,
+    )
+    .get_result(db)
+    .await
+    .optional()
+    .map_err(DatabaseError::backend)?;
+    row.map(AuthorStats::from_row).transpose()
+}
+
+/// Disk usage of the crate's tables
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageStats {
+    /// Size of all tables including their indexes and TOAST data
+    pub total_bytes: u64,
+    /// Size of all tables including TOAST data, without indexes
+    pub table_bytes: u64,
+    /// Size of all indexes
+    pub index_bytes: u64,
+    /// The individual tables
+    pub tables: Vec<TableStats>,
+}
+
+/// Disk usage of one table, summed over its partitions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableStats {
+    /// Name of the table, without the schema
+    pub name: String,
+    /// Size including TOAST data, without indexes
+    pub table_bytes: u64,
+    /// Size of the indexes
+    pub index_bytes: u64,
+    /// Number of live rows as estimated by the last `ANALYZE` or `VACUUM`
+    pub live_rows: u64,
+    /// Number of dead rows waiting for `VACUUM`
+    pub dead_rows: u64,
+}
+
+#[derive(QueryableByName)]
+struct TableRow {
+    #[diesel(sql_type = Text)]
+    name: String,
+    #[diesel(sql_type = BigInt)]
+    table_bytes: i64,
+    #[diesel(sql_type = BigInt)]
+    index_bytes: i64,
+    #[diesel(sql_type = BigInt)]
+    live_rows: i64,
+    #[diesel(sql_type = BigInt)]
+    dead_rows: i64,
+}
+
+/// the disk usage of the crate's tables in the current schema
+pub(crate) async fn storage_stats(
+    db: &mut AsyncPgConnection,
+) -> Result<StorageStats, DatabaseError> {
+    let rows: Vec<TableRow> = diesel::sql_query(STORAGE)
+        .load(db)
+        .await
+        .map_err(DatabaseError::backend)?;
+    let tables: Vec<TableStats> = rows
+        .into_iter()
+        .map(|row| TableStats {
+            name: row.name,
+            table_bytes: row.table_bytes as u64,
+            index_bytes: row.index_bytes as u64,
+            live_rows: row.live_rows as u64,
+            dead_rows: row.dead_rows as u64,
+        })
+        .collect();
+    let table_bytes = tables.iter().map(|t| t.table_bytes).sum();
+    let index_bytes = tables.iter().map(|t| t.index_bytes).sum();
+    Ok(StorageStats {
+        total_bytes: table_bytes + index_bytes,
+        table_bytes,
+        index_bytes,
+        tables,
+    })
+}
+
+/// counts the events matching `filter` by kind, most events first
+pub(crate) async fn kind_stats(
+    db: &mut AsyncPgConnection,
+    filter: Filter,
+    statement_tags: bool,
+) -> Result<Vec<KindStats>, DatabaseError> {
+    let rows: Vec<(i64, i64, i64, i64, Option<i64>)> = tagged(
+        events::table
+            .filter(filter_conditions(filter, true))
+            .group_by(events::kind)
+            .select((
+                events::kind,
+                sql::<BigInt>(LIVE_COUNT),
+                sql::<BigInt>(DELETED_COUNT),
+                sql::<BigInt>(PAYLOAD_BYTES),
+                diesel::dsl::max(events::created_at),
+            ))
+            .order_by((sql::<BigInt>(LIVE_COUNT).desc(), events::kind)),
+        Operation::Stats,
+        statement_tags,
+    )
+    .load(db)
+    .await
+    .map_err(DatabaseError::backend)?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(kind, count, deleted, payload_bytes, newest)| KindStats {
+            kind: Kind::from(kind as u16),
+            count: count as u64,
+            deleted: deleted as u64,
+            payload_bytes: payload_bytes as u64,
+            newest: Timestamp::from(newest.unwrap_or_default() as u64),
+        })
+        .collect())
+}
+
+/// counts the events matching `filter` by `group_by`, most events first, at most as many
+/// groups as the limit of the filter
+pub(crate) async fn count_grouped(
+    db: &mut AsyncPgConnection,
+    filter: Filter,
+    group_by: GroupBy,
+    statement_tags: bool,
+) -> Result<Vec<(GroupKey, u64)>, DatabaseError> {
+    let limit = filter.limit.map_or(i64::MAX, |limit| limit as i64);
+    let events = events::table.filter(filter_conditions(filter, false));
+    let groups: Vec<(GroupKey, i64)> = match group_by {
+        GroupBy::Kind => {
+            let rows: Vec<(i64, i64)> = tagged(
+                events
+                    .group_by(events::kind)
+                    .select((events::kind, count_star()))
+                    .order_by((count_star().desc(), events::kind))
+                    .limit(limit),
+                Operation::Stats,
+                statement_tags,
+            )
+            .load(db)
+            .await
+            .map_err(DatabaseError::backend)?;
+            rows.into_iter()
+                .map(|(kind, count)| (GroupKey::Kind(Kind::from(kind as u16)), count))
+                .collect()
+        }
+        GroupBy::Author => {
+            let rows: Vec<(Vec<u8>, i64)> = tagged(
+                events
+                    .group_by(events::pubkey)
+                    .select((events::pubkey, count_star()))
+                    .order_by((count_star().desc(), events::pubkey))
+                    .limit(limit),
+                Operation::Stats,
+                statement_tags,
+            )
+            .load(db)
+            .await
+            .map_err(DatabaseError::backend)?;
+            rows.into_iter()
+                .map(|(pubkey, count)| {
+                    let pubkey = PublicKey::from_slice(&pubkey)
+                        .map_err(|e| PostgresDbError::Decode(Box::new(e)))?;
+                    Ok((GroupKey::Author(pubkey), count))
+                })
+                .collect::<Result<_, DatabaseError>>()?
+        }
+        GroupBy::Day => {
+            let day = || sql::<BigInt>(DAY);
+            let rows: Vec<(i64, i64)> = tagged(
+                events
+                    .group_by(day())
+                    .select((day(), count_star()))
+                    .order_by((count_star().desc(), day()))
+                    .limit(limit),
+                Operation::Stats,
+                statement_tags,
+            )
+            .load(db)
+            .await
+            .map_err(DatabaseError::backend)?;
+            rows.into_iter()
+                .map(|(day, count)| (GroupKey::Day(Timestamp::from(day as u64)), count))
+                .collect()
+        }
+    };
+    Ok(groups
+        .into_iter()
+        .map(|(key, count)| (key, count as u64))
+        .collect())
+}
+
+/// counts the distinct authors of the events matching `filter`, expired ones left out
+pub(crate) async fn count_authors(
+    db: &mut AsyncPgConnection,
+    filter: Filter,
+    now: Timestamp,
+    statement_tags: bool,
+) -> Result<u64, DatabaseError> {
+    let count: i64 = tagged(
+        events::table
+            .filter(filter_conditions(filter, false))
+            .filter(not_expired(now.as_u64() as i64))
+            .select(count(events::pubkey).aggregate_distinct()),
+        Operation::Count,
+        statement_tags,
+    )
+    .get_result(db)
+    .await
+    .map_err(DatabaseError::backend)?;
+    Ok(count as u64)
+}
+
+/// counts the events matching `filter` per `bucket` of `column`, a Unix time, oldest bucket
+/// first
+pub(crate) async fn histogram(
+    db: &mut AsyncPgConnection,
+    filter: Filter,
+    bucket: HistogramBucket,
+    column: &str,
+    now: Timestamp,
+    statement_tags: bool,
+) -> Result<Vec<(Timestamp, u64)>, DatabaseError> {
+    let start = || {
+        sql::<BigInt>(&format!(
+            "extract(epoch FROM date_trunc('{}', to_timestamp({column}) AT TIME ZONE 'UTC'))::bigint",
+            bucket.field()
+        ))
+    };
+    let rows: Vec<(i64, i64)> = tagged(
+        events::table
+            .filter(filter_conditions(filter, false))
+            .filter(not_expired(now.as_u64() as i64))
+            .group_by(start())
+            .select((start(), count_star()))
+            .order_by(start()),
+        Operation::Stats,
+        statement_tags,
+    )
+    .load(db)
+    .await
+    .map_err(DatabaseError::backend)?;
+    Ok(rows
+        .into_iter()
+        .map(|(start, count)| (Timestamp::from(start as u64), count as u64))
+        .collect())
+}
+
+/// the indexed tag names with the number of events using them, most used first, of the
+/// events created since `since`, deleted ones left out
+pub(crate) async fn tag_names(
+    db: &mut AsyncPgConnection,
+    since: Option<Timestamp>,
+    statement_tags: bool,
+) -> Result<Vec<(String, u64)>, DatabaseError> {
+    let since = since.map_or(i64::MIN, |since| since.as_u64() as i64);
+    let events_count = || sql::<BigInt>("count(DISTINCT event_tags.event_id)");
+    let rows: Vec<(String, i64)> = tagged(
+        event_tags::table
+            .inner_join(events::table)
+            .filter(events::deleted.eq(false))
+            .filter(events::created_at.ge(since))
+            .group_by(event_tags::tag)
+            .select((event_tags::tag, events_count()))
+            .order_by((events_count().desc(), event_tags::tag)),
+        Operation::Stats,
+        statement_tags,
+    )
+    .load(db)
+    .await
+    .map_err(DatabaseError::backend)?;
+    Ok(rows
+        .into_iter()
+        .map(|(name, count)| (name, count as u64))
+        .collect())
+}
+
+/// the `limit` most used values of the indexed tag `name` with the number of events using
+/// them, of the events created since `since`, deleted ones left out
+pub(crate) async fn tag_values(
+    db: &mut AsyncPgConnection,
+    name: &str,
+    limit: usize,
+    since: Option<Timestamp>,
+    statement_tags: bool,
+) -> Result<Vec<(String, u64)>, DatabaseError> {
+    let since = since.map_or(i64::MIN, |since| since.as_u64() as i64);
+    let rows: Vec<(String, i64)> = tagged(
+        event_tags::table
+            .inner_join(events::table)
+            .filter(event_tags::tag.eq(name))
+            .filter(events::deleted.eq(false))
+            .filter(events::created_at.ge(since))
+            .group_by(event_tags::tag_value)
+            .select((event_tags::tag_value, count_star()))
+            .order_by((count_star().desc(), event_tags::tag_value))
+            .limit(limit as i64),
+        Operation::Stats,
+        statement_tags,
+    )
+    .load(db)
+    .await
+    .map_err(DatabaseError::backend)?;
+    Ok(rows
+        .into_iter()
+        .map(|(value, count)| (value, count as u64))
+        .collect())
+}
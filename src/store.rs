@@ -0,0 +1,188 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use nostr::Timestamp;
+use nostr::event::{Event, EventId};
+use nostr::filter::Filter;
+use nostr::key::PublicKey;
+use nostr_database::prelude::BoxedFuture;
+use nostr_database::{DatabaseError, Events, NostrDatabase};
+
+use crate::import::ImportReport;
+use crate::postgres::NostrPostgres;
+use crate::stats::{AuthorStats, KindStats};
+
+/// The event store API of [`NostrPostgres`] beyond [`NostrDatabase`]
+///
+/// Accept `impl NostrEventStore` or `Arc<dyn NostrEventStore>` instead of [`NostrPostgres`]
+/// to unit test code without a database, e.g. with the in-memory `MemoryEventStore` of the
+/// `test-utils` feature. The methods behave like the inherent methods of the same name on
+/// [`NostrPostgres`]; operations specific to Postgres, like migrations, partitions or
+/// diagnostics, are left out.
+pub trait NostrEventStore: NostrDatabase {
+    /// Check that the store is reachable
+    fn ping(&self) -> BoxedFuture<'_, Result<(), DatabaseError>>;
+
+    /// Save many events at once, skipping already stored ones
+    fn save_events(
+        &self,
+        events: Vec<Event>,
+    ) -> BoxedFuture<'_, Result<ImportReport, DatabaseError>>;
+
+    /// Whether the event is stored, deleted and archived events included
+    fn has_event<'a>(&'a self, id: &'a EventId) -> BoxedFuture<'a, Result<bool, DatabaseError>>;
+
+    /// Whether the event is stored and not deleted; archived events don't count
+    fn has_active_event<'a>(
+        &'a self,
+        id: &'a EventId,
+    ) -> BoxedFuture<'a, Result<bool, DatabaseError>>;
+
+    /// The ids of `ids` that are stored, deleted and archived events included
+    fn which_exist<'a>(
+        &'a self,
+        ids: &'a [EventId],
+    ) -> BoxedFuture<'a, Result<HashSet<EventId>, DatabaseError>>;
+
+    /// Query stored events, reading your own writes
+    fn query_on_primary(&self, filter: Filter) -> BoxedFuture<'_, Result<Events, DatabaseError>>;
+
+    /// Query stored events, cancelling the query if it runs longer than `timeout`
+    fn query_with_timeout(
+        &self,
+        filter: Filter,
+        timeout: Duration,
+    ) -> BoxedFuture<'_, Result<Events, DatabaseError>>;
+
+    /// Move the events matching `filter` to the archive, returning how many were moved
+    fn archive(&self, filter: Filter) -> BoxedFuture<'_, Result<u64, DatabaseError>>;
+
+    /// Query stored events including the archived ones
+    fn query_including_archived(
+        &self,
+        filter: Filter,
+    ) -> BoxedFuture<'_, Result<Events, DatabaseError>>;
+
+    /// Get an event by id, looking into the archive if it isn't in the hot tables
+    fn event_by_id_including_archived<'a>(
+        &'a self,
+        event_id: &'a EventId,
+    ) -> BoxedFuture<'a, Result<Option<Event>, DatabaseError>>;
+
+    /// Number and size of the stored events by kind, most events first
+    fn stats_by_kind(
+        &self,
+        filter: Option<Filter>,
+    ) -> BoxedFuture<'_, Result<Vec<KindStats>, DatabaseError>>;
+
+    /// Number and size of the stored events by author, most events first
+    fn stats_by_author(
+        &self,
+        limit: usize,
+        offset: usize,
+    ) -> BoxedFuture<'_, Result<Vec<AuthorStats>, DatabaseError>>;
+
+    /// Number and size of the stored events of `pubkey`, `None` if none are stored
+    fn author_stats<'a>(
+        &'a self,
+        pubkey: &'a PublicKey,
+    ) -> BoxedFuture<'a, Result<Option<AuthorStats>, DatabaseError>>;
+
+    /// Creation times of the oldest and newest stored event matching `filter`
+    fn time_range(
+        &self,
+        filter: Option<Filter>,
+    ) -> BoxedFuture<'_, Result<Option<(Timestamp, Timestamp)>, DatabaseError>>;
+}
+
+impl NostrEventStore for NostrPostgres {
+    fn ping(&self) -> BoxedFuture<'_, Result<(), DatabaseError>> {
+        Box::pin(NostrPostgres::ping(self))
+    }
+
+    fn save_events(
+        &self,
+        events: Vec<Event>,
+    ) -> BoxedFuture<'_, Result<ImportReport, DatabaseError>> {
+        Box::pin(NostrPostgres::save_events(self, events))
+    }
+
+    fn has_event<'a>(&'a self, id: &'a EventId) -> BoxedFuture<'a, Result<bool, DatabaseError>> {
+        Box::pin(NostrPostgres::has_event(self, id))
+    }
+
+    fn has_active_event<'a>(
+        &'a self,
+        id: &'a EventId,
+    ) -> BoxedFuture<'a, Result<bool, DatabaseError>> {
+        Box::pin(NostrPostgres::has_active_event(self, id))
+    }
+
+    fn which_exist<'a>(
+        &'a self,
+        ids: &'a [EventId],
+    ) -> BoxedFuture<'a, Result<HashSet<EventId>, DatabaseError>> {
+        Box::pin(NostrPostgres::which_exist(self, ids))
+    }
+
+    fn query_on_primary(&self, filter: Filter) -> BoxedFuture<'_, Result<Events, DatabaseError>> {
+        Box::pin(NostrPostgres::query_on_primary(self, filter))
+    }
+
+    fn query_with_timeout(
+        &self,
+        filter: Filter,
+        timeout: Duration,
+    ) -> BoxedFuture<'_, Result<Events, DatabaseError>> {
+        Box::pin(NostrPostgres::query_with_timeout(self, filter, timeout))
+    }
+
+    fn archive(&self, filter: Filter) -> BoxedFuture<'_, Result<u64, DatabaseError>> {
+        Box::pin(NostrPostgres::archive(self, filter))
+    }
+
+    fn query_including_archived(
+        &self,
+        filter: Filter,
+    ) -> BoxedFuture<'_, Result<Events, DatabaseError>> {
+        Box::pin(NostrPostgres::query_including_archived(self, filter))
+    }
+
+    fn event_by_id_including_archived<'a>(
+        &'a self,
+        event_id: &'a EventId,
+    ) -> BoxedFuture<'a, Result<Option<Event>, DatabaseError>> {
+        Box::pin(NostrPostgres::event_by_id_including_archived(
+            self, event_id,
+        ))
+    }
+
+    fn stats_by_kind(
+        &self,
+        filter: Option<Filter>,
+    ) -> BoxedFuture<'_, Result<Vec<KindStats>, DatabaseError>> {
+        Box::pin(NostrPostgres::stats_by_kind(self, filter))
+    }
+
+    fn stats_by_author(
+        &self,
+        limit: usize,
+        offset: usize,
+    ) -> BoxedFuture<'_, Result<Vec<AuthorStats>, DatabaseError>> {
+        Box::pin(NostrPostgres::stats_by_author(self, limit, offset))
+    }
+
+    fn author_stats<'a>(
+        &'a self,
+        pubkey: &'a PublicKey,
+    ) -> BoxedFuture<'a, Result<Option<AuthorStats>, DatabaseError>> {
+        Box::pin(NostrPostgres::author_stats(self, pubkey))
+    }
+
+    fn time_range(
+        &self,
+        filter: Option<Filter>,
+    ) -> BoxedFuture<'_, Result<Option<(Timestamp, Timestamp)>, DatabaseError>> {
+        Box::pin(NostrPostgres::time_range(self, filter))
+    }
+}
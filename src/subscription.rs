@@ -0,0 +1,605 @@
+use std::collections::VecDeque;
+use std::pin::{Pin, pin};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use diesel::sql_types::Text;
+use diesel_async::RunQueryDsl;
+use futures_util::future::{Either, select};
+use futures_util::stream::{self, BoxStream};
+use futures_util::{Stream, StreamExt};
+use nostr::event::Event;
+use nostr::filter::{Filter, MatchEventOptions};
+use nostr_database::DatabaseError;
+use tokio::sync::{Notify, oneshot};
+use tokio::time::Instant;
+use tokio_postgres::{AsyncMessage, NoTls};
+use tracing::{debug, warn};
+
+use crate::error::{LaggedError, TimeoutError};
+use crate::identifier::quote_identifier;
+use crate::notify::EventNotification;
+use crate::postgres::NostrPostgres;
+
+/// Most events fetched in one query
+const FETCH_BATCH: usize = 50;
+
+/// Prefix of the markers notified by [`follow`]
+const MARKER_PREFIX: &str = "follow:";
+
+/// Longest wait for the marker of [`follow`]
+const MARKER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest wait between two reconnection attempts of the listening connection
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// What a subscription does when its subscriber doesn't keep up
+///
+/// Skipped events are reported in the stream as a [`LaggedError`] with their number, at the
+/// position they would have been delivered; the stream goes on afterwards.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Skip new events while the buffer is full
+    #[default]
+    DropNewest,
+    /// Skip the oldest buffered event to make room for a new one
+    DropOldest,
+    /// Stop reading notifications while the buffer is full, for at most the given time, then
+    /// skip new events
+    ///
+    /// Meanwhile Postgres queues the notifications. Its queue is shared by all listeners of
+    /// the database, and saves fail once it is full, so keep the deadline short.
+    Block(Duration),
+}
+
+/// Settings of the subscriptions of an instance, see
+/// [`NostrPostgresBuilder::subscriptions`](crate::NostrPostgresBuilder::subscriptions)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionOptions {
+    buffer: usize,
+    overflow: OverflowPolicy,
+}
+
+impl Default for SubscriptionOptions {
+    fn default() -> Self {
+        Self {
+            buffer: 1024,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+impl SubscriptionOptions {
+    /// Number of notified events buffered for a subscriber (default 1024)
+    ///
+    /// Events not matching the ids, authors, kinds or time bounds of the subscription's filter
+    /// are dropped before they take space.
+    pub fn buffer(mut self, buffer: usize) -> Self {
+        self.buffer = buffer.max(1);
+        self
+    }
+
+    /// What happens when the buffer is full (default [`OverflowPolicy::DropNewest`])
+    pub fn overflow(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow = policy;
+        self
+    }
+}
+
+/// A stream of newly saved events, see [`NostrPostgres::subscribe`]
+///
+/// Dropping the subscription closes its listening connection.
+pub struct EventSubscription {
+    events: BoxStream<'static, Result<Event, DatabaseError>>,
+}
+
+impl Stream for EventSubscription {
+    type Item = Result<Event, DatabaseError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
+
+impl std::fmt::Debug for EventSubscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventSubscription").finish_non_exhaustive()
+    }
+}
+
+/// An item of [`NostrPostgres::query_and_follow`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FollowItem {
+    /// A stored event matching the filter, newest first
+    Stored(Event),
+    /// All stored events were delivered, only live events follow
+    EndOfStoredEvents,
+    /// A newly saved event matching the filter
+    Live(Event),
+}
+
+/// The stored events matching a filter followed by the newly saved ones, see
+/// [`NostrPostgres::query_and_follow`]
+///
+/// Dropping the stream closes its listening connection.
+pub struct FollowStream {
+    items: BoxStream<'static, Result<FollowItem, DatabaseError>>,
+}
+
+impl Stream for FollowStream {
+    type Item = Result<FollowItem, DatabaseError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.items.poll_next_unpin(cx)
+    }
+}
+
+impl std::fmt::Debug for FollowStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FollowStream").finish_non_exhaustive()
+    }
+}
+
+/// A message of the listening connection
+#[derive(Debug)]
+enum Message {
+    Event(EventNotification),
+    /// number of events skipped at this position
+    Lagged(u64),
+    /// a notification sent by [`follow`] to find the end of the overlap
+    Marker(String),
+}
+
+/// The messages waiting for the subscriber
+///
+/// Markers and lags don't take space, so they are never dropped.
+struct Buffer {
+    messages: VecDeque<Message>,
+    events: usize,
+    options: SubscriptionOptions,
+    /// the subscriber is gone
+    closed: bool,
+    /// the listening task is gone
+    ended: bool,
+}
+
+impl Buffer {
+    fn is_full(&self) -> bool {
+        self.events >= self.options.buffer
+    }
+
+    fn push(&mut self, message: Message) {
+        if !matches!(message, Message::Event(_)) {
+            self.messages.push_back(message);
+            return;
+        }
+        if !self.is_full() {
+            self.events += 1;
+            self.messages.push_back(message);
+            return;
+        }
+        match self.options.overflow {
+            OverflowPolicy::DropOldest => {
+                let Some(oldest) = self
+                    .messages
+                    .iter()
+                    .position(|m| matches!(m, Message::Event(_)))
+                else {
+                    return;
+                };
+                self.messages.remove(oldest);
+                self.messages.push_back(message);
+                match oldest.checked_sub(1).map(|i| &mut self.messages[i]) {
+                    Some(Message::Lagged(skipped)) => *skipped += 1,
+                    _ => self.messages.insert(oldest, Message::Lagged(1)),
+                }
+            }
+            OverflowPolicy::DropNewest | OverflowPolicy::Block(_) => {
+                match self.messages.back_mut() {
+                    Some(Message::Lagged(skipped)) => *skipped += 1,
+                    _ => self.messages.push_back(Message::Lagged(1)),
+                }
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<Message> {
+        let message = self.messages.pop_front()?;
+        if matches!(message, Message::Event(_)) {
+            self.events -= 1;
+        }
+        Some(message)
+    }
+}
+
+/// The buffer between the listening task and the subscriber
+struct Shared {
+    buffer: Mutex<Buffer>,
+    /// wakes the subscriber when a message was buffered or the task ended
+    buffered: Notify,
+    /// wakes the task when a message was taken or the subscriber is gone
+    taken: Notify,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Buffer> {
+        self.buffer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The subscriber's end of a listening connection
+struct Receiver {
+    shared: Arc<Shared>,
+}
+
+impl Receiver {
+    async fn recv(&self) -> Option<Message> {
+        loop {
+            if let Some(message) = self.try_recv() {
+                return Some(message);
+            }
+            if self.shared.lock().ended {
+                return None;
+            }
+            self.shared.buffered.notified().await;
+        }
+    }
+
+    fn try_recv(&self) -> Option<Message> {
+        let message = self.shared.lock().pop()?;
+        self.shared.taken.notify_one();
+        Some(message)
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.taken.notify_one();
+    }
+}
+
+/// The listening task's end of a listening connection
+struct Sender {
+    shared: Arc<Shared>,
+}
+
+impl Sender {
+    fn is_closed(&self) -> bool {
+        self.shared.lock().closed
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.shared.lock().ended = true;
+        self.shared.buffered.notify_one();
+    }
+}
+
+/// The notifications of a listening connection, after the ones taken out of it
+struct Notifications {
+    pending: VecDeque<Message>,
+    rx: Receiver,
+}
+
+impl Notifications {
+    /// the next event notification or lag, skipping markers
+    async fn next(&mut self) -> Option<Message> {
+        if let Some(message) = self.pending.pop_front() {
+            return Some(message);
+        }
+        loop {
+            match self.rx.recv().await? {
+                Message::Marker(_) => {}
+                message => return Some(message),
+            }
+        }
+    }
+
+    /// the next event notification or lag if one is at hand
+    fn try_next(&mut self) -> Option<Message> {
+        if let Some(message) = self.pending.pop_front() {
+            return Some(message);
+        }
+        loop {
+            match self.rx.try_recv()? {
+                Message::Marker(_) => {}
+                message => return Some(message),
+            }
+        }
+    }
+}
+
+/// subscribes to the notifications of `channel` of events matching `filter`, returning once
+/// the connection listens
+pub(crate) async fn subscribe(
+    db: NostrPostgres,
+    connection_string: Arc<str>,
+    channel: String,
+    filter: Filter,
+    options: SubscriptionOptions,
+) -> Result<EventSubscription, DatabaseError> {
+    let notifications = Notifications {
+        pending: VecDeque::new(),
+        rx: listen(connection_string, channel, filter.clone(), options).await?,
+    };
+    Ok(EventSubscription {
+        events: fetch_events(db, notifications, filter).boxed(),
+    })
+}
+
+/// queries the events matching `filter` and follows with the ones saved from then on
+///
+/// Listens before the query, so no event is missed. An event saved during the query may be
+/// both in its result and notified; to find these, a marker is notified after the query:
+/// notifications before it are of events committed before it, and are dropped if the query
+/// returned their event, while later ones can't have been in the result.
+pub(crate) async fn follow(
+    db: NostrPostgres,
+    connection_string: Arc<str>,
+    channel: String,
+    filter: Filter,
+    options: SubscriptionOptions,
+) -> Result<FollowStream, DatabaseError> {
+    let rx = listen(connection_string, channel.clone(), filter.clone(), options).await?;
+    let stored = db.query_on_primary(filter.clone()).await?;
+
+    let marker = format!("{MARKER_PREFIX}{}", next_marker());
+    let mut conn = db.get_connection().await?;
+    diesel::sql_query("SELECT pg_notify($1, $2)")
+        .bind::<Text, _>(&channel)
+        .bind::<Text, _>(&marker)
+        .execute(&mut conn)
+        .await
+        .map_err(DatabaseError::backend)?;
+    drop(conn);
+
+    let mut overlap = VecDeque::new();
+    let wait = async {
+        while let Some(message) = rx.recv().await {
+            match message {
+                Message::Marker(m) if m == marker => return Ok(()),
+                Message::Marker(_) => {}
+                message => overlap.push_back(message),
+            }
+        }
+        Err(closed())
+    };
+    tokio::time::timeout(MARKER_TIMEOUT, wait)
+        .await
+        .map_err(|_| {
+            DatabaseError::backend(TimeoutError::new("query_and_follow", MARKER_TIMEOUT))
+        })??;
+    overlap.retain(|m| match m {
+        Message::Event(n) => !stored.iter().any(|e| e.id == n.id),
+        _ => true,
+    });
+
+    let notifications = Notifications {
+        pending: overlap,
+        rx,
+    };
+    let stored = stream::iter(stored.into_iter().map(|e| Ok(FollowItem::Stored(e))));
+    let live = fetch_events(db, notifications, filter).map(|e| e.map(FollowItem::Live));
+    Ok(FollowStream {
+        items: stored
+            .chain(stream::once(async { Ok(FollowItem::EndOfStoredEvents) }))
+            .chain(live)
+            .boxed(),
+    })
+}
+
+/// a marker unique to this process and call
+fn next_marker() -> String {
+    static MARKERS: AtomicU64 = AtomicU64::new(0);
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!(
+        "{}:{started}:{}",
+        std::process::id(),
+        MARKERS.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// the events of the notifications, fetched in batches of the notifications at hand, and
+/// the lags between them
+///
+/// The fetched events are matched against the tag and search conditions of `filter`.
+fn fetch_events(
+    db: NostrPostgres,
+    notifications: Notifications,
+    filter: Filter,
+) -> impl Stream<Item = Result<Event, DatabaseError>> + Send + 'static {
+    let check_events = !filter.generic_tags.is_empty() || filter.search.is_some();
+    let state = (db, notifications, VecDeque::new());
+    stream::unfold(state, move |(db, mut notifications, mut items)| {
+        let filter = filter.clone();
+        async move {
+            loop {
+                if let Some(item) = items.pop_front() {
+                    return Some((item, (db, notifications, items)));
+                }
+                let mut ids = Vec::new();
+                let mut lagged = None;
+                let mut next = Some(notifications.next().await?);
+                while let Some(message) = next {
+                    match message {
+                        Message::Event(notification) => ids.push(notification.id),
+                        Message::Lagged(skipped) => {
+                            lagged = Some(skipped);
+                            break;
+                        }
+                        Message::Marker(_) => {}
+                    }
+                    if ids.len() == FETCH_BATCH {
+                        break;
+                    }
+                    next = notifications.try_next();
+                }
+                match db.events_by_ids(&ids).await {
+                    Ok(fetched) => items.extend(
+                        fetched
+                            .into_iter()
+                            .filter(|event| {
+                                !check_events || filter.match_event(event, MatchEventOptions::new())
+                            })
+                            .map(Ok),
+                    ),
+                    Err(e) => items.push_back(Err(e)),
+                }
+                if let Some(skipped) = lagged {
+                    items.push_back(Err(DatabaseError::backend(LaggedError::new(skipped))));
+                }
+            }
+        }
+    })
+}
+
+/// starts a task buffering the notifications of `channel` of events that may match `filter`,
+/// returning once it listens
+///
+/// The task reconnects and listens again whenever the connection fails, and ends when the
+/// receiver is dropped.
+async fn listen(
+    connection_string: Arc<str>,
+    channel: String,
+    filter: Filter,
+    options: SubscriptionOptions,
+) -> Result<Receiver, DatabaseError> {
+    let statement = format!("LISTEN {}", quote_identifier(&channel)?);
+    let shared = Arc::new(Shared {
+        buffer: Mutex::new(Buffer {
+            messages: VecDeque::new(),
+            events: 0,
+            options,
+            closed: false,
+            ended: false,
+        }),
+        buffered: Notify::new(),
+        taken: Notify::new(),
+    });
+    let tx = Sender {
+        shared: Arc::clone(&shared),
+    };
+    let (ready_tx, ready_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let mut ready = Some(ready_tx);
+        let mut delay = Duration::from_millis(100);
+        loop {
+            let res = forward(&connection_string, &statement, &filter, &tx, &mut ready).await;
+            match (res, ready.take()) {
+                (Ok(()), _) => break,
+                // the first connection failed, so there is no subscription to keep alive
+                (Err(e), Some(ready)) => {
+                    let _ = ready.send(Err(e));
+                    break;
+                }
+                (Err(e), None) => {
+                    warn!("Listening connection on {channel} failed, reconnecting: {e}");
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                }
+            }
+            if tx.is_closed() {
+                break;
+            }
+        }
+        debug!("Stopped listening on {channel}");
+    });
+    let rx = Receiver { shared };
+    match ready_rx.await {
+        Ok(Ok(())) => Ok(rx),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(closed()),
+    }
+}
+
+/// listens on a new connection and buffers its notifications until the connection fails or
+/// the subscriber is gone, signalling `ready` once listening
+async fn forward(
+    connection_string: &str,
+    statement: &str,
+    filter: &Filter,
+    tx: &Sender,
+    ready: &mut Option<oneshot::Sender<Result<(), DatabaseError>>>,
+) -> Result<(), DatabaseError> {
+    let (client, mut connection) = tokio_postgres::connect(connection_string, NoTls)
+        .await
+        .map_err(DatabaseError::backend)?;
+    // the connection only makes progress while its messages are polled
+    let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+    let mut listen = pin!(client.batch_execute(statement));
+    loop {
+        match select(listen.as_mut(), messages.next()).await {
+            Either::Left((res, _)) => {
+                res.map_err(DatabaseError::backend)?;
+                break;
+            }
+            Either::Right((Some(Ok(_)), _)) => {}
+            Either::Right((Some(Err(e)), _)) => return Err(DatabaseError::backend(e)),
+            Either::Right((None, _)) => return Err(closed()),
+        }
+    }
+    if let Some(ready) = ready.take() {
+        let _ = ready.send(Ok(()));
+    }
+
+    let shared = &tx.shared;
+    let mut blocked_until: Option<Instant> = None;
+    loop {
+        let blocking = {
+            let buffer = shared.lock();
+            if buffer.closed {
+                return Ok(());
+            }
+            if !buffer.is_full() {
+                blocked_until = None;
+            } else if let (OverflowPolicy::Block(deadline), None) =
+                (buffer.options.overflow, blocked_until)
+            {
+                blocked_until = Some(Instant::now() + deadline);
+            }
+            blocked_until.is_some_and(|until| Instant::now() < until)
+        };
+        let deadline = blocked_until.unwrap_or_else(Instant::now);
+        let message = tokio::select! {
+            _ = shared.taken.notified() => continue,
+            _ = tokio::time::sleep_until(deadline), if blocking => continue,
+            message = messages.next(), if !blocking => message,
+        };
+        let message = match message {
+            Some(Ok(AsyncMessage::Notification(n))) => {
+                match EventNotification::from_payload(n.payload()) {
+                    Ok(notification) if notification.may_match(filter) => {
+                        Message::Event(notification)
+                    }
+                    Ok(_) => continue,
+                    Err(_) if n.payload().starts_with(MARKER_PREFIX) => {
+                        Message::Marker(n.payload().to_string())
+                    }
+                    Err(e) => {
+                        debug!("Ignoring notification {:?}: {e}", n.payload());
+                        continue;
+                    }
+                }
+            }
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(DatabaseError::backend(e)),
+            None => return Err(closed()),
+        };
+        shared.lock().push(message);
+        shared.buffered.notify_one();
+    }
+}
+
+fn closed() -> DatabaseError {
+    DatabaseError::backend(std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "listening connection closed",
+    ))
+}
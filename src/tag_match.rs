@@ -0,0 +1,106 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use diesel::dsl::sql;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::{Array, Bool, Integer, Text};
+use nostr::filter::SingleLetterTag;
+
+use crate::query::EventCondition;
+use crate::schema::postgres::{event_tags, events};
+
+type TagCondition<'a> = Box<dyn BoxableExpression<event_tags::table, Pg, SqlType = Bool> + 'a>;
+
+/// A tag an event must have for
+/// [`NostrPostgres::query_tag_matches`](crate::NostrPostgres::query_tag_matches), constraining
+/// elements beyond the value a [`Filter`](nostr::Filter) looks at
+///
+/// All constraints of a match hold for the same tag, e.g. the `e` tag of a reply that points
+/// to a given event with the `root` marker:
+/// `TagMatch::new(e).values([id]).position(3, ["root"])`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagMatch {
+    tag: SingleLetterTag,
+    values: Option<BTreeSet<String>>,
+    positions: BTreeMap<usize, BTreeSet<String>>,
+}
+
+impl TagMatch {
+    /// A tag named `tag` with any value
+    pub fn new(tag: SingleLetterTag) -> Self {
+        Self {
+            tag,
+            values: None,
+            positions: BTreeMap::new(),
+        }
+    }
+
+    /// Require one of these values, the element at position 1 that `#<tag>` filters match
+    pub fn values<I, S>(mut self, values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.values
+            .get_or_insert_default()
+            .extend(values.into_iter().map(Into::into));
+        self
+    }
+
+    /// Require one of these values at `index` of the tag array, e.g. `3` for the marker of
+    /// an `e` tag
+    ///
+    /// The name is at index 0 and the value at 1, so useful indexes start at 2; index 0
+    /// matches nothing. Tags stored before the `tag_fields` column existed match only once
+    /// [`reindex_tags`](crate::NostrPostgres::reindex_tags) has rewritten them.
+    pub fn position<I, S>(mut self, index: usize, values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.positions
+            .entry(index)
+            .or_default()
+            .extend(values.into_iter().map(Into::into));
+        self
+    }
+
+    /// the name of the matched tag
+    pub(crate) fn tag(&self) -> String {
+        self.tag.to_string()
+    }
+
+    /// the condition keeping the events with a matching tag; empty value sets match nothing
+    pub(crate) fn condition<'a>(&self) -> EventCondition<'a> {
+        let mut tag: TagCondition<'a> = Box::new(event_tags::tag.eq(self.tag.to_string()));
+        if let Some(values) = &self.values {
+            let values: Vec<String> = values.iter().cloned().collect();
+            tag = Box::new(tag.and(event_tags::tag_value.eq_any(values)));
+        }
+        for (index, values) in &self.positions {
+            // Postgres arrays are 1-based like the tag elements after the name, and an index
+            // out of range reads NULL, which matches nothing
+            let index = i32::try_from(*index).unwrap_or(0);
+            let values: Vec<String> = values.iter().cloned().collect();
+            tag = Box::new(
+                tag.and(
+                    sql::<Bool>("event_tags.tag_fields[")
+                        .bind::<Integer, _>(index)
+                        .sql("] = ANY(")
+                        .bind::<Array<Text>, _>(values)
+                        .sql(")"),
+                ),
+            );
+        }
+        // an uncorrelated `IN`, which Postgres plans as a semi-join like the `EXISTS` of tag
+        // filters, as a boxed condition can't reference `events`
+        Box::new(
+            events::id.eq_any(
+                event_tags::table
+                    .select(event_tags::event_id)
+                    .filter(tag)
+                    .into_boxed(),
+            ),
+        )
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashSet;
+
+use nostr::filter::Filter;
+use nostr_database::DatabaseError;
+
+use crate::error::UnindexedTagError;
+use crate::model::EventTagDb;
+
+/// Which tags of an event are stored in the `event_tags` table
+///
+/// Only affects tag filtering: the full tags always remain in the event payload. Changing the
+/// policy applies to newly saved events; existing rows keep their tags until reindexed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum TagIndexing {
+    /// Index every tag, those without a value with an empty one
+    #[default]
+    All,
+    /// Index only single-letter tags, as required by NIP-01
+    SingleLetter,
+    /// Index only the tags with the given names
+    Only(HashSet<String>),
+}
+
+impl TagIndexing {
+    /// Whether tags with the given name are indexed
+    pub fn is_indexed(&self, tag: &str) -> bool {
+        match self {
+            Self::All => true,
+            Self::SingleLetter => tag.len() == 1 && tag.chars().all(|c| c.is_ascii_alphabetic()),
+            Self::Only(tags) => tags.contains(tag),
+        }
+    }
+
+    /// keeps only the indexed tags
+    pub(crate) fn retain(&self, tags: &mut Vec<EventTagDb>) {
+        if *self != Self::All {
+            tags.retain(|t| self.is_indexed(&t.tag));
+        }
+    }
+
+    /// fails if `tag` is not indexed
+    pub(crate) fn check_tag(&self, tag: &str) -> Result<(), DatabaseError> {
+        if self.is_indexed(tag) {
+            Ok(())
+        } else {
+            Err(DatabaseError::backend(UnindexedTagError::new(
+                tag.to_string(),
+            )))
+        }
+    }
+
+    /// fails if the filter asks for tags that are not indexed, as those would never match
+    pub(crate) fn check_filter(&self, filter: &Filter) -> Result<(), DatabaseError> {
+        match filter
+            .generic_tags
+            .keys()
+            .map(|tag| tag.to_string())
+            .find(|tag| !self.is_indexed(tag))
+        {
+            Some(tag) => Err(DatabaseError::backend(UnindexedTagError::new(tag))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Upper bound for the length of indexed tag values
+///
+/// Postgres can't index values much larger than 2KB, so longer values would fail the whole
+/// save. The full value always remains in the event payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagValueLimit {
+    max_len: usize,
+    handling: OversizedTagValues,
+}
+
+/// What happens to tag values longer than the [`TagValueLimit`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OversizedTagValues {
+    /// Don't index the tag
+    ///
+    /// Filters on the full value don't match the event.
+    #[default]
+    Skip,
+    /// Index the first bytes of the value followed by [`TRUNCATION_MARKER`]
+    ///
+    /// Filters have to use the truncated form to match the event.
+    Truncate,
+}
+
+/// Appended to truncated tag values so they never equal a value that was short enough
+pub const TRUNCATION_MARKER: &str = "\u{2026}";
+
+impl TagValueLimit {
+    /// Limit tag values to `max_len` bytes
+    pub fn new(max_len: usize, handling: OversizedTagValues) -> Self {
+        Self { max_len, handling }
+    }
+
+    /// Maximum length in bytes
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    /// How longer values are handled
+    pub fn handling(&self) -> OversizedTagValues {
+        self.handling
+    }
+
+    /// skips or truncates the values exceeding the limit
+    pub(crate) fn apply(&self, tags: &mut Vec<EventTagDb>) {
+        match self.handling {
+            OversizedTagValues::Skip => tags.retain(|t| t.tag_value.len() <= self.max_len),
+            OversizedTagValues::Truncate => {
+                for tag in tags.iter_mut() {
+                    if tag.tag_value.len() > self.max_len {
+                        tag.tag_value = self.truncate(&tag.tag_value);
+                    }
+                }
+            }
+        }
+    }
+
+    fn truncate(&self, value: &str) -> String {
+        let mut end = self.max_len.saturating_sub(TRUNCATION_MARKER.len());
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}{TRUNCATION_MARKER}", &value[..end])
+    }
+}
+
+impl Default for TagValueLimit {
+    fn default() -> Self {
+        Self::new(2048, OversizedTagValues::Skip)
+    }
+}
@@ -0,0 +1,204 @@
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use deadpool::managed::Object;
+use diesel_async::{
+    AnsiTransactionManager, AsyncConnection, AsyncPgConnection, SimpleAsyncConnection,
+    TransactionManager,
+};
+use nostr_database::DatabaseError;
+use testcontainers_modules::postgres::Postgres;
+use testcontainers_modules::testcontainers::runners::AsyncRunner;
+use testcontainers_modules::testcontainers::{ContainerAsync, ImageExt};
+
+use crate::builder::NostrPostgresBuilder;
+use crate::postgres::{NostrPostgres, PinnedConnection, PostgresConnectionPool};
+
+/// Image tag used by [`TestDb::spawn`]
+pub const DEFAULT_POSTGRES_VERSION: &str = "16-alpine";
+
+/// A disposable Postgres server in a Docker container
+///
+/// Each [`database`](Self::database) is a separate database in the same server, so parallel
+/// tests can share one container without seeing each other's events. The container is
+/// removed once the last [`TestDb`] created from it is dropped.
+pub struct TestContainer {
+    container: ContainerAsync<Postgres>,
+    base_url: String,
+    databases: AtomicUsize,
+}
+
+impl TestContainer {
+    /// Start a container with the `postgres` image of the given tag, e.g. `15-alpine`
+    pub async fn start(version: &str) -> Result<Arc<Self>, DatabaseError> {
+        let container = Postgres::default()
+            .with_tag(version)
+            .start()
+            .await
+            .map_err(DatabaseError::backend)?;
+        let host = container.get_host().await.map_err(DatabaseError::backend)?;
+        let port = container
+            .get_host_port_ipv4(5432)
+            .await
+            .map_err(DatabaseError::backend)?;
+        Ok(Arc::new(Self {
+            container,
+            base_url: format!("postgres://postgres:postgres@{host}:{port}"),
+            databases: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Id of the Docker container
+    pub fn id(&self) -> &str {
+        self.container.id()
+    }
+
+    /// Create a new database without running the migrations, returning its connection string
+    ///
+    /// For tests of the migrations themselves or of a schema provisioned by other means. The
+    /// database lives as long as the container.
+    pub async fn empty_database(&self) -> Result<String, DatabaseError> {
+        let name = format!("test_{}", self.databases.fetch_add(1, Ordering::Relaxed));
+        let mut admin = AsyncPgConnection::establish(&format!("{}/postgres", self.base_url))
+            .await
+            .map_err(DatabaseError::backend)?;
+        admin
+            .batch_execute(&format!("CREATE DATABASE {name}"))
+            .await
+            .map_err(DatabaseError::backend)?;
+        Ok(format!("{}/{name}", self.base_url))
+    }
+
+    /// Create a new empty database and open a migrated [`NostrPostgres`] on it
+    pub async fn database(self: &Arc<Self>) -> Result<TestDb, DatabaseError> {
+        self.database_with(|builder| builder).await
+    }
+
+    /// Like [`database`](Self::database), with the builder adjusted by `configure`, e.g. for
+    /// the partitioned layout
+    pub async fn database_with<F>(self: &Arc<Self>, configure: F) -> Result<TestDb, DatabaseError>
+    where
+        F: FnOnce(NostrPostgresBuilder) -> NostrPostgresBuilder,
+    {
+        let connection_string = self.empty_database().await?;
+        let db = configure(NostrPostgres::builder(&connection_string))
+            .build()
+            .await?;
+        Ok(TestDb {
+            db,
+            connection_string,
+            container: Arc::clone(self),
+        })
+    }
+}
+
+/// A migrated [`NostrPostgres`] on its own database in a disposable container, for
+/// integration tests
+///
+/// Dereferences to the [`NostrPostgres`].
+pub struct TestDb {
+    db: NostrPostgres,
+    connection_string: String,
+    container: Arc<TestContainer>,
+}
+
+impl TestDb {
+    /// Start a container with [`DEFAULT_POSTGRES_VERSION`] and open a database in it
+    pub async fn spawn() -> Result<Self, DatabaseError> {
+        Self::spawn_version(DEFAULT_POSTGRES_VERSION).await
+    }
+
+    /// Start a container with the `postgres` image of the given tag and open a database in it
+    pub async fn spawn_version(version: &str) -> Result<Self, DatabaseError> {
+        TestContainer::start(version).await?.database().await
+    }
+
+    /// The connection string of the database, e.g. for a second instance on it
+    pub fn connection_string(&self) -> &str {
+        &self.connection_string
+    }
+
+    /// The container, to create more databases in it
+    pub fn container(&self) -> &Arc<TestContainer> {
+        &self.container
+    }
+}
+
+impl Deref for TestDb {
+    type Target = NostrPostgres;
+
+    fn deref(&self) -> &Self::Target {
+        &self.db
+    }
+}
+
+impl NostrPostgres {
+    /// Run all operations of the returned handle in one transaction that is never committed
+    ///
+    /// The handle takes a single connection from `pool`, whose database must already be
+    /// migrated, and runs every operation on it, one at a time and reading your own writes.
+    /// [`TestTransaction::rollback`] or dropping the handle discards everything, so tests can
+    /// share a database without seeing each other's events. Tests writing the same event wait
+    /// for each other's transaction to end instead of conflicting.
+    ///
+    /// Operations on a dedicated connection, like [`bulk_load`](Self::bulk_load) and
+    /// [`maintenance`](Self::maintenance), don't run in the transaction. An operation that
+    /// needs a second connection while holding one waits forever.
+    pub async fn test_transaction(
+        pool: PostgresConnectionPool,
+    ) -> Result<TestTransaction, DatabaseError> {
+        let mut conn = pool.get().await.map_err(DatabaseError::backend)?;
+        AnsiTransactionManager::begin_transaction(&mut *conn)
+            .await
+            .map_err(DatabaseError::backend)?;
+        let conn: PinnedConnection = Arc::new(tokio::sync::Mutex::new(Some(conn)));
+        Ok(TestTransaction {
+            db: NostrPostgres::from(pool).with_pinned_connection(Arc::clone(&conn)),
+            conn,
+        })
+    }
+}
+
+/// A [`NostrPostgres`] whose operations all run in one transaction, see
+/// [`NostrPostgres::test_transaction`]
+///
+/// Dereferences to the [`NostrPostgres`]; its clones share the transaction and fail with a
+/// [`ClosedError`](crate::ClosedError) once it was rolled back.
+pub struct TestTransaction {
+    db: NostrPostgres,
+    conn: PinnedConnection,
+}
+
+impl TestTransaction {
+    /// Discard everything written in the transaction and return the connection to the pool
+    pub async fn rollback(self) -> Result<(), DatabaseError> {
+        let conn = self.conn.lock().await.take();
+        if let Some(mut conn) = conn {
+            AnsiTransactionManager::rollback_transaction(&mut *conn)
+                .await
+                .map_err(DatabaseError::backend)?;
+        }
+        Ok(())
+    }
+}
+
+impl Deref for TestTransaction {
+    type Target = NostrPostgres;
+
+    fn deref(&self) -> &Self::Target {
+        &self.db
+    }
+}
+
+impl Drop for TestTransaction {
+    /// closes the connection instead of returning it to the pool, which makes the server roll
+    /// the transaction back
+    fn drop(&mut self) {
+        if let Ok(mut conn) = self.conn.try_lock()
+            && let Some(conn) = conn.take()
+        {
+            drop(Object::take(conn));
+        }
+    }
+}
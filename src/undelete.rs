@@ -0,0 +1,61 @@
+use diesel::QueryResult;
+use diesel::sql_types::{Array, Bool, Bytea};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use crate::model::StoredEventDb;
+use crate::query::{Operation, tagged};
+
+/// clears the deleted flag of the events `$1`, unless `$2` is false and their author asked
+/// for the deletion: with a stored deletion request (NIP-09, kind 5) naming the event by id
+/// or, for versions up to the request, by coordinate, or with a request to vanish (NIP-62,
+/// kind 62) made after the event
+const UNDELETE: &str = "UPDATE events e SET deleted = FALSE \
+     WHERE e.id = ANY($1) AND e.deleted AND ($2 OR NOT EXISTS (\
+     SELECT 1 FROM events r WHERE r.pubkey = e.pubkey AND NOT r.deleted AND (\
+     (r.kind = 62 AND r.created_at >= e.created_at) \
+     OR (r.kind = 5 AND EXISTS (SELECT 1 FROM event_tags t WHERE t.event_id = r.id AND (\
+     (t.tag = 'e' AND t.tag_value = encode(e.id, 'hex')) \
+     OR (t.tag = 'a' AND r.created_at >= e.created_at AND t.tag_value = \
+     e.kind || ':' || encode(e.pubkey, 'hex') || ':' || COALESCE((SELECT d.tag_value \
+     FROM event_tags d WHERE d.event_id = e.id AND d.tag = 'd' LIMIT 1), ''))\
+     )))))) RETURNING e.id, e.payload";
+
+/// Settings of [`NostrPostgres::undelete`](crate::NostrPostgres::undelete) and
+/// [`undelete_by_ids`](crate::NostrPostgres::undelete_by_ids)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UndeleteOptions {
+    force: bool,
+}
+
+impl UndeleteOptions {
+    /// Restore events deleted by moderation only
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also restore events whose author asked for their deletion (default false)
+    ///
+    /// Such deletions reflect the author's intent, so they are kept unless forced.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+}
+
+/// clears the deleted flag of the events with the given ids, returning the restored ones
+pub(crate) async fn undelete(
+    db: &mut AsyncPgConnection,
+    ids: &[Vec<u8>],
+    opts: UndeleteOptions,
+    statement_tags: bool,
+) -> QueryResult<Vec<StoredEventDb>> {
+    tagged(
+        diesel::sql_query(UNDELETE)
+            .bind::<Array<Bytea>, _>(ids)
+            .bind::<Bool, _>(opts.force),
+        Operation::Delete,
+        statement_tags,
+    )
+    .load(db)
+    .await
+}
@@ -0,0 +1,221 @@
+use std::collections::HashSet;
+
+use diesel::QueryableByName;
+use diesel::sql_types::{Bool, Text};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use nostr_database::DatabaseError;
+
+use crate::error::SchemaMismatchError;
+use crate::maintenance::payload_compression;
+
+/// name, `information_schema` data type and nullability of a column
+type ExpectedColumn = (&'static str, &'static str, bool);
+
+/// Tables and their columns with type and nullability created by the migrations
+pub(crate) const EXPECTED_TABLES: &[(&str, &[ExpectedColumn])] = &[
+    (
+        "events",
+        &[
+            ("id", "bytea", false),
+            ("pubkey", "bytea", false),
+            ("created_at", "bigint", false),
+            ("kind", "bigint", false),
+            ("payload", "bytea", false),
+            ("deleted", "boolean", false),
+            ("seen_at", "bigint", false),
+        ],
+    ),
+    (
+        "event_tags",
+        &[
+            ("tag", "text", false),
+            ("tag_value", "text", false),
+            ("event_id", "bytea", false),
+            ("tag_fields", "ARRAY", false),
+        ],
+    ),
+];
+
+/// Indexes created by the migrations
+pub(crate) const EXPECTED_INDEXES: &[&str] = &[
+    "events_pkey",
+    "event_created_at",
+    "event_kind_created_at",
+    "event_pubkey_created_at",
+    "event_pubkey_kind_created_at",
+    "event_deleted_pubkey",
+    "event_tags_pkey",
+    "event_tags_event_id",
+    "event_tags_tag_lower_value",
+];
+
+/// Differences between the live schema and the one created by the migrations
+///
+/// Only the crate's tables are compared; other tables in the schema are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchemaReport {
+    /// Tables, columns and indexes that don't exist, e.g. `index event_kind_created_at`
+    pub missing: Vec<String>,
+    /// Columns and indexes on the crate's tables that the migrations don't create, e.g.
+    /// `column events.note text NULL`
+    pub unexpected: Vec<String>,
+    /// Columns with a different type or nullability, e.g.
+    /// `column events.kind: integer NOT NULL, expected bigint NOT NULL`
+    pub changed: Vec<String>,
+    /// Compression method of `events.payload` (`lz4` or `pglz`), `None` if the server's
+    /// `default_toast_compression` applies or the server predates Postgres 14
+    ///
+    /// Informational only, it doesn't affect [`is_valid`](Self::is_valid).
+    pub payload_compression: Option<String>,
+}
+
+impl SchemaReport {
+    /// Whether the live schema matches the migrations
+    pub fn is_valid(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl std::fmt::Display for SchemaReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if !self.missing.is_empty() {
+            parts.push(format!("missing: {}", self.missing.join(", ")));
+        }
+        if !self.unexpected.is_empty() {
+            parts.push(format!("unexpected: {}", self.unexpected.join(", ")));
+        }
+        if !self.changed.is_empty() {
+            parts.push(format!("changed: {}", self.changed.join(", ")));
+        }
+        if parts.is_empty() {
+            write!(f, "schema matches the migrations")
+        } else {
+            write!(f, "{}", parts.join("; "))
+        }
+    }
+}
+
+/// What [`NostrPostgresBuilder::validate_schema`](crate::NostrPostgresBuilder::validate_schema)
+/// does when the schema drifted from the migrations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaValidation {
+    /// Log the [`SchemaReport`] as a warning
+    Warn,
+    /// Fail with a [`SchemaDriftError`](crate::SchemaDriftError)
+    Fail,
+}
+
+#[derive(QueryableByName)]
+struct ColumnDb {
+    #[diesel(sql_type = Text)]
+    table_name: String,
+    #[diesel(sql_type = Text)]
+    column_name: String,
+    #[diesel(sql_type = Text)]
+    data_type: String,
+    #[diesel(sql_type = Bool)]
+    nullable: bool,
+}
+
+#[derive(QueryableByName)]
+struct IndexDb {
+    #[diesel(sql_type = Text)]
+    indexname: String,
+}
+
+fn describe(data_type: &str, nullable: bool) -> String {
+    let null = if nullable { "NULL" } else { "NOT NULL" };
+    format!("{data_type} {null}")
+}
+
+/// compares the crate's tables in the current schema with the migrations
+pub(crate) async fn schema_report(
+    db: &mut AsyncPgConnection,
+) -> Result<SchemaReport, DatabaseError> {
+    let columns: Vec<ColumnDb> = diesel::sql_query(
+        "SELECT table_name::text, column_name::text, data_type::text, \
+         is_nullable = 'YES' AS nullable FROM information_schema.columns \
+         WHERE table_schema = current_schema() AND table_name IN ('events', 'event_tags')",
+    )
+    .load(db)
+    .await
+    .map_err(DatabaseError::backend)?;
+    let indexes: Vec<IndexDb> = diesel::sql_query(
+        "SELECT indexname::text FROM pg_indexes WHERE schemaname = current_schema() \
+         AND tablename IN ('events', 'event_tags')",
+    )
+    .load(db)
+    .await
+    .map_err(DatabaseError::backend)?;
+
+    let indexes: HashSet<String> = indexes.into_iter().map(|i| i.indexname).collect();
+
+    let mut report = SchemaReport::default();
+    for (table, table_columns) in EXPECTED_TABLES {
+        if !columns.iter().any(|c| c.table_name == *table) {
+            report.missing.push(format!("table {table}"));
+            continue;
+        }
+        for (column, data_type, nullable) in table_columns.iter() {
+            match columns
+                .iter()
+                .find(|c| c.table_name == *table && c.column_name == *column)
+            {
+                None => report.missing.push(format!("column {table}.{column}")),
+                Some(c) if c.data_type != *data_type || c.nullable != *nullable => {
+                    report.changed.push(format!(
+                        "column {table}.{column}: {}, expected {}",
+                        describe(&c.data_type, c.nullable),
+                        describe(data_type, *nullable)
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    for c in columns.iter() {
+        let expected = EXPECTED_TABLES.iter().any(|(table, table_columns)| {
+            c.table_name == *table
+                && table_columns
+                    .iter()
+                    .any(|(col, _, _)| c.column_name == *col)
+        });
+        if !expected {
+            report.unexpected.push(format!(
+                "column {}.{} {}",
+                c.table_name,
+                c.column_name,
+                describe(&c.data_type, c.nullable)
+            ));
+        }
+    }
+    for index in EXPECTED_INDEXES {
+        if !indexes.contains(*index) {
+            report.missing.push(format!("index {index}"));
+        }
+    }
+    let mut unexpected: Vec<_> = indexes
+        .iter()
+        .filter(|i| !EXPECTED_INDEXES.contains(&i.as_str()))
+        .map(|i| format!("index {i}"))
+        .collect();
+    unexpected.sort();
+    report.unexpected.extend(unexpected);
+    if report.missing.iter().all(|m| m != "table events") {
+        report.payload_compression = payload_compression(db).await?;
+    }
+    Ok(report)
+}
+
+/// checks the tables, columns and indexes of the current schema against the migrations
+pub(crate) async fn verify_schema(db: &mut AsyncPgConnection) -> Result<(), DatabaseError> {
+    let report = schema_report(db).await?;
+    if report.missing.is_empty() {
+        Ok(())
+    } else {
+        Err(DatabaseError::backend(SchemaMismatchError::new(
+            report.missing,
+        )))
+    }
+}
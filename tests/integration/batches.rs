@@ -0,0 +1,45 @@
+use nostr::{Event, EventBuilder, Filter, Keys};
+use nostr_database::NostrDatabase;
+use nostr_postgres_db::BanOptions;
+
+use crate::common::db;
+
+fn note(keys: &Keys, content: &str) -> Event {
+    EventBuilder::text_note(content)
+        .sign_with_keys(keys)
+        .unwrap()
+}
+
+#[tokio::test]
+async fn ids_repeated_in_a_batch_are_counted_once() {
+    let db = db("batch_repeated_ids").await;
+    let keys = Keys::generate();
+    let stored = note(&keys, "stored before");
+    assert!(db.save_event(&stored).await.unwrap().is_success());
+    let banned = Keys::generate();
+    db.ban_author(&banned.public_key(), BanOptions::default())
+        .await
+        .unwrap();
+    let new = note(&keys, "new");
+
+    let report = db
+        .save_events([
+            new.clone(),
+            new.clone(),
+            stored.clone(),
+            stored,
+            note(&banned, "blocked"),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(report.imported, 1);
+    assert_eq!(report.duplicates, 3);
+    assert_eq!(report.deleted, 0);
+    assert_eq!(report.rejected, 1);
+    assert_eq!(
+        db.count(Filter::new().author(keys.public_key()))
+            .await
+            .unwrap(),
+        2
+    );
+}
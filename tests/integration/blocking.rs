@@ -0,0 +1,60 @@
+use nostr::{EventBuilder, Filter, Keys};
+use nostr_postgres_db::NostrPostgres;
+use nostr_postgres_db::blocking::{AsyncContextError, BlockingNostrPostgres};
+use tokio::runtime::{Builder, Runtime};
+
+use crate::common::{downcast, empty};
+
+/// a runtime to set up the database of a test, which must not run the blocking calls
+fn runtime() -> Runtime {
+    Builder::new_current_thread().enable_all().build().unwrap()
+}
+
+/// saves, queries and deletes an event with `store`
+fn round_trip(store: &BlockingNostrPostgres) {
+    let keys = Keys::generate();
+    let event = EventBuilder::text_note("blocking")
+        .sign_with_keys(&keys)
+        .unwrap();
+    assert!(store.save_event(&event).unwrap().is_success());
+    assert_eq!(store.event_by_id(&event.id).unwrap(), Some(event.clone()));
+    let filter = Filter::new().author(keys.public_key());
+    assert_eq!(store.count(filter.clone()).unwrap(), 1);
+    let events: Vec<_> = store.query(filter.clone()).unwrap().into_iter().collect();
+    assert_eq!(events, [event]);
+    store.delete(filter.clone()).unwrap();
+    assert_eq!(store.count(filter).unwrap(), 0);
+}
+
+#[test]
+fn saves_queries_and_deletes() {
+    let runtime = runtime();
+    let db = runtime.block_on(empty("blocking"));
+    let store = BlockingNostrPostgres::new(db.connection_string()).unwrap();
+    round_trip(&store);
+}
+
+#[test]
+fn saves_queries_and_deletes_on_one_connection() {
+    let runtime = runtime();
+    let db = runtime.block_on(empty("blocking_single_connection"));
+    let store = BlockingNostrPostgres::from_builder(
+        NostrPostgres::builder(db.connection_string()).max_connections(1),
+    )
+    .unwrap();
+    round_trip(&store);
+    assert_eq!(store.inner().pool_status().max_size, 1);
+}
+
+#[test]
+fn calls_from_within_a_runtime_are_refused() {
+    let runtime = runtime();
+    let db = runtime.block_on(empty("blocking_in_runtime"));
+    let store = BlockingNostrPostgres::new(db.connection_string()).unwrap();
+    runtime.block_on(async {
+        let err = store.count(Filter::new()).unwrap_err();
+        assert!(downcast::<AsyncContextError>(&err).is_some(), "{err}");
+        let err = BlockingNostrPostgres::new(db.connection_string()).unwrap_err();
+        assert!(downcast::<AsyncContextError>(&err).is_some(), "{err}");
+    });
+}
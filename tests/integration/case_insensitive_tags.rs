@@ -0,0 +1,97 @@
+use nostr::{Alphabet, EventBuilder, EventId, Filter, Keys, SingleLetterTag, Tag, TagKind};
+use nostr_database::NostrDatabase;
+use nostr_postgres_db::NostrPostgresBuilder;
+
+use crate::common::{Db, db_with};
+
+async fn folding_t(name: &str, configure: fn(NostrPostgresBuilder) -> NostrPostgresBuilder) -> Db {
+    db_with(name, |builder| {
+        configure(builder).case_insensitive_tags([SingleLetterTag::lowercase(Alphabet::T)])
+    })
+    .await
+}
+
+/// saves a note tagged with `values` of the `letter` tag
+async fn tagged(db: &Db, letter: Alphabet, values: &[&str]) -> EventId {
+    let tags = values.iter().map(|v| {
+        Tag::custom(
+            TagKind::SingleLetter(SingleLetterTag::lowercase(letter)),
+            [*v],
+        )
+    });
+    let event = EventBuilder::text_note("tagged")
+        .tags(tags)
+        .sign_with_keys(&Keys::generate())
+        .unwrap();
+    assert!(db.save_event(&event).await.unwrap().is_success());
+    event.id
+}
+
+async fn matching(db: &Db, letter: Alphabet, value: &str) -> Vec<EventId> {
+    let filter = Filter::new().custom_tag(SingleLetterTag::lowercase(letter), value);
+    let mut ids: Vec<_> = db
+        .query(filter.clone())
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|e| e.id)
+        .collect();
+    assert_eq!(db.count(filter).await.unwrap(), ids.len());
+    ids.sort();
+    ids
+}
+
+async fn check(db: Db) {
+    let lower = tagged(&db, Alphabet::T, &["bitcoin"]).await;
+    let capitalized = tagged(&db, Alphabet::T, &["Bitcoin"]).await;
+    let upper = tagged(&db, Alphabet::T, &["BITCOIN", "nostr"]).await;
+    tagged(&db, Alphabet::T, &["bitcoiners"]).await;
+    let mut topic = vec![lower, capitalized, upper];
+    topic.sort();
+    assert_eq!(matching(&db, Alphabet::T, "bitcoin").await, topic);
+    assert_eq!(matching(&db, Alphabet::T, "BitCoin").await, topic);
+    assert_eq!(matching(&db, Alphabet::T, "NOSTR").await, [upper]);
+
+    // non-ASCII text matches itself, and other cases as far as the collation folds them
+    let name = tagged(&db, Alphabet::T, &["Émile"]).await;
+    assert_eq!(matching(&db, Alphabet::T, "Émile").await, [name]);
+    let folds: bool = db
+        .client()
+        .await
+        .query_one("SELECT lower('ÉMILE') = lower('Émile')", &[])
+        .await
+        .unwrap()
+        .get(0);
+    let expected = if folds { vec![name] } else { vec![] };
+    assert_eq!(matching(&db, Alphabet::T, "ÉMILE").await, expected);
+
+    // other tags still match exactly
+    let reference = "A1".repeat(32);
+    let exact = tagged(&db, Alphabet::E, &[&reference]).await;
+    let lowercase = tagged(&db, Alphabet::E, &[&reference.to_lowercase()]).await;
+    assert_eq!(matching(&db, Alphabet::E, &reference).await, [exact]);
+    assert_eq!(
+        matching(&db, Alphabet::E, &reference.to_lowercase()).await,
+        [lowercase]
+    );
+
+    // the index comes with the migrations
+    assert!(db.validate_schema().await.unwrap().is_valid());
+}
+
+#[tokio::test]
+async fn folds_only_the_configured_tags() {
+    check(folding_t("case_insensitive_tags", |builder| builder).await).await;
+}
+
+#[tokio::test]
+async fn folds_only_the_configured_tags_when_partitioned() {
+    check(
+        folding_t(
+            "case_insensitive_tags_partitioned",
+            NostrPostgresBuilder::partitioned,
+        )
+        .await,
+    )
+    .await;
+}
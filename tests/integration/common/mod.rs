@@ -0,0 +1,183 @@
+use std::ops::Deref;
+use std::sync::Arc;
+
+use nostr_database::DatabaseError;
+use nostr_postgres_db::{
+    DEFAULT_POSTGRES_VERSION, NostrPostgres, NostrPostgresBuilder, TestContainer, TestDb,
+};
+
+/// Server to create the test databases on instead of a container
+const TEST_URL: &str = "NOSTR_POSTGRES_TEST_URL";
+
+/// A migrated database of one test
+pub enum Db {
+    Container(TestDb),
+    Server {
+        db: NostrPostgres,
+        connection_string: String,
+    },
+}
+
+impl Db {
+    /// The connection string of the database, e.g. for a second instance on it
+    pub fn connection_string(&self) -> &str {
+        match self {
+            Self::Container(db) => db.connection_string(),
+            Self::Server {
+                connection_string, ..
+            } => connection_string,
+        }
+    }
+
+    /// A plain client on the database, to inspect or tamper with rows
+    pub async fn client(&self) -> tokio_postgres::Client {
+        client(self.connection_string()).await
+    }
+}
+
+impl Deref for Db {
+    type Target = NostrPostgres;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Container(db) => db,
+            Self::Server { db, .. } => db,
+        }
+    }
+}
+
+/// A new database named after the test, with the default configuration
+pub async fn db(name: &str) -> Db {
+    db_with(name, |builder| builder).await
+}
+
+/// A new database named after the test, with the builder adjusted by `configure`
+pub async fn db_with<F>(name: &str, configure: F) -> Db
+where
+    F: FnOnce(NostrPostgresBuilder) -> NostrPostgresBuilder,
+{
+    if std::env::var(TEST_URL).is_err() {
+        let container = TestContainer::start(DEFAULT_POSTGRES_VERSION)
+            .await
+            .unwrap();
+        return Db::Container(container.database_with(configure).await.unwrap());
+    }
+    let connection_string = server_database(name).await;
+    let db = configure(NostrPostgres::builder(&connection_string))
+        .build()
+        .await
+        .unwrap();
+    Db::Server {
+        db,
+        connection_string,
+    }
+}
+
+/// A database without the migrations, e.g. to run them or to provision it by other means
+pub struct Empty {
+    connection_string: String,
+    /// keeps the container running
+    _container: Option<Arc<TestContainer>>,
+}
+
+impl Empty {
+    pub fn connection_string(&self) -> &str {
+        &self.connection_string
+    }
+
+    /// A plain client on the database
+    pub async fn client(&self) -> tokio_postgres::Client {
+        client(&self.connection_string).await
+    }
+}
+
+/// A new database named after the test, without running the migrations
+pub async fn empty(name: &str) -> Empty {
+    if std::env::var(TEST_URL).is_err() {
+        let container = TestContainer::start(DEFAULT_POSTGRES_VERSION)
+            .await
+            .unwrap();
+        return Empty {
+            connection_string: container.empty_database().await.unwrap(),
+            _container: Some(container),
+        };
+    }
+    Empty {
+        connection_string: server_database(name).await,
+        _container: None,
+    }
+}
+
+/// creates the database of the test on the server given by `NOSTR_POSTGRES_TEST_URL`
+///
+/// The database of a previous run with the same name is dropped first, so failed runs leave
+/// nothing behind for long.
+async fn server_database(name: &str) -> String {
+    let base_url = std::env::var(TEST_URL).unwrap();
+    let base_url = base_url.trim_end_matches('/');
+    let name = format!("nostr_test_{name}");
+    let admin = client(&format!("{base_url}/postgres")).await;
+    // separate statements, as a multi-statement string runs in one transaction
+    for statement in [
+        format!("DROP DATABASE IF EXISTS {name} WITH (FORCE)"),
+        format!("CREATE DATABASE {name}"),
+    ] {
+        admin.batch_execute(&statement).await.unwrap();
+    }
+    format!("{base_url}/{name}")
+}
+
+/// A plain client on the database of `connection_string`
+pub async fn client(connection_string: &str) -> tokio_postgres::Client {
+    let (client, connection) = tokio_postgres::connect(connection_string, tokio_postgres::NoTls)
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+    client
+}
+
+/// A client holding an exclusive lock on the events table, so statements on it block until
+/// the transaction is committed, like a long-running query
+pub async fn lock_events(connection_string: &str) -> tokio_postgres::Client {
+    let client = client(connection_string).await;
+    client
+        .batch_execute("BEGIN; LOCK TABLE events IN ACCESS EXCLUSIVE MODE")
+        .await
+        .unwrap();
+    client
+}
+
+/// waits until `n` other sessions wait for a lock held by `client`
+pub async fn wait_for_blocked(client: &tokio_postgres::Client, n: i64) {
+    loop {
+        // the statistics are otherwise read once per transaction, and the lock keeps it open
+        client
+            .batch_execute("SELECT pg_stat_clear_snapshot()")
+            .await
+            .unwrap();
+        let blocked: i64 = client
+            .query_one(
+                "SELECT count(*) FROM pg_stat_activity \
+                 WHERE pg_backend_pid() = ANY (pg_blocking_pids(pid))",
+                &[],
+            )
+            .await
+            .unwrap()
+            .get(0);
+        if blocked >= n {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+}
+
+/// The error boxed into `error` if it is a `T`
+pub fn downcast<T>(error: &DatabaseError) -> Option<&T>
+where
+    T: std::error::Error + 'static,
+{
+    match error {
+        DatabaseError::Backend(e) => e.downcast_ref(),
+        _ => None,
+    }
+}
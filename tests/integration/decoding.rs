@@ -0,0 +1,35 @@
+use nostr::{EventBuilder, Filter, Keys};
+use nostr_database::NostrDatabase;
+use nostr_postgres_db::ErrorClass;
+
+use crate::common::db;
+
+#[tokio::test]
+async fn a_null_column_is_an_error_not_a_panic() {
+    let db = db("decoding_null").await;
+    let keys = Keys::generate();
+    let event = EventBuilder::text_note("drifted")
+        .sign_with_keys(&keys)
+        .unwrap();
+    assert!(db.save_event(&event).await.unwrap().is_success());
+    let client = db.client().await;
+    client
+        .batch_execute("ALTER TABLE events ALTER COLUMN payload DROP NOT NULL")
+        .await
+        .unwrap();
+    client
+        .execute(
+            "UPDATE events SET payload = NULL WHERE id = $1",
+            &[&event.id.as_bytes().to_vec()],
+        )
+        .await
+        .unwrap();
+
+    let err = db.try_event_by_id(&event.id).await.unwrap_err();
+    assert_eq!(err.class(), ErrorClass::Decode, "{err}");
+    let err = db
+        .try_query(Filter::new().author(keys.public_key()))
+        .await
+        .unwrap_err();
+    assert_eq!(err.class(), ErrorClass::Decode, "{err}");
+}
@@ -0,0 +1,28 @@
+use nostr_postgres_db::{DifferentialCorpus, assert_agrees_with_memory};
+
+use crate::common::{Db, db, db_with};
+
+/// Seeds of the corpora; a failing seed reproduces the same events and filters
+const SEEDS: [u64; 3] = [1, 42, 0x5eed];
+
+async fn agrees(db: &Db, seed: u64) {
+    let mut corpus = DifferentialCorpus::new(seed);
+    let events = corpus.events(150);
+    let filters = corpus.filters(&events, 100);
+    assert_agrees_with_memory(db, &events, &filters).await;
+}
+
+#[tokio::test]
+async fn agrees_with_memory() {
+    for seed in SEEDS {
+        agrees(&db(&format!("differential_{seed}")).await, seed).await;
+    }
+}
+
+#[tokio::test]
+async fn agrees_with_memory_when_partitioned() {
+    for seed in SEEDS {
+        let name = format!("differential_partitioned_{seed}");
+        agrees(&db_with(&name, |builder| builder.partitioned()).await, seed).await;
+    }
+}
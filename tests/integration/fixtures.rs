@@ -0,0 +1,53 @@
+use std::collections::HashSet;
+
+use nostr::{Filter, Kind, Timestamp};
+use nostr_database::NostrDatabase;
+use nostr_postgres_db::{EventFactory, seed};
+
+use crate::common::db;
+
+#[test]
+fn the_same_seed_gives_the_same_events() {
+    let events = EventFactory::new(7).events(50);
+    assert_eq!(EventFactory::new(7).events(50), events);
+    let ids: HashSet<_> = events.iter().map(|e| e.id).collect();
+    assert_eq!(ids.len(), 50);
+    let others = EventFactory::new(8).events(50);
+    assert!(others.iter().all(|e| !ids.contains(&e.id)));
+    assert!(events.iter().all(|e| e.verify().is_ok()));
+}
+
+#[test]
+fn follows_the_configuration() {
+    let (since, until) = (Timestamp::from(1_000), Timestamp::from(2_000));
+    let mut factory = EventFactory::new(3)
+        .kind(Kind::from(30))
+        .authors(4)
+        .tags_per_event(2..5)
+        .time_range(since, until);
+    let authors: HashSet<_> = factory.keys().iter().map(|k| k.public_key()).collect();
+    assert_eq!(authors.len(), 4);
+    let events = factory.events(100);
+    for event in &events {
+        assert_eq!(event.kind, Kind::from(30));
+        assert!(authors.contains(&event.pubkey));
+        // a tag drawn twice is kept once
+        assert!((1..5).contains(&event.tags.len()));
+        assert!(event.created_at >= since && event.created_at < until);
+    }
+    assert!(events.iter().any(|e| e.tags.len() == 4));
+}
+
+#[tokio::test]
+async fn seeds_a_database() {
+    let db = db("fixtures_seed").await;
+    let report = seed(&db, 300).await.unwrap();
+    assert_eq!(report.imported, 300);
+    assert_eq!(db.count(Filter::new()).await.unwrap(), 300);
+    for event in EventFactory::new(0).events(300).iter().step_by(50) {
+        assert_eq!(
+            db.event_by_id(&event.id).await.unwrap().as_ref(),
+            Some(event)
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use nostr::{EventBuilder, Filter, Keys, Kind};
+use nostr_database::NostrDatabase;
+use nostr_postgres_db::NostrPostgres;
+
+use crate::common::{db, db_with};
+
+#[tokio::test]
+async fn saves_and_queries_on_a_test_database() {
+    let db = db("harness_round_trip").await;
+    let keys = Keys::generate();
+    let event = EventBuilder::text_note("hello")
+        .sign_with_keys(&keys)
+        .unwrap();
+    assert!(db.save_event(&event).await.unwrap().is_success());
+    let events = db
+        .query(Filter::new().author(keys.public_key()))
+        .await
+        .unwrap();
+    assert_eq!(events.first(), Some(&event));
+}
+
+#[tokio::test]
+async fn opens_the_partitioned_layout() {
+    let db = db_with("harness_partitioned", |builder| builder.partitioned()).await;
+    let keys = Keys::generate();
+    let events: Vec<_> = (0..10)
+        .map(|i| {
+            EventBuilder::new(Kind::from(i), "partitioned")
+                .sign_with_keys(&keys)
+                .unwrap()
+        })
+        .collect();
+    let report = db.save_events(events).await.unwrap();
+    assert_eq!(report.imported, 10);
+    assert_eq!(db.count(Filter::new()).await.unwrap(), 10);
+    assert!(db.validate_schema().await.unwrap().is_valid());
+}
+
+#[tokio::test]
+async fn test_transactions_are_rolled_back() {
+    let db = db("harness_transaction").await;
+    let keys = Keys::generate();
+    let event = EventBuilder::text_note("rolled back")
+        .sign_with_keys(&keys)
+        .unwrap();
+    let tx = NostrPostgres::test_transaction(db.pool().clone())
+        .await
+        .unwrap();
+    assert!(tx.save_event(&event).await.unwrap().is_success());
+    assert_eq!(tx.count(Filter::new()).await.unwrap(), 1);
+    // not committed, so invisible to the pool
+    assert_eq!(db.count(Filter::new()).await.unwrap(), 0);
+    tx.rollback().await.unwrap();
+    assert_eq!(db.count(Filter::new()).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn a_second_instance_shares_the_database() {
+    let db = db("harness_second_instance").await;
+    let keys = Keys::generate();
+    let event = EventBuilder::text_note("shared")
+        .sign_with_keys(&keys)
+        .unwrap();
+    db.save_event(&event).await.unwrap();
+    let second = NostrPostgres::builder(db.connection_string())
+        .skip_migrations()
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(second.event_by_id(&event.id).await.unwrap(), Some(event));
+    let rows = db
+        .client()
+        .await
+        .query_one("SELECT count(*) FROM events", &[])
+        .await
+        .unwrap();
+    assert_eq!(rows.get::<_, i64>(0), 1);
+}
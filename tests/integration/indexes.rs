@@ -0,0 +1,167 @@
+use nostr::{Filter, Keys, Kind, PublicKey};
+use nostr_postgres_db::NostrPostgres;
+use serde_json::Value;
+
+use crate::common::{Empty, empty};
+
+/// a kind stored by one in a thousand events
+const RARE_KIND: u16 = 30023;
+
+/// a database with 20000 events of 200 authors, analyzed so that the planner sees a
+/// realistic distribution instead of an empty table
+async fn populated(name: &str) -> (Empty, NostrPostgres, Vec<PublicKey>) {
+    let db = empty(name).await;
+    let store = NostrPostgres::new(db.connection_string()).await.unwrap();
+    let authors: Vec<_> = (0..200).map(|_| Keys::generate().public_key()).collect();
+    let pubkeys: Vec<_> = authors.iter().map(|a| a.to_bytes().to_vec()).collect();
+    let client = db.client().await;
+    // the plans don't depend on the payloads, so the rows are generated on the server
+    client
+        .execute(
+            "INSERT INTO events (id, pubkey, created_at, kind, payload, deleted) \
+             SELECT sha256(i::text::bytea), ($1::bytea[])[1 + i % 200], 1700000000 + i, \
+             CASE WHEN i % 1000 = 0 THEN $2::bigint ELSE i % 20 END, '\\x00', i % 10 = 5 \
+             FROM generate_series(1, 20000) i",
+            &[&pubkeys, &i64::from(RARE_KIND)],
+        )
+        .await
+        .unwrap();
+    client.batch_execute("ANALYZE events").await.unwrap();
+    (db, store, authors)
+}
+
+/// names of the indexes scanned anywhere in `plan`
+fn indexes(plan: &Value, names: &mut Vec<String>) {
+    match plan {
+        Value::Object(node) => {
+            if let Some(Value::String(name)) = node.get("Index Name") {
+                names.push(name.clone());
+            }
+            node.values().for_each(|v| indexes(v, names));
+        }
+        Value::Array(nodes) => nodes.iter().for_each(|v| indexes(v, names)),
+        _ => {}
+    }
+}
+
+/// asserts that the plan of `filter` scans `index`
+async fn assert_uses(store: &NostrPostgres, filter: Filter, index: &str) {
+    let output = store.explain(filter.limit(20), false).await.unwrap();
+    let mut names = Vec::new();
+    indexes(&output.plan, &mut names);
+    assert!(
+        names.iter().any(|n| n == index),
+        "{index} not in {names:?}: {}",
+        output.sql
+    );
+}
+
+#[tokio::test]
+async fn author_and_kind_queries_use_the_composite_indexes() {
+    let (_db, store, authors) = populated("indexes_composite").await;
+    assert_uses(
+        &store,
+        Filter::new().author(authors[7]).kind(Kind::TextNote),
+        "event_pubkey_kind_created_at",
+    )
+    .await;
+    assert_uses(
+        &store,
+        Filter::new().kind(Kind::from(RARE_KIND)),
+        "event_kind_created_at",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn queries_of_active_events_use_the_partial_indexes() {
+    let (db, store, authors) = populated("indexes_partial").await;
+    assert_uses(
+        &store,
+        Filter::new().author(authors[7]),
+        "event_pubkey_created_at",
+    )
+    .await;
+    assert_uses(&store, Filter::new(), "event_created_at").await;
+
+    let partial: Vec<String> = db
+        .client()
+        .await
+        .query(
+            "SELECT indexname::text FROM pg_indexes \
+             WHERE tablename = 'events' AND indexdef LIKE '%WHERE (deleted = false)'",
+            &[],
+        )
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    for index in ["event_pubkey_created_at", "event_created_at"] {
+        assert!(partial.iter().any(|p| p == index), "{partial:?}");
+    }
+}
+
+/// sequential scans of event_tags and scans of its event_id index by all sessions so far
+async fn tag_scans(client: &tokio_postgres::Client) -> (i64, i64) {
+    let row = client
+        .query_one(
+            "SELECT t.seq_scan, i.idx_scan FROM pg_stat_user_tables t \
+             JOIN pg_stat_user_indexes i USING (relid) \
+             WHERE t.relname = 'event_tags' AND i.indexrelname = 'event_tags_event_id'",
+            &[],
+        )
+        .await
+        .unwrap();
+    (row.get(0), row.get(1))
+}
+
+#[tokio::test]
+async fn deleting_an_event_cascades_to_its_tags_through_the_index() {
+    let (db, _store, _) = populated("indexes_cascade").await;
+    let client = db.client().await;
+    // 10 tags on each of the 20000 events, 2000 on the deleted one
+    client
+        .batch_execute(
+            "INSERT INTO event_tags (tag, tag_value, event_id) \
+             SELECT 't', v::text, id FROM events, generate_series(1, 10) v; \
+             INSERT INTO event_tags (tag, tag_value, event_id) \
+             SELECT 'p', v::text, sha256('1'::bytea) FROM generate_series(1, 1990) v; \
+             ANALYZE event_tags; \
+             SET stats_fetch_consistency = none",
+        )
+        .await
+        .unwrap();
+    client
+        .batch_execute("SELECT pg_stat_force_next_flush()")
+        .await
+        .unwrap();
+    let before = tag_scans(&client).await;
+
+    let start = std::time::Instant::now();
+    let deleted = client
+        .execute("DELETE FROM events WHERE id = sha256('1'::bytea)", &[])
+        .await
+        .unwrap();
+    let elapsed = start.elapsed();
+    assert_eq!(deleted, 1);
+    // the counters of this session are only published once it is idle
+    client
+        .batch_execute("SELECT pg_stat_force_next_flush()")
+        .await
+        .unwrap();
+    let after = tag_scans(&client).await;
+
+    let remaining: i64 = client
+        .query_one(
+            "SELECT count(*) FROM event_tags WHERE event_id = sha256('1'::bytea)",
+            &[],
+        )
+        .await
+        .unwrap()
+        .get(0);
+    assert_eq!(remaining, 0);
+    assert_eq!(after.0, before.0, "the cascade scanned all tags");
+    assert!(after.1 > before.1, "{before:?} {after:?}");
+    assert!(elapsed < std::time::Duration::from_secs(1), "{elapsed:?}");
+}
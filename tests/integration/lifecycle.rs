@@ -0,0 +1,66 @@
+use std::time::{Duration, Instant};
+
+use nostr::{EventBuilder, Filter, Keys};
+use nostr_database::NostrDatabase;
+use nostr_postgres_db::{ClosedError, TimeoutError};
+
+use crate::common::{db_with, downcast, lock_events, wait_for_blocked};
+
+const CLOSE_TIMEOUT: Duration = Duration::from_millis(300);
+
+#[tokio::test]
+async fn close_gives_up_on_a_running_query_at_the_deadline() {
+    let db = db_with("close_deadline", |builder| {
+        builder.close_timeout(CLOSE_TIMEOUT)
+    })
+    .await;
+    let lock = lock_events(db.connection_string()).await;
+    let store = db.clone();
+    let running = tokio::spawn(async move { store.query(Filter::new()).await });
+    wait_for_blocked(&lock, 1).await;
+
+    let start = Instant::now();
+    let err = db.close().await.unwrap_err();
+    let elapsed = start.elapsed();
+    let timeout = downcast::<TimeoutError>(&err).unwrap();
+    assert_eq!(timeout.operation(), "close");
+    assert!(elapsed >= CLOSE_TIMEOUT, "{elapsed:?}");
+    assert!(elapsed < CLOSE_TIMEOUT * 3, "{elapsed:?}");
+    assert!(db.is_closed());
+
+    let event = EventBuilder::text_note("too late")
+        .sign_with_keys(&Keys::generate())
+        .unwrap();
+    let err = db.save_event(&event).await.unwrap_err();
+    assert!(downcast::<ClosedError>(&err).is_some(), "{err}");
+    let err = db.query(Filter::new()).await.unwrap_err();
+    assert!(downcast::<ClosedError>(&err).is_some(), "{err}");
+
+    lock.batch_execute("COMMIT").await.unwrap();
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn close_waits_for_a_query_finishing_in_time() {
+    let db = db_with("close_in_time", |builder| {
+        builder.close_timeout(CLOSE_TIMEOUT * 10)
+    })
+    .await;
+    let lock = lock_events(db.connection_string()).await;
+    let store = db.clone();
+    let running = tokio::spawn(async move { store.query(Filter::new()).await });
+    wait_for_blocked(&lock, 1).await;
+
+    // releases the lock after a while, from the server, while close is waiting
+    let release = tokio::spawn(async move {
+        lock.batch_execute("SELECT pg_sleep(0.2); COMMIT")
+            .await
+            .unwrap();
+    });
+    db.close().await.unwrap();
+    assert!(running.is_finished());
+    running.await.unwrap().unwrap();
+    release.await.unwrap();
+    let err = db.count(Filter::new()).await.unwrap_err();
+    assert!(downcast::<ClosedError>(&err).is_some(), "{err}");
+}
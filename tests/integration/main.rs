@@ -0,0 +1,30 @@
+//! Integration tests against a real Postgres, run with `cargo test --features test-utils`
+//!
+//! Each test opens its own database in a disposable container, see [`TestDb`]. With
+//! `NOSTR_POSTGRES_TEST_URL` set to a server, e.g. `postgres://postgres@localhost:5432`, the
+//! databases are created on it instead, for machines without Docker. The tests of the
+//! blocking wrapper also need the `blocking` feature.
+//!
+//! [`TestDb`]: nostr_postgres_db::TestDb
+
+mod batches;
+#[cfg(feature = "blocking")]
+mod blocking;
+mod case_insensitive_tags;
+mod common;
+mod decoding;
+mod differential;
+mod fixtures;
+mod harness;
+mod indexes;
+mod lifecycle;
+mod migrations;
+mod pool;
+mod quota;
+mod rate_limit;
+mod replica;
+mod retry;
+mod schema;
+mod single_connection;
+mod statement_cache;
+mod timeouts;
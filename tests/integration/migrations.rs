@@ -0,0 +1,214 @@
+use std::thread;
+
+use nostr::{EventBuilder, Filter, Keys, Tag};
+use nostr_database::{FlatBufferBuilder, FlatBufferEncode, NostrDatabase};
+use nostr_postgres_db::{
+    MIGRATIONS_LOCK_KEY, NostrPostgres, migration_status, run_migrations, run_migrations_in_schema,
+};
+
+use crate::common::{Empty, empty};
+
+/// whether the migrations lock is free, taking and releasing it from another session
+async fn lock_is_free(db: &Empty) -> bool {
+    let client = db.client().await;
+    let free: bool = client
+        .query_one("SELECT pg_try_advisory_lock($1)", &[&MIGRATIONS_LOCK_KEY])
+        .await
+        .unwrap()
+        .get(0);
+    if free {
+        client
+            .execute("SELECT pg_advisory_unlock($1)", &[&MIGRATIONS_LOCK_KEY])
+            .await
+            .unwrap();
+    }
+    free
+}
+
+#[tokio::test]
+async fn concurrent_instances_migrate_once() {
+    let db = empty("migrations_concurrent").await;
+    // threads with their own runtime, as the migrations block while they run
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let url = db.connection_string().to_string();
+            thread::spawn(move || {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap()
+                    .block_on(async {
+                        let store = NostrPostgres::new(&url).await?;
+                        store.count(Filter::new()).await
+                    })
+            })
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap().unwrap(), 0);
+    }
+    let status = migration_status(db.connection_string(), None).unwrap();
+    assert!(status.pending.is_empty(), "{status:?}");
+    assert!(lock_is_free(&db).await);
+}
+
+#[tokio::test]
+async fn a_failed_migration_releases_the_lock() {
+    let db = empty("migrations_failed").await;
+    // collides with the table of a later migration, which then fails
+    let client = db.client().await;
+    client
+        .batch_execute("CREATE TABLE blocked_authors (note TEXT)")
+        .await
+        .unwrap();
+    assert!(NostrPostgres::new(db.connection_string()).await.is_err());
+    assert!(lock_is_free(&db).await);
+    let status = migration_status(db.connection_string(), None).unwrap();
+    assert!(!status.pending.is_empty());
+
+    client
+        .batch_execute("DROP TABLE blocked_authors")
+        .await
+        .unwrap();
+    let store = NostrPostgres::new(db.connection_string()).await.unwrap();
+    assert_eq!(store.count(Filter::new()).await.unwrap(), 0);
+    let status = migration_status(db.connection_string(), None).unwrap();
+    assert!(status.pending.is_empty(), "{status:?}");
+}
+
+#[tokio::test]
+async fn running_the_migrations_again_changes_nothing() {
+    let db = empty("migrations_twice").await;
+    run_migrations(db.connection_string()).unwrap();
+    let status = migration_status(db.connection_string(), None).unwrap();
+    assert!(status.pending.is_empty(), "{status:?}");
+    run_migrations(db.connection_string()).unwrap();
+    assert_eq!(
+        migration_status(db.connection_string(), None).unwrap(),
+        status
+    );
+    let store = NostrPostgres::new(db.connection_string()).await.unwrap();
+    assert!(store.validate_schema().await.unwrap().is_valid());
+}
+
+/// The schema of the first release, as diesel left it: its two migrations applied and recorded
+const BASELINE: [(&str, &str); 2] = [
+    (
+        "00000000000000",
+        include_str!("../../migrations/postgres/00000000000000_diesel_initial_setup/up.sql"),
+    ),
+    (
+        "20250411095120",
+        include_str!("../../migrations/postgres/2025-04-11-095120_events/up.sql"),
+    ),
+];
+
+#[tokio::test]
+async fn upgrades_a_database_of_the_first_release() {
+    let db = empty("migrations_baseline").await;
+    let client = db.client().await;
+    client
+        .batch_execute(
+            "CREATE TABLE __diesel_schema_migrations (\
+             version VARCHAR(50) PRIMARY KEY NOT NULL, \
+             run_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP)",
+        )
+        .await
+        .unwrap();
+    for (version, sql) in BASELINE {
+        client.batch_execute(sql).await.unwrap();
+        client
+            .execute(
+                "INSERT INTO __diesel_schema_migrations (version) VALUES ($1)",
+                &[&version],
+            )
+            .await
+            .unwrap();
+    }
+    // an event stored by the first release
+    let event = EventBuilder::text_note("from the first release")
+        .tag(Tag::hashtag("history"))
+        .sign_with_keys(&Keys::generate())
+        .unwrap();
+    let id = event.id.as_bytes().to_vec();
+    client
+        .execute(
+            "INSERT INTO events (id, pubkey, created_at, kind, payload, deleted) \
+             VALUES ($1, $2, $3, $4, $5, FALSE)",
+            &[
+                &id,
+                &event.pubkey.as_bytes().to_vec(),
+                &(event.created_at.as_u64() as i64),
+                &i64::from(event.kind.as_u16()),
+                &event.encode(&mut FlatBufferBuilder::new()).to_vec(),
+            ],
+        )
+        .await
+        .unwrap();
+    client
+        .execute(
+            "INSERT INTO event_tags (tag, tag_value, event_id) VALUES ('t', 'history', $1)",
+            &[&id],
+        )
+        .await
+        .unwrap();
+
+    // the events table of migration 1 exists, so re-applying it would fail
+    let store = NostrPostgres::new(db.connection_string()).await.unwrap();
+    let status = migration_status(db.connection_string(), None).unwrap();
+    assert!(status.pending.is_empty(), "{status:?}");
+    assert!(status.applied.iter().any(|v| v == "20250411095120"));
+    assert!(store.validate_schema().await.unwrap().is_valid());
+    assert_eq!(
+        store.event_by_id(&event.id).await.unwrap(),
+        Some(event.clone())
+    );
+    let tagged = store.query(Filter::new().hashtag("history")).await.unwrap();
+    assert_eq!(tagged.first(), Some(&event));
+}
+
+/// names of the invalid indexes in `schema`
+async fn invalid_indexes(client: &tokio_postgres::Client, schema: &str) -> Vec<String> {
+    client
+        .query(
+            "SELECT c.relname::text FROM pg_index i \
+             JOIN pg_class c ON c.oid = i.indexrelid \
+             JOIN pg_namespace n ON n.oid = c.relnamespace \
+             WHERE NOT i.indisvalid AND n.nspname = $1",
+            &[&schema],
+        )
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect()
+}
+
+#[tokio::test]
+async fn invalid_indexes_of_the_schema_are_dropped() {
+    let db = empty("migrations_invalid_indexes").await;
+    run_migrations_in_schema(db.connection_string(), "relay").unwrap();
+    let client = db.client().await;
+    // a failed concurrent build of a unique index leaves it behind invalid
+    for schema in ["relay", "public"] {
+        client
+            .batch_execute(&format!(
+                "CREATE TABLE {schema}.notes (note TEXT); \
+                 INSERT INTO {schema}.notes VALUES ('twice'), ('twice')"
+            ))
+            .await
+            .unwrap();
+        client
+            .batch_execute(&format!(
+                "CREATE UNIQUE INDEX CONCURRENTLY notes_note ON {schema}.notes (note)"
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(invalid_indexes(&client, schema).await, ["notes_note"]);
+    }
+
+    run_migrations_in_schema(db.connection_string(), "relay").unwrap();
+    assert!(invalid_indexes(&client, "relay").await.is_empty());
+    // other schemas are left alone
+    assert_eq!(invalid_indexes(&client, "public").await, ["notes_note"]);
+}
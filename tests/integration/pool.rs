@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use nostr::Filter;
+use nostr_database::NostrDatabase;
+use nostr_postgres_db::{
+    ClosedError, ErrorClass, PoolErrorKind, is_pool_exhausted, migration_status, pool_error_kind,
+};
+
+use crate::common::{db, db_with, downcast};
+
+#[tokio::test]
+async fn waiting_past_the_acquire_timeout_is_pool_exhaustion() {
+    let db = db_with("pool_exhausted", |builder| {
+        builder
+            .max_connections(1)
+            .acquire_timeout(Duration::from_millis(100))
+    })
+    .await;
+    let held = db.pool().get().await.unwrap();
+
+    let err = db.query(Filter::new()).await.unwrap_err();
+    assert!(is_pool_exhausted(&err), "{err}");
+    assert_eq!(pool_error_kind(&err), Some(PoolErrorKind::Exhausted));
+    let err = db.try_count(Filter::new()).await.unwrap_err();
+    assert_eq!(err.class(), ErrorClass::Pool);
+
+    drop(held);
+    assert_eq!(db.count(Filter::new()).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn ping_and_health_on_a_reachable_database() {
+    let db = db("pool_health").await;
+    db.ping().await.unwrap();
+    let report = db.health().await.unwrap();
+    let status = migration_status(db.connection_string(), None).unwrap();
+    assert_eq!(report.schema_version.as_ref(), status.applied.last());
+    assert!(report.pool.size >= 1);
+    assert_eq!(report.pool.waiting, 0);
+    assert!(report.latency < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn ping_and_health_fail_once_the_pool_is_closed() {
+    let db = db("pool_closed").await;
+    db.pool().close();
+    let err = db.ping().await.unwrap_err();
+    assert_eq!(pool_error_kind(&err), Some(PoolErrorKind::Closed), "{err}");
+    let err = db.health().await.unwrap_err();
+    assert_eq!(pool_error_kind(&err), Some(PoolErrorKind::Closed), "{err}");
+
+    db.close().await.unwrap();
+    let err = db.ping().await.unwrap_err();
+    assert!(downcast::<ClosedError>(&err).is_some(), "{err}");
+}
+
+#[tokio::test]
+async fn warm_up_opens_the_connections() {
+    let db = db_with("pool_warm_up", |builder| builder.max_connections(3)).await;
+    let before = db.pool_status().size;
+    db.warm_up(0).await.unwrap();
+    assert_eq!(db.pool_status().size, before);
+
+    db.warm_up(2).await.unwrap();
+    let status = db.pool_status();
+    assert!(status.size >= 2, "{status:?}");
+    assert_eq!(status.available, status.size);
+
+    // capped at the pool size
+    db.warm_up(10).await.unwrap();
+    let status = db.pool_status();
+    assert_eq!((status.size, status.available), (3, 3));
+}
+
+#[tokio::test]
+async fn the_builder_warms_up_the_pool() {
+    let db = db_with("pool_warm_up_builder", |builder| {
+        builder.max_connections(4).warm_up(2)
+    })
+    .await;
+    let status = db.pool_status();
+    assert!(status.size >= 2, "{status:?}");
+    assert_eq!(status.available, status.size);
+}
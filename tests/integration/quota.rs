@@ -0,0 +1,136 @@
+use nostr::{Event, EventBuilder, Keys, Timestamp};
+use nostr_database::{DatabaseEventStatus, NostrDatabase};
+use nostr_postgres_db::{NostrPostgres, Quota, QuotaMode, SaveOutcome};
+
+use crate::common::{Db, db, db_with};
+
+/// text notes of the same size, a second apart and oldest first
+fn notes(keys: &Keys, count: u64) -> Vec<Event> {
+    let now = Timestamp::now().as_u64();
+    (0..count)
+        .map(|i| {
+            EventBuilder::text_note(format!("note {i}"))
+                .custom_created_at(Timestamp::from(now - 100 + i))
+                .sign_with_keys(keys)
+                .unwrap()
+        })
+        .collect()
+}
+
+async fn with_quota(name: &str, quota: Quota) -> Db {
+    db_with(name, |builder| builder.quota(quota)).await
+}
+
+async fn save_all(db: &NostrPostgres, events: &[Event]) -> Vec<SaveOutcome> {
+    let mut outcomes = Vec::new();
+    for event in events {
+        outcomes.push(db.try_save_event(event).await.unwrap());
+    }
+    outcomes
+}
+
+#[tokio::test]
+async fn reject_mode_stops_at_the_event_quota() {
+    let db = with_quota("quota_reject_events", Quota::new().max_events(3)).await;
+    let keys = Keys::generate();
+    let events = notes(&keys, 4);
+    let outcomes = save_all(&db, &events).await;
+    assert_eq!(
+        outcomes,
+        [
+            SaveOutcome::Saved,
+            SaveOutcome::Saved,
+            SaveOutcome::Saved,
+            SaveOutcome::OverQuota
+        ]
+    );
+    assert_eq!(db.quota_usage(&keys.public_key()).await.unwrap().events, 3);
+    // save_event reports it without the reason
+    assert!(!db.save_event(&events[3]).await.unwrap().is_success());
+}
+
+#[tokio::test]
+async fn reject_mode_stops_at_the_byte_quota() {
+    let keys = Keys::generate();
+    let events = notes(&keys, 3);
+    let sized = db("quota_reject_bytes").await;
+    sized.save_event(&events[0]).await.unwrap();
+    let size = sized.quota_usage(&keys.public_key()).await.unwrap().bytes;
+    let db = NostrPostgres::builder(sized.connection_string())
+        .quota(Quota::new().max_bytes(size * 2))
+        .build()
+        .await
+        .unwrap();
+    // exactly at the quota
+    assert_eq!(
+        db.try_save_event(&events[1]).await.unwrap(),
+        SaveOutcome::Saved
+    );
+    assert_eq!(
+        db.quota_usage(&keys.public_key()).await.unwrap().bytes,
+        size * 2
+    );
+    assert_eq!(
+        db.try_save_event(&events[2]).await.unwrap(),
+        SaveOutcome::OverQuota
+    );
+}
+
+#[tokio::test]
+async fn evict_mode_marks_the_oldest_events_deleted() {
+    let quota = Quota::new().max_events(3).mode(QuotaMode::Evict);
+    let db = with_quota("quota_evict", quota).await;
+    let keys = Keys::generate();
+    let events = notes(&keys, 5);
+    let outcomes = save_all(&db, &events).await;
+    assert!(outcomes.iter().all(SaveOutcome::is_saved));
+    assert_eq!(db.quota_usage(&keys.public_key()).await.unwrap().events, 3);
+    for (i, event) in events.iter().enumerate() {
+        let expected = if i < 2 {
+            DatabaseEventStatus::Deleted
+        } else {
+            DatabaseEventStatus::Saved
+        };
+        assert_eq!(db.check_id(&event.id).await.unwrap(), expected, "event {i}");
+    }
+}
+
+#[tokio::test]
+async fn blocked_authors_evict_nothing() {
+    let quota = Quota::new().max_events(2).mode(QuotaMode::Evict);
+    let db = with_quota("quota_blocked", quota).await;
+    let keys = Keys::generate();
+    let events = notes(&keys, 3);
+    save_all(&db, &events[..2]).await;
+    db.client()
+        .await
+        .execute(
+            "INSERT INTO blocked_authors (pubkey, blocked_at) VALUES ($1, 0)",
+            &[&keys.public_key().as_bytes().to_vec()],
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        db.try_save_event(&events[2]).await.unwrap(),
+        SaveOutcome::Blocked
+    );
+    assert_eq!(db.quota_usage(&keys.public_key()).await.unwrap().events, 2);
+    assert_eq!(
+        db.check_id(&events[0].id).await.unwrap(),
+        DatabaseEventStatus::Saved
+    );
+}
+
+#[tokio::test]
+async fn exempt_authors_and_batches() {
+    let admin = Keys::generate();
+    let quota = Quota::new().max_events(3).exempt([admin.public_key()]);
+    let db = with_quota("quota_batches", quota).await;
+    let outcomes = save_all(&db, &notes(&admin, 5)).await;
+    assert!(outcomes.iter().all(SaveOutcome::is_saved));
+
+    let keys = Keys::generate();
+    let report = db.save_events(notes(&keys, 5)).await.unwrap();
+    assert_eq!((report.imported, report.rejected), (3, 2));
+    assert_eq!(db.quota_usage(&keys.public_key()).await.unwrap().events, 3);
+}
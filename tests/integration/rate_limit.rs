@@ -0,0 +1,30 @@
+use nostr::{EventBuilder, Keys};
+use nostr_database::NostrDatabase;
+use nostr_postgres_db::{RateLimit, SaveOutcome};
+
+use crate::common::db_with;
+
+#[tokio::test]
+async fn reports_rate_limited_saves() {
+    let db = db_with("rate_limited", |builder| {
+        builder.rate_limit(RateLimit::new(2, 0.01))
+    })
+    .await;
+    let keys = Keys::generate();
+    let events: Vec<_> = (0..5)
+        .map(|i| {
+            EventBuilder::text_note(format!("note {i}"))
+                .sign_with_keys(&keys)
+                .unwrap()
+        })
+        .collect();
+    assert!(db.try_save_event(&events[0]).await.unwrap().is_saved());
+    assert!(db.save_event(&events[1]).await.unwrap().is_success());
+    assert_eq!(
+        db.try_save_event(&events[2]).await.unwrap(),
+        SaveOutcome::RateLimited
+    );
+    assert!(!db.save_event(&events[3]).await.unwrap().is_success());
+    let report = db.save_events(events[4..].to_vec()).await.unwrap();
+    assert_eq!((report.imported, report.rejected), (0, 1));
+}
@@ -0,0 +1,85 @@
+use nostr::{EventBuilder, Filter, Keys};
+use nostr_database::NostrDatabase;
+use nostr_postgres_db::NostrPostgres;
+
+use crate::common::db;
+
+/// an event of a new author and the filter for it
+fn event() -> (nostr::Event, Filter) {
+    let keys = Keys::generate();
+    let event = EventBuilder::text_note("replicated")
+        .sign_with_keys(&keys)
+        .unwrap();
+    (event, Filter::new().author(keys.public_key()))
+}
+
+/// a connection string to a database that does not exist on the server of `connection_string`
+fn missing_database(connection_string: &str) -> String {
+    let (server, _) = connection_string.rsplit_once('/').unwrap();
+    format!("{server}/nostr_test_missing_replica")
+}
+
+#[tokio::test]
+async fn reads_go_to_the_replica_and_writes_to_the_primary() {
+    // a second database stands in for the replica, so it is visible where a statement ran
+    let primary = db("replica_primary").await;
+    let replica = db("replica_replica").await;
+    let store = NostrPostgres::builder(primary.connection_string())
+        .read_replica(replica.connection_string())
+        .build()
+        .await
+        .unwrap();
+
+    let (written, written_filter) = event();
+    assert!(store.save_event(&written).await.unwrap().is_success());
+    assert_eq!(primary.count(written_filter.clone()).await.unwrap(), 1);
+    assert_eq!(replica.count(written_filter.clone()).await.unwrap(), 0);
+    assert_eq!(store.count(written_filter.clone()).await.unwrap(), 0);
+    assert_eq!(store.event_by_id(&written.id).await.unwrap(), None);
+    let on_primary: Vec<_> = store
+        .query_on_primary(written_filter)
+        .await
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(on_primary, [written]);
+
+    let (replicated, replicated_filter) = event();
+    assert!(replica.save_event(&replicated).await.unwrap().is_success());
+    let read: Vec<_> = store
+        .query(replicated_filter)
+        .await
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(read, [replicated]);
+}
+
+#[tokio::test]
+async fn an_unavailable_replica_falls_back_to_the_primary() {
+    let primary = db("replica_fallback").await;
+    let (event, filter) = event();
+    assert!(primary.save_event(&event).await.unwrap().is_success());
+    let store = NostrPostgres::builder(primary.connection_string())
+        .read_replica(missing_database(primary.connection_string()))
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(store.count(filter).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn without_fallback_an_unavailable_replica_fails_the_read() {
+    let primary = db("replica_no_fallback").await;
+    let (event, filter) = event();
+    let store = NostrPostgres::builder(primary.connection_string())
+        .read_replica(missing_database(primary.connection_string()))
+        .replica_fallback(false)
+        .build()
+        .await
+        .unwrap();
+    // writes don't touch the replica
+    assert!(store.save_event(&event).await.unwrap().is_success());
+    assert!(store.count(filter.clone()).await.is_err());
+    assert!(store.query(filter).await.is_err());
+}
@@ -0,0 +1,57 @@
+use std::net::TcpListener;
+use std::time::Duration;
+
+use nostr_postgres_db::{ConnectRetry, ConnectRetryError, NostrPostgres};
+
+use crate::common::downcast;
+
+const BACKOFF: Duration = Duration::from_millis(20);
+
+/// a connection string to a local port nothing listens on
+fn closed_port() -> String {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    format!("postgres://postgres@127.0.0.1:{port}/nostr")
+}
+
+/// the attempts made and the time spent building an instance on a closed port with `retry`
+async fn connect(retry: ConnectRetry) -> (u32, Duration) {
+    let err = NostrPostgres::builder(closed_port())
+        .retry(retry)
+        .build()
+        .await
+        .unwrap_err();
+    let err = downcast::<ConnectRetryError>(&err).unwrap_or_else(|| panic!("{err}"));
+    (err.attempts(), err.elapsed())
+}
+
+#[tokio::test]
+async fn gives_up_after_the_last_attempt() {
+    let (attempts, elapsed) = connect(ConnectRetry::exponential(BACKOFF, 3)).await;
+    assert_eq!(attempts, 3);
+    // slept 20ms and 40ms between the attempts, which fail right away
+    assert!(elapsed >= BACKOFF * 3, "{elapsed:?}");
+    assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+}
+
+#[tokio::test]
+async fn the_backoff_is_capped() {
+    let retry = ConnectRetry::exponential(BACKOFF, 5).max_backoff(BACKOFF);
+    let (attempts, elapsed) = connect(retry).await;
+    assert_eq!(attempts, 5);
+    assert!(elapsed >= BACKOFF * 4, "{elapsed:?}");
+    assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+}
+
+#[tokio::test]
+async fn no_attempt_starts_past_the_deadline() {
+    let deadline = Duration::from_millis(50);
+    let retry = ConnectRetry::exponential(BACKOFF, 10).deadline(deadline);
+    let (attempts, elapsed) = connect(retry).await;
+    // a third attempt would only start after 20ms + 40ms
+    assert_eq!(attempts, 2);
+    assert!(elapsed >= BACKOFF && elapsed < deadline, "{elapsed:?}");
+}
@@ -0,0 +1,104 @@
+use nostr::{EventBuilder, Filter, Keys};
+use nostr_database::NostrDatabase;
+use nostr_postgres_db::{
+    NostrPostgres, SchemaMismatchError, migration_status, run_migrations, run_migrations_in_schema,
+};
+
+use crate::common::{Empty, downcast, empty};
+
+/// names of the tables in `schema`
+async fn tables(db: &Empty, schema: &str) -> Vec<String> {
+    db.client()
+        .await
+        .query(
+            "SELECT table_name::text FROM information_schema.tables \
+             WHERE table_schema = $1 ORDER BY table_name",
+            &[&schema],
+        )
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect()
+}
+
+#[tokio::test]
+async fn stores_events_in_a_custom_schema() {
+    let db = empty("custom_schema").await;
+    run_migrations_in_schema(db.connection_string(), "relay").unwrap();
+    let status = migration_status(db.connection_string(), Some("relay")).unwrap();
+    assert!(status.pending.is_empty(), "{status:?}");
+    let store = NostrPostgres::builder(db.connection_string())
+        .schema("relay")
+        .skip_migrations()
+        .build()
+        .await
+        .unwrap();
+    store.verify_schema().await.unwrap();
+
+    let keys = Keys::generate();
+    let event = EventBuilder::text_note("in a schema of its own")
+        .sign_with_keys(&keys)
+        .unwrap();
+    assert!(store.save_event(&event).await.unwrap().is_success());
+    let filter = Filter::new().author(keys.public_key());
+    let events: Vec<_> = store
+        .query(filter.clone())
+        .await
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(events, [event]);
+    store.delete(filter.clone()).await.unwrap();
+    assert_eq!(store.count(filter).await.unwrap(), 0);
+
+    let relay = tables(&db, "relay").await;
+    assert!(relay.iter().any(|t| t == "events"), "{relay:?}");
+    assert!(relay.iter().any(|t| t == "__diesel_schema_migrations"));
+    assert_eq!(tables(&db, "public").await, Vec::<String>::new());
+}
+
+#[tokio::test]
+async fn skip_migrations_uses_a_schema_provisioned_elsewhere() {
+    let db = empty("skip_migrations").await;
+    // provisioned by other tooling, which keeps no record of the migrations
+    run_migrations(db.connection_string()).unwrap();
+    db.client()
+        .await
+        .batch_execute("DROP TABLE __diesel_schema_migrations")
+        .await
+        .unwrap();
+
+    let store = NostrPostgres::builder(db.connection_string())
+        .skip_migrations()
+        .build()
+        .await
+        .unwrap();
+    store.verify_schema().await.unwrap();
+    let event = EventBuilder::text_note("provisioned")
+        .sign_with_keys(&Keys::generate())
+        .unwrap();
+    assert!(store.save_event(&event).await.unwrap().is_success());
+    assert_eq!(store.event_by_id(&event.id).await.unwrap(), Some(event));
+    assert!(
+        !tables(&db, "public")
+            .await
+            .iter()
+            .any(|t| t == "__diesel_schema_migrations")
+    );
+}
+
+#[tokio::test]
+async fn verify_schema_names_the_missing_tables() {
+    let db = empty("verify_empty").await;
+    let store = NostrPostgres::builder(db.connection_string())
+        .skip_migrations()
+        .build()
+        .await
+        .unwrap();
+    let err = store.verify_schema().await.unwrap_err();
+    let missing = downcast::<SchemaMismatchError>(&err).unwrap().missing();
+    for table in ["table events", "table event_tags"] {
+        assert!(missing.iter().any(|m| m == table), "{missing:?}");
+    }
+}
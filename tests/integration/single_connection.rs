@@ -0,0 +1,66 @@
+use futures_util::future::try_join_all;
+use nostr::{EventBuilder, Filter, Keys};
+use nostr_database::NostrDatabase;
+use nostr_postgres_db::NostrPostgres;
+
+use crate::common::empty;
+
+#[tokio::test]
+async fn saves_queries_and_deletes_on_one_connection() {
+    let db = empty("single_connection").await;
+    let store = NostrPostgres::single_connection(db.connection_string())
+        .await
+        .unwrap();
+    assert_eq!(store.pool_status().max_size, 1);
+
+    let keys = Keys::generate();
+    let events: Vec<_> = (0..3)
+        .map(|i| {
+            EventBuilder::text_note(format!("note {i}"))
+                .sign_with_keys(&keys)
+                .unwrap()
+        })
+        .collect();
+    // concurrent calls take turns on the connection
+    let statuses = try_join_all(events.iter().map(|e| store.save_event(e)))
+        .await
+        .unwrap();
+    assert!(statuses.iter().all(|s| s.is_success()));
+    let filter = Filter::new().author(keys.public_key());
+    let counts = try_join_all((0..3).map(|_| store.count(filter.clone())))
+        .await
+        .unwrap();
+    assert_eq!(counts, [3, 3, 3]);
+    assert_eq!(
+        store.event_by_id(&events[0].id).await.unwrap(),
+        Some(events[0].clone())
+    );
+
+    store.delete(filter.clone()).await.unwrap();
+    assert!(store.query(filter).await.unwrap().is_empty());
+    assert_eq!(store.pool_status().size, 1);
+}
+
+#[tokio::test]
+async fn a_dropped_connection_is_re_established() {
+    let db = empty("single_connection_dropped").await;
+    let store = NostrPostgres::single_connection(db.connection_string())
+        .await
+        .unwrap();
+    store.ping().await.unwrap();
+    let terminated: bool = db
+        .client()
+        .await
+        .query_one(
+            "SELECT pg_terminate_backend(pid) FROM pg_stat_activity \
+             WHERE datname = current_database() AND pid <> pg_backend_pid() \
+             AND application_name = 'nostr-postgres-db'",
+            &[],
+        )
+        .await
+        .unwrap()
+        .get(0);
+    assert!(terminated);
+    store.ping().await.unwrap();
+    assert_eq!(store.count(Filter::new()).await.unwrap(), 0);
+}
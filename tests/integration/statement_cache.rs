@@ -0,0 +1,80 @@
+use diesel::dsl::sql;
+use diesel::sql_types::{BigInt, Integer};
+use diesel_async::RunQueryDsl;
+use nostr::{Alphabet, EventBuilder, Filter, Keys, Kind, SingleLetterTag, Tag, TagKind};
+use nostr_database::NostrDatabase;
+use nostr_postgres_db::NostrPostgres;
+
+use crate::common::db_with;
+
+/// the backend of the only pooled connection and the statements prepared on it
+async fn prepared(db: &NostrPostgres) -> (i32, i64) {
+    let mut conn = db.pool().get().await.unwrap();
+    let pid = diesel::select(sql::<Integer>("pg_backend_pid()"))
+        .get_result(&mut conn)
+        .await
+        .unwrap();
+    // without the cache the running statement is still listed, so it is left out
+    let statements = diesel::select(sql::<BigInt>(
+        "(SELECT count(*) FROM pg_prepared_statements \
+         WHERE statement NOT LIKE '%pg_prepared_statements%')",
+    ))
+    .get_result(&mut conn)
+    .await
+    .unwrap();
+    (pid, statements)
+}
+
+/// saves, reads and counts an event, querying it by the tags in `letters` and `kinds`
+async fn round_trip(db: &NostrPostgres, letters: &[Alphabet], kinds: &[u16]) {
+    let keys = Keys::generate();
+    let tags = letters.iter().map(|l| {
+        Tag::custom(
+            TagKind::SingleLetter(SingleLetterTag::lowercase(*l)),
+            ["value"],
+        )
+    });
+    let event = EventBuilder::new(Kind::from(kinds[0]), "cached")
+        .tags(tags)
+        .sign_with_keys(&keys)
+        .unwrap();
+    assert!(db.save_event(&event).await.unwrap().is_success());
+    assert_eq!(
+        db.event_by_id(&event.id).await.unwrap(),
+        Some(event.clone())
+    );
+    let mut filter = Filter::new()
+        .author(keys.public_key())
+        .kinds(kinds.iter().map(|k| Kind::from(*k)));
+    for letter in letters {
+        filter = filter.custom_tag(SingleLetterTag::lowercase(*letter), "value");
+    }
+    assert_eq!(db.count(filter.clone()).await.unwrap(), 1);
+    let events: Vec<_> = db.query(filter).await.unwrap().into_iter().collect();
+    assert_eq!(events, [event]);
+}
+
+#[tokio::test]
+async fn filter_statements_are_not_cached() {
+    let db = db_with("statement_cache", |builder| builder.max_connections(1)).await;
+    round_trip(&db, &[Alphabet::T], &[1]).await;
+    let (pid, cached) = prepared(&db).await;
+    assert!(cached > 0);
+
+    // new filter shapes, on the same recycled connection reusing the cached statements
+    let runs: Vec<u16> = (100..140).collect();
+    round_trip(&db, &[Alphabet::A, Alphabet::B], &[1, 7]).await;
+    round_trip(&db, &[Alphabet::C], &runs).await;
+    round_trip(&db, &[Alphabet::T], &[1]).await;
+    assert_eq!(prepared(&db).await, (pid, cached));
+}
+
+#[tokio::test]
+async fn the_cache_can_be_disabled() {
+    let db = db_with("statement_cache_disabled", |builder| {
+        builder.max_connections(1).statement_cache(false)
+    })
+    .await;
+    round_trip(&db, &[Alphabet::T], &[1]).await;
+    assert_eq!(prepared(&db).await.1, 0);
+}
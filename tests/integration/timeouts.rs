@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use nostr::Filter;
+use nostr_database::NostrDatabase;
+use nostr_postgres_db::{ErrorClass, TimeoutError};
+
+use crate::common::{db, db_with, downcast, lock_events};
+
+const QUERY_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[tokio::test]
+async fn a_query_running_past_the_query_timeout_is_cancelled() {
+    let db = db_with("query_timeout", |builder| {
+        builder.query_timeout(QUERY_TIMEOUT)
+    })
+    .await;
+    let lock = lock_events(db.connection_string()).await;
+
+    let err = db.query(Filter::new()).await.unwrap_err();
+    let timeout = downcast::<TimeoutError>(&err).unwrap();
+    assert_eq!(timeout.operation(), "query");
+    assert_eq!(timeout.timeout(), QUERY_TIMEOUT);
+
+    let err = db.try_query(Filter::new()).await.unwrap_err();
+    assert_eq!(err.class(), ErrorClass::Timeout);
+    assert_eq!(err.sqlstate(), Some("57014"));
+
+    // the connection is usable again once the lock is gone
+    lock.batch_execute("COMMIT").await.unwrap();
+    assert!(db.query(Filter::new()).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn query_with_timeout_overrides_the_configured_timeout() {
+    let db = db("query_with_timeout").await;
+    let lock = lock_events(db.connection_string()).await;
+    let err = db
+        .query_with_timeout(Filter::new(), QUERY_TIMEOUT)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        downcast::<TimeoutError>(&err).unwrap().timeout(),
+        QUERY_TIMEOUT
+    );
+    lock.batch_execute("COMMIT").await.unwrap();
+}